@@ -0,0 +1,43 @@
+//! Compares the time to run a network of 64 nodes with sequential and with parallel message
+//! processing on one thread per available CPU, see Network::set_threads. With a single CPU, the
+//! parallel run uses two threads, which shows the overhead of the threads rather than a speedup.
+//! Run with `cargo bench --bench parallel_delivery`.
+
+use consensus::{ConsensusBuilder, Workload};
+use std::time::{Duration, Instant};
+
+const N: usize = 64;
+const EPOCHS: usize = 3;
+const ITERATIONS: u32 = 3;
+
+fn run(threads: usize) -> Duration {
+    let mut total = Duration::ZERO;
+    for _ in 0..ITERATIONS {
+        let mut network = ConsensusBuilder::new(N).threads(threads).build();
+        for (i, tx) in Workload::default().transactions(N).into_iter().enumerate() {
+            network.nodes[i % N].send_transaction(tx);
+        }
+        let start = Instant::now();
+        network.run_simple(EPOCHS);
+        total += start.elapsed();
+    }
+    total / ITERATIONS
+}
+
+fn main() {
+    let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+    let threads = cpus.max(2);
+    let sequential = run(1);
+    let parallel = run(threads);
+    eprintln!(
+        "n = {}, {} epochs: sequential {:?}, parallel {:?} on {} threads with {} CPU(s), \
+         speedup {:.2}x",
+        N,
+        EPOCHS,
+        sequential,
+        parallel,
+        threads,
+        cpus,
+        sequential.as_secs_f64() / parallel.as_secs_f64()
+    );
+}
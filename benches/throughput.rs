@@ -0,0 +1,112 @@
+//! Measures where time goes as the number of nodes grows: the time to run a network of n nodes
+//! for a fixed number of epochs, and the cost of the building blocks of each epoch, hashing a new
+//! block and notarizing a block given a quorum of votes. Run with
+//! `cargo bench --bench throughput > /dev/null`, as nodes log to stdout and results go to stderr.
+
+use consensus::{Block, HashAlgo, Network, Node, Transaction, Workload};
+use std::time::{Duration, Instant};
+
+const NETWORK_SIZES: [usize; 4] = [4, 10, 31, 100];
+const EPOCHS: usize = 5;
+const ITERATIONS: u32 = 3;
+// The transactions per block and the number of blocks of the microbenchmarks
+const TXS_PER_BLOCK: usize = 100;
+const BLOCKS: usize = 200;
+// The number of validators of the notarization microbenchmark
+const VALIDATORS: usize = 100;
+
+fn run_simple(n: usize) -> Duration {
+    let mut total = Duration::ZERO;
+    for _ in 0..ITERATIONS {
+        let mut network = Network::new(n);
+        for (i, tx) in Workload::default().transactions(n).into_iter().enumerate() {
+            network.nodes[i % n].send_transaction(tx);
+        }
+        let start = Instant::now();
+        network.run_simple(EPOCHS);
+        total += start.elapsed();
+    }
+    total / ITERATIONS
+}
+
+fn transactions(e: usize) -> Vec<Transaction> {
+    (0..TXS_PER_BLOCK)
+        .map(|i| Transaction::new(i, e as u64, format!("transaction {} of epoch {}", i, e)))
+        .collect()
+}
+
+/// Returns the average time to create, and thus hash, a block of TXS_PER_BLOCK transactions
+fn block_new() -> Duration {
+    let txs: Vec<Vec<Transaction>> = (1..=BLOCKS).map(transactions).collect();
+    let genesis = Block::new(
+        None,
+        0,
+        Vec::new(),
+        "genesis".to_string(),
+        0,
+        HashAlgo::default(),
+    )
+    .hash;
+    let start = Instant::now();
+    for (e, txs) in txs.into_iter().enumerate() {
+        Block::new(
+            Some(genesis),
+            e + 1,
+            txs,
+            format!("{}", e + 1),
+            1,
+            HashAlgo::default(),
+        );
+    }
+    start.elapsed() / BLOCKS as u32
+}
+
+/// Returns the average time to notarize a block with the votes of all but the fewest validators
+/// that still form a quorum. The blocks all extend genesis, so none of them gets finalized.
+fn notarize() -> Duration {
+    let mut node = Node::new(0, (0..VALIDATORS).collect(), 2);
+    let genesis = node.chain.genesis;
+    let quorum = 2 * VALIDATORS / 3 + 1;
+    let blocks: Vec<_> = (1..=BLOCKS)
+        .map(|e| {
+            let block = Block::new(
+                Some(genesis),
+                e,
+                transactions(e),
+                format!("{}", e),
+                1,
+                HashAlgo::default(),
+            );
+            let block_hash = block.hash;
+            node.chain.validate_and_extend(block, genesis);
+            node.chain.votes.insert(block_hash, (0..quorum).collect());
+            block_hash
+        })
+        .collect();
+    let start = Instant::now();
+    for block_hash in &blocks {
+        node.notarize(*block_hash);
+    }
+    let elapsed = start.elapsed();
+    assert!(blocks
+        .iter()
+        .all(|block_hash| node.chain.notarized.contains(block_hash)));
+    elapsed / BLOCKS as u32
+}
+
+fn main() {
+    for n in NETWORK_SIZES {
+        eprintln!(
+            "run_simple, n = {}, {} epochs: {:?}",
+            n,
+            EPOCHS,
+            run_simple(n)
+        );
+    }
+    eprintln!(
+        "Block::new, {} transactions: {:?}",
+        TXS_PER_BLOCK,
+        block_new()
+    );
+    eprintln!("notarize, {} validators: {:?}", VALIDATORS, notarize());
+}
@@ -0,0 +1,72 @@
+//! Extends the protocol by a custom kind of message without changing the node: after a run, each
+//! node announces its highest finalized block, and the other nodes check it against their chains.
+//!
+//! cargo run --example custom_message
+
+use consensus::{ConsensusError, Message, MessageHandler, Network, Node};
+use std::any::Any;
+use std::sync::Arc;
+
+/// Announces the highest finalized block of its creator. Implementing Message is all it takes to
+/// define a new kind of message.
+#[derive(Clone, Debug)]
+struct CheckpointMessage {
+    creator: usize,
+    block_hash: [u8; 32],
+}
+
+impl Message for CheckpointMessage {
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+    fn creator(&self) -> usize {
+        self.creator
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn name(&self) -> String {
+        format!("<CheckpointM: {}>", hex::encode(&self.block_hash[0..2]))
+    }
+    fn kind(&self) -> &'static str {
+        "checkpoint"
+    }
+    fn size_bytes(&self) -> usize {
+        self.block_hash.len() + 8
+    }
+}
+
+fn main() {
+    let n = 4;
+    let mut network = Network::new(n);
+    network.run(10);
+
+    // A checkpoint is valid if the receiver finalized the announced block as well. Blocks the
+    // receiver has not finalized yet are rejected, and logged as an attack.
+    let handler: MessageHandler = Arc::new(|node: &mut Node, m: &dyn Message, j: usize| {
+        let checkpoint = m.as_any().downcast_ref::<CheckpointMessage>().unwrap();
+        if !node.chain.finalized.contains(&checkpoint.block_hash) {
+            return Err(ConsensusError::InvalidMessage { kind: m.kind() });
+        }
+        println!(
+            "Node {} confirms the checkpoint {} of node {}",
+            node.id,
+            m.name(),
+            j
+        );
+        Ok(())
+    });
+    network.register_handler("checkpoint", handler);
+
+    for i in 0..n {
+        let node = network.nodes[i].as_any().downcast_ref::<Node>().unwrap();
+        let checkpoint = CheckpointMessage {
+            creator: i,
+            block_hash: *node.chain.highest_finalized_block(),
+        };
+        for j in (0..n).filter(|j| *j != i) {
+            network.send(i, Box::new(checkpoint.clone()), j);
+        }
+    }
+    network.drain();
+}
@@ -0,0 +1,64 @@
+//! Runs a single honest node that exchanges messages with its peers over TCP, e.g., for 4 nodes
+//! starting 2 seconds from now:
+//!
+//! START=$(($(date +%s%3N) + 2000))
+//! PEERS="127.0.0.1:7000 127.0.0.1:7001 127.0.0.1:7002 127.0.0.1:7003"
+//! for i in 0 1 2 3; do cargo run --bin tcp_node -- $i 20 300 $START $PEERS & done
+//!
+//! The arguments are the node id, the number of epochs, the duration of an epoch in milliseconds,
+//! the start time in milliseconds since the Unix epoch, and the addresses of all nodes. At the
+//! end, the node prints its finalized chain.
+
+use consensus::consensus::node::DEFAULT_FINALIZATION_DEPTH;
+use consensus::{Node, TcpTransport};
+use std::env;
+use std::net::SocketAddr;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 6 {
+        eprintln!("Usage: tcp_node <id> <epochs> <epoch_ms> <start_ms> <address>...");
+        process::exit(1);
+    }
+    let number = |i: usize| -> u64 {
+        args[i].parse().unwrap_or_else(|_| {
+            eprintln!("Expected an integer, got `{}`", args[i]);
+            process::exit(1);
+        })
+    };
+    let (id, epochs) = (number(0) as usize, number(1) as usize);
+    let (epoch_ms, start_ms) = (number(2), number(3));
+    let peers: Vec<SocketAddr> = args[4..]
+        .iter()
+        .map(|addr| {
+            addr.parse().unwrap_or_else(|_| {
+                eprintln!("Expected an address, got `{}`", addr);
+                process::exit(1);
+            })
+        })
+        .collect();
+    let n = peers.len();
+    let mut transport = TcpTransport::bind(id, peers).unwrap_or_else(|err| {
+        eprintln!("Cannot listen: {}", err);
+        process::exit(1);
+    });
+    let start = UNIX_EPOCH + Duration::from_millis(start_ms);
+    let timeout = start
+        .duration_since(SystemTime::now())
+        .unwrap_or(Duration::ZERO);
+    if let Err(err) = transport.connect_all(timeout) {
+        eprintln!("Cannot connect to all peers: {}", err);
+        process::exit(1);
+    }
+    let mut node = Node::new(id, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+    transport.run(&mut node, start, epochs, Duration::from_millis(epoch_ms));
+    let chain: Vec<String> = node
+        .chain
+        .finalized_chain()
+        .iter()
+        .map(hex::encode)
+        .collect();
+    println!("finalized chain: {}", chain.join(" "));
+}
@@ -1,8 +1,69 @@
-use super::blockchain::{Block, BlockMessage, Message, MessageType, VoteMessage};
-use super::node::{Node, NodeTrait};
-use super::utils::Crypto;
+use super::blockchain::{
+    Block, BlockMessage, ChainParams, GenesisConfig, GenesisError, Message, MessageType,
+    Transaction, VoteMessage,
+};
+use super::events::EventRecord;
+use super::fork_choice::ForkChoice;
+use super::leader_election::LeaderElection;
+use super::mempool::MempoolPolicy;
+use super::node::{ConsensusError, MessageHandler, Node, NodeStats, NodeTrait, TxPoolOverflow};
+use super::utils::{Crypto, Hash};
 use std::any::Any;
 use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// How many copies of each received message a flooding attacker sends to each peer
+pub const FLOOD_COPIES: usize = 10;
+
+/// Shared state of colluding attacker nodes. Equivocating attackers that share a coordinator
+/// send their first message to the same half of the honest nodes and their second message to the
+/// other half, which maximizes the chance of conflicting notarizations. They send both messages
+/// to each other.
+#[derive(Debug, Default)]
+pub struct AttackerCoordinator {
+    // The ids of the colluding attackers
+    attackers: HashSet<usize>,
+    // The honest nodes that receive the first of two equivocating messages, chosen on first use
+    first_half: Option<HashSet<usize>>,
+}
+
+impl AttackerCoordinator {
+    pub fn new(attackers: HashSet<usize>) -> Self {
+        AttackerCoordinator {
+            attackers,
+            first_half: None,
+        }
+    }
+
+    /// Returns whether node i is one of the colluding attackers
+    pub fn is_colluding(&self, i: usize) -> bool {
+        self.attackers.contains(&i)
+    }
+
+    /// Returns the honest validators that receive the first of two equivocating messages: the
+    /// lower half of them by id, rounded up. The split is the same for all colluding attackers.
+    pub fn first_half(&mut self, validators: &[usize]) -> HashSet<usize> {
+        let attackers = &self.attackers;
+        self.first_half
+            .get_or_insert_with(|| {
+                let mut honest: Vec<usize> = validators
+                    .iter()
+                    .copied()
+                    .filter(|v| !attackers.contains(v))
+                    .collect();
+                honest.sort();
+                honest.truncate(honest.len().div_ceil(2));
+                honest.into_iter().collect()
+            })
+            .clone()
+    }
+
+    /// Forget the split of the honest validators, so that first_half splits the validator set
+    /// anew on its next use
+    pub fn reset_first_half(&mut self) {
+        self.first_half = None;
+    }
+}
 
 /// This struct represents an attacker node. The attacker configuration can
 /// have the following options:
@@ -13,121 +74,293 @@ use std::collections::HashSet;
 ///         "vote_everything", # Vote for all blocks
 ///         "equivocate", # Propose different blocks to different nodes
 ///         "fake_block_signature" # Produce blocks with an invalid signature
+///         "flood" # Re-send every received message many times to all peers
+///         "withhold_votes" # Participate normally, but never send own votes
+///         "vote_all_forks" # Cast validly signed votes for every block received, on every fork
+///         "old_parent" # Propose on the grandparent of the highest notarized block, orphaning it
+///         "vote_equivocate" # Vote for one of two competing blocks towards each half of the nodes
+///         "forge_votes" # Send votes for every block received in the name of all other validators
+///         "withhold_relay" # Participate normally, but never relay blocks proposed by others
+///         "oversized_block" # Propose blocks of the maximal valid size and of one byte more
 /// }
+/// Attackers collude if they share an AttackerCoordinator, see set_coordinator.
 /// Note that (adversarial) network behavior is covered in the Network class.
 pub struct AttackerNode {
     node: Node,
     attacker_config: HashSet<String>,
+    // The blocks we already cast a vote for as part of the vote_all_forks attack
+    voted_forks: HashSet<Hash>,
+    // The epochs in which we already sent conflicting votes as part of the vote_equivocate attack
+    vote_equivocated: HashSet<usize>,
+    // The coordinator we share with the other colluding attackers, if any
+    coordinator: Option<Arc<Mutex<AttackerCoordinator>>>,
 }
 impl AttackerNode {
-    pub fn new(id: usize, n: usize, attacker_config: HashSet<String>) -> Self {
-        AttackerNode {
-            node: Node::new(id, n),
+    pub fn new(id: usize, validators: Vec<usize>, attacker_config: HashSet<String>) -> Self {
+        AttackerNode::with_genesis(
+            id,
             attacker_config,
-        }
+            GenesisConfig::with_validators(validators),
+        )
+        .unwrap_or_else(|err| panic!("{}", err))
     }
 
-    pub fn new_epoch(&mut self, e: usize) {
+    /// Creates an attacker starting from the genesis block of the given configuration, see
+    /// Node::with_genesis
+    pub fn with_genesis(
+        id: usize,
+        attacker_config: HashSet<String>,
+        genesis_config: GenesisConfig,
+    ) -> Result<Self, GenesisError> {
+        Ok(AttackerNode {
+            node: Node::with_genesis(id, genesis_config)?,
+            attacker_config,
+            voted_forks: HashSet::new(),
+            vote_equivocated: HashSet::new(),
+            coordinator: None,
+        })
+    }
+
+    /// Collude with the other attackers that share the given coordinator
+    pub fn set_coordinator(&mut self, coordinator: Arc<Mutex<AttackerCoordinator>>) {
+        self.coordinator = Some(coordinator);
+    }
+
+    pub fn new_epoch(&mut self, e: usize, clock: u64) {
+        self.node.enter_epoch(e, clock);
         // If the attacker is configured to fail-stop, it does not participate in the protocol
         if self.attacker_config.contains("fail_stop") {
             return;
         }
         // If the attacker is configured to always act like the leader, it proposes a block in each epoch
         if self.node.leader(e) == self.node.id || self.attacker_config.contains("always_leader") {
-            self.propose_block(e);
+            if let Err(err) = self.propose_block(e) {
+                self.node.dbg_type(
+                    &format!("Cannot propose a block for epoch {}: {}", e, err),
+                    Some("ERROR"),
+                );
+            }
         }
     }
 
-    pub fn propose_block(&mut self, e: usize) -> Block {
+    /// Returns the block to propose on: the highest notarized block, or, if configured, its
+    /// grandparent (or the oldest stored block if it has none)
+    fn proposal_parent(&self) -> Hash {
+        let highest = self.node.chain.get_highest_notarized_block();
+        if !self.attacker_config.contains("old_parent") {
+            return highest;
+        }
+        let parent = self
+            .node
+            .chain
+            .parent_of(highest)
+            .unwrap_or(self.node.chain.root);
+        self.node
+            .chain
+            .parent_of(parent)
+            .unwrap_or(self.node.chain.root)
+    }
+
+    pub fn propose_block(&mut self, e: usize) -> Result<Block, ConsensusError> {
+        let parent_hash = self.proposal_parent();
+        if self.attacker_config.contains("oversized_block") {
+            return self.propose_padded_block(e, parent_hash);
+        }
         if !self.attacker_config.contains("fake_block_signature")
             && !self.attacker_config.contains("equivocate")
         {
-            return self.node.propose_block(e);
+            return self.node.propose_block_on(e, parent_hash);
         }
 
-        let parent_hash = self.node.chain.get_highest_notarized_block().clone();
-        let parent = self
-            .node
-            .chain
-            .blocks
-            .get(&parent_hash.clone())
-            .unwrap()
-            .clone();
+        let Some(parent) = self.node.chain.blocks.get(&parent_hash).cloned() else {
+            return Err(ConsensusError::MissingParent {
+                parent: parent_hash,
+            });
+        };
         let mut name = format!("{}/{}", e, self.node.id);
         if self.attacker_config.contains("equivocate") {
             name.push_str(" equivocate #1");
         }
         let block1 = Block::new(
-            Some(parent_hash.clone()),
+            Some(parent_hash),
             e,
-            "1".to_string(),
+            vec![Transaction::new(self.node.id, e as u64, "1".to_string())],
             name.clone(),
             parent.height + 1,
-        );
-        self.node
+            self.node.chain.hash_algo(),
+        )
+        .with_timestamp(self.node.timestamp())
+        .with_proposer(Some(self.node.id));
+        if !self
+            .node
             .chain
-            .validate_and_extend(block1.clone(), parent_hash.clone());
-        let signed1 = (MessageType::BlockProposal, block1.hash.clone());
+            .validate_and_extend(block1.clone(), parent_hash)
+        {
+            return Err(ConsensusError::InvalidBlock);
+        }
+        let signed1 = (MessageType::BlockProposal, block1.hash);
         let signature1 = if !self.attacker_config.contains("fake_block_signature") {
             Crypto::sign(self.node.id as u64, &bincode::serialize(&signed1).unwrap())
         } else {
             (0, vec![])
         };
-        let block1_message = block1.to_block_message(self.node.id, signature1);
+        let block1_message = BlockMessage {
+            leader_proof: self.node.leadership_proof(e),
+            ..block1.to_block_message(self.node.id, signature1)
+        };
 
+        self.node.stats.blocks_proposed += 1;
         if !self.attacker_config.contains("equivocate") {
             self.broadcast_message(Box::new(block1_message));
         } else {
             let mut name2 = name.clone();
             name2.push_str(" equivocate #2");
             let block2 = Block::new(
-                Some(parent.hash.clone()),
+                Some(parent.hash),
                 e,
-                "2".to_string(),
+                vec![Transaction::new(self.node.id, e as u64, "2".to_string())],
                 name2,
                 parent.height + 1,
-            );
-            self.node
+                self.node.chain.hash_algo(),
+            )
+            .with_timestamp(self.node.timestamp())
+            .with_proposer(Some(self.node.id));
+            if !self
+                .node
                 .chain
-                .validate_and_extend(block2.clone(), parent_hash);
-            let signed2 = (MessageType::BlockProposal, block2.hash.clone());
+                .validate_and_extend(block2.clone(), parent_hash)
+            {
+                return Err(ConsensusError::InvalidBlock);
+            }
+            let signed2 = (MessageType::BlockProposal, block2.hash);
             let signature2 = if !self.attacker_config.contains("fake_block_signature") {
                 Crypto::sign(self.node.id as u64, &bincode::serialize(&signed2).unwrap())
             } else {
                 (0, vec![])
             };
-            let block2_message = block2.to_block_message(self.node.id, signature2);
+            let block2_message = BlockMessage {
+                leader_proof: self.node.leadership_proof(e),
+                ..block2.to_block_message(self.node.id, signature2)
+            };
+            self.node.stats.blocks_proposed += 1;
             self.node.dbg(&format!(
                 "Attacker equivocating and proposing blocks {} and {}",
                 block1, block2
             ));
-            self.node
-                .chain
-                .votes
-                .insert(block2.hash.clone(), HashSet::new());
-            let votes = self.node.chain.votes.get_mut(&block2.hash).unwrap();
-            votes.insert(self.node.id);
+            self.node.count_vote(block2.hash, e, self.node.id);
             self.equivocate_message(
                 Box::new(block1_message) as Box<dyn Message>,
                 Box::new(block2_message) as Box<dyn Message>,
             );
+            // Relays never send our own proposals back to us, so the vote_equivocate attack in
+            // receive_block would not see these two blocks. We hold both already, so vote now.
+            if self.attacker_config.contains("vote_equivocate") {
+                self.equivocate_vote(e);
+            }
         }
-        self.node
-            .chain
-            .votes
-            .insert(block1.hash.clone(), HashSet::new());
-        self.node
+        self.node.count_vote(block1.hash, e, self.node.id);
+        Ok(block1)
+    }
+
+    /// Returns transactions of ours whose serialized length is exactly size bytes, each with a
+    /// valid payload length. Their nonces do not collide with those of our other transactions.
+    pub fn padded_transactions(&self, e: usize, size: usize) -> Vec<Transaction> {
+        // Besides its payload, a serialized transaction holds its sender, nonce, fee, and the
+        // length of the payload
+        let overhead = Transaction::new(self.node.id, 0, String::new())
+            .serialize()
+            .len();
+        let max_tx = overhead + self.node.chain.params.max_single_tx;
+        let count = size.div_ceil(max_tx);
+        assert!(
+            size >= count * overhead,
+            "Cannot pad transactions to {} bytes",
+            size
+        );
+        let payload = size - count * overhead;
+        (0..count)
+            .map(|i| {
+                // Spread the payload evenly, the first transactions taking the remainder
+                let length = payload / count + usize::from(i < payload % count);
+                let nonce = ((e as u64) << 32) + i as u64;
+                Transaction::new(self.node.id, nonce, "x".repeat(length))
+            })
+            .collect()
+    }
+
+    /// Propose a block whose transactions have the maximal valid size in even epochs, and one byte
+    /// more in odd epochs, which honest nodes reject. Fails in odd epochs, since we cannot store
+    /// the block either.
+    fn propose_padded_block(
+        &mut self,
+        e: usize,
+        parent_hash: Hash,
+    ) -> Result<Block, ConsensusError> {
+        let Some(parent) = self.node.chain.blocks.get(&parent_hash).cloned() else {
+            return Err(ConsensusError::MissingParent {
+                parent: parent_hash,
+            });
+        };
+        let max_txs = self.node.chain.params.max_txs;
+        let size = if e.is_multiple_of(2) {
+            max_txs - 1
+        } else {
+            max_txs
+        };
+        let block = Block::new(
+            Some(parent_hash),
+            e,
+            self.padded_transactions(e, size),
+            format!("{}/{} padded to {}", e, self.node.id, size),
+            parent.height + 1,
+            self.node.chain.hash_algo(),
+        )
+        .with_timestamp(self.node.timestamp())
+        .with_proposer(Some(self.node.id));
+        self.node.dbg(&format!(
+            "Attacker proposing block {} of {} bytes",
+            block, size
+        ));
+        // We cannot store our own oversized block, but broadcast it all the same
+        let stored = self
+            .node
             .chain
-            .votes
-            .get_mut(&block1.hash)
-            .unwrap()
-            .insert(self.node.id);
-        block1
+            .validate_and_extend(block.clone(), parent_hash);
+        self.node.count_vote(block.hash, e, self.node.id);
+        let signed = (MessageType::BlockProposal, block.hash);
+        let signature = Crypto::sign(self.node.id as u64, &bincode::serialize(&signed).unwrap());
+        let block_message = BlockMessage {
+            leader_proof: self.node.leadership_proof(e),
+            ..block.to_block_message(self.node.id, signature)
+        };
+        self.node.stats.blocks_proposed += 1;
+        self.broadcast_message(Box::new(block_message));
+        if !stored {
+            return Err(ConsensusError::InvalidBlock);
+        }
+        Ok(block)
     }
 
-    /// This function sends two different messages to different nodes
+    /// This function sends two different messages to different nodes. Colluding attackers split
+    /// the honest nodes as their coordinator decides, and send both messages to each other.
     pub fn equivocate_message(&mut self, m1: Box<dyn Message>, m2: Box<dyn Message>) {
-        for i in 0..self.node.n {
+        if let Some(coordinator) = self.coordinator.clone() {
+            let mut coordinator = coordinator.lock().unwrap();
+            let first_half = coordinator.first_half(self.node.validators());
+            for i in self.node.validators().to_vec() {
+                if i == self.node.id {
+                    continue;
+                }
+                if first_half.contains(&i) || coordinator.is_colluding(i) {
+                    self.node.outgoing_messages.push_back((i, m1.clone()));
+                }
+                if !first_half.contains(&i) {
+                    self.node.outgoing_messages.push_back((i, m2.clone()));
+                }
+            }
+            return;
+        }
+        for i in self.node.validators().to_vec() {
             if i != self.node.id {
                 if i % 2 == 0 {
                     self.node.outgoing_messages.push_back((i, m1.clone()));
@@ -150,9 +383,16 @@ impl AttackerNode {
             return;
         }
         if let Some(block_message) = m.as_any().downcast_ref::<BlockMessage>() {
-            self.receive_block((*block_message).clone());
+            if let Err(err) = self.receive_block((*block_message).clone(), j) {
+                self.node.log_error(err, &m.name());
+            }
         } else {
-            self.node.incoming_message(&*m, j);
+            self.node.incoming_message(m, j);
+        }
+        if self.attacker_config.contains("flood") {
+            for _ in 0..FLOOD_COPIES {
+                self.node.broadcast_message(m.clone_box());
+            }
         }
     }
 
@@ -160,21 +400,105 @@ impl AttackerNode {
         let signature = (self.id as u64, b.signature.clone().1);
         VoteMessage {
             creator: b.creator,
-            parent_hash: b.parent_hash.clone(),
+            parent_hash: b.parent_hash,
             e: b.e,
             txs: b.txs.clone(),
             name: b.name.clone(),
             signer: self.id,
             signature,
+            hop: 0,
+            notarized_height: None,
+            timestamp: b.timestamp,
+            proposer: b.proposer,
+        }
+    }
+
+    /// Creates a vote for the block that carries a valid signature of ours, so that honest nodes
+    /// accept it
+    fn signed_vote(&self, b: &BlockMessage) -> VoteMessage {
+        let signed = (MessageType::Vote, b.block_hash(self.chain.hash_algo()));
+        let signed = bincode::serialize(&signed).unwrap();
+        VoteMessage {
+            signature: Crypto::sign(self.id as u64, &signed),
+            ..self.block_message_to_vote(b.clone())
         }
     }
 
-    pub fn receive_block(&mut self, b: BlockMessage) {
-        self.node.receive_block(b.clone());
+    /// Creates votes for the block in the name of all other validators. We cannot produce their
+    /// signatures, so the votes carry our own signature instead.
+    fn forged_votes(&self, b: &BlockMessage) -> Vec<VoteMessage> {
+        let own_vote = self.signed_vote(b);
+        self.node
+            .validators()
+            .iter()
+            .filter(|v| **v != self.id)
+            .map(|v| VoteMessage {
+                signer: *v,
+                ..own_vote.clone()
+            })
+            .collect()
+    }
+
+    /// Like signed_vote, but for a block we hold
+    fn signed_block_vote(&self, block: &Block) -> VoteMessage {
+        let signed = bincode::serialize(&(MessageType::Vote, block.hash)).unwrap();
+        block.to_vote_message(self.id, Crypto::sign(self.id as u64, &signed))
+    }
+
+    /// If we hold two competing blocks of epoch e, send a signed vote for one of them to one half
+    /// of the nodes, and a signed vote for the other to the other half. We do this once per epoch.
+    fn equivocate_vote(&mut self, e: usize) {
+        if self.vote_equivocated.contains(&e) {
+            return;
+        }
+        let Some(blocks) = self.node.chain.block_by_epoch.get(e) else {
+            return;
+        };
+        let mut blocks: Vec<Hash> = blocks.iter().copied().collect();
+        if blocks.len() < 2 {
+            return;
+        }
+        blocks.sort();
+        let block1 = self.node.chain.blocks[&blocks[0]].clone();
+        let block2 = self.node.chain.blocks[&blocks[1]].clone();
+        self.vote_equivocated.insert(e);
+        self.node.dbg(&format!(
+            "Attacker equivocating and voting for blocks {} and {}",
+            block1, block2
+        ));
+        self.equivocate_message(
+            Box::new(self.signed_block_vote(&block1)),
+            Box::new(self.signed_block_vote(&block2)),
+        );
+    }
+
+    pub fn receive_block(&mut self, b: BlockMessage, j: usize) -> Result<(), ConsensusError> {
+        let result = self.node.receive_block(b.clone(), j);
         if self.attacker_config.contains("vote_everything") {
-            let vote_message = self.block_message_to_vote(b);
+            let vote_message = self.block_message_to_vote(b.clone());
             self.node.broadcast_message(Box::new(vote_message));
         }
+        // Nothing at stake: vote for every block on every fork, regardless of the epochs we
+        // already voted in and of the height of the block
+        if self.attacker_config.contains("vote_all_forks")
+            && self
+                .voted_forks
+                .insert(b.block_hash(self.node.chain.hash_algo()))
+        {
+            self.node.dbg(&format!("Attacker voting for fork {}", b));
+            let vote_message = self.signed_vote(&b);
+            self.node.broadcast_message(Box::new(vote_message));
+        }
+        if self.attacker_config.contains("vote_equivocate") {
+            self.equivocate_vote(b.e);
+        }
+        if self.attacker_config.contains("forge_votes") {
+            self.node.dbg(&format!("Attacker forging votes for {}", b));
+            for vote in self.forged_votes(&b) {
+                self.node.broadcast_message(Box::new(vote));
+            }
+        }
+        result
     }
 }
 
@@ -206,19 +530,103 @@ impl NodeTrait for AttackerNode {
     }
 
     fn clear_outgoing_messages(&mut self) -> Vec<(usize, Box<dyn Message>)> {
-        let messages: Vec<_> = self.outgoing_messages.drain(..).collect();
+        let mut messages: Vec<_> = self.outgoing_messages.drain(..).collect();
+        // If the attacker is configured to withhold votes, its own votes never leave the node,
+        // while blocks and the votes of others are still relayed
+        if self.attacker_config.contains("withhold_votes") {
+            let id = self.node.id;
+            messages.retain(|(_, m)| {
+                m.as_any()
+                    .downcast_ref::<VoteMessage>()
+                    .is_none_or(|vote| vote.signer != id)
+            });
+        }
+        // A rational attacker does not pass on the blocks of its competitors, neither by relaying
+        // them nor when they are requested, hoping that its own proposals win instead
+        if self.attacker_config.contains("withhold_relay") {
+            let id = self.node.id;
+            messages.retain(|(_, m)| {
+                m.as_any()
+                    .downcast_ref::<BlockMessage>()
+                    .is_none_or(|block| block.signer == id)
+            });
+        }
         messages
     }
     fn process_unprocessed_pool(&mut self) {
         self.node.process_unprocessed_pool();
     }
-    fn send_transaction(&mut self, transaction: String) {
+    fn send_transaction(&mut self, transaction: Transaction) {
         self.node.send_transaction(transaction);
     }
-    fn new_epoch(&mut self, e: usize) {
-        self.new_epoch(e);
+    fn new_epoch(&mut self, e: usize, clock: u64) {
+        self.new_epoch(e, clock);
+    }
+    fn new_round(&mut self) {
+        if self.attacker_config.contains("fail_stop") {
+            return;
+        }
+        self.node.new_round();
+    }
+    fn delayed_proposals(&self) -> usize {
+        // A fail-stop attacker never broadcasts them
+        if self.attacker_config.contains("fail_stop") {
+            return 0;
+        }
+        self.node.delayed_proposals()
+    }
+    fn set_validators(&mut self, e: usize, validators: Vec<usize>) {
+        self.node.set_validators(e, validators);
+        if let Some(coordinator) = &self.coordinator {
+            coordinator.lock().unwrap().reset_first_half();
+        }
+    }
+    fn stats(&self) -> NodeStats {
+        self.node.stats
+    }
+    fn set_leader_election(&mut self, leader_election: Box<dyn LeaderElection>) {
+        self.node.set_leader_election(leader_election);
+    }
+    fn set_mempool_policy(&mut self, mempool_policy: Box<dyn MempoolPolicy>) {
+        self.node.set_mempool_policy(mempool_policy);
+    }
+    fn set_fork_choice(&mut self, fork_choice: Box<dyn ForkChoice>) {
+        self.node.set_fork_choice(fork_choice);
+    }
+    fn set_stake(&mut self, stake: Vec<u64>) {
+        self.node.set_stake(stake);
+    }
+    fn set_chain_params(&mut self, params: ChainParams) {
+        self.node.set_chain_params(params);
+    }
+    fn set_max_tx_pool(&mut self, max_tx_pool: usize, overflow: TxPoolOverflow) {
+        self.node.set_max_tx_pool(max_tx_pool, overflow);
+    }
+    fn set_empty_block_timeout(&mut self, timeout: Option<usize>) {
+        self.node.set_empty_block_timeout(timeout);
+    }
+    fn set_backup_leader_timeout(&mut self, timeout: Option<usize>) {
+        self.node.set_backup_leader_timeout(timeout);
+    }
+    fn register_handler(&mut self, kind: &'static str, handler: MessageHandler) {
+        self.node.register_handler(kind, handler);
+    }
+    fn events(&self) -> &[EventRecord] {
+        &self.node.events
+    }
+    fn events_emitted(&self) -> usize {
+        self.node.events_emitted
+    }
+    fn timeout(&mut self, e: usize) {
+        if self.attacker_config.contains("fail_stop") {
+            return;
+        }
+        self.node.timeout(e);
     }
     fn as_any(&self) -> &dyn Any {
         self
     }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
 }
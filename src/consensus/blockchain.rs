@@ -1,92 +1,410 @@
 use super::utils;
-use utils::{Crypto, Debug, Signature, Hash};
 use hex;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use utils::{Crypto, Debug, Hash, HashAlgo, MerkleProof, Signature, VrfProof};
 
+/// Default of ChainParams::max_txs
 pub const MAXLENGTH_TXS: usize = 10000;
+/// Default of ChainParams::max_single_tx
 pub const MAXLENGTH_SINGLE_TX: usize = 2000;
 
+/// Parameters of the chain, which all nodes must agree on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainParams {
+    // The serialized transactions of a block must be shorter than max_txs bytes
+    pub max_txs: usize,
+    // The payload of a transaction must have at most max_single_tx characters
+    pub max_single_tx: usize,
+    // If set, blocks carry the time of their proposal, and a block is invalid if its timestamp
+    // is more than max_clock_skew ahead of our clock, or before the timestamp of its parent
+    pub max_clock_skew: Option<u64>,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams {
+            max_txs: MAXLENGTH_TXS,
+            max_single_tx: MAXLENGTH_SINGLE_TX,
+            max_clock_skew: None,
+        }
+    }
+}
+
+/// The configuration of the genesis block, which all nodes of a network must agree on. Since
+/// every block hash covers the genesis hash through its ancestors, blocks and votes of one
+/// network are meaningless in a network with a different configuration.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenesisConfig {
+    // Distinguishes networks that are otherwise configured alike. The default, 0, adds nothing
+    // to the genesis hash.
+    pub chain_id: u64,
+    // The initial validator set, if the genesis block defines it
+    pub validators: Option<Vec<usize>>,
+    // The hash function of all blocks of the chain, including genesis
+    #[serde(default)]
+    pub hash_algo: HashAlgo,
+}
+
+impl GenesisConfig {
+    /// The configuration of a network with the given chain id, whose genesis block does not
+    /// define the validator set
+    pub fn with_chain_id(chain_id: u64) -> Self {
+        GenesisConfig {
+            chain_id,
+            ..GenesisConfig::default()
+        }
+    }
+
+    /// The configuration of a network whose blocks use the given hash function
+    pub fn with_hash_algo(hash_algo: HashAlgo) -> Self {
+        GenesisConfig {
+            hash_algo,
+            ..GenesisConfig::default()
+        }
+    }
+
+    /// The configuration of a network whose genesis block defines the given validator set
+    pub fn with_validators(validators: Vec<usize>) -> Self {
+        GenesisConfig {
+            validators: Some(validators),
+            ..GenesisConfig::default()
+        }
+    }
+}
+
+/// Why a genesis configuration cannot start a node
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GenesisError {
+    // The genesis block does not define the validator set
+    MissingValidators,
+    // The validator set of the genesis block is empty, so no quorum can ever form
+    NoValidators,
+}
+
+impl fmt::Display for GenesisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenesisError::MissingValidators => {
+                write!(f, "The genesis block does not define the validator set")
+            }
+            GenesisError::NoValidators => write!(f, "The genesis validator set is empty"),
+        }
+    }
+}
+
+/// The payload prefix of transactions that carry a ReconfigMessage
+pub const RECONFIG_PREFIX: &str = "reconfig:";
+
+/// The only sender whose transactions can carry a ReconfigMessage, see Network::reconfigure. It is
+/// not a validator id, so that its nonces cannot collide with those of the validators'
+/// transactions.
+pub const RECONFIG_SENDER: usize = usize::MAX;
+
+/// A change of the validator set, submitted as a transaction. Once a block containing it is
+/// finalized, the given validators form the validator set from epoch from_epoch on. Only
+/// transactions of RECONFIG_SENDER carry reconfigurations, which stands in for the signature of a
+/// quorum of the current validators that a real deployment would require.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReconfigMessage {
+    pub validators: Vec<usize>,
+    pub from_epoch: usize,
+}
+
+impl ReconfigMessage {
+    pub fn new(validators: Vec<usize>, from_epoch: usize) -> Self {
+        ReconfigMessage {
+            validators,
+            from_epoch,
+        }
+    }
+
+    /// Returns the transaction carrying the reconfiguration, submitted by the given sender
+    pub fn to_transaction(&self, sender: usize) -> Transaction {
+        let encoded = hex::encode(bincode::serialize(self).unwrap());
+        Transaction::new(
+            sender,
+            self.from_epoch as u64,
+            format!("{}{}", RECONFIG_PREFIX, encoded),
+        )
+    }
+
+    /// Returns the reconfiguration carried by the transaction, if any. Transactions of senders
+    /// other than RECONFIG_SENDER never carry one.
+    pub fn from_transaction(tx: &Transaction) -> Option<ReconfigMessage> {
+        if tx.sender != RECONFIG_SENDER {
+            return None;
+        }
+        let encoded = tx.payload.strip_prefix(RECONFIG_PREFIX)?;
+        bincode::deserialize(&hex::decode(encoded).ok()?).ok()
+    }
+}
+
+/// A transaction submitted by a user. It is identified by its sender and a
+/// per-sender nonce; the payload itself is opaque to the consensus protocol. The fee is offered to
+/// the proposer, which may prefer transactions with higher fees, see MempoolPolicy.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Transaction {
+    pub sender: usize,
+    pub nonce: u64,
+    pub payload: String,
+    pub fee: u64,
+}
+
+impl Transaction {
+    pub fn new(sender: usize, nonce: u64, payload: String) -> Self {
+        Transaction {
+            sender,
+            nonce,
+            payload,
+            fee: 0,
+        }
+    }
+
+    /// Returns the transaction offering the given fee to the proposer instead. The fee is
+    /// serialized with the transaction, so it is part of its hash: the same sender and nonce with
+    /// a different fee make a different transaction, which still reuses the nonce.
+    pub fn with_fee(mut self, fee: u64) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    /// Serializes the transaction. The encoding is length-prefixed, so a list of serialized
+    /// transactions can always be split up again unambiguously.
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    /// Returns the hash of the serialized transaction with the hash function of the chain, which
+    /// identifies it
+    pub fn hash(&self, hash_algo: HashAlgo) -> Hash {
+        Crypto::hash(hash_algo, &self.serialize())
+    }
+
+    /// Deserializes a transaction, or returns None if the bytes are malformed
+    pub fn deserialize(bytes: &[u8]) -> Option<Transaction> {
+        bincode::deserialize(bytes).ok()
+    }
+
+    /// Serializes a list of transactions by concatenating their serialized forms
+    pub fn serialize_all(txs: &[Transaction]) -> Vec<u8> {
+        txs.iter().flat_map(|tx| tx.serialize()).collect()
+    }
+}
+
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.sender, self.nonce)
+    }
+}
 
 /// A block is represented by the parent hash, epoch number, and the
-/// transaction string txs. We additionally give it a name and store the
+/// list of transactions txs. We additionally give it a name and store the
 /// children, parent, and the height. This information could be re-computed on
-/// the fly.
-#[derive(Clone, Debug)]
+/// the fly. The block hash covers the Merkle root tx_root over the transactions, so the inclusion
+/// of a single transaction can be proven without the other transactions.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Block {
     pub parent_hash: Option<Hash>,
     pub e: usize,
-    pub txs: String,
+    pub txs: Vec<Transaction>,
     pub name: String,
     pub children: HashSet<Hash>,
     pub height: usize,
+    pub tx_root: Hash,
+    // The logical time of the proposal, if the chain uses timestamps, see ChainParams
+    pub timestamp: Option<u64>,
+    // The node that proposed the block. Empty blocks and genesis have no proposer.
+    pub proposer: Option<usize>,
+    // The hash function of the chain, with which we compute tx_root and hash
+    pub hash_algo: HashAlgo,
     pub hash: Hash,
 }
 
 impl Block {
+    /// Creates a block whose transaction root and hash are computed with the given hash function,
+    /// that of the chain the block belongs to
     pub fn new(
         parent_hash: Option<Hash>,
         e: usize,
-        txs: String,
+        txs: Vec<Transaction>,
         name: String,
         height: usize,
+        hash_algo: HashAlgo,
     ) -> Self {
-        let e_bytes: Vec<u8> = Crypto::var_to_bytes(e);
-        let parent_bytes: [u8; 32] = parent_hash.unwrap_or_default();
-        let txs_bytes = txs.clone().into_bytes();
-        let mut combined_bytes = Vec::new();
-        combined_bytes.extend_from_slice(&parent_bytes);
-        combined_bytes.extend_from_slice(&e_bytes);
-        combined_bytes.extend_from_slice(&txs_bytes);
-        let hash = Crypto::hash(&combined_bytes);
-
-        Block {
+        let tx_root = Block::compute_tx_root(hash_algo, &txs);
+        let mut block = Block {
             parent_hash,
             e,
             txs,
             name,
             children: HashSet::new(),
             height,
-            hash,
+            tx_root,
+            timestamp: None,
+            proposer: None,
+            hash_algo,
+            hash: [0; 32],
+        };
+        block.hash = block.compute_hash();
+        block
+    }
+
+    /// Creates the empty block of epoch e extending the given parent. It has neither a proposer
+    /// nor transactions. Nodes vote for it if the leader of epoch e is silent, so that the chain
+    /// keeps advancing.
+    pub fn empty(parent: &Block, e: usize) -> Self {
+        Block::new(
+            Some(parent.hash),
+            e,
+            Vec::new(),
+            Block::empty_block_name(e),
+            parent.height + 1,
+            parent.hash_algo,
+        )
+    }
+
+    /// Returns the name of the empty block of epoch e
+    pub fn empty_block_name(e: usize) -> String {
+        format!("{}/empty", e)
+    }
+
+    /// Returns whether this is an empty block, see Block::empty: a block other than genesis
+    /// without proposer and without transactions. The name is irrelevant.
+    pub fn is_empty_block(&self) -> bool {
+        self.parent_hash.is_some() && self.proposer.is_none() && self.txs.is_empty()
+    }
+
+    /// Returns the block with the given timestamp, which the block hash covers
+    pub fn with_timestamp(mut self, timestamp: Option<u64>) -> Self {
+        if timestamp != self.timestamp {
+            self.timestamp = timestamp;
+            self.hash = self.compute_hash();
+        }
+        self
+    }
+
+    /// Returns the block proposed by the given node, which the block hash covers, so that blocks
+    /// of different proposers differ even if they contain the same transactions
+    pub fn with_proposer(mut self, proposer: Option<usize>) -> Self {
+        if proposer != self.proposer {
+            self.proposer = proposer;
+            self.hash = self.compute_hash();
         }
+        self
     }
 
-    /// Validate a block. SIMPLIFYING ASSUMPTION: A block is valid iff its length is 
-    /// < MAXLENGTH_TXS, and e is > 0. In a real blockchain, validation would obviously be more
-    /// complicated.
+    /// Computes the hash over the parent hash, the epoch, the transaction root, and the
+    /// timestamp and the proposer if there are any
+    fn compute_hash(&self) -> Hash {
+        let e_bytes: Vec<u8> = Crypto::var_to_bytes(self.e);
+        let parent_bytes: [u8; 32] = self.parent_hash.unwrap_or_default();
+        let mut combined_bytes = Vec::new();
+        combined_bytes.extend_from_slice(&parent_bytes);
+        combined_bytes.extend_from_slice(&e_bytes);
+        combined_bytes.extend_from_slice(&self.tx_root);
+        if let Some(timestamp) = self.timestamp {
+            combined_bytes.extend_from_slice(&timestamp.to_le_bytes());
+        }
+        if let Some(proposer) = self.proposer {
+            combined_bytes.extend_from_slice(&Crypto::var_to_bytes(proposer));
+        }
+        self.hash_algo.hash(&combined_bytes)
+    }
+
+    /// Computes the Merkle root over the serialized transactions with the given hash function
+    pub fn compute_tx_root(hash_algo: HashAlgo, txs: &[Transaction]) -> Hash {
+        let leaves: Vec<Vec<u8>> = txs.iter().map(|tx| tx.serialize()).collect();
+        Crypto::merkle_root(hash_algo, &leaves)
+    }
+
+    /// Returns the proof that the i-th transaction is included in tx_root, or None if the block
+    /// has fewer transactions
+    pub fn tx_inclusion_proof(&self, i: usize) -> Option<MerkleProof> {
+        let leaves: Vec<Vec<u8>> = self.txs.iter().map(|tx| tx.serialize()).collect();
+        Crypto::merkle_proof(self.hash_algo, &leaves, i)
+    }
+
+    /// Creates the genesis block of the given configuration. The validator set, if given, and
+    /// the chain id, unless it is 0, are encoded into the genesis hash, so that nodes with
+    /// different configurations also have different genesis blocks.
+    pub fn genesis(config: &GenesisConfig) -> Self {
+        let hash_algo = config.hash_algo;
+        let mut genesis = Block::new(None, 0, Vec::new(), "0".to_string(), 0, hash_algo);
+        if let Some(validators) = &config.validators {
+            let mut combined_bytes = genesis.hash.to_vec();
+            combined_bytes.extend_from_slice(&bincode::serialize(validators).unwrap());
+            genesis.hash = hash_algo.hash(&combined_bytes);
+        }
+        if config.chain_id != 0 {
+            let mut combined_bytes = genesis.hash.to_vec();
+            combined_bytes.extend_from_slice(&config.chain_id.to_le_bytes());
+            genesis.hash = hash_algo.hash(&combined_bytes);
+        }
+        genesis
+    }
+
+    /// Validate a block. SIMPLIFYING ASSUMPTION: A block is valid iff its serialized transactions
+    /// are < params.max_txs bytes long, and e is > 0. If the chain uses timestamps, the block
+    /// must moreover have a timestamp that is at most params.max_clock_skew ahead of our clock,
+    /// and not before the timestamp of its parent. In a real blockchain, validation would
+    /// obviously be more complicated.
     /// Note that this function does NOT check the validity of the signature,
     /// which is contained in BlockMessage, not in the Block itself.
-    pub fn validate_block(&self) -> bool {
-        self.txs.len() < MAXLENGTH_TXS && self.e > 0
+    pub fn validate_block(&self, params: &ChainParams, parent: &Block, clock: u64) -> bool {
+        let timestamp_valid = match (params.max_clock_skew, self.timestamp) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(skew), Some(timestamp)) => {
+                timestamp <= clock.saturating_add(skew)
+                    && parent
+                        .timestamp
+                        .is_none_or(|parent_timestamp| timestamp >= parent_timestamp)
+            }
+        };
+        Transaction::serialize_all(&self.txs).len() < params.max_txs
+            && self.e > 0
+            && timestamp_valid
     }
 
     /// This function converts a Block into a BlockMessage. Note that the
     /// sender and the signer are set to the same value
     pub fn to_block_message(&self, sender: usize, signature: Signature) -> BlockMessage {
-        BlockMessage::new(
-            sender,
-            self.parent_hash,
-            self.e,
-            self.txs.clone(),
-            self.name.clone(),
-            sender,
-            signature,
-        )
+        BlockMessage {
+            timestamp: self.timestamp,
+            proposer: self.proposer,
+            ..BlockMessage::new(
+                sender,
+                self.parent_hash,
+                self.e,
+                self.txs.clone(),
+                self.name.clone(),
+                sender,
+                signature,
+            )
+        }
     }
 
     /// This function converts a Block into a VoteMessage. Note that the
     /// sender and the signer are set to the same value
     pub fn to_vote_message(&self, sender: usize, signature: Signature) -> VoteMessage {
-        VoteMessage::new(
-            sender,
-            self.parent_hash,
-            self.e,
-            self.txs.clone(),
-            self.name.clone(),
-            sender,
-            signature,
-        )
+        VoteMessage {
+            timestamp: self.timestamp,
+            proposer: self.proposer,
+            ..VoteMessage::new(
+                sender,
+                self.parent_hash,
+                self.e,
+                self.txs.clone(),
+                self.name.clone(),
+                sender,
+                signature,
+            )
+        }
     }
 }
 
@@ -123,6 +441,27 @@ pub trait Message: fmt::Debug + Send + Sync {
     fn creator(&self) -> usize;
     fn as_any(&self) -> &dyn std::any::Any;
     fn name(&self) -> String;
+    /// The type of the message, e.g. "block" or "vote"
+    fn kind(&self) -> &'static str;
+    /// The length of the serialized message in bytes
+    fn size_bytes(&self) -> usize;
+    /// The number of times the message was relayed before it reached us
+    fn hop(&self) -> usize {
+        0
+    }
+    /// Returns the message as relayed once more, i.e., with an incremented hop count. The
+    /// notarized height attached by the previous sender is dropped, as it is not the relayer's.
+    fn relayed(&self) -> Box<dyn Message> {
+        self.clone_box()
+    }
+    /// The highest notarized height that the sender reported along with the message, if any
+    fn notarized_height(&self) -> Option<usize> {
+        None
+    }
+    /// Returns the message with the given highest notarized height of its sender attached
+    fn with_notarized_height(&self, _height: usize) -> Box<dyn Message> {
+        self.clone_box()
+    }
 }
 impl Clone for Box<dyn Message> {
     fn clone(&self) -> Box<dyn Message> {
@@ -135,17 +474,88 @@ impl fmt::Display for dyn Message {
     }
 }
 
+/// A message of any type, together with its type, as it is serialized
+#[derive(Serialize, Deserialize)]
+enum EncodedMessage {
+    Block(BlockMessage),
+    Vote(VoteMessage),
+    GetBlock(GetBlockMessage),
+    EquivocationProof(Box<EquivocationProof>),
+    SyncRequest(SyncRequest),
+    SyncResponse(SyncResponse),
+    TimeoutMarker(TimeoutMarker),
+}
+
+impl dyn Message {
+    /// Serializes the message together with its type, such that from_bytes can restore it. Fails
+    /// for messages of kinds the node does not process natively, see MessageHandler.
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let m = self.as_any();
+        let encoded = if let Some(m) = m.downcast_ref::<BlockMessage>() {
+            EncodedMessage::Block(m.clone())
+        } else if let Some(m) = m.downcast_ref::<VoteMessage>() {
+            EncodedMessage::Vote(m.clone())
+        } else if let Some(m) = m.downcast_ref::<GetBlockMessage>() {
+            EncodedMessage::GetBlock(m.clone())
+        } else if let Some(m) = m.downcast_ref::<EquivocationProof>() {
+            EncodedMessage::EquivocationProof(Box::new(m.clone()))
+        } else if let Some(m) = m.downcast_ref::<SyncRequest>() {
+            EncodedMessage::SyncRequest(m.clone())
+        } else if let Some(m) = m.downcast_ref::<SyncResponse>() {
+            EncodedMessage::SyncResponse(m.clone())
+        } else if let Some(m) = m.downcast_ref::<TimeoutMarker>() {
+            EncodedMessage::TimeoutMarker(m.clone())
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Cannot serialize message {} of kind {}",
+                    self.name(),
+                    self.kind()
+                ),
+            ));
+        };
+        Ok(bincode::serialize(&encoded).unwrap())
+    }
+
+    /// Restores a message serialized with to_bytes, or returns None if the bytes are malformed
+    pub fn from_bytes(bytes: &[u8]) -> Option<Box<dyn Message>> {
+        let m: Box<dyn Message> = match bincode::deserialize(bytes).ok()? {
+            EncodedMessage::Block(m) => Box::new(m),
+            EncodedMessage::Vote(m) => Box::new(m),
+            EncodedMessage::GetBlock(m) => Box::new(m),
+            EncodedMessage::EquivocationProof(m) => m,
+            EncodedMessage::SyncRequest(m) => Box::new(m),
+            EncodedMessage::SyncResponse(m) => Box::new(m),
+            EncodedMessage::TimeoutMarker(m) => Box::new(m),
+        };
+        Some(m)
+    }
+}
+
 /// A message containing a proposed block, and the signature of the block's
 /// creator (which might be different from the block's sender).
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BlockMessage {
     pub creator: usize,
     pub parent_hash: Option<Hash>,
     pub e: usize,
-    pub txs: String,
+    pub txs: Vec<Transaction>,
     pub name: String,
     pub signer: usize,
     pub signature: Signature,
+    // The number of relays before the message reached us
+    pub hop: usize,
+    // The highest notarized height of the sender, if it reports it. Like the hop count, it is
+    // not signed.
+    pub notarized_height: Option<usize>,
+    // The VRF output and proof showing that the signer leads epoch e, if the leader election
+    // requires it, see LeaderElection::leadership_proof
+    pub leader_proof: Option<(Hash, VrfProof)>,
+    // The timestamp of the block, if the chain uses timestamps
+    pub timestamp: Option<u64>,
+    // The proposer of the block, if it is not an empty block
+    pub proposer: Option<usize>,
 }
 
 impl Message for BlockMessage {
@@ -157,20 +567,48 @@ impl Message for BlockMessage {
         self
     }
 
+    fn kind(&self) -> &'static str {
+        "block"
+    }
+
+    fn size_bytes(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
     fn clone_box(&self) -> Box<dyn Message> {
         Box::new(self.clone())
     }
     fn name(&self) -> String {
-        format!("<BlockM: {}>", self.name.clone())
+        format!("<BlockM: {} hop={}>", self.name, self.hop)
+    }
+    fn hop(&self) -> usize {
+        self.hop
+    }
+    fn relayed(&self) -> Box<dyn Message> {
+        Box::new(BlockMessage {
+            hop: self.hop + 1,
+            notarized_height: None,
+            ..self.clone()
+        })
+    }
+    fn notarized_height(&self) -> Option<usize> {
+        self.notarized_height
+    }
+    fn with_notarized_height(&self, height: usize) -> Box<dyn Message> {
+        Box::new(BlockMessage {
+            notarized_height: Some(height),
+            ..self.clone()
+        })
     }
 }
 
 impl BlockMessage {
+    /// Creates the proposal of the signer, which is the proposer of the block
     pub fn new(
         creator: usize,
         parent_hash: Option<Hash>,
         e: usize,
-        txs: String,
+        txs: Vec<Transaction>,
         name: String,
         signer: usize,
         signature: Signature,
@@ -183,27 +621,64 @@ impl BlockMessage {
             name,
             signer,
             signature,
+            hop: 0,
+            notarized_height: None,
+            leader_proof: None,
+            timestamp: None,
+            proposer: Some(signer),
         }
     }
+
+    /// Returns the hash of the proposed block in a chain with the given hash function
+    pub fn block_hash(&self, hash_algo: HashAlgo) -> Hash {
+        Block::new(
+            self.parent_hash,
+            self.e,
+            self.txs.clone(),
+            self.name.clone(),
+            0,
+            hash_algo,
+        )
+        .with_timestamp(self.timestamp)
+        .with_proposer(self.proposer)
+        .hash
+    }
+
+    /// Checks the signature of the block's signer on the proposal, for a chain with the given
+    /// hash function
+    pub fn check_signature(&self, hash_algo: HashAlgo) -> bool {
+        let signed = (MessageType::BlockProposal, self.block_hash(hash_algo));
+        let signed_bytes = bincode::serialize(&signed).unwrap();
+        Crypto::check_signature(self.signer as u64, &signed_bytes, &self.signature)
+    }
 }
 
 impl fmt::Display for BlockMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "<BlockM: {}>", self.name)
+        write!(f, "{}", self.name())
     }
 }
 
 /// A message containing a vote: a block and signature on the block by a node
 /// that supports this block.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct VoteMessage {
     pub creator: usize,
     pub parent_hash: Option<Hash>,
     pub e: usize,
-    pub txs: String,
+    pub txs: Vec<Transaction>,
     pub name: String,
     pub signer: usize,
     pub signature: Signature,
+    // The number of relays before the message reached us
+    pub hop: usize,
+    // The highest notarized height of the sender, if it reports it. Like the hop count, it is
+    // not signed.
+    pub notarized_height: Option<usize>,
+    // The timestamp of the block, if the chain uses timestamps
+    pub timestamp: Option<u64>,
+    // The proposer of the block, if it is not an empty block
+    pub proposer: Option<usize>,
 }
 
 impl Message for VoteMessage {
@@ -215,11 +690,38 @@ impl Message for VoteMessage {
         self
     }
 
+    fn kind(&self) -> &'static str {
+        "vote"
+    }
+
+    fn size_bytes(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
     fn clone_box(&self) -> Box<dyn Message> {
         Box::new(self.clone())
     }
     fn name(&self) -> String {
-        format!("<VoteM: {}>", self.name.clone())
+        format!("<VoteM: {} hop={}>", self.name, self.hop)
+    }
+    fn hop(&self) -> usize {
+        self.hop
+    }
+    fn relayed(&self) -> Box<dyn Message> {
+        Box::new(VoteMessage {
+            hop: self.hop + 1,
+            notarized_height: None,
+            ..self.clone()
+        })
+    }
+    fn notarized_height(&self) -> Option<usize> {
+        self.notarized_height
+    }
+    fn with_notarized_height(&self, height: usize) -> Box<dyn Message> {
+        Box::new(VoteMessage {
+            notarized_height: Some(height),
+            ..self.clone()
+        })
     }
 }
 
@@ -228,7 +730,7 @@ impl VoteMessage {
         creator: usize,
         parent_hash: Option<Hash>,
         e: usize,
-        txs: String,
+        txs: Vec<Transaction>,
         name: String,
         signer: usize,
         signature: Signature,
@@ -241,39 +743,562 @@ impl VoteMessage {
             name,
             signer,
             signature,
+            hop: 0,
+            notarized_height: None,
+            timestamp: None,
+            proposer: None,
         }
     }
+
+    /// Returns the hash of the block voted for in a chain with the given hash function
+    pub fn block_hash(&self, hash_algo: HashAlgo) -> Hash {
+        Block::new(
+            self.parent_hash,
+            self.e,
+            self.txs.clone(),
+            self.name.clone(),
+            0,
+            hash_algo,
+        )
+        .with_timestamp(self.timestamp)
+        .with_proposer(self.proposer)
+        .hash
+    }
 }
 
 impl fmt::Display for VoteMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "<VoteM: {}>", self.name)
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A request for a block we are missing, e.g. the parent of a block we received. The receiver
+/// replies with the corresponding block proposal, if it has it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GetBlockMessage {
+    pub creator: usize,
+    pub block_hash: Hash,
+}
+
+impl Message for GetBlockMessage {
+    fn creator(&self) -> usize {
+        self.creator
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn kind(&self) -> &'static str {
+        "get_block"
+    }
+
+    fn size_bytes(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+    fn name(&self) -> String {
+        format!("<GetBlockM: {}>", hex::encode(&self.block_hash[0..2]))
+    }
+}
+
+impl GetBlockMessage {
+    pub fn new(creator: usize, block_hash: Hash) -> Self {
+        GetBlockMessage {
+            creator,
+            block_hash,
+        }
+    }
+}
+
+impl fmt::Display for GetBlockMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A request of a node that joined late for the blocks from the given height on, see
+/// Node::begin_sync. The receiver replies with a SyncResponse.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncRequest {
+    pub creator: usize,
+    pub from_height: usize,
+}
+
+impl Message for SyncRequest {
+    fn creator(&self) -> usize {
+        self.creator
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn kind(&self) -> &'static str {
+        "sync_request"
+    }
+
+    fn size_bytes(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+    fn name(&self) -> String {
+        format!("<SyncRequest: from height {}>", self.from_height)
+    }
+}
+
+/// A block together with its notarization certificate: the signatures of the validators that
+/// voted for it, where the proposal of a block counts as the vote of its signer
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CertifiedBlock {
+    pub parent_hash: Hash,
+    pub e: usize,
+    pub txs: Vec<Transaction>,
+    pub name: String,
+    pub timestamp: Option<u64>,
+    pub proposer: Option<usize>,
+    pub signatures: BTreeMap<usize, Signature>,
+}
+
+impl CertifiedBlock {
+    /// Returns the block, at the given height of a chain with the given hash function
+    pub fn block(&self, height: usize, hash_algo: HashAlgo) -> Block {
+        Block::new(
+            Some(self.parent_hash),
+            self.e,
+            self.txs.clone(),
+            self.name.clone(),
+            height,
+            hash_algo,
+        )
+        .with_timestamp(self.timestamp)
+        .with_proposer(self.proposer)
+    }
+}
+
+/// The reply to a SyncRequest: the requested blocks of the finalized chain, followed by the
+/// notarized blocks extending it, in the order of their heights and each with its certificate
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncResponse {
+    pub creator: usize,
+    pub blocks: Vec<CertifiedBlock>,
+}
+
+impl Message for SyncResponse {
+    fn creator(&self) -> usize {
+        self.creator
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn kind(&self) -> &'static str {
+        "sync_response"
+    }
+
+    fn size_bytes(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+    fn name(&self) -> String {
+        format!("<SyncResponse: {} blocks>", self.blocks.len())
+    }
+}
+
+/// Not sent between nodes: marks in a recorded trace that the node timed out epoch e, so that
+/// a replay times out the same epochs. See Network::record_trace.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TimeoutMarker {
+    pub creator: usize,
+    pub e: usize,
+}
+
+impl Message for TimeoutMarker {
+    fn creator(&self) -> usize {
+        self.creator
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn kind(&self) -> &'static str {
+        "timeout"
+    }
+
+    fn size_bytes(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+    fn name(&self) -> String {
+        format!("<Timeout: epoch {}>", self.e)
+    }
+}
+
+/// Evidence that a validator equivocated: two distinct block proposals for the same epoch, both
+/// signed by the same signer. Anyone can verify the evidence without trusting its creator.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EquivocationProof {
+    pub creator: usize,
+    pub proposal1: BlockMessage,
+    pub proposal2: BlockMessage,
+    // The number of relays before the message reached us
+    pub hop: usize,
+}
+
+impl Message for EquivocationProof {
+    fn creator(&self) -> usize {
+        self.creator
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn kind(&self) -> &'static str {
+        "equivocation_proof"
+    }
+
+    fn size_bytes(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+    fn name(&self) -> String {
+        format!(
+            "<EquivocationProof: {} in epoch {} hop={}>",
+            self.proposal1.signer, self.proposal1.e, self.hop
+        )
+    }
+    fn hop(&self) -> usize {
+        self.hop
+    }
+    fn relayed(&self) -> Box<dyn Message> {
+        Box::new(EquivocationProof {
+            hop: self.hop + 1,
+            ..self.clone()
+        })
+    }
+}
+
+impl EquivocationProof {
+    pub fn new(creator: usize, proposal1: BlockMessage, proposal2: BlockMessage) -> Self {
+        EquivocationProof {
+            creator,
+            proposal1,
+            proposal2,
+            hop: 0,
+        }
+    }
+
+    /// Returns the equivocating validator
+    pub fn offender(&self) -> usize {
+        self.proposal1.signer
+    }
+
+    /// The evidence is valid iff both proposals are for the same epoch, are signed by the same
+    /// signer, propose different blocks, and both signatures are valid in a chain with the given
+    /// hash function.
+    pub fn verify(&self, hash_algo: HashAlgo) -> bool {
+        self.proposal1.signer == self.proposal2.signer
+            && self.proposal1.e == self.proposal2.e
+            && self.proposal1.block_hash(hash_algo) != self.proposal2.block_hash(hash_algo)
+            && self.proposal1.check_signature(hash_algo)
+            && self.proposal2.check_signature(hash_algo)
+    }
+}
+
+impl fmt::Display for EquivocationProof {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
     }
 }
 
+/// Two finalized blocks at the same height, i.e., on conflicting forks. Honest nodes can never
+/// observe this if fewer than n/3 nodes are attackers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SafetyViolation {
+    pub height: usize,
+    pub block1: Hash,
+    pub block2: Hash,
+}
+
+impl fmt::Display for SafetyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Finalized blocks {} and {} conflict at height {}",
+            hex::encode(&self.block1[0..2]),
+            hex::encode(&self.block2[0..2]),
+            self.height
+        )
+    }
+}
+
+/// The finalized and notarized blocks of a node's chain, each given as (height, hash) and sorted
+/// by height. Snapshots of different nodes can be compared with diff.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChainSnapshot {
+    pub id: usize,
+    pub finalized: Vec<(usize, Hash)>,
+    pub notarized: Vec<(usize, Hash)>,
+}
+
+/// The blocks that one node finalized or notarized, but another did not, each given as (height,
+/// hash) and sorted by height
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChainDiff {
+    pub id: usize,
+    pub other_id: usize,
+    pub only_finalized: Vec<(usize, Hash)>,
+    pub only_other_finalized: Vec<(usize, Hash)>,
+    pub only_notarized: Vec<(usize, Hash)>,
+    pub only_other_notarized: Vec<(usize, Hash)>,
+}
+
+impl ChainSnapshot {
+    /// Returns the blocks that this snapshot or the other contains, but not both
+    pub fn diff(&self, other: &ChainSnapshot) -> ChainDiff {
+        let missing = |blocks: &[(usize, Hash)], others: &[(usize, Hash)]| {
+            blocks
+                .iter()
+                .filter(|block| !others.contains(block))
+                .copied()
+                .collect()
+        };
+        ChainDiff {
+            id: self.id,
+            other_id: other.id,
+            only_finalized: missing(&self.finalized, &other.finalized),
+            only_other_finalized: missing(&other.finalized, &self.finalized),
+            only_notarized: missing(&self.notarized, &other.notarized),
+            only_other_notarized: missing(&other.notarized, &self.notarized),
+        }
+    }
+}
+
+impl ChainDiff {
+    /// Returns if both chains finalized and notarized the same blocks
+    pub fn is_empty(&self) -> bool {
+        self.only_finalized.is_empty()
+            && self.only_other_finalized.is_empty()
+            && self.only_notarized.is_empty()
+            && self.only_other_notarized.is_empty()
+    }
+}
+
+impl fmt::Display for ChainDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let blocks = |blocks: &[(usize, Hash)]| {
+            blocks
+                .iter()
+                .map(|(height, h)| format!("{} ({})", height, hex::encode(&h[0..2])))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        writeln!(
+            f,
+            "Finalized only by {}: [{}]",
+            self.id,
+            blocks(&self.only_finalized)
+        )?;
+        writeln!(
+            f,
+            "Finalized only by {}: [{}]",
+            self.other_id,
+            blocks(&self.only_other_finalized)
+        )?;
+        writeln!(
+            f,
+            "Notarized only by {}: [{}]",
+            self.id,
+            blocks(&self.only_notarized)
+        )?;
+        write!(
+            f,
+            "Notarized only by {}: [{}]",
+            self.other_id,
+            blocks(&self.only_other_notarized)
+        )
+    }
+}
+
+/// A block of a ChainDag
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DagNode {
+    // The hex-encoded block hash
+    pub hash: String,
+    pub name: String,
+    pub epoch: usize,
+    pub height: usize,
+    // The number of validators we received votes of
+    pub votes: usize,
+    pub notarized: bool,
+    pub finalized: bool,
+}
+
+/// A parent link of a ChainDag, given as hex-encoded block hashes
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DagEdge {
+    pub parent: String,
+    pub child: String,
+}
+
+/// The fork tree of a chain as a graph, e.g. for feeding into a graph visualizer. Nodes are
+/// sorted by height, then hash.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainDag {
+    pub nodes: Vec<DagNode>,
+    pub edges: Vec<DagEdge>,
+}
+
+impl ChainDag {
+    /// Returns the graph as a JSON object with the fields "nodes" and "edges", whose entries have
+    /// the fields of DagNode and DagEdge, respectively
+    pub fn to_json(&self) -> String {
+        let nodes: Vec<String> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                format!(
+                    "{{\"hash\":{},\"name\":{},\"epoch\":{},\"height\":{},\"votes\":{},\"notarized\":{},\"finalized\":{}}}",
+                    json_string(&node.hash),
+                    json_string(&node.name),
+                    node.epoch,
+                    node.height,
+                    node.votes,
+                    node.notarized,
+                    node.finalized
+                )
+            })
+            .collect();
+        let edges: Vec<String> = self
+            .edges
+            .iter()
+            .map(|edge| {
+                format!(
+                    "{{\"parent\":{},\"child\":{}}}",
+                    json_string(&edge.parent),
+                    json_string(&edge.child)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"nodes\":[{}],\"edges\":[{}]}}",
+            nodes.join(","),
+            edges.join(",")
+        )
+    }
+}
+
+/// Encodes s as a JSON string literal
+fn json_string(s: &str) -> String {
+    let mut encoded = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => encoded.push_str("\\\""),
+            '\\' => encoded.push_str("\\\\"),
+            c if (c as u32) < 0x20 => encoded.push_str(&format!("\\u{:04x}", c as u32)),
+            c => encoded.push(c),
+        }
+    }
+    encoded.push('"');
+    encoded
+}
+
 /// This is the actual blockchain that each node keeps track of.
 /// Blocks are stored in a HashMap, where the key is the hash of the block given as type Hash.
 /// Instead of using references of Blocks, we mostly use the hash of the block to reference it.
+#[derive(Serialize, Deserialize)]
 pub struct Blockchain {
     // empty genesis block is stored as a hash
     pub genesis: Hash,
+    // The oldest block we store. This is genesis, unless we pruned the chain below a checkpoint,
+    // in which case the checkpoint acts as genesis for chain traversal.
+    pub root: Hash,
+    // The checkpoint hash of the finalized chain up to the root, see checkpoint()
+    pub root_checkpoint: Hash,
+    // The highest block we finalized with finalize() and the checkpoint hash of the chain up to
+    // it, from which we roll the checkpoint forward as finalization advances
+    #[serde(skip)]
+    pub finalized_checkpoint: Option<(Hash, Hash)>,
+    // The configuration encoded in the genesis block
+    pub genesis_config: GenesisConfig,
+    // The block and transaction size limits
+    pub params: ChainParams,
+    // The stake of each validator, indexed by node id. Validators without an entry have a stake
+    // of one, so by default all validators weigh the same.
+    pub stake: Vec<u64>,
     // blocks are stored as a map from block hash to block
     pub blocks: HashMap<Hash, Block>,
     // votes are stored as a map from block hash to a set of node ids that voted for it
     pub votes: HashMap<Hash, HashSet<usize>>,
+    // The stake behind the votes of each block, as the number of votes counted and the stake of
+    // the validators among them. Entries that counted fewer votes than stored, e.g. after votes
+    // were inserted directly, are recomputed.
+    #[serde(skip)]
+    pub vote_weight: HashMap<Hash, (usize, u64)>,
+    // The signatures of the votes of each block, which form its notarization certificate
+    pub signatures: HashMap<Hash, BTreeMap<usize, Signature>>,
     // notarized blocks are stored as a set of block hashes
     pub notarized: HashSet<Hash>,
+    // The sorted ids of the validators whose votes formed the quorum of each notarized block, as
+    // of its notarization, see notarization_voters
+    pub quorums: HashMap<Hash, Vec<usize>>,
     // finalized blocks are stored as a set of block hashes
     pub finalized: HashSet<Hash>,
+    // The epoch in which we finalized each block, except genesis
+    pub finalized_at: HashMap<Hash, usize>,
+    // The height of the finalized block using each (sender, nonce) pair, for the blocks we
+    // finalized with finalize(), so that used_nonces need not walk the finalized chain
+    #[serde(skip)]
+    pub finalized_nonces: HashMap<(usize, u64), usize>,
+    // The blocks whose nonces are in finalized_nonces, together with those of their ancestors
+    #[serde(skip)]
+    pub nonce_indexed: HashSet<Hash>,
     // Auxiliary data structure to enable accesing the block hashes per epoch
     pub block_by_epoch: Vec<HashSet<Hash>>,
     // The id of the node that runs the blockchain. Used for debugging purposes.
     pub id: usize,
+    // The time of our logical clock, provided by the network at the start of each epoch. Used to
+    // validate block timestamps.
+    pub clock: u64,
 }
 
 impl Blockchain {
     pub fn new(id: usize) -> Self {
-        let genesis = Block::new(None, 0, "".to_string(), "0".to_string(), 0);
+        Blockchain::with_genesis(id, GenesisConfig::default())
+    }
+
+    /// Creates a blockchain whose genesis block encodes the given validator set
+    pub fn with_validators(id: usize, validators: Option<Vec<usize>>) -> Self {
+        Blockchain::with_genesis(
+            id,
+            GenesisConfig {
+                validators,
+                ..GenesisConfig::default()
+            },
+        )
+    }
+
+    /// Creates a blockchain starting from the genesis block of the given configuration
+    pub fn with_genesis(id: usize, genesis_config: GenesisConfig) -> Self {
+        let genesis = Block::genesis(&genesis_config);
         let genesis_hash = genesis.hash;
         let mut blocks = HashMap::new();
         let mut genesis_map = HashMap::new();
@@ -284,13 +1309,204 @@ impl Blockchain {
 
         Blockchain {
             genesis: genesis_hash,
+            root: genesis_hash,
+            root_checkpoint: genesis_hash,
+            finalized_checkpoint: None,
+            genesis_config,
+            params: ChainParams::default(),
+            stake: Vec::new(),
             blocks,
             votes: HashMap::new(),
+            vote_weight: HashMap::new(),
+            signatures: HashMap::new(),
             notarized: genesis_set.clone(),
+            quorums: HashMap::new(),
             finalized: genesis_set.clone(),
+            finalized_at: HashMap::new(),
+            finalized_nonces: HashMap::new(),
+            nonce_indexed: HashSet::new(),
             block_by_epoch: vec![genesis_set],
             id,
+            clock: 0,
+        }
+    }
+
+    /// Returns the hash function of the chain, see GenesisConfig
+    pub fn hash_algo(&self) -> HashAlgo {
+        self.genesis_config.hash_algo
+    }
+
+    /// Returns the stake of validator v
+    pub fn stake_of(&self, v: usize) -> u64 {
+        self.stake.get(v).copied().unwrap_or(1)
+    }
+
+    /// Returns the fraction of the stake of the given validators that voted for the block, like
+    /// quorums are weighted by stake. Low participation in notarized blocks explains slow
+    /// notarization, e.g. due to silent attackers or delays.
+    pub fn participation_rate(&self, block_hash: Hash, validators: &[usize]) -> f64 {
+        let total: u64 = validators.iter().map(|v| self.stake_of(*v)).sum();
+        if total == 0 {
+            return 0.0;
         }
+        let voted: u64 = self.votes.get(&block_hash).map_or(0, |votes| {
+            votes
+                .iter()
+                .filter(|v| validators.contains(v))
+                .map(|v| self.stake_of(*v))
+                .sum()
+        });
+        voted as f64 / total as f64
+    }
+
+    /// Mark a block notarized, and record the validators among its voters so far as the quorum
+    /// that notarized it, see notarization_voters
+    pub fn notarize(&mut self, block_hash: Hash, validators: &HashSet<usize>) {
+        let mut voters: Vec<usize> = self.votes.get(&block_hash).map_or_else(Vec::new, |votes| {
+            votes
+                .iter()
+                .filter(|v| validators.contains(v))
+                .copied()
+                .collect()
+        });
+        voters.sort_unstable();
+        self.notarized.insert(block_hash);
+        self.quorums.insert(block_hash, voters);
+    }
+
+    /// Returns the ids of the validators whose votes formed the quorum when we notarized the
+    /// block, in ascending order, or None if the block is not notarized. Votes we received after
+    /// notarizing the block are not included. Genesis, and blocks marked notarized without
+    /// notarize, have no voters.
+    pub fn notarization_voters(&self, block_hash: Hash) -> Option<Vec<usize>> {
+        if !self.notarized.contains(&block_hash) {
+            return None;
+        }
+        Some(self.quorums.get(&block_hash).cloned().unwrap_or_default())
+    }
+
+    /// Returns whether the voters that are among the validators hold strictly more than two
+    /// thirds of the validators' total stake
+    pub fn is_quorum<'a>(
+        &self,
+        voters: impl IntoIterator<Item = &'a usize>,
+        validators: &[usize],
+    ) -> bool {
+        let voters = voters.into_iter().filter(|v| validators.contains(v));
+        // With equal stakes, we need strictly more than 2n/3 votes. Use integer arithmetic, as
+        // truncating the float 2n/3 lets e.g. 2 of 4 votes pass.
+        if self.stake.is_empty() {
+            return voters.count() > 2 * validators.len() / 3;
+        }
+        let total: u64 = validators.iter().map(|v| self.stake_of(*v)).sum();
+        let voted: u64 = voters.map(|v| self.stake_of(*v)).sum();
+        3 * voted > 2 * total
+    }
+
+    /// Adds the vote of voter for a block whose epoch has the given validators, and returns
+    /// whether the vote is new. Updates the stake behind the block's votes incrementally.
+    pub fn add_vote(
+        &mut self,
+        block_hash: Hash,
+        voter: usize,
+        validators: &HashSet<usize>,
+    ) -> bool {
+        let votes = self.votes.entry(block_hash).or_default();
+        if !votes.insert(voter) {
+            return false;
+        }
+        let count = votes.len();
+        let stake = if validators.contains(&voter) {
+            self.stake_of(voter)
+        } else {
+            0
+        };
+        let (counted, weight) = self.vote_weight.entry(block_hash).or_insert((0, 0));
+        if *counted + 1 == count {
+            *counted = count;
+            *weight += stake;
+        }
+        true
+    }
+
+    /// Returns whether the votes for the block form a quorum of the given validators, whose total
+    /// stake is total_stake, see is_quorum. Only recounts the votes if some were added without
+    /// add_vote.
+    pub fn has_quorum(
+        &mut self,
+        block_hash: Hash,
+        validators: &HashSet<usize>,
+        total_stake: u64,
+    ) -> bool {
+        let count = self.votes.get(&block_hash).map_or(0, HashSet::len);
+        let weight = match self.vote_weight.get(&block_hash) {
+            Some(&(counted, weight)) if counted == count => weight,
+            _ => {
+                let weight = self
+                    .votes
+                    .get(&block_hash)
+                    .into_iter()
+                    .flatten()
+                    .filter(|v| validators.contains(v))
+                    .map(|v| self.stake_of(*v))
+                    .sum();
+                self.vote_weight.insert(block_hash, (count, weight));
+                weight
+            }
+        };
+        3 * weight > 2 * total_stake
+    }
+
+    /// Returns the fork tree of all stored blocks, with their votes, notarization and finalization
+    pub fn dag(&self) -> ChainDag {
+        let mut blocks: Vec<&Block> = self.blocks.values().collect();
+        blocks.sort_by_key(|block| (block.height, block.hash));
+        let nodes = blocks
+            .iter()
+            .map(|block| DagNode {
+                hash: hex::encode(block.hash),
+                name: block.name.clone(),
+                epoch: block.e,
+                height: block.height,
+                votes: self.votes.get(&block.hash).map_or(0, HashSet::len),
+                notarized: self.notarized.contains(&block.hash),
+                finalized: self.finalized.contains(&block.hash),
+            })
+            .collect();
+        let edges = blocks
+            .iter()
+            .filter_map(|block| {
+                self.parent_of(block.hash).map(|parent_hash| DagEdge {
+                    parent: hex::encode(parent_hash),
+                    child: hex::encode(block.hash),
+                })
+            })
+            .collect();
+        ChainDag { nodes, edges }
+    }
+
+    /// Returns the fork tree as JSON, see ChainDag::to_json
+    pub fn to_json(&self) -> String {
+        self.dag().to_json()
+    }
+
+    /// Writes the blockchain to the file at path, e.g. to restore it after a restart
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let bytes =
+            bincode::serialize(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, bytes)
+    }
+
+    /// Reads a blockchain that was previously written to the file at path
+    pub fn load(path: &Path) -> io::Result<Blockchain> {
+        let bytes = fs::read(path)?;
+        bincode::deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Returns if two blockchains start from the same genesis block. Nodes with different genesis
+    /// blocks cannot agree on any block.
+    pub fn same_genesis(&self, other: &Blockchain) -> bool {
+        self.genesis == other.genesis
     }
 
     /// Returns if a given block is already part of the blockchain
@@ -298,19 +1514,31 @@ impl Blockchain {
         self.blocks.contains_key(&b)
     }
 
-    /// Returns the parent block hash given a block hash, or None if it does not exist
+    /// Returns the parent block hash given a block hash, or None if it does not exist or was
+    /// pruned
     pub fn parent_of(&self, b: Hash) -> Option<Hash> {
-        self.blocks.get(&b).unwrap().parent_hash
+        if b == self.root {
+            return None;
+        }
+        self.blocks
+            .get(&b)
+            .unwrap()
+            .parent_hash
+            .filter(|parent_hash| self.blocks.contains_key(parent_hash))
     }
 
-    /// Returns the highest notarized block of the chain. Note that we find it by traversing 
+    /// Returns the highest notarized block of the chain. Note that we find it by traversing
     /// blocks_by_epoch backwards, but the notarized block with the highest epoch number is also
-    /// guaranteed to be (one of) the highest notarized blocks.
+    /// guaranteed to be (one of) the highest notarized blocks. If several blocks of that epoch are
+    /// notarized, we deterministically pick the one with the lexicographically smallest hash.
     pub fn get_highest_notarized_block(&self) -> Hash {
         for block_level in self.block_by_epoch.iter().rev() {
-            for block in block_level.iter() {
-                if self.notarized.contains(block) {
-                    let b = self.blocks.get(block).unwrap();
+            let highest = block_level
+                .iter()
+                .filter(|block| self.notarized.contains(*block))
+                .min();
+            if let Some(block) = highest {
+                if let Some(b) = self.blocks.get(block) {
                     Debug::dbg(
                         &format!(
                             "Highest notarized block is: {} of height {}",
@@ -319,11 +1547,17 @@ impl Blockchain {
                         self.id,
                         None,
                     );
-                    return block.clone();
                 }
+                return *block;
             }
         }
-        self.genesis.clone()
+        self.root
+    }
+
+    /// Returns the height up to which our chain is safe: the height of our highest finalized
+    /// block. All honest nodes that finalized up to this height agree on the chain below it.
+    pub fn safe_height(&self) -> usize {
+        self.blocks[self.highest_finalized_block()].height
     }
 
     /// Returns the highest finalized block of the chain.
@@ -338,15 +1572,399 @@ impl Blockchain {
         panic!("should not happen, we always have a finalized genesis");
     }
 
-    /// Validate a given new block and extend the chain by it. 
+    /// Returns the finalized chain in order, starting with genesis (or the checkpoint we pruned
+    /// the chain below) and ending with the highest finalized block. Like the consistency check,
+    /// we construct it by following the parents of the highest finalized block.
+    pub fn finalized_chain(&self) -> Vec<Hash> {
+        let mut block_hash = *self.highest_finalized_block();
+        let mut chain = vec![block_hash];
+        while let Some(parent_hash) = self.parent_of(block_hash) {
+            block_hash = parent_hash;
+            chain.push(block_hash);
+        }
+        chain.reverse();
+        chain
+    }
+
+    /// Returns every maximal chain of the block tree: for each leaf, i.e., each block without
+    /// children, the path from the leaf down to genesis (or the checkpoint we pruned the chain
+    /// below). Forks with higher leaves come first, and forks with leaves of the same height are
+    /// ordered by the hash of the leaf.
+    pub fn forks(&self) -> Vec<Vec<Hash>> {
+        let mut leaves: Vec<&Block> = self
+            .blocks
+            .values()
+            .filter(|block| self.is_leaf(block))
+            .collect();
+        leaves.sort_by_key(|block| (std::cmp::Reverse(block.height), block.hash));
+        leaves
+            .iter()
+            .map(|leaf| {
+                let mut block_hash = leaf.hash;
+                let mut fork = vec![block_hash];
+                while let Some(parent_hash) = self.parent_of(block_hash) {
+                    block_hash = parent_hash;
+                    fork.push(block_hash);
+                }
+                fork
+            })
+            .collect()
+    }
+
+    /// Returns the number of forks, see forks. A chain that never forked has one.
+    pub fn fork_count(&self) -> usize {
+        self.blocks
+            .values()
+            .filter(|block| self.is_leaf(block))
+            .count()
+    }
+
+    /// Returns whether we store none of the block's children, i.e., the block ends a fork
+    fn is_leaf(&self, block: &Block) -> bool {
+        !block
+            .children
+            .iter()
+            .any(|child| self.blocks.contains_key(child))
+    }
+
+    /// Returns the most blocks a fork has above the highest block it shares with another fork,
+    /// or 0 if the chain never forked.
+    pub fn max_fork_depth(&self) -> usize {
+        let forks = self.forks();
+        if forks.len() <= 1 {
+            return 0;
+        }
+        let branches = |block_hash: &Hash| {
+            self.blocks[block_hash]
+                .children
+                .iter()
+                .filter(|child| self.blocks.contains_key(*child))
+                .count()
+                > 1
+        };
+        forks
+            .iter()
+            .map(|fork| fork.iter().position(branches).unwrap_or(fork.len()))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns the height of the highest finalized block and the checkpoint hash of the finalized
+    /// chain up to it. The checkpoint of genesis is its hash, and the checkpoint of every further
+    /// finalized block is the hash of the previous checkpoint and the block hash. Nodes with the
+    /// same checkpoint therefore agree on the whole finalized chain, even after pruning.
+    pub fn checkpoint(&self) -> (usize, Hash) {
+        let block_hash = *self.highest_finalized_block();
+        let height = self.blocks.get(&block_hash).unwrap().height;
+        (height, self.checkpoint_of(block_hash))
+    }
+
+    /// Returns the checkpoint hash of the chain up to the given block. If the block extends the
+    /// block of finalized_checkpoint, we only chain the blocks above it, and otherwise all blocks
+    /// above the root.
+    fn checkpoint_of(&self, block_hash: Hash) -> Hash {
+        let (base, base_checkpoint) = self
+            .finalized_checkpoint
+            .unwrap_or((self.root, self.root_checkpoint));
+        let mut chain = vec![block_hash];
+        let mut h = block_hash;
+        while h != base {
+            match self.parent_of(h) {
+                Some(parent_hash) => {
+                    h = parent_hash;
+                    chain.push(h);
+                }
+                None => {
+                    chain.reverse();
+                    return self.chain_checkpoint(self.root_checkpoint, &chain);
+                }
+            }
+        }
+        chain.reverse();
+        self.chain_checkpoint(base_checkpoint, &chain)
+    }
+
+    /// Chains the given block hashes, except the first one, into the given checkpoint of the
+    /// first one
+    fn chain_checkpoint(&self, checkpoint: Hash, chain: &[Hash]) -> Hash {
+        chain
+            .iter()
+            .skip(1)
+            .fold(checkpoint, |checkpoint, block_hash| {
+                self.hash_algo()
+                    .hash(&[checkpoint.as_slice(), block_hash.as_slice()].concat())
+            })
+    }
+
+    /// Returns the lowest height at which the finalized chains of two blockchains differ, or None
+    /// if one of them is a prefix of the other. If a chain was pruned, it starts at the height of
+    /// its checkpoint, and we only compare the heights both chains still hold.
+    pub fn divergence_point(&self, other: &Blockchain) -> Option<usize> {
+        let (chain1, chain2) = (self.finalized_chain(), other.finalized_chain());
+        let start1 = self.blocks[&chain1[0]].height;
+        let start2 = other.blocks[&chain2[0]].height;
+        let start = start1.max(start2);
+        chain1
+            .iter()
+            .skip(start - start1)
+            .zip(chain2.iter().skip(start - start2))
+            .position(|(b1, b2)| b1 != b2)
+            .map(|i| start + i)
+    }
+
+    /// Returns the transactions of all finalized blocks, in chain order.
+    pub fn finalized_transactions(&self) -> Vec<Transaction> {
+        self.finalized_chain()
+            .iter()
+            .flat_map(|block_hash| self.blocks.get(block_hash).unwrap().txs.clone())
+            .collect()
+    }
+
+    /// Audits that every finalized block is also notarized. Finalization requires notarization,
+    /// so a violation means the state of the chain was corrupted.
+    pub fn check_finalized_subset_notarized(&self) -> bool {
+        let mut ok = true;
+        for block_hash in self.finalized.difference(&self.notarized) {
+            self.dbg(
+                &format!(
+                    "Finalized block {} is not notarized",
+                    hex::encode(&block_hash[0..2])
+                ),
+                Some("ERROR"),
+            );
+            ok = false;
+        }
+        ok
+    }
+
+    /// Returns the number of epochs between the proposal of a block, i.e., its epoch, and its
+    /// finalization, averaged over all blocks we finalized. Returns 0 if we did not finalize any
+    /// block yet.
+    pub fn average_finalization_delay(&self) -> f64 {
+        if self.finalized_at.is_empty() {
+            return 0.0;
+        }
+        let total: usize = self
+            .finalized_at
+            .iter()
+            .map(|(block_hash, epoch)| epoch.saturating_sub(self.blocks.get(block_hash).unwrap().e))
+            .sum();
+        total as f64 / self.finalized_at.len() as f64
+    }
+
+    /// Checks that the finalized blocks form a single chain. Since the ancestors of finalized
+    /// blocks are finalized, two conflicting finalized blocks imply two finalized blocks at the
+    /// same height. Note that notarized blocks may conflict with finalized ones, e.g., a notarized
+    /// fork that was abandoned before the other fork got finalized. Returns the violation with
+    /// the smallest height, if any.
+    pub fn check_safety_invariant(&self) -> Result<(), SafetyViolation> {
+        let mut finalized: Vec<(usize, Hash)> = self
+            .finalized
+            .iter()
+            .filter_map(|h| self.blocks.get(h).map(|block| (block.height, *h)))
+            .collect();
+        finalized.sort();
+        match finalized.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            Some(pair) => Err(SafetyViolation {
+                height: pair[0].0,
+                block1: pair[0].1,
+                block2: pair[1].1,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the finalized and notarized blocks, for comparison with other nodes' chains
+    pub fn snapshot(&self) -> ChainSnapshot {
+        let sorted = |hashes: &HashSet<Hash>| {
+            let mut blocks: Vec<(usize, Hash)> = hashes
+                .iter()
+                .filter_map(|h| self.blocks.get(h).map(|block| (block.height, *h)))
+                .collect();
+            blocks.sort();
+            blocks
+        };
+        ChainSnapshot {
+            id: self.id,
+            finalized: sorted(&self.finalized),
+            notarized: sorted(&self.notarized),
+        }
+    }
+
+    /// Returns if the block b or one of its ancestors contains the transaction with the given hash
+    pub fn branch_contains_tx(&self, b: Hash, tx_hash: Hash) -> bool {
+        let mut block_hash = Some(b);
+        while let Some(h) = block_hash {
+            let block = self.blocks.get(&h).unwrap();
+            if block
+                .txs
+                .iter()
+                .any(|tx| tx.hash(self.hash_algo()) == tx_hash)
+            {
+                return true;
+            }
+            block_hash = self.parent_of(h);
+        }
+        false
+    }
+
+    /// Returns which of the given (sender, nonce) pairs are used by a transaction of block b or of
+    /// one of its ancestors. Transactions of pruned blocks are not considered. We only walk the
+    /// branch down to the first block we finalized, and look up the nonces of the finalized
+    /// chain below in finalized_nonces.
+    pub fn used_nonces(&self, b: Hash, nonces: &HashSet<(usize, u64)>) -> HashSet<(usize, u64)> {
+        let mut used = HashSet::new();
+        let mut block_hash = Some(b);
+        while let Some(h) = block_hash {
+            let block = self.blocks.get(&h).unwrap();
+            if self.nonce_indexed.contains(&h) {
+                used.extend(nonces.iter().filter(|nonce| {
+                    self.finalized_nonces
+                        .get(nonce)
+                        .is_some_and(|height| *height <= block.height)
+                }));
+                break;
+            }
+            used.extend(
+                block
+                    .txs
+                    .iter()
+                    .map(|tx| (tx.sender, tx.nonce))
+                    .filter(|nonce| nonces.contains(nonce)),
+            );
+            block_hash = self.parent_of(h);
+        }
+        used
+    }
+
+    /// Returns a transaction of block b that uses the same nonce as another transaction of the
+    /// same sender, either in b or in the branch of the given parent. Including it would let the
+    /// sender double-spend.
+    pub fn reused_nonce<'a>(&self, b: &'a Block, parent_hash: Hash) -> Option<&'a Transaction> {
+        let mut nonces = HashSet::new();
+        if let Some(tx) = b
+            .txs
+            .iter()
+            .find(|tx| !nonces.insert((tx.sender, tx.nonce)))
+        {
+            return Some(tx);
+        }
+        let used = self.used_nonces(parent_hash, &nonces);
+        b.txs
+            .iter()
+            .find(|tx| used.contains(&(tx.sender, tx.nonce)))
+    }
+
+    /// Marks block h as finalized in epoch e, and indexes the nonces of its transactions for
+    /// used_nonces. The ancestors of h must be finalized with it, so that finalized_nonces covers
+    /// the finalized chain below each indexed block. If h is our highest finalized block, we roll
+    /// the cached checkpoint forward to it.
+    pub fn finalize(&mut self, h: Hash, e: usize) {
+        let block = self.blocks.get(&h).unwrap();
+        let height = block.height;
+        for tx in block.txs.iter() {
+            self.finalized_nonces
+                .insert((tx.sender, tx.nonce), block.height);
+        }
+        self.nonce_indexed.insert(h);
+        self.finalized.insert(h);
+        self.finalized_at.insert(h, e);
+        let highest = self
+            .finalized_checkpoint
+            .is_none_or(|(block_hash, _)| height > self.blocks[&block_hash].height);
+        if highest {
+            self.finalized_checkpoint = Some((h, self.checkpoint_of(h)));
+        }
+    }
+
+    /// Prune all blocks below the given height, which must be the height of a finalized block.
+    /// This finalized block becomes the new root of the chain. Blocks of other branches below
+    /// that height are pruned as well, since they can no longer be finalized.
+    pub fn prune_below(&mut self, checkpoint_height: usize) {
+        let chain = self.finalized_chain();
+        let position = chain.iter().position(|block_hash| {
+            self.blocks.get(block_hash).unwrap().height == checkpoint_height
+        });
+        let Some(position) = position else {
+            self.dbg(
+                &format!(
+                    "No finalized block of height {} to prune below",
+                    checkpoint_height
+                ),
+                Some("ERROR"),
+            );
+            return;
+        };
+        let checkpoint = chain[position];
+        let root_checkpoint = self.chain_checkpoint(self.root_checkpoint, &chain[..=position]);
+        let pruned: HashSet<Hash> = self
+            .blocks
+            .values()
+            .filter(|block| block.height < checkpoint_height)
+            .map(|block| block.hash)
+            .collect();
+        self.dbg(
+            &format!(
+                "Pruning {} blocks below height {}",
+                pruned.len(),
+                checkpoint_height
+            ),
+            None,
+        );
+        self.blocks
+            .retain(|block_hash, _| !pruned.contains(block_hash));
+        self.votes
+            .retain(|block_hash, _| !pruned.contains(block_hash));
+        self.vote_weight
+            .retain(|block_hash, _| !pruned.contains(block_hash));
+        self.signatures
+            .retain(|block_hash, _| !pruned.contains(block_hash));
+        self.notarized
+            .retain(|block_hash| !pruned.contains(block_hash));
+        self.quorums
+            .retain(|block_hash, _| !pruned.contains(block_hash));
+        self.finalized
+            .retain(|block_hash| !pruned.contains(block_hash));
+        self.finalized_at
+            .retain(|block_hash, _| !pruned.contains(block_hash));
+        self.finalized_nonces
+            .retain(|_, height| *height >= checkpoint_height);
+        self.nonce_indexed
+            .retain(|block_hash| !pruned.contains(block_hash));
+        for block_level in self.block_by_epoch.iter_mut() {
+            block_level.retain(|block_hash| !pruned.contains(block_hash));
+        }
+        if self
+            .finalized_checkpoint
+            .is_some_and(|(block_hash, _)| pruned.contains(&block_hash))
+        {
+            self.finalized_checkpoint = None;
+        }
+        self.root = checkpoint;
+        self.root_checkpoint = root_checkpoint;
+    }
+
+    /// Validate a given new block and extend the chain by it. Besides validating the block itself,
+    /// we check that none of its transactions reuses a nonce of its branch.
     /// PRECONDITION: The parent block must already be part of the chain.
     pub fn validate_and_extend(&mut self, b: Block, parent_hash: Hash) -> bool {
-        if !b.validate_block() && !self.contains_block(b.hash) {
+        let parent = &self.blocks[&parent_hash];
+        if !b.validate_block(&self.params, parent, self.clock) && !self.contains_block(b.hash) {
+            return false;
+        }
+        if let Some(tx) = self.reused_nonce(&b, parent_hash) {
+            self.dbg(
+                &format!("Block {} reuses the nonce of transaction {}", b, tx),
+                Some("ATTACK"),
+            );
             return false;
         }
         let parent = self.blocks.get_mut(&parent_hash).unwrap();
         parent.children.insert(b.hash);
-        self.block_by_epoch.resize(b.e + 1, HashSet::new());
+        // Only grow, a block of an old epoch must not drop the blocks of later epochs
+        if self.block_by_epoch.len() <= b.e {
+            self.block_by_epoch.resize(b.e + 1, HashSet::new());
+        }
         self.block_by_epoch[b.e].insert(b.hash);
         Debug::dbg(
             &format!("added block {} of epoch {} after {}", b, parent, b.e),
@@ -396,9 +2014,9 @@ impl Blockchain {
                 None,
             );
             for child in &b.children {
-                print_blockchain_rec(blockchain, child.clone(), height + 1);
+                print_blockchain_rec(blockchain, *child, height + 1);
             }
         }
-        print_blockchain_rec(self, self.genesis.clone(), 0);
+        print_blockchain_rec(self, self.root, 0);
     }
 }
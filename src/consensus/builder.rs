@@ -0,0 +1,123 @@
+use super::blockchain::{ChainParams, GenesisConfig};
+use super::network::{Network, NetworkModel, Topology, DEFAULT_ROUNDS_PER_EPOCH, DEFAULT_SEED};
+use super::utils::HashAlgo;
+use std::collections::HashSet;
+
+/// Configures a network fluently, e.g.
+///
+/// ```
+/// use consensus::{ConsensusBuilder, NetworkModel};
+///
+/// let mut network = ConsensusBuilder::new(4)
+///     .attackers(1, &["fail_stop"])
+///     .seed([1; 32])
+///     .network_model(NetworkModel::Reorder)
+///     .build();
+/// network.run(10);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ConsensusBuilder {
+    n: usize,
+    num_attackers: usize,
+    attacker_config: HashSet<String>,
+    seed: [u8; 32],
+    model: NetworkModel,
+    rounds_per_epoch: usize,
+    params: ChainParams,
+    genesis_config: GenesisConfig,
+    topology: Topology,
+    threads: usize,
+}
+
+impl ConsensusBuilder {
+    /// Starts configuring a network of n honest nodes
+    pub fn new(n: usize) -> Self {
+        ConsensusBuilder {
+            n,
+            num_attackers: 0,
+            attacker_config: HashSet::new(),
+            seed: DEFAULT_SEED,
+            model: NetworkModel::Simple,
+            rounds_per_epoch: DEFAULT_ROUNDS_PER_EPOCH,
+            params: ChainParams::default(),
+            genesis_config: GenesisConfig::default(),
+            topology: Topology::Complete,
+            threads: 1,
+        }
+    }
+
+    /// The nodes with the num_attackers highest ids are attackers with the given configuration,
+    /// see AttackerNode
+    pub fn attackers(mut self, num_attackers: usize, attacker_config: &[&str]) -> Self {
+        self.num_attackers = num_attackers;
+        self.attacker_config = attacker_config.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    /// The seed of the random number generator that schedules messages
+    pub fn seed(mut self, seed: [u8; 32]) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// How messages are delivered when running the network
+    pub fn network_model(mut self, model: NetworkModel) -> Self {
+        self.model = model;
+        self
+    }
+
+    /// The number of rounds of message passing per epoch
+    pub fn rounds_per_epoch(mut self, rounds_per_epoch: usize) -> Self {
+        self.rounds_per_epoch = rounds_per_epoch;
+        self
+    }
+
+    /// The block and transaction size limits of all nodes
+    pub fn chain_params(mut self, params: ChainParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// The configuration of the genesis block of all nodes, e.g. their chain id
+    pub fn genesis(mut self, genesis_config: GenesisConfig) -> Self {
+        self.genesis_config = genesis_config;
+        self
+    }
+
+    /// Which peers each node can reach directly
+    pub fn topology(mut self, topology: Topology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    /// The hash function of all nodes, which is part of the genesis configuration. This overrides
+    /// the hash function of a configuration given to genesis before.
+    pub fn hash_algo(mut self, hash_algo: HashAlgo) -> Self {
+        self.genesis_config.hash_algo = hash_algo;
+        self
+    }
+
+    /// The number of threads on which nodes process the messages delivered to them, see
+    /// Network::set_threads
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Returns the configured network, ready to run
+    pub fn build(self) -> Network {
+        let mut network = Network::new_byzantine_with_genesis(
+            self.n,
+            self.num_attackers,
+            self.attacker_config,
+            self.seed,
+            self.genesis_config,
+        );
+        network.set_network_model(self.model);
+        network.set_rounds_per_epoch(self.rounds_per_epoch);
+        network.set_chain_params(self.params);
+        network.set_topology(self.topology);
+        network.set_threads(self.threads);
+        network
+    }
+}
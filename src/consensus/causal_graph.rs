@@ -0,0 +1,117 @@
+use super::utils::Hash;
+use hex;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// A consensus decision of a node, i.e., a message it produced or a local state change
+#[derive(Clone, Copy, Debug, PartialEq, Eq, std::hash::Hash)]
+pub enum CausalEvent {
+    // The creator proposed the block
+    Proposal { block: Hash, creator: usize },
+    // The voter voted for the block
+    Vote { block: Hash, voter: usize },
+    // The block got notarized
+    Notarization { block: Hash },
+    // The block got finalized
+    Finalization { block: Hash },
+}
+
+impl fmt::Display for CausalEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CausalEvent::Proposal { block, creator } => {
+                write!(f, "Proposal {} by {}", hex::encode(&block[0..2]), creator)
+            }
+            CausalEvent::Vote { block, voter } => {
+                write!(f, "Vote {} by {}", hex::encode(&block[0..2]), voter)
+            }
+            CausalEvent::Notarization { block } => {
+                write!(f, "Notarization {}", hex::encode(&block[0..2]))
+            }
+            CausalEvent::Finalization { block } => {
+                write!(f, "Finalization {}", hex::encode(&block[0..2]))
+            }
+        }
+    }
+}
+
+/// Records, for each event a node produced, which events triggered it: the proposal that caused a
+/// vote, the votes that caused a notarization, and so on.
+#[derive(Clone, Debug, Default)]
+pub struct CausalGraph {
+    // Map from an event to the events that caused it
+    causes: HashMap<CausalEvent, HashSet<CausalEvent>>,
+}
+
+impl CausalGraph {
+    pub fn new() -> Self {
+        CausalGraph::default()
+    }
+
+    /// Records that the given causes triggered the event
+    pub fn record(&mut self, event: CausalEvent, causes: impl IntoIterator<Item = CausalEvent>) {
+        self.causes.entry(event).or_default().extend(causes);
+    }
+
+    /// Returns if no event was recorded
+    pub fn is_empty(&self) -> bool {
+        self.causes.is_empty()
+    }
+
+    /// Returns the events that directly caused the given event
+    pub fn causes_of(&self, event: &CausalEvent) -> HashSet<CausalEvent> {
+        self.causes.get(event).cloned().unwrap_or_default()
+    }
+
+    /// Returns if the event was (transitively) caused by the given cause
+    pub fn traces_back_to(&self, event: &CausalEvent, cause: &CausalEvent) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack = vec![*event];
+        while let Some(e) = stack.pop() {
+            if !visited.insert(e) {
+                continue;
+            }
+            for c in self.causes.get(&e).into_iter().flatten() {
+                if c == cause {
+                    return true;
+                }
+                stack.push(*c);
+            }
+        }
+        false
+    }
+
+    /// Exports the graph in the DOT format, with edges pointing from cause to effect
+    pub fn to_dot(&self) -> String {
+        let mut events: Vec<&CausalEvent> = self
+            .causes
+            .iter()
+            .flat_map(|(event, causes)| std::iter::once(event).chain(causes.iter()))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        events.sort_by_key(|event| event.to_string());
+        let index: HashMap<&CausalEvent, usize> = events
+            .iter()
+            .enumerate()
+            .map(|(i, event)| (*event, i))
+            .collect();
+
+        let mut edges: Vec<(usize, usize)> = self
+            .causes
+            .iter()
+            .flat_map(|(event, causes)| causes.iter().map(|cause| (index[cause], index[event])))
+            .collect();
+        edges.sort();
+
+        let mut dot = String::from("digraph causal {\n");
+        for (i, event) in events.iter().enumerate() {
+            dot.push_str(&format!("    e{} [label=\"{}\"];\n", i, event));
+        }
+        for (cause, event) in edges {
+            dot.push_str(&format!("    e{} -> e{};\n", cause, event));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
@@ -0,0 +1,54 @@
+use super::utils::Hash;
+use hex;
+use std::fmt;
+
+/// The number of events a node keeps. Once it emitted that many, it drops the older half of
+/// them, so that the events of a long execution do not grow without bound.
+pub const MAX_EVENTS: usize = 100_000;
+
+/// A structured record of something a node did or observed. In contrast to the debug output, the
+/// events can be inspected programmatically, e.g., by tests.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    // We proposed the block of epoch e
+    BlockProposed { block: Hash, e: usize },
+    // We voted for the block of epoch e
+    Voted { block: Hash, e: usize },
+    // We notarized the block of epoch e
+    Notarized { block: Hash, e: usize },
+    // We finalized the block of epoch e
+    Finalized { block: Hash, e: usize },
+    // We detected misbehavior of another node
+    AttackDetected { reason: String },
+    // Our local state violates an assumption of the protocol
+    Error { reason: String },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::BlockProposed { block, e } => {
+                write!(f, "Proposed {} of epoch {}", hex::encode(&block[0..2]), e)
+            }
+            Event::Voted { block, e } => {
+                write!(f, "Voted {} of epoch {}", hex::encode(&block[0..2]), e)
+            }
+            Event::Notarized { block, e } => {
+                write!(f, "Notarized {} of epoch {}", hex::encode(&block[0..2]), e)
+            }
+            Event::Finalized { block, e } => {
+                write!(f, "Finalized {} of epoch {}", hex::encode(&block[0..2]), e)
+            }
+            Event::AttackDetected { reason } => write!(f, "Attack detected: {}", reason),
+            Event::Error { reason } => write!(f, "Error: {}", reason),
+        }
+    }
+}
+
+/// An event, together with the node that emitted it and the epoch that node was in at the time
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EventRecord {
+    pub node: usize,
+    pub epoch: usize,
+    pub event: Event,
+}
@@ -0,0 +1,115 @@
+use super::blockchain::{Block, Blockchain};
+use super::utils::Hash;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// Determines the notarized block a node extends: the parent of the blocks it proposes, and of
+/// the blocks it votes for. Unlike the leader election, nodes may use different rules.
+pub trait ForkChoice: Send {
+    /// Returns the notarized block to extend
+    fn head(&self, chain: &Blockchain) -> Hash;
+    /// Returns whether we may vote for the block, which is stored in the chain, given the head
+    /// returned by head(chain). By default, we vote for blocks one higher than the head.
+    fn may_vote(&self, chain: &Blockchain, head: Hash, block: &Block) -> bool {
+        block.height == chain.blocks[&head].height + 1
+    }
+    fn clone_box(&self) -> Box<dyn ForkChoice>;
+}
+
+impl Clone for Box<dyn ForkChoice> {
+    fn clone(&self) -> Box<dyn ForkChoice> {
+        self.clone_box()
+    }
+}
+
+/// The rule of the protocol: extend the notarized block of the highest epoch, which is also one of
+/// the highest notarized blocks, see Blockchain::get_highest_notarized_block. We vote for every
+/// block that extends a longest notarized chain.
+#[derive(Clone, Debug, Default)]
+pub struct HighestNotarizedEpoch;
+
+impl ForkChoice for HighestNotarizedEpoch {
+    fn head(&self, chain: &Blockchain) -> Hash {
+        chain.get_highest_notarized_block()
+    }
+
+    fn clone_box(&self) -> Box<dyn ForkChoice> {
+        Box::new(self.clone())
+    }
+}
+
+/// A GHOST-style rule: starting from the highest finalized block, descend into the notarized child
+/// whose subtree accumulated the most stake in votes, until reaching a block without notarized
+/// children. Ties are broken in favor of the higher epoch, then the smaller hash. The head may be
+/// lower than the highest notarized block, in which case we do not vote, since the consistency
+/// argument of the protocol requires that honest nodes only vote for blocks extending a longest
+/// notarized chain. Otherwise, we only vote for children of the head.
+#[derive(Clone, Debug, Default)]
+pub struct HeaviestSubtree;
+
+impl HeaviestSubtree {
+    /// Returns the stake behind the votes of each block in the subtree of root, including root
+    fn subtree_weights(chain: &Blockchain, root: Hash) -> HashMap<Hash, u64> {
+        let mut order = vec![root];
+        let mut i = 0;
+        while i < order.len() {
+            let children = &chain.blocks[&order[i]].children;
+            order.extend(
+                children
+                    .iter()
+                    .filter(|child| chain.blocks.contains_key(*child)),
+            );
+            i += 1;
+        }
+        let mut weights = HashMap::new();
+        // Children come after their parents, so we visit them first in reverse order
+        for block_hash in order.into_iter().rev() {
+            let block = &chain.blocks[&block_hash];
+            let votes: u64 = chain
+                .votes
+                .get(&block_hash)
+                .into_iter()
+                .flatten()
+                .map(|voter| chain.stake_of(*voter))
+                .sum();
+            let children: u64 = block
+                .children
+                .iter()
+                .filter_map(|child| weights.get(child))
+                .sum();
+            weights.insert(block_hash, votes + children);
+        }
+        weights
+    }
+}
+
+impl ForkChoice for HeaviestSubtree {
+    fn head(&self, chain: &Blockchain) -> Hash {
+        let mut head = *chain.highest_finalized_block();
+        let weights = HeaviestSubtree::subtree_weights(chain, head);
+        while let Some(child) = chain.blocks[&head]
+            .children
+            .iter()
+            .filter(|child| chain.notarized.contains(*child))
+            .max_by_key(|child| {
+                (
+                    weights.get(*child),
+                    chain.blocks[*child].e,
+                    Reverse(**child),
+                )
+            })
+        {
+            head = *child;
+        }
+        head
+    }
+
+    fn may_vote(&self, chain: &Blockchain, head: Hash, block: &Block) -> bool {
+        let highest = chain.blocks[&chain.get_highest_notarized_block()].height;
+        block.parent_hash == Some(head) && chain.blocks[&head].height == highest
+    }
+
+    fn clone_box(&self) -> Box<dyn ForkChoice> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,166 @@
+use super::utils::{Crypto, Hash, HashAlgo, VrfProof};
+
+/// Determines the leader of each epoch. All nodes, honest or not, must use the same leader
+/// election, otherwise they do not agree on who may propose blocks. Hashes and VRF outputs are
+/// computed with the hash function of the chain, see HashAlgo.
+pub trait LeaderElection: Send {
+    /// Returns the position of the leader of epoch e within the validator set of size n
+    fn leader(&self, e: usize, n: usize, hash_algo: HashAlgo) -> usize;
+    /// Returns the id of the leader of epoch e among the given validators
+    fn leader_of(&self, e: usize, validators: &[usize], hash_algo: HashAlgo) -> usize {
+        validators[self.leader(e, validators.len(), hash_algo)]
+    }
+    /// Returns the id of the backup leader for the given attempt of epoch e among the given
+    /// validators, which may propose if no proposal for e arrived in time. The backup leader of
+    /// attempt a is at position hash(e, a) mod n, for any leader election.
+    fn backup_leader_of(
+        &self,
+        e: usize,
+        attempt: usize,
+        validators: &[usize],
+        hash_algo: HashAlgo,
+    ) -> usize {
+        let input = [Crypto::var_to_bytes(e), Crypto::var_to_bytes(attempt)].concat();
+        validators[Crypto::short_hash(&Crypto::hash(hash_algo, &input)) % validators.len()]
+    }
+    /// Returns the proof that validator id leads epoch e, if the leader election requires one.
+    /// The leader attaches it to its block proposal.
+    fn leadership_proof(
+        &self,
+        _e: usize,
+        _id: usize,
+        _hash_algo: HashAlgo,
+    ) -> Option<(Hash, VrfProof)> {
+        None
+    }
+    /// Checks the proof attached to a block proposal of validator id for epoch e
+    fn verify_leadership(
+        &self,
+        _e: usize,
+        _id: usize,
+        _proof: Option<&(Hash, VrfProof)>,
+        _hash_algo: HashAlgo,
+    ) -> bool {
+        true
+    }
+    fn clone_box(&self) -> Box<dyn LeaderElection>;
+}
+
+impl Clone for Box<dyn LeaderElection> {
+    fn clone(&self) -> Box<dyn LeaderElection> {
+        self.clone_box()
+    }
+}
+
+/// The leader of epoch e is at position hash(e) mod n
+#[derive(Clone, Debug, Default)]
+pub struct HashLeaderElection;
+
+impl LeaderElection for HashLeaderElection {
+    fn leader(&self, e: usize, n: usize, hash_algo: HashAlgo) -> usize {
+        Crypto::short_hash(&Crypto::hash_var(hash_algo, e)) % n
+    }
+
+    fn clone_box(&self) -> Box<dyn LeaderElection> {
+        Box::new(self.clone())
+    }
+}
+
+/// Each validator is elected with a probability proportional to its stake. The stake of the
+/// validator at position i of the validator set is stakes[i]; missing stakes count as zero.
+#[derive(Clone, Debug)]
+pub struct StakeWeightedLeaderElection {
+    pub stakes: Vec<u64>,
+}
+
+impl StakeWeightedLeaderElection {
+    pub fn new(stakes: Vec<u64>) -> Self {
+        StakeWeightedLeaderElection { stakes }
+    }
+}
+
+impl LeaderElection for StakeWeightedLeaderElection {
+    fn leader(&self, e: usize, n: usize, hash_algo: HashAlgo) -> usize {
+        let stake = |i: usize| self.stakes.get(i).copied().unwrap_or(0);
+        let total: u64 = (0..n).map(stake).sum();
+        if total == 0 {
+            return HashLeaderElection.leader(e, n, hash_algo);
+        }
+        // Pick a point in [0, total) and find the validator whose stake range contains it
+        let mut point = Crypto::short_hash(&Crypto::hash_var(hash_algo, e)) as u64 % total;
+        for i in 0..n {
+            if point < stake(i) {
+                return i;
+            }
+            point -= stake(i);
+        }
+        unreachable!("the point is below the total stake")
+    }
+
+    fn clone_box(&self) -> Box<dyn LeaderElection> {
+        Box::new(self.clone())
+    }
+}
+
+/// Each validator evaluates a VRF over the epoch, and the validator with the lowest output leads
+/// the epoch. Unlike with the hash leader election, only a validator itself can tell in advance
+/// whether it will lead, so that attackers cannot target future leaders. The leader proves its
+/// output with the proof attached to its proposal. Validators cannot grind for a low output,
+/// since the VRF is deterministic and its input is the epoch only.
+/// With our dummy VRF, every node can compute the outputs of all validators, so it knows the
+/// leader before seeing the proposal. With a real VRF, nodes would instead accept the proposal
+/// with the lowest verified output.
+#[derive(Clone, Debug, Default)]
+pub struct VrfLeaderElection;
+
+impl VrfLeaderElection {
+    /// The VRF output of validator id for epoch e, along with its proof
+    pub fn evaluate(e: usize, id: usize, hash_algo: HashAlgo) -> (Hash, VrfProof) {
+        Crypto::vrf(hash_algo, id as u64, &Crypto::var_to_bytes(e))
+    }
+}
+
+impl LeaderElection for VrfLeaderElection {
+    fn leader(&self, e: usize, n: usize, hash_algo: HashAlgo) -> usize {
+        let validators: Vec<usize> = (0..n).collect();
+        self.leader_of(e, &validators, hash_algo)
+    }
+
+    fn leader_of(&self, e: usize, validators: &[usize], hash_algo: HashAlgo) -> usize {
+        *validators
+            .iter()
+            .min_by_key(|id| VrfLeaderElection::evaluate(e, **id, hash_algo).0)
+            .unwrap()
+    }
+
+    fn leadership_proof(
+        &self,
+        e: usize,
+        id: usize,
+        hash_algo: HashAlgo,
+    ) -> Option<(Hash, VrfProof)> {
+        Some(VrfLeaderElection::evaluate(e, id, hash_algo))
+    }
+
+    fn verify_leadership(
+        &self,
+        e: usize,
+        id: usize,
+        proof: Option<&(Hash, VrfProof)>,
+        hash_algo: HashAlgo,
+    ) -> bool {
+        proof.is_some_and(|(output, proof)| {
+            Crypto::verify_vrf(
+                hash_algo,
+                id as u64,
+                &Crypto::var_to_bytes(e),
+                output,
+                proof,
+            )
+        })
+    }
+
+    fn clone_box(&self) -> Box<dyn LeaderElection> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,48 @@
+use super::blockchain::Transaction;
+use std::collections::VecDeque;
+
+/// Determines the order in which a node includes the transactions of its transaction pool in the
+/// blocks it proposes. Unlike the leader election, nodes may use different policies.
+pub trait MempoolPolicy: Send {
+    /// Returns the positions of the transactions in the pool, in the order in which they should be
+    /// included. The pool holds the transactions in the order they were submitted.
+    fn order(&self, pool: &VecDeque<Transaction>) -> Vec<usize>;
+    fn clone_box(&self) -> Box<dyn MempoolPolicy>;
+}
+
+impl Clone for Box<dyn MempoolPolicy> {
+    fn clone(&self) -> Box<dyn MempoolPolicy> {
+        self.clone_box()
+    }
+}
+
+/// Transactions are included in the order they were submitted
+#[derive(Clone, Debug, Default)]
+pub struct FifoPolicy;
+
+impl MempoolPolicy for FifoPolicy {
+    fn order(&self, pool: &VecDeque<Transaction>) -> Vec<usize> {
+        (0..pool.len()).collect()
+    }
+
+    fn clone_box(&self) -> Box<dyn MempoolPolicy> {
+        Box::new(self.clone())
+    }
+}
+
+/// Transactions with higher fees are included first. Transactions with the same fee are included
+/// in the order they were submitted.
+#[derive(Clone, Debug, Default)]
+pub struct FeePriorityPolicy;
+
+impl MempoolPolicy for FeePriorityPolicy {
+    fn order(&self, pool: &VecDeque<Transaction>) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..pool.len()).collect();
+        order.sort_by_key(|i| std::cmp::Reverse(pool[*i].fee));
+        order
+    }
+
+    fn clone_box(&self) -> Box<dyn MempoolPolicy> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,65 @@
+use super::node::NodeStats;
+
+/// Metrics of a single epoch. Proposals, notarizations and finalizations are summed over all
+/// nodes, e.g., a block that is notarized by four nodes counts as four notarizations.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EpochMetrics {
+    pub epoch: usize,
+    pub blocks_proposed: usize,
+    pub messages_sent: usize,
+    pub blocks_notarized: usize,
+    pub blocks_finalized: usize,
+    pub relays_avoided: usize,
+}
+
+/// Metrics collected by the network while running, one entry per epoch
+#[derive(Clone, Debug, Default)]
+pub struct NetworkMetrics {
+    pub epochs: Vec<EpochMetrics>,
+}
+
+impl NetworkMetrics {
+    pub fn new() -> Self {
+        NetworkMetrics::default()
+    }
+
+    /// Start recording the metrics of epoch e
+    pub fn begin_epoch(&mut self, e: usize) {
+        self.epochs.push(EpochMetrics {
+            epoch: e,
+            ..EpochMetrics::default()
+        });
+    }
+
+    /// Count messages sent in the current epoch
+    pub fn record_messages_sent(&mut self, count: usize) {
+        if let Some(current) = self.epochs.last_mut() {
+            current.messages_sent += count;
+        }
+    }
+
+    /// Finish recording the current epoch, given the node counters summed over all nodes at the
+    /// beginning and at the end of the epoch
+    pub fn end_epoch(&mut self, before: NodeStats, after: NodeStats) {
+        if let Some(current) = self.epochs.last_mut() {
+            current.blocks_proposed = after.blocks_proposed - before.blocks_proposed;
+            current.blocks_notarized = after.blocks_notarized - before.blocks_notarized;
+            current.blocks_finalized = after.blocks_finalized - before.blocks_finalized;
+            current.relays_avoided = after.relays_avoided - before.relays_avoided;
+        }
+    }
+
+    /// Returns the metrics summed over all epochs
+    pub fn total(&self) -> EpochMetrics {
+        self.epochs
+            .iter()
+            .fold(EpochMetrics::default(), |total, epoch| EpochMetrics {
+                epoch: epoch.epoch,
+                blocks_proposed: total.blocks_proposed + epoch.blocks_proposed,
+                messages_sent: total.messages_sent + epoch.messages_sent,
+                blocks_notarized: total.blocks_notarized + epoch.blocks_notarized,
+                blocks_finalized: total.blocks_finalized + epoch.blocks_finalized,
+                relays_avoided: total.relays_avoided + epoch.relays_avoided,
+            })
+    }
+}
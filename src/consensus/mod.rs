@@ -1,6 +1,16 @@
 pub mod attacker_node;
 pub mod blockchain;
+pub mod builder;
+pub mod causal_graph;
+pub mod events;
+pub mod fork_choice;
+pub mod leader_election;
+pub mod mempool;
+pub mod metrics;
 pub mod network;
 pub mod node;
+pub mod scenario;
 pub mod test_network;
+pub mod transport;
 pub mod utils;
+pub mod workload;
@@ -1,10 +1,129 @@
-use super::attacker_node::AttackerNode;
-use super::blockchain::Message;
-use super::node::{Node, NodeTrait};
-use super::utils::Debug;
+use super::attacker_node::{AttackerCoordinator, AttackerNode};
+use super::blockchain::{
+    BlockMessage, ChainParams, GenesisConfig, Message, ReconfigMessage, TimeoutMarker, Transaction,
+    RECONFIG_SENDER,
+};
+use super::events::{Event, EventRecord};
+use super::fork_choice::ForkChoice;
+use super::leader_election::{HashLeaderElection, LeaderElection};
+use super::mempool::MempoolPolicy;
+use super::metrics::NetworkMetrics;
+use super::node::{
+    MessageHandler, Node, NodeStats, NodeTrait, TxPoolOverflow, DEFAULT_FINALIZATION_DEPTH,
+    MAX_PAST_EPOCHS,
+};
+use super::scenario::Scenario;
+use super::utils::{Debug, Hash, HashAlgo};
+use super::workload::Workload;
 use rand::seq::SliceRandom;
-use rand::{rngs::StdRng, SeedableRng, Rng};
-use std::collections::{HashSet, VecDeque};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::panic;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A change of the validator set
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Churn {
+    // The validator with the given id leaves
+    Remove(usize),
+    // A new honest validator joins, starting from genesis
+    Add,
+}
+
+/// Fixed seed for deterministic behavior, used unless a network is created with a seed
+pub const DEFAULT_SEED: [u8; 32] = [0; 32];
+
+/// Maximum number of rounds of message passing when draining the network, see Network::drain
+pub const MAX_DRAIN_ROUNDS: usize = 50;
+
+/// Number of rounds of message passing per epoch, used unless configured otherwise
+pub const DEFAULT_ROUNDS_PER_EPOCH: usize = 3;
+
+/// How many epochs back the replay adversary keeps the messages it may re-send, see
+/// Network::set_replay_adversary. It reaches past MAX_PAST_EPOCHS, so that it also replays
+/// messages that nodes reject as stale.
+pub const REPLAY_HISTORY_EPOCHS: usize = 2 * MAX_PAST_EPOCHS;
+
+/// How messages are delivered in each round of an epoch
+#[derive(Clone, Copy, Debug)]
+enum Delivery {
+    // All messages, in the order they were sent
+    InOrder,
+    // All messages, in random order
+    Reordered,
+    // Each message with the given probability, in random order. The rest remains queued.
+    Delayed(f64),
+    // Chosen to make the honest nodes diverge, see recv_all_adversarial
+    Adversarial,
+    // The deliveries of the trace being replayed, see Network::replay
+    Replay,
+}
+
+/// The network model of an execution, i.e., how messages are delivered. See the respective run_*
+/// functions.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum NetworkModel {
+    // See run_simple
+    Simple,
+    // See run_reorder
+    Reorder,
+    // See run_delays_then_synchrony, with the given fraction of messages delivered per round
+    DelaysThenSynchrony(f64),
+    // See run_adversarial_schedule
+    Adversarial,
+}
+
+/// Which peers a node's messages can reach directly. Messages to other peers are dropped by the
+/// network, so they only reach them when relayed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Topology {
+    // Every node is connected to every other node
+    Complete,
+    // Node i is connected to nodes i - 1 and i + 1 (modulo n)
+    Ring,
+    // Every node is connected to d other nodes, chosen at random. The graph is a ring over a
+    // random permutation of the nodes, where each node is connected to the d / 2 nodes on either
+    // side, and, if d is odd, to the node opposite of it. This guarantees connectivity.
+    RandomRegular(usize),
+}
+
+/// When nodes process their unprocessed_pool within an epoch. At the start of the epoch, this
+/// happens right after the first round has delivered the messages queued at the epoch start.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoolProcessing {
+    EpochStart,
+    EpochEnd,
+    Both,
+}
+
+/// What happened in a single round of message passing, see Network::step
+#[derive(Clone, Debug)]
+pub struct StepResult {
+    // The epoch of the round
+    pub epoch: usize,
+    // The round within the epoch, starting from 0
+    pub round: usize,
+    // Whether this was the last round of the epoch
+    pub epoch_ended: bool,
+    // The messages sent, but not delivered yet
+    pub messages_in_flight: usize,
+    // The events emitted by all nodes in this round, including the proposals of a new epoch
+    pub events: Vec<EventRecord>,
+    // The blocks finalized in this round, as (node, block)
+    pub finalized: Vec<(usize, Hash)>,
+}
+
+/// A message emitted by an attacker node, given as (epoch, sender, receiver, message).
+pub type CapturedMessage = (usize, usize, usize, Box<dyn Message>);
+
+/// A delivered message, given as (round, sender, receiver, message), see Network::record_trace.
+/// That a node timed out an epoch is recorded as a TimeoutMarker it sent to itself.
+pub type TraceEntry = (usize, usize, usize, Box<dyn Message>);
 
 /// Simulator of network of nodes, some of which are malicious.
 pub struct Network {
@@ -13,111 +132,1409 @@ pub struct Network {
     recv_queue: Vec<VecDeque<(Box<dyn Message>, usize)>>,
     e: usize,
     rng: StdRng,
+    // All messages that attacker nodes sent, so that the attack can be replayed later
+    captured: Vec<CapturedMessage>,
+    // Maximum number of messages a sender may send per round. Excess messages are dropped.
+    rate_limit: Option<usize>,
+    // Number of messages dropped per sender due to the rate limit
+    dropped: Vec<usize>,
+    // Number of bytes sent per sender
+    bytes_sent: Vec<usize>,
+    // Number of bytes sent per message type
+    bytes_sent_by_kind: BTreeMap<&'static str, usize>,
+    // When nodes process their unprocessed_pool within an epoch
+    pool_processing: PoolProcessing,
+    // The number of rounds of message passing per epoch. It must cover the network diameter,
+    // such that blocks, votes and their relays reach all nodes within an epoch.
+    rounds_per_epoch: usize,
+    // The number of rounds of message passing so far, over all epochs
+    round: usize,
+    // Additional rounds it takes until a message reaches a receiver, per receiver
+    receiver_delay: Vec<usize>,
+    // The number of rounds a message from i to j takes, given as latency[i][j], or None if every
+    // message takes a single round. See run_with_latency_matrix.
+    latency: Option<Vec<Vec<usize>>>,
+    // Messages that are delayed, given as (release round, message, sender, receiver)
+    delayed: Vec<(usize, Box<dyn Message>, usize, usize)>,
+    // If set, messages are only delivered between nodes of the same group
+    partition: Option<Vec<HashSet<usize>>>,
+    // The network model used by run
+    model: NetworkModel,
+    // Validators that left. Messages from and to them are no longer delivered.
+    removed: HashSet<usize>,
+    // The validator sets so far, starting with the genesis set, as the epoch from which on a set
+    // applies, and the set. Like the validator sets of the nodes, they include the changes of
+    // apply_churn and the finalized reconfigurations. Replayed to nodes that join later.
+    validator_sets: Vec<(usize, Vec<usize>)>,
+    // The reconfigurations submitted by reconfigure that the validators have not finalized yet,
+    // as the epoch from which on they apply, the new set, and the nodes created for it
+    pending_reconfigs: Vec<(usize, Vec<usize>, Vec<usize>)>,
+    // Metrics collected while running
+    metrics: NetworkMetrics,
+    // Nodes that are currently crashed. They keep their state, but do not take part in the
+    // protocol, and messages from and to them are lost.
+    down: HashSet<usize>,
+    // The leader election all nodes use, also given to nodes that join later
+    leader_election: Box<dyn LeaderElection>,
+    // The neighbors of each node according to the topology, or None for a complete graph. Nodes
+    // that joined after the topology was set are connected to every node.
+    neighbors: Option<Vec<HashSet<usize>>>,
+    // The workload whose transactions are submitted at the start of each epoch
+    workload: Option<Workload>,
+    // The transactions of the workload, built once for the nodes when it was set, so that they
+    // keep their senders and nonces when nodes join later
+    workload_transactions: Vec<Transaction>,
+    // The next round within the current epoch, or None if no epoch is in progress
+    epoch_round: Option<usize>,
+    // The node counters summed over all nodes at the beginning of the current epoch
+    epoch_stats: NodeStats,
+    // All deliveries so far, if recording, see record_trace
+    trace: Option<Vec<TraceEntry>>,
+    // The deliveries of the trace being replayed that are still due, see replay
+    replaying: Option<VecDeque<TraceEntry>>,
+    // The hash function of all nodes, part of the genesis configuration, see with_genesis
+    hash_algo: HashAlgo,
+    // The number of threads on which nodes process the messages delivered to them, see set_threads
+    threads: usize,
+    // The number of old messages the replay adversary re-sends per epoch, see set_replay_adversary
+    replay_per_epoch: usize,
+    // The block and vote messages sent in the last REPLAY_HISTORY_EPOCHS epochs, if the replay
+    // adversary is active
+    history: Vec<CapturedMessage>,
+    // The messages the replay adversary re-sent, with the epoch in which it re-sent them
+    replayed: Vec<CapturedMessage>,
+    // Whether the chains of the honest nodes are pruned below the global safe height at the end
+    // of every epoch, see set_pruning
+    pruning: bool,
 }
 
 impl Network {
+    /// Create a new network of honest nodes. A block is notarized with strictly more than 2n/3
+    /// votes, so with n = 1, the single node notarizes its own proposals right away, and with
+    /// n = 2, both nodes need to vote. Panics if n is zero.
     pub fn new(number: usize) -> Network {
+        Network::with_finalization_depth(number, DEFAULT_FINALIZATION_DEPTH)
+    }
+
+    /// Create a new network of honest nodes whose chain uses the given hash function
+    pub fn with_hash_algo(n: usize, hash_algo: HashAlgo) -> Network {
+        Network::with_genesis(n, GenesisConfig::with_hash_algo(hash_algo))
+    }
+
+    /// Returns the hash function of all nodes
+    pub fn hash_algo(&self) -> HashAlgo {
+        self.hash_algo
+    }
+
+    /// Create a new network of honest nodes that require finalization_depth notarized blocks of
+    /// consecutive epochs to finalize a block
+    pub fn with_finalization_depth(number: usize, finalization_depth: usize) -> Network {
         let mut nodes: Vec<Box<dyn NodeTrait>> = Vec::new();
         for i in 0..number {
-            nodes.push(Box::new(Node::new(i, number)));
-        }
-        let mut recv_queue = Vec::with_capacity(number);
-        for _ in 0..number {
-            recv_queue.push(VecDeque::new());
-        }
-        let seed: [u8; 32] = [0; 32]; // Fixed seed for deterministic behavior
-        let rng = StdRng::from_seed(seed);
-        Network {
-            nodes,
-            n: number,
-            recv_queue,
-            e: 0,
-            rng,
+            nodes.push(Box::new(Node::new(
+                i,
+                (0..number).collect(),
+                finalization_depth,
+            )));
         }
+        Network::from_nodes(nodes)
     }
 
-    // Create a new network with floor(n/3) of the nodes being of attacker nodes
-    pub fn new_byzantine(n: usize, attacker_config: HashSet<String>) -> Network {
+    /// Create a new network where num_attackers of the nodes are attacker nodes. The attackers
+    /// are always the nodes with the highest ids. Safety is only guaranteed if fewer than n/3
+    /// nodes are attackers, but we allow more in order to explore what breaks. Panics if n is zero
+    /// or there are more attackers than nodes.
+    pub fn new_byzantine(
+        n: usize,
+        num_attackers: usize,
+        attacker_config: HashSet<String>,
+    ) -> Network {
+        Network::new_byzantine_with_genesis(
+            n,
+            num_attackers,
+            attacker_config,
+            DEFAULT_SEED,
+            GenesisConfig::default(),
+        )
+    }
+
+    /// Like new_byzantine_with_seed, but all nodes start from the genesis block of the given
+    /// configuration. If the configuration does not define the validator set, it consists of all
+    /// n nodes. Panics if the validator set is empty.
+    pub fn new_byzantine_with_genesis(
+        n: usize,
+        num_attackers: usize,
+        attacker_config: HashSet<String>,
+        seed: [u8; 32],
+        genesis_config: GenesisConfig,
+    ) -> Network {
+        assert!(
+            num_attackers <= n,
+            "{} attackers, but only {} nodes",
+            num_attackers,
+            n
+        );
+        if 3 * num_attackers >= n {
+            Debug::dbg(
+                &format!(
+                    "{} of {} nodes are attackers, safety is no longer guaranteed",
+                    num_attackers, n
+                ),
+                0,
+                Some("NETWORK"),
+            );
+        }
+        let mut genesis_config = genesis_config;
+        genesis_config
+            .validators
+            .get_or_insert_with(|| (0..n).collect());
         let mut nodes: Vec<Box<dyn NodeTrait>> = Vec::new();
         for i in 0..n {
-            if i < (2.0 / 3.0 * n as f64) as usize {
-                nodes.push(Box::new(Node::new(i, n)));
+            let genesis_config = genesis_config.clone();
+            let node: Result<Box<dyn NodeTrait>, _> = if i + num_attackers < n {
+                Node::with_genesis(i, genesis_config).map(|node| Box::new(node) as _)
             } else {
-                nodes.push(Box::new(AttackerNode::new(i, n, attacker_config.clone())));
-            }
+                let attacker_config = attacker_config.clone();
+                AttackerNode::with_genesis(i, attacker_config, genesis_config)
+                    .map(|node| Box::new(node) as _)
+            };
+            nodes.push(node.unwrap_or_else(|err| panic!("{}", err)));
         }
+        let validators = genesis_config.validators.clone().unwrap();
+        Network {
+            rng: StdRng::from_seed(seed),
+            hash_algo: genesis_config.hash_algo,
+            validator_sets: vec![(0, validators)],
+            ..Network::from_nodes(nodes)
+        }
+    }
+
+    /// Create a new network of honest nodes starting from the genesis block of the given
+    /// configuration
+    pub fn with_genesis(n: usize, genesis_config: GenesisConfig) -> Network {
+        Network::new_byzantine_with_genesis(n, 0, HashSet::new(), DEFAULT_SEED, genesis_config)
+    }
+
+    /// Create a new network of honest nodes whose message schedule (reordering, delays) is drawn
+    /// from a random number generator with the given seed
+    pub fn with_seed(n: usize, seed: [u8; 32]) -> Network {
+        Network {
+            rng: StdRng::from_seed(seed),
+            ..Network::new(n)
+        }
+    }
+
+    /// Like new_byzantine, but with the given seed for the random number generator
+    pub fn new_byzantine_with_seed(
+        n: usize,
+        num_attackers: usize,
+        attacker_config: HashSet<String>,
+        seed: [u8; 32],
+    ) -> Network {
+        Network::new_byzantine_with_genesis(
+            n,
+            num_attackers,
+            attacker_config,
+            seed,
+            GenesisConfig::default(),
+        )
+    }
+
+    /// Create the network of a scenario, with its attackers, network model and workload, ready to
+    /// run for scenario.epochs epochs
+    pub fn from_scenario(scenario: &Scenario) -> Network {
+        let attacker_config = scenario.attacker_config.iter().cloned().collect();
+        let mut network = Network::new_byzantine_with_seed(
+            scenario.n,
+            scenario.attackers,
+            attacker_config,
+            scenario.seed,
+        );
+        network.set_network_model(scenario.model);
+        network.set_rounds_per_epoch(scenario.rounds_per_epoch);
+        network.set_workload(scenario.workload.clone());
+        network
+    }
+
+    /// Create a new network consisting of the given nodes, with default network settings
+    fn from_nodes(nodes: Vec<Box<dyn NodeTrait>>) -> Network {
+        assert!(!nodes.is_empty(), "A network needs at least one node");
+        let n = nodes.len();
         let mut recv_queue = Vec::with_capacity(n);
         for _ in 0..n {
             recv_queue.push(VecDeque::new());
         }
-        let seed: [u8; 32] = [0; 32]; // Fixed seed for deterministic behavior
-        let rng = StdRng::from_seed(seed);
+        let rng = StdRng::from_seed(DEFAULT_SEED);
         Network {
             nodes,
             n,
             recv_queue,
             e: 0,
             rng,
+            captured: Vec::new(),
+            rate_limit: None,
+            dropped: vec![0; n],
+            bytes_sent: vec![0; n],
+            bytes_sent_by_kind: BTreeMap::new(),
+            pool_processing: PoolProcessing::EpochEnd,
+            rounds_per_epoch: DEFAULT_ROUNDS_PER_EPOCH,
+            model: NetworkModel::Simple,
+            round: 0,
+            receiver_delay: vec![0; n],
+            latency: None,
+            delayed: Vec::new(),
+            partition: None,
+            removed: HashSet::new(),
+            validator_sets: vec![(0, (0..n).collect())],
+            pending_reconfigs: Vec::new(),
+            metrics: NetworkMetrics::new(),
+            down: HashSet::new(),
+            leader_election: Box::new(HashLeaderElection),
+            neighbors: None,
+            workload: None,
+            workload_transactions: Vec::new(),
+            epoch_round: None,
+            epoch_stats: NodeStats::default(),
+            trace: None,
+            replaying: None,
+            hash_algo: HashAlgo::default(),
+            threads: 1,
+            replay_per_epoch: 0,
+            history: Vec::new(),
+            replayed: Vec::new(),
+            pruning: false,
+        }
+    }
+
+    /// Submit the transactions of the workload to the nodes as they arrive. The transactions are
+    /// built for the current nodes. Transactions that would have arrived in epochs that already
+    /// began are submitted right away.
+    pub fn set_workload(&mut self, workload: Workload) {
+        self.workload_transactions = workload.transactions(self.n);
+        self.workload = Some(workload);
+        for epoch in 0..=self.e {
+            self.feed_workload(epoch);
+        }
+    }
+
+    /// Returns the workload whose transactions are submitted, if any
+    pub fn workload(&self) -> Option<&Workload> {
+        self.workload.as_ref()
+    }
+
+    /// Returns the transactions of the workload, see set_workload
+    pub fn workload_transactions(&self) -> &[Transaction] {
+        &self.workload_transactions
+    }
+
+    /// Submit the transactions of the workload that arrive in the given epoch. They are spread
+    /// over the current validators that are not down.
+    pub fn feed_workload(&mut self, epoch: usize) {
+        let Some(workload) = &self.workload else {
+            return;
+        };
+        let arriving = workload.arriving(epoch);
+        let receivers: Vec<usize> = self
+            .validators()
+            .into_iter()
+            .filter(|i| !self.down.contains(i))
+            .collect();
+        if receivers.is_empty() {
+            return;
+        }
+        for i in arriving {
+            let tx = self.workload_transactions[i].clone();
+            self.nodes[receivers[i % receivers.len()]].send_transaction(tx);
+        }
+    }
+
+    /// Restrict which peers each node can reach directly to the given topology. Panics if a
+    /// random regular topology has a degree of at least n, or an odd degree with an odd n.
+    pub fn set_topology(&mut self, topology: Topology) {
+        let n = self.n;
+        let mut neighbors = vec![HashSet::new(); n];
+        let mut connect = |i: usize, j: usize| {
+            if i != j {
+                neighbors[i].insert(j);
+                neighbors[j].insert(i);
+            }
+        };
+        match topology {
+            Topology::Complete => {
+                self.neighbors = None;
+                return;
+            }
+            Topology::Ring => {
+                for i in 0..n {
+                    connect(i, (i + 1) % n);
+                }
+            }
+            Topology::RandomRegular(d) => {
+                assert!(d < n, "The degree must be smaller than the number of nodes");
+                assert!(
+                    d.is_multiple_of(2) || n.is_multiple_of(2),
+                    "An odd degree requires an even number of nodes"
+                );
+                let mut order: Vec<usize> = (0..n).collect();
+                order.shuffle(&mut self.rng);
+                for k in 0..n {
+                    for offset in 1..=d / 2 {
+                        connect(order[k], order[(k + offset) % n]);
+                    }
+                    if !d.is_multiple_of(2) {
+                        connect(order[k], order[(k + n / 2) % n]);
+                    }
+                }
+            }
+        }
+        self.dbg(
+            &format!("Topology {:?}: {:?}", topology, neighbors),
+            None,
+            Some("NETWORK"),
+        );
+        self.neighbors = Some(neighbors);
+    }
+
+    /// Returns whether node i can send messages to node j directly
+    fn is_connected(&self, i: usize, j: usize) -> bool {
+        match &self.neighbors {
+            Some(neighbors) if i < neighbors.len() && j < neighbors.len() => {
+                neighbors[i].contains(&j)
+            }
+            _ => true,
+        }
+    }
+
+    /// Let all nodes use the given block and transaction size limits
+    pub fn set_chain_params(&mut self, params: ChainParams) {
+        for node in self.nodes.iter_mut() {
+            node.set_chain_params(params);
+        }
+    }
+
+    /// Make all attacker nodes collude by sharing a single AttackerCoordinator
+    pub fn set_colluding(&mut self) {
+        let attackers: HashSet<usize> = (0..self.n)
+            .filter(|i| self.nodes[*i].is_attacker())
+            .collect();
+        let coordinator = Arc::new(Mutex::new(AttackerCoordinator::new(attackers)));
+        for node in self.nodes.iter_mut() {
+            if let Some(attacker) = node.as_any_mut().downcast_mut::<AttackerNode>() {
+                attacker.set_coordinator(coordinator.clone());
+            }
+        }
+    }
+
+    /// Let all nodes vote for empty blocks when the leader is silent, see
+    /// Node::set_empty_block_timeout
+    pub fn set_empty_block_timeout(&mut self, timeout: Option<usize>) {
+        for node in self.nodes.iter_mut() {
+            node.set_empty_block_timeout(timeout);
+        }
+    }
+
+    /// Let backup leaders propose when the leader is silent, see Node::set_backup_leader_timeout
+    pub fn set_backup_leader_timeout(&mut self, timeout: Option<usize>) {
+        for node in self.nodes.iter_mut() {
+            node.set_backup_leader_timeout(timeout);
+        }
+    }
+
+    /// Handle the messages of the given kind with the handler at all nodes, see
+    /// Node::register_handler
+    pub fn register_handler(&mut self, kind: &'static str, handler: MessageHandler) {
+        for node in self.nodes.iter_mut() {
+            node.register_handler(kind, handler.clone());
+        }
+    }
+
+    /// Bound the transaction pool of all nodes, see Node::set_max_tx_pool
+    pub fn set_max_tx_pool(&mut self, max_tx_pool: usize, overflow: TxPoolOverflow) {
+        for node in self.nodes.iter_mut() {
+            node.set_max_tx_pool(max_tx_pool, overflow);
+        }
+    }
+
+    /// Let all nodes weigh votes with the given stake of each validator, indexed by node id
+    pub fn set_stake(&mut self, stake: Vec<u64>) {
+        for node in self.nodes.iter_mut() {
+            node.set_stake(stake.clone());
+        }
+    }
+
+    /// Let all nodes, honest and attackers, determine leaders with the given leader election
+    pub fn set_leader_election(&mut self, leader_election: &dyn LeaderElection) {
+        self.leader_election = leader_election.clone_box();
+        for node in self.nodes.iter_mut() {
+            node.set_leader_election(leader_election.clone_box());
+        }
+    }
+
+    /// Let all nodes order the transactions of their pools with the given mempool policy
+    pub fn set_mempool_policy(&mut self, mempool_policy: &dyn MempoolPolicy) {
+        for node in self.nodes.iter_mut() {
+            node.set_mempool_policy(mempool_policy.clone_box());
+        }
+    }
+
+    /// Let all nodes choose the notarized block to extend with the given fork choice rule. Set
+    /// the rule of a single node with NodeTrait::set_fork_choice.
+    pub fn set_fork_choice(&mut self, fork_choice: &dyn ForkChoice) {
+        for node in self.nodes.iter_mut() {
+            node.set_fork_choice(fork_choice.clone_box());
         }
     }
 
+    /// Returns the current epoch, i.e., the number of epochs run so far
+    pub fn epoch(&self) -> usize {
+        self.e
+    }
+
     pub fn send(&mut self, i: usize, m: Box<dyn Message>, j: usize) {
-        self.recv_queue[j].push_back((m, i));
+        if self.is_partitioned(i, j)
+            || self.removed.contains(&i)
+            || self.removed.contains(&j)
+            || self.down.contains(&i)
+            || self.down.contains(&j)
+        {
+            // The message is lost
+            return;
+        }
+        let delay = self.receiver_delay[j] + self.link_delay(i, j);
+        if delay > 0 {
+            // The message would be delivered in the next round, but is delayed further
+            self.delayed.push((self.round + 1 + delay, m, i, j));
+        } else {
+            self.recv_queue[j].push_back((m, i));
+        }
+    }
+
+    /// Returns if nodes i and j are currently in different groups of a partition. Nodes that are
+    /// not in any group are isolated.
+    fn is_partitioned(&self, i: usize, j: usize) -> bool {
+        match &self.partition {
+            Some(groups) => !groups
+                .iter()
+                .any(|group| group.contains(&i) && group.contains(&j)),
+            None => false,
+        }
+    }
+
+    /// Returns the number of rounds beyond the next one that a message from i to j takes due to
+    /// the latency of their link. Links of nodes that joined after the latency matrix was set
+    /// take a single round.
+    fn link_delay(&self, i: usize, j: usize) -> usize {
+        self.latency
+            .as_ref()
+            .and_then(|latency| latency.get(i)?.get(j))
+            .map_or(0, |rounds| rounds.saturating_sub(1))
+    }
+
+    /// Make the given nodes slow: every message takes delay additional rounds to reach them.
+    /// This models poorly-connected, but honest validators.
+    pub fn set_slow_nodes(&mut self, slow_nodes: &HashSet<usize>, delay: usize) {
+        for i in slow_nodes {
+            self.receiver_delay[*i] = delay;
+        }
+    }
+
+    /// Move delayed messages that are due in the current round into the receive queues
+    fn release_delayed(&mut self) {
+        let (due, delayed): (Vec<_>, Vec<_>) = self
+            .delayed
+            .drain(..)
+            .partition(|(release_round, _, _, _)| *release_round <= self.round);
+        self.delayed = delayed;
+        for (_, m, i, j) in due {
+            self.recv_queue[j].push_back((m, i));
+        }
+    }
+
+    /// Limit the number of messages each sender may send per round, modeling the rate limits of
+    /// a real P2P network. Messages beyond the limit are dropped.
+    pub fn set_rate_limit(&mut self, limit: usize) {
+        self.rate_limit = Some(limit);
+    }
+
+    /// Returns the number of messages dropped due to the rate limit, per sender
+    pub fn dropped_messages(&self) -> &[usize] {
+        &self.dropped
+    }
+
+    /// Returns the number of bytes sent so far, summed over all nodes
+    pub fn total_bytes_sent(&self) -> usize {
+        self.bytes_sent.iter().sum()
+    }
+
+    /// Returns the number of bytes sent so far, per sender
+    pub fn bytes_sent(&self) -> &[usize] {
+        &self.bytes_sent
+    }
+
+    /// Returns the number of bytes sent so far, per message type
+    pub fn bytes_sent_by_kind(&self) -> &BTreeMap<&'static str, usize> {
+        &self.bytes_sent_by_kind
     }
 
     fn send_all(&mut self) {
         for sender in 0..self.n {
-            let messages: Vec<_> = self.nodes[sender]
+            let mut messages: Vec<_> = self.nodes[sender]
                 .clear_outgoing_messages()
-                .iter()
-                .cloned()
+                .into_iter()
+                .filter(|(receiver, _)| self.is_connected(sender, *receiver))
                 .collect();
+            if let Some(limit) = self.rate_limit {
+                if messages.len() > limit {
+                    self.dbg(
+                        &format!(
+                            "Rate limit: dropping {} messages of node {}",
+                            messages.len() - limit,
+                            sender
+                        ),
+                        None,
+                        Some("NETWORK"),
+                    );
+                    self.dropped[sender] += messages.len() - limit;
+                    messages.truncate(limit);
+                }
+            }
+            self.metrics.record_messages_sent(messages.len());
             for (receiver, m) in messages {
+                let size = m.size_bytes();
+                self.bytes_sent[sender] += size;
+                *self.bytes_sent_by_kind.entry(m.kind()).or_default() += size;
+                if self.nodes[sender].is_attacker() {
+                    self.captured.push((self.e, sender, receiver, m.clone()));
+                }
+                if self.replay_per_epoch > 0 && matches!(m.kind(), "block" | "vote") {
+                    self.history.push((self.e, sender, receiver, m.clone()));
+                }
                 self.send(sender, m, receiver);
             }
         }
     }
 
+    /// Returns all messages that attacker nodes have sent so far
+    pub fn captured_attacker_messages(&self) -> &[CapturedMessage] {
+        &self.captured
+    }
+
+    /// Activate an adversary in the network, rather than in a node, that replays old messages:
+    /// at the start of each epoch, it re-sends messages_per_epoch genuinely signed block and vote
+    /// messages, chosen at random among those sent in the REPLAY_HISTORY_EPOCHS epochs before, to
+    /// random nodes. Honest nodes must ignore them, rejecting those of epochs far behind their
+    /// own.
+    pub fn set_replay_adversary(&mut self, messages_per_epoch: usize) {
+        self.replay_per_epoch = messages_per_epoch;
+    }
+
+    /// Returns the messages the replay adversary re-sent so far, see set_replay_adversary, given
+    /// as (epoch of the replay, sender, receiver, message)
+    pub fn replayed_messages(&self) -> &[CapturedMessage] {
+        &self.replayed
+    }
+
+    /// Re-send a random sample of the block and vote messages of recent earlier epochs, each in
+    /// the name of its original sender to a random other node. Forgets the messages of epochs
+    /// that left the window of REPLAY_HISTORY_EPOCHS.
+    fn replay_old_messages(&mut self) {
+        let expired = self
+            .history
+            .partition_point(|(e, _, _, _)| e + REPLAY_HISTORY_EPOCHS < self.e);
+        self.history.drain(..expired);
+        let old = self.history.partition_point(|(e, _, _, _)| *e < self.e);
+        let count = self.replay_per_epoch.min(old);
+        for index in rand::seq::index::sample(&mut self.rng, old, count) {
+            let (_, sender, _, m) = &self.history[index];
+            let (sender, m) = (*sender, m.clone());
+            let receiver = (sender + self.rng.gen_range(1..self.n.max(2))) % self.n;
+            self.replayed.push((self.e, sender, receiver, m.clone()));
+            self.send(sender, m, receiver);
+        }
+    }
+
     fn recv_all(&mut self) {
+        if self.threads > 1 {
+            self.recv_all_parallel();
+            return;
+        }
         for i in 0..self.n {
             if !self.recv_queue[i].is_empty() {
                 while let Some((m, j)) = self.recv_queue[i].pop_front() {
-                    self.nodes[i].incoming_message(&*m, j);
+                    self.deliver(m, j, i);
                 }
             }
         }
     }
 
+    /// Like recv_all, but the nodes process their queues in parallel, on one scoped thread per
+    /// chunk of the nodes. The threads only borrow their chunk for the round, so the nodes stay
+    /// in the network even if a node panics, and the panic is passed on once all threads are
+    /// done. Each node only mutates its own state when processing a message, and we record the
+    /// deliveries in the same order as recv_all, so the execution is the same as with recv_all.
+    fn recv_all_parallel(&mut self) {
+        let recording = self.trace.is_some();
+        let chunk_size = self.nodes.len().div_ceil(self.threads).max(1);
+        let delivered: Vec<Vec<(usize, Box<dyn Message>)>> = thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .nodes
+                .chunks_mut(chunk_size)
+                .zip(self.recv_queue.chunks_mut(chunk_size))
+                .map(|(nodes, queues)| {
+                    scope.spawn(move || Network::process_queues(nodes, queues, recording))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|panic| panic::resume_unwind(panic))
+                })
+                .collect()
+        });
+        if let Some(trace) = &mut self.trace {
+            for (i, received) in delivered.into_iter().enumerate() {
+                for (j, m) in received {
+                    trace.push((self.round, j, i, m));
+                }
+            }
+        }
+    }
+
+    /// Let each of the given nodes process its queue. Returns, for each node, the messages it
+    /// received and their senders, if recording.
+    fn process_queues(
+        nodes: &mut [Box<dyn NodeTrait>],
+        queues: &mut [VecDeque<(Box<dyn Message>, usize)>],
+        recording: bool,
+    ) -> Vec<Vec<(usize, Box<dyn Message>)>> {
+        nodes
+            .iter_mut()
+            .zip(queues.iter_mut())
+            .map(|(node, queue)| {
+                let mut received = Vec::new();
+                while let Some((m, j)) = queue.pop_front() {
+                    node.incoming_message(&*m, j);
+                    if recording {
+                        received.push((j, m));
+                    }
+                }
+                received
+            })
+            .collect()
+    }
+
+    /// Let the nodes process the messages delivered to them in parallel on the given number of
+    /// threads, e.g. one per available CPU, or sequentially if it is 1 (the default). This only
+    /// speeds up the execution: the deliveries and their order are the same as without
+    /// parallelism, so the execution stays reproducible. It applies to the rounds that deliver all
+    /// messages in order, e.g. those of run_simple, and to drain.
+    pub fn set_threads(&mut self, threads: usize) {
+        assert!(threads > 0, "At least one thread is needed");
+        self.threads = threads;
+    }
+
+    /// Deliver message m from sender i to receiver j, and record the delivery if recording
+    fn deliver(&mut self, m: Box<dyn Message>, i: usize, j: usize) {
+        self.nodes[j].incoming_message(&*m, i);
+        if let Some(trace) = &mut self.trace {
+            trace.push((self.round, i, j, m));
+        }
+    }
+
+    /// Deliver the messages of the replayed trace that are due in the current round. The
+    /// messages the nodes sent themselves are discarded, since the trace determines all
+    /// deliveries. The timeouts recorded at the end of the round are left for end_epoch.
+    fn recv_replayed(&mut self) {
+        for queue in self.recv_queue.iter_mut() {
+            queue.clear();
+        }
+        self.delayed.clear();
+        let round = self.round;
+        while let Some((_, i, j, m)) = self.replaying.as_mut().and_then(|replaying| {
+            replaying.pop_front_if(|entry| entry.0 <= round && entry.3.kind() != "timeout")
+        }) {
+            self.deliver(m, i, j);
+        }
+    }
+
+    /// Returns the nodes that timed out the current epoch according to the replayed trace
+    fn replayed_timeouts(&mut self) -> Vec<usize> {
+        let round = self.round;
+        let mut timed_out = Vec::new();
+        while let Some((_, _, j, _)) = self.replaying.as_mut().and_then(|replaying| {
+            replaying.pop_front_if(|entry| entry.0 <= round && entry.3.kind() == "timeout")
+        }) {
+            timed_out.push(j);
+        }
+        timed_out
+    }
+
+    /// Nodes process messages from their unprocessed_pool
+    fn process_unprocessed_pools(&mut self) {
+        for i in 0..self.n {
+            if !self.down.contains(&i) {
+                self.nodes[i].process_unprocessed_pool();
+            }
+        }
+    }
+
+    /// Configure when nodes process their unprocessed_pool within each epoch
+    pub fn set_pool_processing(&mut self, pool_processing: PoolProcessing) {
+        self.pool_processing = pool_processing;
+    }
+
+    /// Set the number of rounds of message passing per epoch of all run_* models
+    pub fn set_rounds_per_epoch(&mut self, rounds_per_epoch: usize) {
+        self.rounds_per_epoch = rounds_per_epoch;
+    }
+
+    /// Run a single epoch: the leader proposes, followed by rounds_per_epoch rounds of message
+    /// passing, where each round delivers messages according to the given delivery model.
+    /// Messages in inject are added to the receive queues right after the proposals. If an epoch
+    /// was started with step, only its remaining rounds are run.
+    fn run_epoch(&mut self, delivery: Delivery, inject: &[CapturedMessage]) {
+        if self.epoch_round.is_none() {
+            self.begin_epoch(inject);
+        }
+        while self.epoch_round.is_some() {
+            self.run_round(delivery);
+        }
+    }
+
+    /// Start a new epoch, in which the leader proposes
+    fn begin_epoch(&mut self, inject: &[CapturedMessage]) {
+        self.e += 1;
+        self.dbg(
+            &format!("========= New Epoch {} =========", self.e),
+            None,
+            Some("NETWORK"),
+        );
+        self.metrics.begin_epoch(self.e);
+        self.epoch_stats = self.node_stats();
+        self.feed_workload(self.e);
+        for i in 0..self.n {
+            if !self.down.contains(&i) {
+                self.nodes[i].new_epoch(self.e, self.round as u64);
+            }
+        }
+        for (_, sender, receiver, m) in inject {
+            self.send(*sender, m.clone(), *receiver);
+        }
+        if self.replay_per_epoch > 0 {
+            self.replay_old_messages();
+        }
+        self.epoch_round = Some(0);
+    }
+
+    /// Run the next round of message passing of the current epoch, and end the epoch after its
+    /// last round
+    fn run_round(&mut self, delivery: Delivery) {
+        let round = self.epoch_round.unwrap();
+        self.round += 1;
+        self.release_delayed();
+        match delivery {
+            Delivery::InOrder => self.recv_all(),
+            Delivery::Reordered => self.recv_all_randomized(),
+            Delivery::Adversarial => self.recv_all_adversarial(round),
+            Delivery::Delayed(fraction) => {
+                let randomized_queue = self.pick_random_messages(fraction);
+                for (m, i, j) in randomized_queue {
+                    self.deliver(m, j, i);
+                }
+            }
+            Delivery::Replay => self.recv_replayed(),
+        }
+        // At the start of the epoch, once the messages that were already queued have been
+        // delivered, orphans may be resolvable
+        if round == 0 && self.pool_processing != PoolProcessing::EpochEnd {
+            self.process_unprocessed_pools();
+        }
+        for i in 0..self.n {
+            if !self.down.contains(&i) {
+                self.nodes[i].new_round();
+            }
+        }
+        self.send_all();
+        if round + 1 < self.rounds_per_epoch {
+            self.epoch_round = Some(round + 1);
+        } else {
+            self.end_epoch(delivery);
+        }
+    }
+
+    /// End the current epoch. If messages were delivered late in it, so that proposals of slow
+    /// leaders may still be in flight, nodes time out the epoch if they have not seen a
+    /// notarized block for it. The other delivery models deliver all proposals in time. Replays
+    /// time out the epochs that timed out in the recorded trace.
+    fn end_epoch(&mut self, delivery: Delivery) {
+        if self.pool_processing != PoolProcessing::EpochStart {
+            self.process_unprocessed_pools();
+        }
+        let timed_out: Vec<usize> = match delivery {
+            Delivery::Delayed(_) => (0..self.n).filter(|i| !self.down.contains(i)).collect(),
+            Delivery::Replay => self.replayed_timeouts(),
+            _ => Vec::new(),
+        };
+        for i in timed_out {
+            self.nodes[i].timeout(self.e);
+            if let Some(trace) = &mut self.trace {
+                let marker = TimeoutMarker {
+                    creator: i,
+                    e: self.e,
+                };
+                trace.push((self.round, i, i, Box::new(marker)));
+            }
+        }
+        self.apply_finalized_reconfigs();
+        if self.pruning {
+            self.prune();
+        }
+        let stats_after = self.node_stats();
+        self.metrics.end_epoch(self.epoch_stats, stats_after);
+        self.epoch_round = None;
+    }
+
+    /// Advance the execution by exactly one round of message passing, starting a new epoch first
+    /// if the previous one ended. Messages are delivered according to the network model, where
+    /// DelaysThenSynchrony delays messages throughout, since the point of synchrony depends on
+    /// the number of epochs run. Returns what happened in this round.
+    pub fn step(&mut self) -> StepResult {
+        let delivery = match self.model {
+            NetworkModel::Simple => Delivery::InOrder,
+            NetworkModel::Reorder => Delivery::Reordered,
+            NetworkModel::DelaysThenSynchrony(fraction) => Delivery::Delayed(fraction),
+            NetworkModel::Adversarial => Delivery::Adversarial,
+        };
+        let events_before: Vec<usize> = self
+            .nodes
+            .iter()
+            .map(|node| node.events_emitted())
+            .collect();
+        if self.epoch_round.is_none() {
+            self.begin_epoch(&[]);
+        }
+        let round = self.epoch_round.unwrap();
+        self.run_round(delivery);
+        let events: Vec<EventRecord> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(i, node)| {
+                // The node may have dropped some of the events it emitted since
+                let new = node.events_emitted() - events_before.get(i).copied().unwrap_or(0);
+                let events = node.events();
+                events[events.len() - new.min(events.len())..].to_vec()
+            })
+            .collect();
+        let finalized = events
+            .iter()
+            .filter_map(|record| match record.event {
+                Event::Finalized { block, .. } => Some((record.node, block)),
+                _ => None,
+            })
+            .collect();
+        StepResult {
+            epoch: self.e,
+            round,
+            epoch_ended: self.epoch_round.is_none(),
+            messages_in_flight: self.recv_queue.iter().map(VecDeque::len).sum::<usize>()
+                + self.delayed.len(),
+            events,
+            finalized,
+        }
+    }
+
+    /// Returns the counters of all nodes, summed up
+    fn node_stats(&self) -> NodeStats {
+        self.nodes
+            .iter()
+            .map(|node| node.stats())
+            .fold(NodeStats::default(), |total, stats| NodeStats {
+                blocks_proposed: total.blocks_proposed + stats.blocks_proposed,
+                blocks_notarized: total.blocks_notarized + stats.blocks_notarized,
+                blocks_finalized: total.blocks_finalized + stats.blocks_finalized,
+                relays_avoided: total.relays_avoided + stats.relays_avoided,
+                txs_dropped: total.txs_dropped + stats.txs_dropped,
+                finalize_attempts: total.finalize_attempts + stats.finalize_attempts,
+            })
+    }
+
+    /// Returns the metrics collected so far, per epoch
+    pub fn metrics(&self) -> &NetworkMetrics {
+        &self.metrics
+    }
+
+    /// Set the network model used by run
+    pub fn set_network_model(&mut self, model: NetworkModel) {
+        self.model = model;
+    }
+
+    /// Deliver all messages in flight, including delayed ones, and let nodes process their
+    /// unprocessed pools, without advancing the epoch, until no node sends new messages. Rounds
+    /// still advance, so that nodes broadcast the proposals they delay. This lets notarizations
+    /// and finalizations complete that only lack message delivery. Gives up after
+    /// MAX_DRAIN_ROUNDS rounds, e.g. if attackers keep sending messages.
+    pub fn drain(&mut self) {
+        for _ in 0..MAX_DRAIN_ROUNDS {
+            self.send_all();
+            for (_, m, i, j) in std::mem::take(&mut self.delayed) {
+                self.recv_queue[j].push_back((m, i));
+            }
+            let quiet = match &self.replaying {
+                Some(replaying) => replaying.is_empty(),
+                None => self.recv_queue.iter().all(VecDeque::is_empty),
+            };
+            let delaying = (0..self.n)
+                .any(|i| !self.down.contains(&i) && self.nodes[i].delayed_proposals() > 0);
+            if quiet && !delaying {
+                return;
+            }
+            self.round += 1;
+            if self.replaying.is_some() {
+                self.recv_replayed();
+            } else {
+                self.recv_all();
+            }
+            self.process_unprocessed_pools();
+            for i in 0..self.n {
+                if !self.down.contains(&i) {
+                    self.nodes[i].new_round();
+                }
+            }
+        }
+        self.dbg(
+            "Network did not quiesce while draining",
+            None,
+            Some("NETWORK"),
+        );
+    }
+
+    /// End a run: deliver all messages in flight, then check that every honest node only
+    /// finalized blocks it notarized. A violation is a soundness bug.
+    fn finish_run(&mut self) {
+        self.drain();
+        self.apply_finalized_reconfigs();
+        debug_assert!(
+            self.finalized_subset_notarized(),
+            "An honest node finalized a block it did not notarize"
+        );
+    }
+
+    /// Returns the participation rate (see Node::participation_rate) averaged over the notarized
+    /// blocks of all honest nodes, except the roots of their chains, or 0 if there are none
+    pub fn average_participation(&self) -> f64 {
+        let rates: Vec<f64> = self
+            .nodes
+            .iter()
+            .filter_map(|node| node.as_any().downcast_ref::<Node>())
+            .flat_map(|node| {
+                node.chain
+                    .notarized
+                    .iter()
+                    .filter(|block_hash| **block_hash != node.chain.root)
+                    .map(|block_hash| node.participation_rate(*block_hash))
+            })
+            .collect();
+        if rates.is_empty() {
+            return 0.0;
+        }
+        rates.iter().sum::<f64>() / rates.len() as f64
+    }
+
+    /// Returns whether every honest node only finalized blocks it notarized, see
+    /// Blockchain::check_finalized_subset_notarized
+    pub fn finalized_subset_notarized(&self) -> bool {
+        self.nodes
+            .iter()
+            .filter_map(|node| node.as_any().downcast_ref::<Node>())
+            .all(|node| node.chain.check_finalized_subset_notarized())
+    }
+
+    /// Record all deliveries from now on, see trace. Start recording on a fresh network, such that
+    /// the trace can be replayed on a network configured in the same way.
+    pub fn record_trace(&mut self) {
+        self.trace = Some(Vec::new());
+    }
+
+    /// Returns the deliveries recorded so far, if recording
+    pub fn trace(&self) -> Option<&[TraceEntry]> {
+        self.trace.as_deref()
+    }
+
+    /// Writes the recorded deliveries to the file at path, e.g. to attach them to a bug report.
+    /// Writes an empty trace if not recording. Fails if the trace contains messages of a custom
+    /// kind, which cannot be serialized.
+    pub fn save_trace(&self, path: &Path) -> io::Result<()> {
+        let entries: Vec<(usize, usize, usize, Vec<u8>)> = self
+            .trace()
+            .unwrap_or_default()
+            .iter()
+            .map(|(round, i, j, m)| Ok((*round, *i, *j, m.to_bytes()?)))
+            .collect::<io::Result<_>>()?;
+        fs::write(path, bincode::serialize(&entries).unwrap())
+    }
+
+    /// Reads a trace that was previously written to the file at path
+    pub fn load_trace(path: &Path) -> io::Result<Vec<TraceEntry>> {
+        let bytes = fs::read(path)?;
+        let entries: Vec<(usize, usize, usize, Vec<u8>)> = bincode::deserialize(&bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        entries
+            .into_iter()
+            .map(|(round, i, j, bytes)| {
+                let m = <dyn Message>::from_bytes(&bytes).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Malformed message delivered in round {}", round),
+                    )
+                })?;
+                Ok((round, i, j, m))
+            })
+            .collect()
+    }
+
+    /// In this execution, messages are delivered exactly as in the recorded trace, regardless of
+    /// the random number generator: each message in the round it was delivered originally, in
+    /// the same order. The messages the nodes send are discarded, since the trace already
+    /// contains their deliveries. The network must be configured like the one that recorded the
+    /// trace over epoch_limit epochs of a single run, e.g. with the same nodes and rounds per
+    /// epoch. Then, all nodes end up in the same state.
+    pub fn replay(&mut self, trace: &[TraceEntry], epoch_limit: usize) {
+        self.replaying = Some(trace.iter().cloned().collect());
+        for _epoch in 0..epoch_limit {
+            self.run_epoch(Delivery::Replay, &[]);
+        }
+        self.finish_run();
+        self.replaying = None;
+    }
+
+    /// Run the given number of epochs in the configured network model
+    pub fn run(&mut self, epoch_limit: usize) {
+        match self.model {
+            NetworkModel::Simple => self.run_simple(epoch_limit),
+            NetworkModel::Reorder => self.run_reorder(epoch_limit),
+            NetworkModel::DelaysThenSynchrony(fraction) => {
+                self.run_delays_then_synchrony(epoch_limit, fraction)
+            }
+            NetworkModel::Adversarial => self.run_adversarial_schedule(epoch_limit),
+        }
+    }
+
     /// In this execution, messages are perfectly arriving in order and without packet loss.
     pub fn run_simple(&mut self, epoch_limit: usize) {
         for _epoch in 0..epoch_limit {
-            // New Epoch
-            self.e += 1;
-            self.dbg(&format!("========= New Epoch {} =========", self.e), None, Some("NETWORK"));
-            for i in 0..self.n {
-                self.nodes[i].new_epoch(self.e);
+            self.run_epoch(Delivery::InOrder, &[]);
+        }
+        self.finish_run();
+    }
+
+    /// In this execution, the messages captured from the attacker nodes of another execution are
+    /// injected as if they came from an external adversary, in the same epoch in which they were
+    /// originally sent. Otherwise, messages are delivered like in run_simple. This allows checking
+    /// whether the nodes of this network withstand a specific attack in isolation.
+    pub fn run_replay_attack(&mut self, captured: &[CapturedMessage], epoch_limit: usize) {
+        for _epoch in 0..epoch_limit {
+            let inject: Vec<CapturedMessage> = captured
+                .iter()
+                .filter(|(e, _, _, _)| *e == self.e + 1)
+                .cloned()
+                .collect();
+            self.run_epoch(Delivery::InOrder, &inject);
+        }
+        self.finish_run();
+    }
+
+    /// In this execution, the network is split into the groups of the partition. Messages are
+    /// only delivered within each group, messages between groups are lost. From epoch heal_epoch
+    /// on, all messages flow again and are delivered like in run_simple.
+    pub fn run_with_partition(
+        &mut self,
+        epoch_limit: usize,
+        partition: Vec<HashSet<usize>>,
+        heal_epoch: usize,
+    ) {
+        self.partition = Some(partition);
+        for _epoch in 0..epoch_limit {
+            if self.partition.is_some() && self.e + 1 >= heal_epoch {
+                self.dbg("Partition healed", None, Some("NETWORK"));
+                self.partition = None;
             }
+            self.run_epoch(Delivery::InOrder, &[]);
+        }
+        self.finish_run();
+        self.partition = None;
+    }
 
-            // Three rounds of message passing
-            self.recv_all();
-            self.send_all();
-            self.recv_all();
-            self.send_all();
-            self.recv_all();
-            self.send_all();
+    /// In this execution, each link has its own latency: a message from i to j takes latency[i][j]
+    /// rounds to arrive, where a latency of one is the next round, like in run_simple. Messages
+    /// are otherwise delivered in order. Nodes finalize as long as the latencies are bounded.
+    /// Panics if latency is not an n x n matrix.
+    pub fn run_with_latency_matrix(&mut self, epoch_limit: usize, latency: Vec<Vec<usize>>) {
+        assert!(
+            latency.len() == self.n && latency.iter().all(|row| row.len() == self.n),
+            "The latency matrix must be {} x {}",
+            self.n,
+            self.n
+        );
+        self.latency = Some(latency);
+        for _epoch in 0..epoch_limit {
+            self.run_epoch(Delivery::InOrder, &[]);
+        }
+        self.finish_run();
+        self.latency = None;
+    }
 
-            // Nodes process messages from unprocessed_pool
-            for i in 0..self.n {
-                self.nodes[i].process_unprocessed_pool();
+    /// Returns the minimum safe height over the honest current validators that are not down, see
+    /// Blockchain::safe_height. Each of them finalized the chain up to this height, so none of
+    /// them needs the blocks below it anymore. Removed validators and nodes that are down do not
+    /// hold it back, since they may never finalize again. Once back, they catch up by syncing.
+    pub fn global_safe_height(&self) -> usize {
+        self.validators()
+            .into_iter()
+            .filter(|i| !self.down.contains(i))
+            .filter_map(|i| self.nodes[i].as_any().downcast_ref::<Node>())
+            .map(|node| node.chain.safe_height())
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Prune the chains of all honest nodes below the global safe height, see
+    /// Blockchain::prune_below. Nodes that did not finalize up to that height, e.g. removed
+    /// validators, keep their chains.
+    pub fn prune(&mut self) {
+        let height = self.global_safe_height();
+        for node in self.nodes.iter_mut() {
+            if let Some(node) = node.as_any_mut().downcast_mut::<Node>() {
+                if node.chain.safe_height() >= height {
+                    node.chain.prune_below(height);
+                }
             }
         }
     }
 
+    /// Prune the chains of all honest nodes below the global safe height at the end of every
+    /// epoch, see prune. Disabled by default.
+    pub fn set_pruning(&mut self, pruning: bool) {
+        self.pruning = pruning;
+    }
+
+    /// Returns the ids of the current validators, i.e., the latest validator set, like
+    /// Node::validators
+    pub fn validators(&self) -> Vec<usize> {
+        self.validator_sets.last().unwrap().1.clone()
+    }
+
+    /// Record the validator set that applies from epoch e on. Like Node::set_validators, it
+    /// replaces the sets of later epochs.
+    fn push_validator_set(&mut self, e: usize, validators: Vec<usize>) {
+        self.validator_sets.retain(|(from, _)| *from < e);
+        self.validator_sets.push((e, validators));
+    }
+
+    /// Apply a change of the validator set from the next epoch on, and inform all current
+    /// validators about it. A joining node learns about all previous changes as well, and requests
+    /// the chain from its peers, see Node::begin_sync.
+    pub fn apply_churn(&mut self, churn: Churn) {
+        let mut validators = self.validators();
+        match churn {
+            Churn::Remove(id) => {
+                self.removed.insert(id);
+                validators.retain(|i| *i != id);
+            }
+            Churn::Add => {
+                self.add_honest_node(self.n);
+                validators.push(self.n - 1);
+            }
+        }
+        self.dbg(
+            &format!("Validator set change: {:?}", churn),
+            None,
+            Some("NETWORK"),
+        );
+        self.push_validator_set(self.e + 1, validators.clone());
+        for i in validators.clone() {
+            self.nodes[i].set_validators(self.e + 1, validators.clone());
+        }
+        if churn == Churn::Add {
+            if let Some(node) = self.nodes[self.n - 1].as_any_mut().downcast_mut::<Node>() {
+                node.begin_sync(1);
+            }
+        }
+    }
+
+    /// Create an honest node with the next free id, configured like node 0 if it is honest. Its
+    /// genesis validator set consists of the first n nodes, followed by the validator set changes
+    /// applied so far.
+    fn add_honest_node(&mut self, n: usize) {
+        let id = self.n;
+        let existing = self.nodes[0].as_any().downcast_ref::<Node>();
+        let finalization_depth =
+            existing.map_or(DEFAULT_FINALIZATION_DEPTH, |node| node.finalization_depth);
+        let genesis_config = existing.map_or_else(
+            || GenesisConfig::with_validators((0..n).collect()),
+            |node| node.chain.genesis_config.clone(),
+        );
+        let mut node =
+            Node::with_genesis(id, genesis_config).unwrap_or_else(|err| panic!("{}", err));
+        node.finalization_depth = finalization_depth;
+        if let Some(existing) = existing {
+            node.set_stake(existing.chain.stake.clone());
+            node.set_chain_params(existing.chain.params);
+            node.set_mempool_policy(existing.mempool_policy().clone_box());
+            node.set_fork_choice(existing.fork_choice().clone_box());
+            node.set_empty_block_timeout(existing.empty_block_timeout());
+            node.set_backup_leader_timeout(existing.backup_leader_timeout());
+            for (kind, handler) in existing.handlers() {
+                node.register_handler(kind, handler.clone());
+            }
+            if let Some(max_tx_pool) = existing.max_tx_pool {
+                node.set_max_tx_pool(max_tx_pool, existing.tx_pool_overflow);
+            }
+        }
+        for (e, validators) in &self.validator_sets {
+            node.set_validators(*e, validators.clone());
+        }
+        node.set_leader_election(self.leader_election.clone());
+        self.nodes.push(Box::new(node));
+        self.recv_queue.push(VecDeque::new());
+        self.dropped.push(0);
+        self.bytes_sent.push(0);
+        self.receiver_delay.push(0);
+        self.n += 1;
+    }
+
+    /// Submit a reconfiguration of the validator set to all current validators. Once finalized, it
+    /// takes effect from epoch from_epoch on, so it must be included in a block of an earlier
+    /// epoch. Honest nodes are created for new validators. Since they cannot follow the chain
+    /// before they are validators, they learn about the reconfiguration directly once the
+    /// validators finalized it, see apply_finalized_reconfigs.
+    pub fn reconfigure(&mut self, validators: Vec<usize>, from_epoch: usize) {
+        let n = self.n;
+        let max_id = validators.iter().copied().max().unwrap_or(0);
+        while self.n <= max_id {
+            self.add_honest_node(n);
+        }
+        self.dbg(
+            &format!(
+                "Reconfiguration to {:?} from epoch {}",
+                validators, from_epoch
+            ),
+            None,
+            Some("NETWORK"),
+        );
+        let tx =
+            ReconfigMessage::new(validators.clone(), from_epoch).to_transaction(RECONFIG_SENDER);
+        for i in self.validators() {
+            if i < n {
+                self.nodes[i].send_transaction(tx.clone());
+            }
+        }
+        self.pending_reconfigs
+            .push((from_epoch, validators, (n..self.n).collect()));
+    }
+
+    /// Inform the nodes created for pending reconfigurations about those that an honest validator
+    /// finalized and applied by now. Reconfigurations that honest nodes ignored, e.g. since they
+    /// were included too late, stay pending.
+    fn apply_finalized_reconfigs(&mut self) {
+        for (from_epoch, validators, joined) in std::mem::take(&mut self.pending_reconfigs) {
+            let applied = self
+                .nodes
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !joined.contains(i))
+                .filter_map(|(_, node)| node.as_any().downcast_ref::<Node>())
+                .any(|node| node.validators_at(from_epoch) == validators.as_slice());
+            if applied {
+                for i in joined {
+                    self.nodes[i].set_validators(from_epoch, validators.clone());
+                }
+                self.push_validator_set(from_epoch, validators);
+            } else {
+                self.pending_reconfigs
+                    .push((from_epoch, validators, joined));
+            }
+        }
+    }
+
+    /// In this execution, validators join and leave according to the schedule, given as pairs of
+    /// the epoch in which the change takes effect and the change. Otherwise, messages are
+    /// delivered like in run_simple.
+    pub fn run_with_churn(&mut self, epoch_limit: usize, schedule: &[(usize, Churn)]) {
+        for _epoch in 0..epoch_limit {
+            for (e, churn) in schedule {
+                if *e == self.e + 1 {
+                    self.apply_churn(*churn);
+                }
+            }
+            self.run_epoch(Delivery::InOrder, &[]);
+        }
+        self.finish_run();
+    }
+
+    /// In this execution, nodes crash and recover according to the schedule, given as triples of
+    /// the epoch from which on the change applies, the node, and whether the node is down from
+    /// then on. A crashed node keeps its state, and catches up on the blocks it missed by
+    /// requesting them from its peers once it recovers. Otherwise, messages are delivered like in
+    /// run_simple.
+    pub fn run_with_crashes(&mut self, epoch_limit: usize, schedule: Vec<(usize, usize, bool)>) {
+        for _epoch in 0..epoch_limit {
+            for (e, i, is_down) in &schedule {
+                if *e != self.e + 1 {
+                    continue;
+                }
+                if *is_down {
+                    self.dbg(&format!("Node {} crashed", i), None, Some("NETWORK"));
+                    self.down.insert(*i);
+                } else {
+                    self.dbg(&format!("Node {} recovered", i), None, Some("NETWORK"));
+                    self.down.remove(i);
+                }
+            }
+            self.run_epoch(Delivery::InOrder, &[]);
+        }
+        self.finish_run();
+    }
+
     /// Let nodes receive all messages, but in random order
     fn recv_all_randomized(&mut self) {
-        let mut randomized_queue = self.randomize_messages();
+        let randomized_queue = self.randomize_messages();
         for (m, i, j) in randomized_queue {
-            self.nodes[i].incoming_message(&*m, j);
+            self.deliver(m, j, i);
+        }
+    }
+
+    /// Deliver messages such that honest nodes diverge as much as possible: in the first round of
+    /// an epoch, block proposals only reach one half of the honest nodes, while the other half
+    /// receives them a round later. The late half also receives all other messages, e.g. votes,
+    /// newest first. The halves swap every epoch.
+    fn recv_all_adversarial(&mut self, round: usize) {
+        let honest: Vec<usize> = (0..self.n)
+            .filter(|i| !self.nodes[*i].is_attacker())
+            .collect();
+        let (first_half, second_half) = honest.split_at(honest.len() / 2);
+        let late: HashSet<usize> = if self.e.is_multiple_of(2) {
+            first_half.iter().copied().collect()
+        } else {
+            second_half.iter().copied().collect()
+        };
+        for i in 0..self.n {
+            let mut messages: Vec<_> = self.recv_queue[i].drain(..).collect();
+            if late.contains(&i) {
+                if round == 0 {
+                    let (blocks, others): (Vec<_>, Vec<_>) = messages
+                        .into_iter()
+                        .partition(|(m, _)| m.as_any().downcast_ref::<BlockMessage>().is_some());
+                    self.recv_queue[i].extend(blocks);
+                    messages = others;
+                }
+                messages.reverse();
+            }
+            for (m, j) in messages {
+                self.deliver(m, j, i);
+            }
         }
     }
 
@@ -142,26 +1559,20 @@ impl Network {
     /// Make deterministic. Reproducible results are good for debugging.
     pub fn run_reorder(&mut self, epoch_limit: usize) {
         for _epoch in 0..epoch_limit {
-            // New Epoch
-            self.e += 1;
-            self.dbg(&format!("========= New Epoch {} =========", self.e), None, Some("NETWORK"));
-            for i in 0..self.n {
-                self.nodes[i].new_epoch(self.e);
-            }
-
-            // Three rounds, but within each, nodes receive messages in random order
-            self.recv_all_randomized();
-            self.send_all();
-            self.recv_all_randomized();
-            self.send_all();
-            self.recv_all_randomized();
-            self.send_all();
+            // Within each round, nodes receive messages in random order
+            self.run_epoch(Delivery::Reordered, &[]);
+        }
+        self.finish_run();
+    }
 
-            // Nodes process messages from unprocessed_pool
-            for i in 0..self.n {
-                self.nodes[i].process_unprocessed_pool();
-            }
+    /// In this execution, an adversarial scheduler delivers messages such that the honest nodes
+    /// see different blocks and notarizations for as long as possible, see recv_all_adversarial.
+    /// All messages are still delivered within two rounds.
+    pub fn run_adversarial_schedule(&mut self, epoch_limit: usize) {
+        for _epoch in 0..epoch_limit {
+            self.run_epoch(Delivery::Adversarial, &[]);
         }
+        self.finish_run();
     }
 
     /// Pick fraction many messages out of the queue, the rest remains in the queue.
@@ -192,39 +1603,9 @@ impl Network {
     /// synchronous way, just like in run_reorder.
     /// Make deterministic. Reproducible results are good for debugging
     pub fn run_delays_then_synchrony(&mut self, epoch_limit: usize, fraction: f64) {
-        for epoch in 0..epoch_limit / 2 {
-            // New Epoch
-            self.e += 1;
-            self.dbg(&format!("========= New Epoch {} =========", self.e), None, Some("NETWORK"));
-            for i in 0..self.n {
-                self.nodes[i].new_epoch(self.e);
-            }
-
-            // Round 1: Nodes receive some messages in random order
-            let randomized_queue = self.pick_random_messages(fraction);
-            for (m, i, j) in randomized_queue {
-                self.nodes[i].incoming_message(&*m, j);
-            }
-            self.send_all();
-
-            // Round 2: Nodes receive some messages in random order
-            let randomized_queue = self.pick_random_messages(fraction);
-            for (m, i, j) in randomized_queue {
-                self.nodes[i].incoming_message(&*m, j);
-            }
-            self.send_all();
-
-            // Round 3: Nodes receive some messages in random order
-            let randomized_queue = self.pick_random_messages(fraction);
-            for (m, i, j) in randomized_queue {
-                self.nodes[i].incoming_message(&*m, j);
-            }
-            self.send_all();
-
-            // Nodes process messages from unprocessed_pool
-            for i in 0..self.n {
-                self.nodes[i].process_unprocessed_pool();
-            }
+        for _epoch in 0..epoch_limit / 2 {
+            // Nodes receive some messages in random order
+            self.run_epoch(Delivery::Delayed(fraction), &[]);
         }
 
         self.dbg(
@@ -232,51 +1613,14 @@ impl Network {
             None,
             Some("NETWORK"),
         );
-        for epoch in 0..epoch_limit - epoch_limit / 2 {
-            // New Epoch
-            self.e += 1;
-            for i in 0..self.n {
-                self.nodes[i].new_epoch(self.e);
-            }
-
-            // Round 1: Nodes receive all messages, but in random order
-            let randomized_queue = self.randomize_messages();
-            for (m, i, j) in randomized_queue {
-                self.nodes[i].incoming_message(&*m, j);
-            }
-            self.send_all();
-
-            // Round 2: Nodes receive all messages, but in random order
-            let randomized_queue = self.randomize_messages();
-            for (m, i, j) in randomized_queue {
-                self.nodes[i].incoming_message(&*m, j);
-            }
-            self.send_all();
-
-            // Round 3: Nodes receive all messages, but in random order
-            let randomized_queue = self.randomize_messages();
-            for (m, i, j) in randomized_queue {
-                self.nodes[i].incoming_message(&*m, j);
-            }
-            self.send_all();
-
-            // Nodes process messages from unprocessed_pool
-            for i in 0..self.n {
-                self.nodes[i].process_unprocessed_pool();
-            }
+        for _epoch in 0..epoch_limit - epoch_limit / 2 {
+            // Nodes receive all messages, but in random order
+            self.run_epoch(Delivery::Reordered, &[]);
         }
+        self.finish_run();
     }
 
     fn dbg(&self, text: &str, id: Option<usize>, type_: Option<&str>) {
         Debug::dbg(text, id.unwrap_or(0), type_);
     }
 }
-
-fn main() {
-    let mut network = Network::new(10);
-
-    // Example usage
-    let fraction = 0.5;
-    let messages = network.pick_random_messages(fraction);
-    // Do something with messages
-}
@@ -1,16 +1,45 @@
 use super::blockchain::{
-    Block, BlockMessage, Blockchain, Message, MessageType, VoteMessage, MAXLENGTH_SINGLE_TX,
-    MAXLENGTH_TXS,
+    Block, BlockMessage, Blockchain, CertifiedBlock, ChainParams, EquivocationProof, GenesisConfig,
+    GenesisError, GetBlockMessage, Message, MessageType, ReconfigMessage, SyncRequest,
+    SyncResponse, Transaction, VoteMessage,
 };
-use super::utils::{Crypto, Debug, Signature, Hash};
+use super::causal_graph::{CausalEvent, CausalGraph};
+use super::events::{Event, EventRecord, MAX_EVENTS};
+use super::fork_choice::{ForkChoice, HighestNotarizedEpoch};
+use super::leader_election::{HashLeaderElection, LeaderElection};
+use super::mempool::{FifoPolicy, MempoolPolicy};
+use super::utils::{Crypto, Debug, Hash, Signature, VrfProof};
 use bincode;
 use std::any::Any;
-use std::collections::{HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt;
+use std::sync::Arc;
+
+/// The default number of consecutive-epoch notarized blocks required for finalization (the
+/// Streamlet finalization rule).
+pub const DEFAULT_FINALIZATION_DEPTH: usize = 2;
+
+/// How many epochs ahead of our current epoch the blocks and votes we accept may be. We reject
+/// messages of later epochs, since we store blocks per epoch and an attacker could otherwise make
+/// us allocate memory for arbitrarily many epochs.
+pub const MAX_FUTURE_EPOCHS: usize = 10;
+/// How many epochs behind our current epoch the blocks and votes we accept may be, unless they may
+/// still extend our finalized chain. Such stale messages can only concern abandoned forks.
+pub const MAX_PAST_EPOCHS: usize = 10;
+/// The number of backup leaders that may propose in an epoch whose leader is silent, see
+/// Node::set_backup_leader_timeout
+pub const MAX_BACKUP_ATTEMPTS: usize = 3;
+/// The number of distinct proposals we accept from the same signer per epoch. Two already prove
+/// equivocation, and dropping further ones keeps a leader from making us store arbitrarily many
+/// blocks.
+pub const MAX_PROPOSALS_PER_LEADER: usize = 2;
+/// The number of epochs after which we repeat our sync request for the unnotarized ancestors of
+/// a pending finalization, in case the request or its responses were lost
+pub const SYNC_RETRY_EPOCHS: usize = 2;
 
 /// This trait defines the interface that a node must implement. It is implemented by:
 /// Node, the normal node, and AttackerNode, the attacker node.
-pub trait NodeTrait {
+pub trait NodeTrait: Send {
     // The node identifier (validator ID)
     fn id(&self) -> usize;
     // Whether the node is an attacker (true in AttackerNode struct)
@@ -19,14 +48,244 @@ pub trait NodeTrait {
     fn incoming_message(&mut self, m: &dyn Message, j: usize);
     // Returns the messages queued for sending and clears the queue
     fn clear_outgoing_messages(&mut self) -> Vec<(usize, Box<dyn Message>)>;
-    // Process those messages that we previously could not process (e.g. block whose parent we have 
+    // Process those messages that we previously could not process (e.g. block whose parent we have
     // not received yet)
     fn process_unprocessed_pool(&mut self);
     // Users send transactions to be included in the blockchain
-    fn send_transaction(&mut self, transaction: String);
-    // Invoked whenever a new epoch e begins. Leader proposes a block.
-    fn new_epoch(&mut self, e: usize);
+    fn send_transaction(&mut self, transaction: Transaction);
+    // Invoked whenever a new epoch e begins, with the time of the network's logical clock. Leader
+    // proposes a block.
+    fn new_epoch(&mut self, e: usize, clock: u64);
+    // Invoked at the end of every round of message passing, before messages are sent
+    fn new_round(&mut self);
+    // Returns the number of our proposals that new_round has yet to broadcast
+    fn delayed_proposals(&self) -> usize;
+    // Invoked when validators join or leave
+    fn set_validators(&mut self, e: usize, validators: Vec<usize>);
+    // Returns the counters of the node's consensus decisions so far
+    fn stats(&self) -> NodeStats;
+    // Change how the leader of each epoch is determined
+    fn set_leader_election(&mut self, leader_election: Box<dyn LeaderElection>);
+    // Change the order in which transactions of the pool are included in proposals
+    fn set_mempool_policy(&mut self, mempool_policy: Box<dyn MempoolPolicy>);
+    // Set the rule choosing the notarized block to extend
+    fn set_fork_choice(&mut self, fork_choice: Box<dyn ForkChoice>);
+    // Set the stake of each validator, which weighs their votes
+    fn set_stake(&mut self, stake: Vec<u64>);
+    // Set the block and transaction size limits
+    fn set_chain_params(&mut self, params: ChainParams);
+    // Bound the number of transactions in the transaction pool
+    fn set_max_tx_pool(&mut self, max_tx_pool: usize, overflow: TxPoolOverflow);
+    // Vote for the empty block of an epoch if no proposal arrived in time
+    fn set_empty_block_timeout(&mut self, timeout: Option<usize>);
+    // Let backup leaders propose in an epoch if no proposal arrived in time
+    fn set_backup_leader_timeout(&mut self, timeout: Option<usize>);
+    // Handle messages of the given kind, which we do not know natively, with the handler
+    fn register_handler(&mut self, kind: &'static str, handler: MessageHandler);
+    // Returns the most recent structured events the node emitted, at most MAX_EVENTS
+    fn events(&self) -> &[EventRecord];
+    // Returns the number of structured events the node emitted so far, including dropped ones
+    fn events_emitted(&self) -> usize;
+    // Invoked when the round budget of epoch e is exhausted. If the node has not seen a notarized
+    // block for e, it stops voting for late proposals of that epoch.
+    fn timeout(&mut self, e: usize);
     fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+/// Handles a message of a kind that nodes do not process natively, given the receiving node, the
+/// message and the id of the node it came from. See Node::register_handler.
+pub type MessageHandler =
+    Arc<dyn Fn(&mut Node, &dyn Message, usize) -> Result<(), ConsensusError> + Send + Sync>;
+
+/// What a node does with a new transaction when its transaction pool is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxPoolOverflow {
+    // Reject the new transaction
+    RejectNew,
+    // Drop the oldest transaction of the pool to make room for the new one
+    DropOldest,
+}
+
+/// The confirmation status of a transaction, as seen by a node
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxStatus {
+    // We do not know the transaction
+    Unknown,
+    // The transaction is in our transaction pool, or only in blocks that are not notarized
+    Pending,
+    // The transaction is in the given notarized block, but not finalized yet
+    InNotarizedBlock(Hash),
+    // The transaction is in the given finalized block
+    Finalized(Hash),
+}
+
+/// Why a node rejected a message, or accepted a block without voting for it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsensusError {
+    // The message does not reference a parent block
+    NoParentHash,
+    // We do not store the parent of the block (yet). We keep the block and request the parent.
+    // Also returned when we cannot propose a block, since we store neither its parent nor the
+    // root of our chain.
+    MissingParent {
+        parent: Hash,
+    },
+    // The signer of the proposal is neither the leader of its epoch nor a backup leader whose
+    // attempt has started
+    WrongLeader {
+        e: usize,
+        signer: usize,
+        leader: usize,
+    },
+    // The proposal of the leader lacks a valid leadership proof
+    BadLeadershipProof {
+        e: usize,
+        signer: usize,
+    },
+    // The signature of the message is invalid
+    BadSignature {
+        signer: usize,
+    },
+    // The epoch of the message is more than MAX_FUTURE_EPOCHS ahead of ours
+    FutureEpoch {
+        e: usize,
+    },
+    // The epoch of the message is more than MAX_PAST_EPOCHS behind ours, and its block cannot
+    // extend our finalized chain
+    StaleEpoch {
+        e: usize,
+        current_epoch: usize,
+    },
+    // The block does not validate against its parent
+    InvalidBlock,
+    // We know several blocks of the epoch, so we do not vote for any of them
+    Equivocation {
+        e: usize,
+    },
+    // We already voted in the epoch
+    AlreadyVoted {
+        e: usize,
+    },
+    // We are not a validator of the epoch, so we do not vote in it
+    NotValidator {
+        e: usize,
+    },
+    // The epoch timed out before we could vote
+    EpochTimedOut {
+        e: usize,
+        view: usize,
+    },
+    // The block does not extend our highest notarized block, so we do not vote for it
+    StaleHeight {
+        height: usize,
+        notarized_height: usize,
+    },
+    // A registered handler rejected the message of the given kind
+    InvalidMessage {
+        kind: &'static str,
+    },
+    // The certificate of a synced block of epoch e lacks a quorum of valid signatures
+    MissingQuorum {
+        e: usize,
+    },
+    // We already accepted MAX_PROPOSALS_PER_LEADER proposals of the signer in epoch e
+    TooManyProposals {
+        e: usize,
+        signer: usize,
+    },
+}
+
+impl ConsensusError {
+    /// Returns whether the error can only be caused by a faulty or malicious sender
+    pub fn is_attack(&self) -> bool {
+        matches!(
+            self,
+            ConsensusError::NoParentHash
+                | ConsensusError::WrongLeader { .. }
+                | ConsensusError::BadLeadershipProof { .. }
+                | ConsensusError::BadSignature { .. }
+                | ConsensusError::FutureEpoch { .. }
+                | ConsensusError::InvalidMessage { .. }
+                | ConsensusError::MissingQuorum { .. }
+                | ConsensusError::TooManyProposals { .. }
+        )
+    }
+}
+
+impl fmt::Display for ConsensusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsensusError::NoParentHash => write!(f, "No parent hash"),
+            ConsensusError::MissingParent { parent } => {
+                write!(f, "Parent {} is missing", hex::encode(&parent[0..2]))
+            }
+            ConsensusError::WrongLeader { e, signer, leader } => write!(
+                f,
+                "Proposed by {}, but leader of epoch {} is {}",
+                signer, e, leader
+            ),
+            ConsensusError::BadLeadershipProof { e, signer } => write!(
+                f,
+                "Proposed by {} without a valid leadership proof for epoch {}",
+                signer, e
+            ),
+            ConsensusError::BadSignature { signer } => {
+                write!(f, "Signature check failed for signer {}", signer)
+            }
+            ConsensusError::FutureEpoch { e } => {
+                write!(f, "Epoch {} is too far in the future", e)
+            }
+            ConsensusError::StaleEpoch { e, current_epoch } => write!(
+                f,
+                "Epoch {} is too far in the past (current epoch is {})",
+                e, current_epoch
+            ),
+            ConsensusError::InvalidBlock => write!(f, "Invalid block"),
+            ConsensusError::Equivocation { e } => {
+                write!(f, "Not voting since epoch {} has more blocks", e)
+            }
+            ConsensusError::AlreadyVoted { e } => {
+                write!(f, "Not voting since we already voted in epoch {}", e)
+            }
+            ConsensusError::NotValidator { e } => {
+                write!(f, "Not voting since we are no validator of epoch {}", e)
+            }
+            ConsensusError::EpochTimedOut { e, view } => write!(
+                f,
+                "Not voting since epoch {} timed out (view is {})",
+                e, view
+            ),
+            ConsensusError::StaleHeight {
+                height,
+                notarized_height,
+            } => write!(
+                f,
+                "Not voting for height {} since it does not advance max notarization height of {}",
+                height, notarized_height
+            ),
+            ConsensusError::InvalidMessage { kind } => write!(f, "Invalid {} message", kind),
+            ConsensusError::MissingQuorum { e } => {
+                write!(f, "Certificate of block of epoch {} lacks a quorum", e)
+            }
+            ConsensusError::TooManyProposals { e, signer } => {
+                write!(f, "Dropping further proposals of {} in epoch {}", signer, e)
+            }
+        }
+    }
+}
+
+/// Counters of a node's consensus decisions, read by the network to collect metrics
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NodeStats {
+    pub blocks_proposed: usize,
+    pub blocks_notarized: usize,
+    pub blocks_finalized: usize,
+    // How often we received a message again and did not relay it again, see relay_once
+    pub relays_avoided: usize,
+    pub txs_dropped: usize,
+    // How often a notarization triggered an attempt to finalize a block
+    pub finalize_attempts: usize,
 }
 
 /// This struct represents an honest node. The struct AttackerNode implements the same NodeTrait and
@@ -42,6 +301,12 @@ pub struct Node {
     pub id: usize,
     // The total number of validators
     pub n: usize,
+    // The validator sets, each with the epoch from which on it applies. Changes when validators
+    // join or leave.
+    pub validator_sets: Vec<(usize, Vec<usize>)>,
+    // The members of each validator set in validator_sets together with their total stake, which
+    // we count votes against. Updated whenever the validator sets or the stake change.
+    pub validator_stake: Vec<(HashSet<usize>, u64)>,
     // The blockchain
     pub chain: Blockchain,
     // Outgoing messages, which are queued and sent in batches
@@ -49,261 +314,1309 @@ pub struct Node {
     // Messages that we previously could not process
     unprocessed_pool: VecDeque<Box<dyn Message>>,
     // The transaction pool, populated by users, drained by including transactions in blocks
-    tx_pool: VecDeque<String>,
+    tx_pool: VecDeque<Transaction>,
+    // The locally advanced view: we do not vote for blocks of epochs below it
+    pub view: usize,
+    // The signed block proposals we accepted, per epoch. Used as evidence of equivocation.
+    proposals: HashMap<usize, Vec<BlockMessage>>,
+    // The block proposals waiting in the unprocessed_pool for their parent, by block hash, with
+    // their epoch and signer
+    pooled_proposals: HashMap<Hash, (usize, usize)>,
+    // The blocks we requested from a peer because a block we received extends them, with the epoch
+    // in which we requested them. Their proposals are exempt from MAX_PROPOSALS_PER_LEADER.
+    requested_blocks: HashMap<Hash, usize>,
+    // Validators that we have proof of having equivocated
+    pub slashed: HashSet<usize>,
+    // The number of notarized blocks with consecutive epochs required to finalize a block
+    pub finalization_depth: usize,
+    // The epochs in which we have voted. We never vote twice in the same epoch, even after a
+    // reset of the chain.
+    pub voted_epochs: HashSet<usize>,
+    // For each block, vote, notarization and finalization of this node, the events causing it.
+    // Only recorded with causal tracking on, see set_causal_tracking.
+    pub causal_graph: CausalGraph,
+    // Whether we record the causal graph
+    causal_tracking: bool,
+    // The epoch that most recently began
+    current_epoch: usize,
+    // The epoch in which each transaction was submitted to us
+    tx_submitted: HashMap<Transaction, usize>,
+    // The number of epochs between submission and finalization, for each finalized transaction
+    // that was submitted to us
+    tx_confirmation: HashMap<Transaction, usize>,
+    // Counters of our consensus decisions
+    pub stats: NodeStats,
+    // Determines the leader of each epoch
+    leader_election: Box<dyn LeaderElection>,
+    // Determines the order in which we include the transactions of our pool in our proposals
+    mempool_policy: Box<dyn MempoolPolicy>,
+    // Determines the notarized block we extend when proposing and voting
+    fork_choice: Box<dyn ForkChoice>,
+    // The most recent structured events we emitted, in order, see MAX_EVENTS
+    pub events: Vec<EventRecord>,
+    // The number of structured events we emitted so far, including the dropped ones
+    pub events_emitted: usize,
+    // The maximum hop count of messages we relay. Messages that were already relayed this often
+    // are processed, but not relayed any further.
+    pub max_hops: Option<usize>,
+    // Whether we attach our highest notarized height to the blocks and votes we send
+    pub report_status: bool,
+    // Whether we request the missing parent of a proposal from the node that sent it to us, see
+    // receive_block. Otherwise, the proposal waits in the unprocessed_pool until the parent
+    // arrives on its own.
+    pub request_parents: bool,
+    // The handlers of message kinds we do not process natively, see register_handler
+    handlers: HashMap<&'static str, MessageHandler>,
+    // The highest notarized height each peer reported, see report_status
+    pub peer_status: HashMap<usize, usize>,
+    // The maximum number of transactions in the transaction pool, if bounded
+    pub max_tx_pool: Option<usize>,
+    // What to do with new transactions when the transaction pool is full
+    pub tx_pool_overflow: TxPoolOverflow,
+    // The (hash of the signed content, signer) pairs of the messages we already relayed, with the
+    // epoch of each message. We forget them MAX_PAST_EPOCHS epochs later, see enter_epoch.
+    relayed: HashMap<(Hash, usize), usize>,
+    // The number of rounds by which we delay broadcasting our block proposals, e.g. to model a
+    // leader on a slow machine
+    pub proposal_delay: usize,
+    // Our block proposals that are not broadcast yet, each with the number of rounds left
+    delayed_proposals: VecDeque<(usize, BlockMessage)>,
+    // The number of rounds after the start of an epoch after which we vote for the empty block of
+    // the epoch if we received no proposal, or None if we never vote for empty blocks. All nodes
+    // must use the same setting.
+    empty_block_timeout: Option<usize>,
+    // The number of rounds since the current epoch began
+    epoch_rounds: usize,
+    // The number of rounds after which the next backup leader of an epoch may propose if there is
+    // no proposal for the epoch yet, or None if only the leader may propose. All nodes must use
+    // the same setting.
+    backup_leader_timeout: Option<usize>,
+    // The blocks, with the epoch of their notarized child, whose finalization waits for the votes
+    // of an ancestor that is not notarized yet, see finalize
+    pending_finalizations: HashMap<Hash, usize>,
+    // The epoch of our last sync request for the ancestors of the pending finalizations, if any
+    // of them is still pending
+    pending_sync: Option<usize>,
+    // The last epoch in which we answered a sync request of each node, see receive_sync_request
+    sync_answered: HashMap<usize, usize>,
 }
 
 impl Node {
-    pub fn new(id: usize, n: usize) -> Self {
+    /// Creates a node whose genesis block defines the given validator set, and that finalizes a
+    /// block once finalization_depth notarized blocks of consecutive epochs extend the chain up
+    /// to it, the block included. The depth must be at least one.
+    pub fn new(id: usize, validators: Vec<usize>, finalization_depth: usize) -> Self {
+        assert!(
+            finalization_depth > 0,
+            "The finalization depth must be at least one"
+        );
+        let node = Node::with_genesis(id, GenesisConfig::with_validators(validators))
+            .unwrap_or_else(|err| panic!("{}", err));
         Node {
+            finalization_depth,
+            ..node
+        }
+    }
+
+    /// Creates a node starting from the genesis block of the given configuration. The number of
+    /// validators is taken from the validator set of the genesis block, so that all nodes of a
+    /// network agree on it. Fails if the configuration does not define a non-empty validator set.
+    pub fn with_genesis(id: usize, genesis_config: GenesisConfig) -> Result<Self, GenesisError> {
+        let validators = match &genesis_config.validators {
+            None => return Err(GenesisError::MissingValidators),
+            Some(validators) if validators.is_empty() => return Err(GenesisError::NoValidators),
+            Some(validators) => validators.clone(),
+        };
+        let chain = Blockchain::with_genesis(id, genesis_config);
+        let n = validators.len();
+        let validator_stake = vec![Node::members_and_stake(&chain, &validators)];
+        Ok(Node {
             id,
             n,
-            chain: Blockchain::new(id),
+            validator_sets: vec![(0, validators)],
+            validator_stake,
+            chain,
             outgoing_messages: VecDeque::new(),
             unprocessed_pool: VecDeque::new(),
             tx_pool: VecDeque::new(),
-        }
+            view: 0,
+            proposals: HashMap::new(),
+            pooled_proposals: HashMap::new(),
+            requested_blocks: HashMap::new(),
+            slashed: HashSet::new(),
+            finalization_depth: DEFAULT_FINALIZATION_DEPTH,
+            voted_epochs: HashSet::new(),
+            causal_graph: CausalGraph::new(),
+            causal_tracking: false,
+            max_hops: None,
+            current_epoch: 0,
+            tx_submitted: HashMap::new(),
+            tx_confirmation: HashMap::new(),
+            stats: NodeStats::default(),
+            leader_election: Box::new(HashLeaderElection),
+            mempool_policy: Box::new(FifoPolicy),
+            fork_choice: Box::new(HighestNotarizedEpoch),
+            events: Vec::new(),
+            events_emitted: 0,
+            relayed: HashMap::new(),
+            report_status: false,
+            request_parents: true,
+            handlers: HashMap::new(),
+            peer_status: HashMap::new(),
+            max_tx_pool: None,
+            tx_pool_overflow: TxPoolOverflow::RejectNew,
+            proposal_delay: 0,
+            delayed_proposals: VecDeque::new(),
+            empty_block_timeout: None,
+            epoch_rounds: 0,
+            backup_leader_timeout: None,
+            pending_finalizations: HashMap::new(),
+            pending_sync: None,
+            sync_answered: HashMap::new(),
+        })
+    }
+
+    /// Reset the node to genesis, e.g. after it lost its disk. The chain, the queues and the pools
+    /// are cleared, but the node keeps its identity and remembers the epochs it voted in, so that
+    /// it does not double-vote after the reset.
+    pub fn reset_chain(&mut self) {
+        self.dbg("Resetting chain to genesis");
+        let stake = self.chain.stake.clone();
+        let params = self.chain.params;
+        let clock = self.chain.clock;
+        self.chain = Blockchain::with_genesis(self.id, self.chain.genesis_config.clone());
+        self.chain.stake = stake;
+        self.chain.params = params;
+        self.chain.clock = clock;
+        self.outgoing_messages.clear();
+        self.unprocessed_pool.clear();
+        self.pooled_proposals.clear();
+        self.requested_blocks.clear();
+        self.tx_pool.clear();
+        self.tx_submitted.clear();
+        self.tx_confirmation.clear();
+        self.proposals.clear();
+        self.delayed_proposals.clear();
+        self.pending_finalizations.clear();
+        self.pending_sync = None;
     }
 
     /// Invoked whenever the node receives a message m from the j-th node.
-    /// Right now, we ignore the sender j. Note that the sender j might be
-    /// different from the creator of the message, m.creator, in case it was
-    /// relayed.
+    /// Note that the sender j might be different from the creator of the
+    /// message, m.creator, in case it was relayed. We use j to know whom to ask
+    /// for missing blocks.
     pub fn incoming_message(&mut self, m: &dyn Message, j: usize) {
-        if let Some(block_message) = m.as_any().downcast_ref::<BlockMessage>() {
-            self.receive_block((*block_message).clone());
+        if let Some(height) = m.notarized_height() {
+            self.peer_status.insert(j, height);
+        }
+        let result = if let Some(block_message) = m.as_any().downcast_ref::<BlockMessage>() {
+            self.receive_block((*block_message).clone(), j)
         } else if let Some(vote_message) = m.as_any().downcast_ref::<VoteMessage>() {
-            self.receive_vote((*vote_message).clone());
+            self.receive_vote((*vote_message).clone())
+        } else if let Some(proof) = m.as_any().downcast_ref::<EquivocationProof>() {
+            self.receive_equivocation_proof((*proof).clone());
+            Ok(())
+        } else if let Some(request) = m.as_any().downcast_ref::<GetBlockMessage>() {
+            self.receive_get_block(request, j);
+            Ok(())
+        } else if let Some(request) = m.as_any().downcast_ref::<SyncRequest>() {
+            self.receive_sync_request(request, j);
+            Ok(())
+        } else if let Some(response) = m.as_any().downcast_ref::<SyncResponse>() {
+            self.receive_sync_response(response)
+        } else if let Some(handler) = self.handlers.get(m.kind()).cloned() {
+            handler(self, m, j)
+        } else {
+            self.dbg_type(
+                &format!("Ignoring message {} of unknown kind {}", m.name(), m.kind()),
+                Some("ATTACK"),
+            );
+            Ok(())
+        };
+        if let Err(err) = result {
+            self.log_error(err, &m.name());
         }
     }
 
+    /// Log why we rejected a message or did not vote for a block, described by context. Errors
+    /// that only a faulty or malicious sender can cause are logged as attacks.
+    pub fn log_error(&mut self, err: ConsensusError, context: &str) {
+        let type_ = err.is_attack().then_some("ATTACK");
+        self.dbg_type(&format!("{}: {}", context, err), type_);
+    }
+
     /// Send a message m to all peers
     pub fn broadcast_message(&mut self, m: Box<dyn Message>) {
-        for i in 0..self.n {
-            if i != self.id {
+        self.broadcast_except(m, self.id);
+    }
+
+    /// Send message m to all validators except ourselves and the given node
+    fn broadcast_except(&mut self, m: Box<dyn Message>, except: usize) {
+        let m = if self.report_status {
+            m.with_notarized_height(self.notarized_height())
+        } else {
+            m
+        };
+        for i in self.validators().to_vec() {
+            if i != self.id && i != except {
                 self.outgoing_messages.push_back((i, m.clone()));
             }
         }
     }
 
-    /// Computes the leader id of round e based on a Hash function. Concretely,
-    /// sha256(e) mod n.
+    /// Returns the height of our highest notarized block
+    pub fn notarized_height(&self) -> usize {
+        self.chain.blocks[&self.chain.get_highest_notarized_block()].height
+    }
+
+    /// Returns the fraction of the stake of the validators of the block's epoch that voted for
+    /// it, see Blockchain::participation_rate
+    pub fn participation_rate(&self, block_hash: Hash) -> f64 {
+        let e = self.chain.blocks[&block_hash].e;
+        self.chain
+            .participation_rate(block_hash, self.validators_at(e))
+    }
+
+    /// Returns the height of our highest finalized block
+    pub fn finalized_height(&self) -> usize {
+        self.chain.blocks[self.chain.highest_finalized_block()].height
+    }
+
+    /// Returns the height of our highest finalized block and the checkpoint hash of our finalized
+    /// chain up to it. Honest nodes at the same finalized height have the same checkpoint.
+    pub fn latest_checkpoint(&self) -> (usize, Hash) {
+        self.chain.checkpoint()
+    }
+
+    /// Returns the peers whose reported highest notarized height is more than one below ours,
+    /// sorted by id. Being one height behind is common while the votes of an epoch are still in
+    /// flight. Only peers that report their status are considered, see report_status.
+    pub fn lagging_peers(&self) -> Vec<usize> {
+        let height = self.notarized_height();
+        let mut lagging: Vec<usize> = self
+            .peer_status
+            .iter()
+            .filter(|(_, peer_height)| **peer_height + 1 < height)
+            .map(|(peer, _)| *peer)
+            .collect();
+        lagging.sort();
+        lagging
+    }
+
+    /// Change the validator set from epoch e on, e.g. when validators join or leave. Leader
+    /// election and the number of votes required for notarization in epochs from e on are based
+    /// on the new set.
+    pub fn set_validators(&mut self, e: usize, validators: Vec<usize>) {
+        self.dbg(&format!(
+            "New validator set {:?} from epoch {}",
+            validators, e
+        ));
+        self.validator_sets.retain(|(from, _)| *from < e);
+        self.validator_sets.push((e, validators));
+        self.update_validator_stake();
+        self.n = self.validators().len();
+        // The stake behind the votes depends on the validators
+        self.chain.vote_weight.clear();
+    }
+
+    /// Apply a finalized reconfiguration, contained in a block of epoch e. It must take effect
+    /// after epoch e, and must not leave the validator set empty. This only depends on the chain,
+    /// so that all honest nodes apply the same reconfigurations, however late they finalize them.
+    fn apply_reconfig(&mut self, reconfig: ReconfigMessage, e: usize) {
+        if reconfig.from_epoch <= e || reconfig.validators.is_empty() {
+            self.dbg(&format!(
+                "Ignoring invalid reconfiguration {:?} finalized in epoch {}",
+                reconfig, e
+            ));
+            return;
+        }
+        self.set_validators(reconfig.from_epoch, reconfig.validators);
+    }
+
+    /// Returns the current validator set
+    pub fn validators(&self) -> &[usize] {
+        &self.validator_sets.last().unwrap().1
+    }
+
+    /// Returns the validator set of epoch e
+    pub fn validators_at(&self, e: usize) -> &[usize] {
+        &self.validator_sets[self.validator_set_index(e)].1
+    }
+
+    /// Returns the index in validator_sets of the validator set of epoch e
+    fn validator_set_index(&self, e: usize) -> usize {
+        self.validator_sets
+            .iter()
+            .rposition(|(from, _)| *from <= e)
+            .unwrap_or(self.validator_sets.len() - 1)
+    }
+
+    /// Returns the given validators as a set, together with their total stake in chain
+    fn members_and_stake(chain: &Blockchain, validators: &[usize]) -> (HashSet<usize>, u64) {
+        let total = validators.iter().map(|v| chain.stake_of(*v)).sum();
+        (validators.iter().copied().collect(), total)
+    }
+
+    /// Recompute validator_stake after the validator sets or the stake changed
+    fn update_validator_stake(&mut self) {
+        self.validator_stake = self
+            .validator_sets
+            .iter()
+            .map(|(_, validators)| Node::members_and_stake(&self.chain, validators))
+            .collect();
+    }
+
+    /// Relay a message m we received to all peers except its origin, i.e., the node that signed
+    /// it, unless it reached the maximum hop count. The origin already has the message, so sending
+    /// it back would only waste bandwidth.
+    pub fn relay_message(&mut self, m: &dyn Message, origin: usize) {
+        if let Some(max_hops) = self.max_hops {
+            if m.hop() >= max_hops {
+                self.dbg(&format!(
+                    "Not relaying {}, maximum hop count reached",
+                    m.name()
+                ));
+                return;
+            }
+        }
+        self.broadcast_except(m.relayed(), origin);
+    }
+
+    /// Relay a message m of epoch e with the given signed content and signer, unless we already
+    /// relayed a message with the same content and signer, e.g. before a reset of our chain.
+    /// Messages we receive again while we still know them are not relayed at all, see
+    /// receive_block and receive_vote.
+    pub fn relay_once(&mut self, m: &dyn Message, signed_bytes: &[u8], signer: usize, e: usize) {
+        let hash = Crypto::hash(self.chain.hash_algo(), signed_bytes);
+        if self.relayed.insert((hash, signer), e).is_some() {
+            self.stats.relays_avoided += 1;
+            return;
+        }
+        self.relay_message(m, signer);
+    }
+
+    /// Computes the leader id of round e using the leader election, for the validator set of
+    /// epoch e. By default, this is the validator at position hash(e) mod n.
     pub fn leader(&self, e: usize) -> usize {
-        Crypto::short_hash(&Crypto::sha256_var(e)) as usize % self.n
+        let validators = self.validators_at(e);
+        self.leader_election
+            .leader_of(e, validators, self.chain.hash_algo())
+    }
+
+    /// Computes the id of the backup leader for the given attempt of epoch e, for the validator
+    /// set of epoch e, see set_backup_leader_timeout
+    pub fn backup_leader(&self, e: usize, attempt: usize) -> usize {
+        let validators = self.validators_at(e);
+        self.leader_election
+            .backup_leader_of(e, attempt, validators, self.chain.hash_algo())
+    }
+
+    /// Returns the first attempt of epoch e in which id is the backup leader, among the attempts
+    /// that have started according to our round count. All attempts of past epochs have started.
+    pub fn backup_attempt(&self, e: usize, id: usize) -> Option<usize> {
+        let timeout = self.backup_leader_timeout?;
+        let attempts = match e.cmp(&self.current_epoch) {
+            std::cmp::Ordering::Less => MAX_BACKUP_ATTEMPTS,
+            std::cmp::Ordering::Equal => MAX_BACKUP_ATTEMPTS.min(self.epoch_rounds / timeout),
+            std::cmp::Ordering::Greater => 0,
+        };
+        (1..=attempts).find(|attempt| self.backup_leader(e, *attempt) == id)
+    }
+
+    /// Change the order in which we include the transactions of our pool in our proposals
+    pub fn set_mempool_policy(&mut self, mempool_policy: Box<dyn MempoolPolicy>) {
+        self.mempool_policy = mempool_policy;
+    }
+
+    /// Returns the order in which we include the transactions of our pool in our proposals
+    pub fn mempool_policy(&self) -> &dyn MempoolPolicy {
+        self.mempool_policy.as_ref()
+    }
+
+    /// Record the causes of our proposals, votes, notarizations and finalizations in
+    /// causal_graph. Off by default, as the graph grows with every block.
+    pub fn set_causal_tracking(&mut self, enabled: bool) {
+        self.causal_tracking = enabled;
+    }
+
+    /// Set the rule choosing the notarized block we extend when proposing and voting
+    pub fn set_fork_choice(&mut self, fork_choice: Box<dyn ForkChoice>) {
+        self.fork_choice = fork_choice;
+    }
+
+    /// Returns the rule choosing the notarized block we extend
+    pub fn fork_choice(&self) -> &dyn ForkChoice {
+        self.fork_choice.as_ref()
+    }
+
+    /// Returns the notarized block we extend, according to our fork choice rule
+    pub fn head(&self) -> Hash {
+        self.fork_choice.head(&self.chain)
+    }
+
+    /// Returns our proof that we lead epoch e, if the leader election requires one
+    pub fn leadership_proof(&self, e: usize) -> Option<(Hash, VrfProof)> {
+        self.leader_election
+            .leadership_proof(e, self.id, self.chain.hash_algo())
+    }
+
+    /// Set the block and transaction size limits. All nodes must use the same parameters.
+    pub fn set_chain_params(&mut self, params: ChainParams) {
+        self.chain.params = params;
+    }
+
+    /// Set the stake of each validator, indexed by node id, which weighs the validators' votes.
+    /// All nodes must use the same stake.
+    pub fn set_stake(&mut self, stake: Vec<u64>) {
+        self.chain.stake = stake;
+        self.chain.vote_weight.clear();
+        self.update_validator_stake();
+    }
+
+    /// Change how the leader of each epoch is determined. All nodes must use the same leader
+    /// election.
+    pub fn set_leader_election(&mut self, leader_election: Box<dyn LeaderElection>) {
+        self.leader_election = leader_election;
     }
 
-    /// Invoked whenever a new epoch e begins. Leader proposes a block.
-    pub fn new_epoch(&mut self, e: usize) {
+    /// Returns the epoch that most recently began
+    pub fn current_epoch(&self) -> usize {
+        self.current_epoch
+    }
+
+    /// Moves the node into epoch e without proposing. Requests for blocks that no peer answered
+    /// within MAX_PAST_EPOCHS expire. Of the proposals of epochs that long ago, we only keep those
+    /// of notarized blocks, until the chain prunes them.
+    pub fn enter_epoch(&mut self, e: usize, clock: u64) {
+        let previous_epoch = self.current_epoch;
+        self.current_epoch = e;
+        self.epoch_rounds = 0;
+        self.chain.clock = clock;
+        self.requested_blocks
+            .retain(|_, requested| *requested + MAX_PAST_EPOCHS >= e);
+        self.relayed
+            .retain(|_, relayed| *relayed + MAX_PAST_EPOCHS >= e);
+        let chain = &self.chain;
+        let hash_algo = chain.hash_algo();
+        self.proposals.retain(|proposal_e, proposals| {
+            if *proposal_e + MAX_PAST_EPOCHS >= e {
+                return true;
+            }
+            // Peers that fall behind still request the notarized blocks of old epochs from us, see
+            // receive_get_block. We filter the proposals of an epoch once, as it expires.
+            if *proposal_e + MAX_PAST_EPOCHS >= previous_epoch {
+                proposals
+                    .retain(|proposal| chain.notarized.contains(&proposal.block_hash(hash_algo)));
+            }
+            !proposals.is_empty()
+                && chain
+                    .block_by_epoch
+                    .get(*proposal_e)
+                    .is_some_and(|blocks| !blocks.is_empty())
+        });
+        self.retry_pending_sync();
+    }
+
+    /// Invoked whenever a new epoch e begins, with the time of the network's logical clock.
+    /// Leader proposes a block.
+    pub fn new_epoch(&mut self, e: usize, clock: u64) {
+        self.enter_epoch(e, clock);
         if self.leader(e) == self.id {
-            self.propose_block(e);
+            if let Err(err) = self.propose_block(e) {
+                self.dbg_type(
+                    &format!("Cannot propose a block for epoch {}: {}", e, err),
+                    Some("ERROR"),
+                );
+            }
         }
     }
 
-    /// Build block txs: start with own id, then include transactions.
-    fn build_block_txs(&mut self, id: usize) -> String {
-        let mut txs = id.to_string();
-        while !self.tx_pool.is_empty() && self.tx_pool[0].len() + txs.len() < MAXLENGTH_TXS {
-            let tx = self.tx_pool.pop_front().unwrap();
-            txs.push_str(&tx);
+    /// Invoked when the round budget of epoch e is exhausted. If we have not seen a notarized block
+    /// for epoch e, the epoch timed out and we advance our view past it, so that we no longer
+    /// vote for late proposals of that epoch's leader.
+    pub fn timeout(&mut self, e: usize) {
+        let notarized = self
+            .chain
+            .block_by_epoch
+            .get(e)
+            .is_some_and(|blocks| blocks.iter().any(|b| self.chain.notarized.contains(b)));
+        if !notarized && self.view <= e {
+            self.dbg(&format!("Epoch {} timed out without a notarized block", e));
+            self.view = e + 1;
+        }
+    }
+
+    /// Build block txs for a child of the given parent: include transactions from the pool for as
+    /// long as their serialized size stays below the max_txs chain parameter. Transactions that
+    /// are already included in the parent or its ancestors, or twice in the pool, are dropped.
+    fn build_block_txs(&mut self, parent_hash: Hash) -> Vec<Transaction> {
+        let mut txs: Vec<Transaction> = Vec::new();
+        let mut size = 0;
+        // The transactions to remove from the pool, since they are included, or their nonce is
+        // used by a duplicate or a conflicting transaction
+        let mut removed = vec![false; self.tx_pool.len()];
+        let pool_nonces = self
+            .tx_pool
+            .iter()
+            .map(|tx| (tx.sender, tx.nonce))
+            .collect();
+        let mut used_nonces = self.chain.used_nonces(parent_hash, &pool_nonces);
+        for i in self.mempool_policy.order(&self.tx_pool) {
+            let tx = &self.tx_pool[i];
+            if used_nonces.contains(&(tx.sender, tx.nonce)) {
+                self.dbg(&format!(
+                    "Dropping transaction {}, its nonce is already used",
+                    tx
+                ));
+                removed[i] = true;
+                continue;
+            }
+            let tx_size = tx.serialize().len();
+            if size + tx_size >= self.chain.params.max_txs {
+                break;
+            }
+            size += tx_size;
+            used_nonces.insert((tx.sender, tx.nonce));
+            txs.push(tx.clone());
+            removed[i] = true;
         }
+        let mut removed = removed.into_iter();
+        self.tx_pool.retain(|_| !removed.next().unwrap());
         txs
     }
 
-    /// Build a block.
-    fn build_block(&mut self, parent_hash: Hash, e: usize) -> Block {
+    /// Returns whether a transaction of the given sender with the given nonce is contained in the
+    /// branch of our highest notarized block, in which case no other transaction of the sender
+    /// may use the nonce
+    pub fn is_nonce_used(&self, sender: usize, nonce: u64) -> bool {
+        let nonces = HashSet::from([(sender, nonce)]);
+        !self
+            .chain
+            .used_nonces(self.chain.get_highest_notarized_block(), &nonces)
+            .is_empty()
+    }
+
+    /// Build a block. If we do not store the given parent, e.g. because it was pruned, we log a
+    /// warning and build on the root of our chain instead, which is genesis unless we pruned.
+    fn build_block(&mut self, parent_hash: Hash, e: usize) -> Result<Block, ConsensusError> {
+        let parent_hash = if self.chain.blocks.contains_key(&parent_hash) {
+            parent_hash
+        } else {
+            self.dbg_type(
+                &format!(
+                    "Parent {} of our proposal is missing, proposing on the root instead",
+                    hex::encode(&parent_hash[0..2])
+                ),
+                Some("WARNING"),
+            );
+            self.chain.root
+        };
+        let Some(parent) = self.chain.blocks.get(&parent_hash) else {
+            return Err(ConsensusError::MissingParent {
+                parent: parent_hash,
+            });
+        };
+        let parent_height = parent.height;
         // Build block payload from transactions
-        let txs = self.build_block_txs(self.id);
+        let txs = self.build_block_txs(parent_hash);
         // Name is a handy string for debugging purposes, can remove for final protocol.
         let name = format!("{}/{}", e, self.id);
-        let parent_height = self.chain.blocks.get(&parent_hash).unwrap().height;
-        Block::new(Some(parent_hash), e, txs, name, parent_height + 1)
+        let block = Block::new(
+            Some(parent_hash),
+            e,
+            txs,
+            name,
+            parent_height + 1,
+            self.chain.hash_algo(),
+        )
+        .with_timestamp(self.timestamp())
+        .with_proposer(Some(self.id));
+        Ok(block)
+    }
+
+    /// Returns the timestamp of the blocks we propose now: the time of our clock if the chain
+    /// uses timestamps, and None otherwise
+    pub fn timestamp(&self) -> Option<u64> {
+        self.chain.params.max_clock_skew.map(|_| self.chain.clock)
     }
 
     /// This node is the leader for this epoch, propose a new block
-    pub fn propose_block(&mut self, e: usize) -> Block {
+    pub fn propose_block(&mut self, e: usize) -> Result<Block, ConsensusError> {
         self.dbg(&format!("I am the leader for epoch {}", e));
         self.chain.print_blockchain();
-        let parent_hash = self.chain.get_highest_notarized_block().clone();
+        let parent_hash = self.head();
+        self.propose_block_on(e, parent_hash)
+    }
 
+    /// Propose a new block for epoch e that extends the given parent, or the root of our chain if
+    /// we do not store the parent. Fails if we store neither, or if the block does not validate.
+    pub fn propose_block_on(
+        &mut self,
+        e: usize,
+        parent_hash: Hash,
+    ) -> Result<Block, ConsensusError> {
         // Construct new block, validate it and extend the blockchain by it.
-        let new_block = self.build_block(parent_hash, e);
-        self.chain
-            .validate_and_extend(new_block.clone(), parent_hash);
+        let new_block = self.build_block(parent_hash, e)?;
+        let parent_hash = new_block.parent_hash.unwrap();
+        if !self
+            .chain
+            .validate_and_extend(new_block.clone(), parent_hash)
+        {
+            return Err(ConsensusError::InvalidBlock);
+        }
         self.dbg(&format!(
             "Proposing new block {}, child of {}",
             new_block,
             self.chain.blocks.get(&parent_hash).unwrap()
         ));
 
-        // Add self-vote for this block
-        let mut vote_set = HashSet::new();
-        vote_set.insert(self.id);
-        self.chain.votes.insert(new_block.hash, vote_set);
-
-        // Broadcast block
+        // Add self-vote for this block, which is our signed proposal
         let signed = (MessageType::BlockProposal, new_block.hash);
         let signed_bytes = bincode::serialize(&signed).unwrap();
         let signature: Signature = Crypto::sign(self.id as u64, &signed_bytes);
-        let broadcast_message = new_block.to_block_message(self.id, signature);
-        self.broadcast_message(Box::new(broadcast_message));
-        new_block
+        self.add_vote(new_block.hash, e, self.id, signature.clone());
+        self.voted_epochs.insert(e);
+        self.stats.blocks_proposed += 1;
+        self.emit(Event::BlockProposed {
+            block: new_block.hash,
+            e,
+        });
+        if self.causal_tracking {
+            let proposal = CausalEvent::Proposal {
+                block: new_block.hash,
+                creator: self.id,
+            };
+            self.causal_graph
+                .record(proposal, [CausalEvent::Notarization { block: parent_hash }]);
+            self.causal_graph.record(
+                CausalEvent::Vote {
+                    block: new_block.hash,
+                    voter: self.id,
+                },
+                [proposal],
+            );
+        }
+
+        // Broadcast block
+        let broadcast_message = BlockMessage {
+            leader_proof: self.leadership_proof(e),
+            ..new_block.to_block_message(self.id, signature)
+        };
+        self.proposals
+            .entry(e)
+            .or_default()
+            .push(broadcast_message.clone());
+        if self.proposal_delay == 0 {
+            self.broadcast_message(Box::new(broadcast_message));
+        } else {
+            self.delayed_proposals
+                .push_back((self.proposal_delay, broadcast_message));
+        }
+
+        // Our own vote might already suffice, e.g. if we are the only validator
+        self.notarize(new_block.hash);
+        Ok(new_block)
     }
 
-    /// This node receives a block, validates it, adds it to its chain, and in
-    /// some cases votes for it
-    pub fn receive_block(&mut self, b: BlockMessage) {
-        if b.parent_hash.is_none() {
-            self.dbg_type(
-                &format!("Received block {} with no parent hash", b),
-                Some("ATTACK"),
-            );
+    /// Invoked at the end of every round. Broadcasts the delayed block proposals whose delay has
+    /// passed, and counts down the others. Votes for the empty block of the current epoch once its
+    /// timeout has passed, see set_empty_block_timeout, and proposes a block for the current epoch
+    /// if we are its backup leader, see set_backup_leader_timeout.
+    pub fn new_round(&mut self) {
+        self.epoch_rounds += 1;
+        if self.empty_block_timeout == Some(self.epoch_rounds) {
+            self.vote_empty_block(self.current_epoch);
+        }
+        self.propose_as_backup(self.current_epoch);
+        let (released, delayed): (Vec<_>, Vec<_>) = std::mem::take(&mut self.delayed_proposals)
+            .into_iter()
+            .partition(|(rounds, _)| *rounds == 0);
+        self.delayed_proposals = delayed
+            .into_iter()
+            .map(|(rounds, proposal)| (rounds - 1, proposal))
+            .collect();
+        for (_, proposal) in released {
+            self.dbg(&format!("Broadcasting delayed proposal {}", proposal));
+            self.broadcast_message(Box::new(proposal));
+        }
+    }
+
+    /// Returns the parent of the empty block of epoch e: the block of the latest proposal of an
+    /// earlier epoch if it extends our highest notarized block, and our highest notarized block
+    /// otherwise. All honest nodes receive the same proposals, so they build the same empty block
+    /// even if only some of them saw the latest proposal notarized by the time they vote.
+    fn empty_block_parent(&self, e: usize) -> Hash {
+        let notarized = self.chain.get_highest_notarized_block();
+        let hash_algo = self.chain.hash_algo();
+        self.proposals
+            .iter()
+            .filter(|(proposal_e, _)| **proposal_e < e)
+            .max_by_key(|(proposal_e, _)| **proposal_e)
+            .and_then(|(_, proposals)| {
+                proposals
+                    .iter()
+                    .map(|proposal| proposal.block_hash(hash_algo))
+                    .filter(|block_hash| {
+                        self.chain
+                            .blocks
+                            .get(block_hash)
+                            .is_some_and(|block| block.parent_hash == Some(notarized))
+                    })
+                    .min()
+            })
+            .unwrap_or(notarized)
+    }
+
+    /// If we neither received a proposal for epoch e nor voted in it, vote for the empty block of
+    /// epoch e, see empty_block_parent
+    fn vote_empty_block(&mut self, e: usize) {
+        let proposed = self
+            .chain
+            .block_by_epoch
+            .get(e)
+            .is_some_and(|blocks| !blocks.is_empty());
+        if proposed || self.voted_epochs.contains(&e) {
             return;
         }
-        // If we don't have the parent, we cannot validate and process this
-        // block
-        let parent = self.chain.blocks.get(b.parent_hash.as_ref().unwrap());
-        if parent.is_none() {
-            self.dbg(&format!(
-                "We cannot process {}, adding to unprocessed_pool",
-                b
-            ));
-            self.unprocessed_pool.push_back(Box::new(b));
+        let parent_hash = self.empty_block_parent(e);
+        let Some(parent) = self.chain.blocks.get(&parent_hash) else {
+            return;
+        };
+        let block = Block::empty(parent, e).with_timestamp(self.timestamp());
+        self.dbg(&format!(
+            "No proposal for epoch {}, voting for {}",
+            e, block
+        ));
+        if !self.chain.validate_and_extend(block.clone(), parent_hash) {
             return;
         }
-        let parent = parent.unwrap();
+        if let Err(err) = self.vote(block.clone()) {
+            self.log_error(err, &block.to_string());
+        }
+    }
 
-        // Check that signer is the leader
-        if b.signer != self.leader(b.e) {
+    /// If the backup leader timeout of epoch e just passed for the next attempt, we are the backup
+    /// leader of that attempt, and we neither received a proposal for epoch e nor voted in it,
+    /// propose a block for epoch e
+    fn propose_as_backup(&mut self, e: usize) {
+        let Some(timeout) = self.backup_leader_timeout else {
+            return;
+        };
+        if !self.epoch_rounds.is_multiple_of(timeout) {
+            return;
+        }
+        let attempt = self.epoch_rounds / timeout;
+        if attempt > MAX_BACKUP_ATTEMPTS
+            || self.backup_leader(e, attempt) != self.id
+            || self.leader(e) == self.id
+        {
+            return;
+        }
+        let proposed = self
+            .chain
+            .block_by_epoch
+            .get(e)
+            .is_some_and(|blocks| !blocks.is_empty());
+        if proposed || self.voted_epochs.contains(&e) {
+            return;
+        }
+        self.dbg(&format!(
+            "No proposal for epoch {}, proposing as backup leader of attempt {}",
+            e, attempt
+        ));
+        if let Err(err) = self.propose_block(e) {
             self.dbg_type(
-                &format!(
-                    "Received block {} from {}, but leader of epoch {} is {}",
-                    b,
-                    b.signer,
-                    b.e,
-                    self.leader(b.e)
-                ),
-                Some("ATTACK"),
+                &format!("Cannot propose a block for epoch {}: {}", e, err),
+                Some("ERROR"),
             );
-            return;
+        }
+    }
+
+    /// Fails if epoch e is more than MAX_FUTURE_EPOCHS ahead of our current epoch, or more than
+    /// MAX_PAST_EPOCHS behind it, in which case we reject messages of it. We still accept old
+    /// blocks above our highest finalized height, e.g. when catching up after a crash. The height
+    /// of a block is at most its epoch, so a block of an epoch up to that height cannot extend
+    /// our finalized chain.
+    fn check_epoch(&self, e: usize) -> Result<(), ConsensusError> {
+        if e > self.current_epoch.saturating_add(MAX_FUTURE_EPOCHS) {
+            return Err(ConsensusError::FutureEpoch { e });
+        }
+        if e + MAX_PAST_EPOCHS < self.current_epoch && e <= self.finalized_height() {
+            return Err(ConsensusError::StaleEpoch {
+                e,
+                current_epoch: self.current_epoch,
+            });
+        }
+        Ok(())
+    }
+
+    /// This node receives a block from the j-th node, validates it, adds it to
+    /// its chain, and in some cases votes for it. Fails if we rejected the block, or if we added
+    /// it to our chain but did not vote for it.
+    pub fn receive_block(&mut self, b: BlockMessage, j: usize) -> Result<(), ConsensusError> {
+        let parent_hash = b.parent_hash.ok_or(ConsensusError::NoParentHash)?;
+        let hash_algo = self.chain.hash_algo();
+        let block_hash = b.block_hash(hash_algo);
+        // Check signature. We do this before storing the block in the unprocessed_pool, so that
+        // forged blocks referencing unknown parents cannot fill it up.
+        let signed = (MessageType::BlockProposal, block_hash);
+        let signed_bytes = bincode::serialize(&signed).unwrap();
+        if !Crypto::check_signature(b.signer as u64, &signed_bytes, &b.signature) {
+            return Err(ConsensusError::BadSignature { signer: b.signer });
+        }
+        self.check_epoch(b.e)?;
+        // We check the cap before pooling, so that a leader cannot fill the unprocessed_pool with
+        // proposals referencing unknown parents either. Blocks we requested are exempt: other
+        // blocks extend them, so we need them to follow the chain even if their leader sent us
+        // equivocating proposals first.
+        if !self.requested_blocks.contains_key(&block_hash)
+            && self.proposals_by(b.e, b.signer, block_hash) >= MAX_PROPOSALS_PER_LEADER
+        {
+            return Err(ConsensusError::TooManyProposals {
+                e: b.e,
+                signer: b.signer,
+            });
+        }
+        // If we don't have the parent, we cannot validate and process this
+        // block
+        let Some(parent) = self.chain.blocks.get(&parent_hash) else {
+            // We request the parent only once per pool processing, no matter how often we
+            // received the block
+            if !self.pooled_proposals.contains_key(&block_hash) {
+                self.dbg(&format!("Requesting parent of {} from {}", b, j));
+                let request = GetBlockMessage::new(self.id, parent_hash);
+                if j != self.id && self.request_parents {
+                    self.outgoing_messages.push_back((j, Box::new(request)));
+                    self.requested_blocks
+                        .insert(parent_hash, self.current_epoch);
+                }
+                self.pooled_proposals.insert(block_hash, (b.e, b.signer));
+                self.unprocessed_pool.push_back(Box::new(b));
+            }
+            return Err(ConsensusError::MissingParent {
+                parent: parent_hash,
+            });
+        };
+
+        // Check that signer is the leader, or a backup leader whose attempt has started. Backup
+        // leaders are determined by the hash of the epoch, so they need no leadership proof.
+        let leader = self.leader(b.e);
+        let backup = b.signer != leader;
+        if backup && self.backup_attempt(b.e, b.signer).is_none() {
+            return Err(ConsensusError::WrongLeader {
+                e: b.e,
+                signer: b.signer,
+                leader,
+            });
+        }
+        if !backup
+            && !self.leader_election.verify_leadership(
+                b.e,
+                b.signer,
+                b.leader_proof.as_ref(),
+                self.chain.hash_algo(),
+            )
+        {
+            return Err(ConsensusError::BadLeadershipProof {
+                e: b.e,
+                signer: b.signer,
+            });
         }
 
         // Create block based on the block message
         let new_block = Block::new(
-            Some(b.parent_hash.as_ref().unwrap().clone()),
+            Some(parent_hash),
             b.e,
             b.txs.clone(),
             b.name.clone(),
             parent.height + 1,
-        );
-        if self.chain.contains_block(new_block.hash) {
-            return;
+            self.chain.hash_algo(),
+        )
+        .with_timestamp(b.timestamp)
+        .with_proposer(b.proposer);
+        // Nobody proposes an empty block, so a leader cannot pass off its proposal as one
+        if new_block.is_empty_block() {
+            return Err(ConsensusError::InvalidBlock);
         }
-
-        // Check signature
-        // FIXME: this should be done before we store the block in the unprocessed_pool (not 
-        // required for soundness, but to limit the number of messages we store).
-        let signed = (MessageType::BlockProposal, new_block.hash);
-        let signed_bytes = bincode::serialize(&signed).unwrap();
-        if !Crypto::check_signature(b.signer as u64, &signed_bytes, &b.signature) {
-            self.dbg_type("Signature check failed", Some("ATTACK"));
-            return;
+        self.requested_blocks.remove(&new_block.hash);
+        if self.chain.contains_block(new_block.hash) {
+            self.stats.relays_avoided += 1;
+            return Ok(());
         }
 
         // Add block to the chain after validating it. If it does not validate, ignore it.
         if !self
             .chain
-            .validate_and_extend(new_block.clone(), parent.hash)
+            .validate_and_extend(new_block.clone(), parent_hash)
         {
-            return;
+            return Err(ConsensusError::InvalidBlock);
         }
 
-        // A block proposal is itself also a vote for this block, so add it to our votes
-        if !self.chain.votes.contains_key(&new_block.hash) {
-            self.chain
-                .votes
-                .insert(new_block.hash, HashSet::new());
+        // Keep the signed proposal as potential evidence, and check for equivocation
+        self.proposals.entry(b.e).or_default().push(b.clone());
+        if let Some((proposal1, proposal2)) = self.detect_equivocation(b.e) {
+            self.dbg_type(
+                &format!(
+                    "Leader {} equivocated in epoch {}: {} and {}",
+                    b.signer, b.e, proposal1, proposal2
+                ),
+                Some("ATTACK"),
+            );
+            self.slashed.insert(b.signer);
+            let proof = EquivocationProof::new(self.id, proposal1, proposal2);
+            self.broadcast_message(Box::new(proof));
         }
-        self.chain
-            .votes
-            .get_mut(&new_block.hash)
-            .unwrap()
-            .insert(b.signer);
+
+        // A block proposal is itself also a vote for this block, so add it to our votes
+        self.add_vote(new_block.hash, new_block.e, b.signer, b.signature.clone());
 
         // Determine if we are going to vote for the block
-        let notarization_height = self
-            .chain
-            .blocks
-            .get(&self.chain.get_highest_notarized_block())
-            .unwrap()
-            .height;
-        if new_block.height == notarization_height + 1 {
-            self.vote(new_block.clone());
+        let notarization_height = self.notarized_height();
+        let head = self.head();
+        let voted = if self.fork_choice.may_vote(&self.chain, head, &new_block) {
+            self.vote(new_block.clone())
+        } else {
+            Err(ConsensusError::StaleHeight {
+                height: new_block.height,
+                notarized_height: notarization_height,
+            })
+        };
+        if voted.is_ok() {
             self.dbg(&format!(
                 "Voting for block {} of height {}",
                 new_block, new_block.height
             ));
-        } else {
-            self.dbg(&format!("Not voting for block {} of height {} since it does not advance 
-            max notarization height of {}", new_block, new_block.height, notarization_height));
+        }
+
+        // Votes for the block may have arrived before the block itself
+        if !self.chain.notarized.contains(&new_block.hash) {
+            self.notarize(new_block.hash);
         }
 
         // Relay block message to other peers
-        self.broadcast_message(Box::new(b));
+        self.relay_once(&b, &signed_bytes, b.signer, b.e);
+        voted
     }
 
-    /// Attempt to vote for a block
-    pub fn vote(&mut self, b: Block) {
-        // Check if this is the only block of this epoch that we know of
-        if self.chain.block_by_epoch[b.e].len() > 1 {
+    /// Returns the number of proposals of the signer for epoch e other than the block with the
+    /// given hash, counting both those we accepted and those waiting in the unprocessed_pool for
+    /// their parent
+    fn proposals_by(&self, e: usize, signer: usize, block_hash: Hash) -> usize {
+        let hash_algo = self.chain.hash_algo();
+        let accepted = self.proposals.get(&e).map_or(0, |proposals| {
+            proposals
+                .iter()
+                .filter(|proposal| {
+                    proposal.signer == signer && proposal.block_hash(hash_algo) != block_hash
+                })
+                .count()
+        });
+        let pooled = self
+            .pooled_proposals
+            .iter()
+            .filter(|(hash, pooled)| **pooled == (e, signer) && **hash != block_hash)
+            .count();
+        accepted + pooled
+    }
+
+    /// Returns two distinct signed proposals for epoch e by the same (not yet slashed) signer, if
+    /// we hold any.
+    pub fn detect_equivocation(&mut self, e: usize) -> Option<(BlockMessage, BlockMessage)> {
+        let proposals = self.proposals.get(&e)?;
+        let hash_algo = self.chain.hash_algo();
+        for (i, proposal1) in proposals.iter().enumerate() {
+            if self.slashed.contains(&proposal1.signer) {
+                continue;
+            }
+            for proposal2 in &proposals[i + 1..] {
+                if proposal1.signer == proposal2.signer
+                    && proposal1.block_hash(hash_algo) != proposal2.block_hash(hash_algo)
+                {
+                    return Some((proposal1.clone(), proposal2.clone()));
+                }
+            }
+        }
+        None
+    }
+
+    /// We have received evidence of an equivocation. If it is valid and new to us, slash the
+    /// offender and relay the evidence.
+    pub fn receive_equivocation_proof(&mut self, proof: EquivocationProof) {
+        if self.slashed.contains(&proof.offender()) {
+            return;
+        }
+        if !proof.verify(self.chain.hash_algo()) {
+            self.dbg_type(
+                &format!("Invalid equivocation proof {}", proof),
+                Some("ATTACK"),
+            );
+            return;
+        }
+        self.dbg(&format!("Slashing {} based on {}", proof.offender(), proof));
+        self.slashed.insert(proof.offender());
+        self.relay_message(&proof, proof.creator);
+    }
+
+    /// The j-th node requests a block it is missing. Reply with the signed proposal, if we have it.
+    pub fn receive_get_block(&mut self, request: &GetBlockMessage, j: usize) {
+        let Some(block) = self.chain.blocks.get(&request.block_hash) else {
+            return;
+        };
+        let hash_algo = self.chain.hash_algo();
+        let proposal = self.proposals.get(&block.e).and_then(|proposals| {
+            proposals
+                .iter()
+                .find(|proposal| proposal.block_hash(hash_algo) == request.block_hash)
+        });
+        if let Some(proposal) = proposal {
+            self.dbg(&format!("Sending requested block {} to {}", proposal, j));
+            self.outgoing_messages
+                .push_back((j, Box::new(proposal.clone())));
+        }
+    }
+
+    /// Catch up on the chain after joining late, having only genesis: request the blocks from
+    /// the given height on from all peers. Their responses carry the certificates of the blocks,
+    /// so we notarize and finalize them ourselves instead of trusting the peers.
+    pub fn begin_sync(&mut self, from_height: usize) {
+        self.dbg(&format!("Requesting blocks from height {} on", from_height));
+        self.broadcast_message(Box::new(SyncRequest {
+            creator: self.id,
+            from_height,
+        }));
+    }
+
+    /// Returns the block with the signatures of its votes, if they form a quorum. Signatures of
+    /// nodes that are no validators of the block's epoch are left out, since the recipient
+    /// rejects certificates containing them.
+    pub fn certified_block(&self, block_hash: Hash) -> Option<CertifiedBlock> {
+        let block = self.chain.blocks.get(&block_hash)?;
+        let validators = self.validators_at(block.e);
+        let signatures: BTreeMap<usize, Signature> = self
+            .chain
+            .signatures
+            .get(&block_hash)?
+            .iter()
+            .filter(|(voter, _)| validators.contains(voter))
+            .map(|(voter, signature)| (*voter, signature.clone()))
+            .collect();
+        if !self.chain.is_quorum(signatures.keys(), validators) {
+            return None;
+        }
+        Some(CertifiedBlock {
+            parent_hash: block.parent_hash?,
+            e: block.e,
+            txs: block.txs.clone(),
+            name: block.name.clone(),
+            timestamp: block.timestamp,
+            proposer: block.proposer,
+            signatures,
+        })
+    }
+
+    /// The j-th node requests the blocks from a height on. Reply with our finalized blocks from
+    /// that height on, followed by the notarized blocks leading to our highest notarized block if
+    /// it extends our finalized chain, each with the certificate of its votes. As the response
+    /// can be large, we only answer validators of the current or the next epoch, and each of them
+    /// at most once per epoch.
+    pub fn receive_sync_request(&mut self, request: &SyncRequest, j: usize) {
+        let e = self.current_epoch;
+        if !self.validators_at(e).contains(&j) && !self.validators_at(e + 1).contains(&j) {
+            self.dbg_type(
+                &format!("Ignoring sync request of non-validator {}", j),
+                Some("ATTACK"),
+            );
+            return;
+        }
+        if self.sync_answered.get(&j) == Some(&e) {
             self.dbg(&format!(
-                "Not voting for {} since epoch {} has more blocks: {:?}",
-                b, b.e, self.chain.block_by_epoch[b.e]
+                "Already answered a sync request of {} in epoch {}",
+                j, e
             ));
             return;
         }
+        let mut chain = self.chain.finalized_chain();
+        let mut extension = Vec::new();
+        let mut h = self.chain.get_highest_notarized_block();
+        while !self.chain.finalized.contains(&h) {
+            extension.push(h);
+            match self.chain.parent_of(h) {
+                Some(parent_hash) => h = parent_hash,
+                None => break,
+            }
+        }
+        if self.chain.finalized.contains(&h) {
+            chain.extend(extension.into_iter().rev());
+        }
+        let blocks: Vec<CertifiedBlock> = chain
+            .into_iter()
+            .filter(|block_hash| self.chain.blocks[block_hash].height >= request.from_height)
+            .filter(|block_hash| *block_hash != self.chain.root)
+            .filter_map(|block_hash| self.certified_block(block_hash))
+            .collect();
+        if blocks.is_empty() {
+            return;
+        }
+        self.sync_answered.insert(j, e);
+        self.dbg(&format!(
+            "Sending {} blocks to {} for syncing",
+            blocks.len(),
+            j
+        ));
+        self.outgoing_messages.push_back((
+            j,
+            Box::new(SyncResponse {
+                creator: self.id,
+                blocks,
+            }),
+        ));
+    }
+
+    /// Install the blocks of a sync response in order, after checking their certificates. Each
+    /// block must extend a block we store, and its certificate must hold a quorum of valid
+    /// signatures of the validators of its epoch. We then notarize the blocks, and finalize them
+    /// according to the finalization rule, as if we had received their votes.
+    pub fn receive_sync_response(&mut self, response: &SyncResponse) -> Result<(), ConsensusError> {
+        for certified in &response.blocks {
+            let Some(parent) = self.chain.blocks.get(&certified.parent_hash) else {
+                return Err(ConsensusError::MissingParent {
+                    parent: certified.parent_hash,
+                });
+            };
+            let block = certified.block(parent.height + 1, self.chain.hash_algo());
+            let validators = self.validators_at(block.e).to_vec();
+            for (voter, signature) in &certified.signatures {
+                if !validators.contains(voter)
+                    || !Node::check_vote_signature(&block, *voter, signature)
+                {
+                    return Err(ConsensusError::BadSignature { signer: *voter });
+                }
+            }
+            if !self
+                .chain
+                .is_quorum(certified.signatures.keys(), &validators)
+            {
+                return Err(ConsensusError::MissingQuorum { e: block.e });
+            }
+            if !self.chain.contains_block(block.hash)
+                && !self
+                    .chain
+                    .validate_and_extend(block.clone(), certified.parent_hash)
+            {
+                return Err(ConsensusError::InvalidBlock);
+            }
+            for (voter, signature) in &certified.signatures {
+                self.add_vote(block.hash, block.e, *voter, signature.clone());
+            }
+            self.notarize(block.hash);
+        }
+        Ok(())
+    }
+
+    /// Returns whether the signature is a valid vote of voter for the block, either as a vote or,
+    /// if the voter proposed the block, as its proposal
+    fn check_vote_signature(block: &Block, voter: usize, signature: &Signature) -> bool {
+        [Node::vote_type(block), MessageType::BlockProposal]
+            .into_iter()
+            .any(|message_type| {
+                let signed_bytes = bincode::serialize(&(message_type, block.hash)).unwrap();
+                Crypto::check_signature(voter as u64, &signed_bytes, signature)
+            })
+    }
+
+    /// Returns the signer of the proposal of block b we accepted, which is the leader of its epoch
+    /// unless a backup leader proposed it
+    fn proposer(&self, b: &Block) -> usize {
+        self.proposals
+            .get(&b.e)
+            .and_then(|proposals| {
+                proposals
+                    .iter()
+                    .find(|proposal| proposal.block_hash(self.chain.hash_algo()) == b.hash)
+            })
+            .map_or_else(|| self.leader(b.e), |proposal| proposal.signer)
+    }
+
+    /// Attempt to vote for a block
+    pub fn vote(&mut self, b: Block) -> Result<(), ConsensusError> {
+        // Check that we have not voted in this epoch before
+        if self.voted_epochs.contains(&b.e) {
+            return Err(ConsensusError::AlreadyVoted { e: b.e });
+        }
+
+        // Check that we are a validator of the block's epoch. Nodes that join through a
+        // reconfiguration receive the blocks of earlier epochs, but must not vote for them.
+        if !self.validators_at(b.e).contains(&self.id) {
+            return Err(ConsensusError::NotValidator { e: b.e });
+        }
+
+        // Check that the block's epoch has not timed out yet
+        if b.e < self.view {
+            return Err(ConsensusError::EpochTimedOut {
+                e: b.e,
+                view: self.view,
+            });
+        }
+
+        // Check if this is the only block of this epoch that we know of
+        if self.chain.block_by_epoch[b.e].len() > 1 {
+            return Err(ConsensusError::Equivocation { e: b.e });
+        }
 
         // Add vote to set of received votes
-        if !self.chain.votes.contains_key(&b.hash) {
-            self.chain.votes.insert(b.hash, HashSet::new());
+        let signed = bincode::serialize(&(Node::vote_type(&b), b.hash)).unwrap();
+        let signature = Crypto::sign(self.id as u64, &signed);
+        self.add_vote(b.hash, b.e, self.id, signature.clone());
+        self.voted_epochs.insert(b.e);
+        if self.causal_tracking {
+            // Nobody proposed an empty block
+            let proposal = (!b.is_empty_block()).then(|| CausalEvent::Proposal {
+                block: b.hash,
+                creator: self.proposer(&b),
+            });
+            self.causal_graph.record(
+                CausalEvent::Vote {
+                    block: b.hash,
+                    voter: self.id,
+                },
+                proposal,
+            );
         }
-        self.chain.votes.get_mut(&b.hash).unwrap().insert(self.id);
+        self.emit(Event::Voted {
+            block: b.hash,
+            e: b.e,
+        });
 
         // Attempt to notarize based on existing votes
         self.notarize(b.hash);
 
         // Broadcast vote
-        let signed = bincode::serialize(&(MessageType::Vote, b.hash)).unwrap();
-        let signature = Crypto::sign(self.id as u64, &signed);
         let vote_message = b.to_vote_message(self.id, signature);
         self.broadcast_message(Box::new(vote_message));
+        Ok(())
     }
 
     /// We have received a vote message. Ignore if we already received it
     /// or if its faulty. Else, add to vote set and relay, then attempt to
     /// notarize the block and finalize its parent.
-    pub fn receive_vote(&mut self, b: VoteMessage) {
+    pub fn receive_vote(&mut self, b: VoteMessage) -> Result<(), ConsensusError> {
         // We might not have received the block yet, so just create a dummy
         // block and we will store the vote under the dummy vote's hash (which
         // is equal to the real block's hash)
-        if b.parent_hash.is_none() {
-            self.dbg_type(
-                &format!("Received vote {} with no parent hash", b),
-                Some("ATTACK"),
-            );
-            return;
-        }
+        let parent_hash = b.parent_hash.ok_or(ConsensusError::NoParentHash)?;
+        self.check_epoch(b.e)?;
         let new_block = Block::new(
-            Some(b.parent_hash.as_ref().unwrap().clone()),
+            Some(parent_hash),
             b.e,
             b.txs.clone(),
             b.name.clone(),
             0,
-        );
+            self.chain.hash_algo(),
+        )
+        .with_timestamp(b.timestamp)
+        .with_proposer(b.proposer);
         // Setup
-        if !self.chain.votes.contains_key(&new_block.hash) {
-            self.chain
-                .votes
-                .insert(new_block.hash, HashSet::new());
-        }
+        self.chain.votes.entry(new_block.hash).or_default();
 
         // Check if we have already received this vote, in which case ignore
         if self
@@ -313,15 +1626,15 @@ impl Node {
             .unwrap()
             .contains(&b.signer)
         {
-            return;
+            self.stats.relays_avoided += 1;
+            return Ok(());
         }
 
         // Check the cryptographic validity of the vote
-        let signed = (MessageType::Vote, new_block.hash);
+        let signed = (Node::vote_type(&new_block), new_block.hash);
         let signed_bytes = bincode::serialize(&signed).unwrap();
         if !Crypto::check_signature(b.signer as u64, &signed_bytes, &b.signature) {
-            self.dbg_type("Signature check failed", Some("ATTACK"));
-            return;
+            return Err(ConsensusError::BadSignature { signer: b.signer });
         }
 
         self.dbg(&format!(
@@ -330,29 +1643,68 @@ impl Node {
         ));
 
         // Add vote to set of received votes
-        self.chain
-            .votes
-            .get_mut(&new_block.hash)
-            .unwrap()
-            .insert(b.signer);
+        self.add_vote(new_block.hash, b.e, b.signer, b.signature.clone());
 
         // Relay vote message to other peers
-        self.broadcast_message(Box::new(b));
+        self.relay_once(&b, &signed_bytes, b.signer, b.e);
+
+        // An empty block is fully described by the vote, so we add it to our chain if we did not
+        // build it ourselves
+        if new_block.is_empty_block() && !self.chain.contains_block(new_block.hash) {
+            if let Some(parent) = self.chain.blocks.get(&parent_hash) {
+                let block = Block::empty(parent, b.e).with_timestamp(b.timestamp);
+                self.chain.validate_and_extend(block, parent.hash);
+            }
+        }
 
         // Attempt to notarize based on existing votes
         // For this, we need the real block, not the dummy.
         if let Some(block) = self.chain.blocks.get(&new_block.hash) {
             self.notarize(block.hash);
         }
+        Ok(())
     }
 
-    /// Attempt to notarize a block given the stored votes
+    /// Returns the message type under which votes for the block are signed
+    fn vote_type(block: &Block) -> MessageType {
+        if block.is_empty_block() {
+            MessageType::Empty
+        } else {
+            MessageType::Vote
+        }
+    }
+
+    /// Add the vote of voter for a block of epoch e together with its signature, and return
+    /// whether the vote is new
+    fn add_vote(&mut self, block_hash: Hash, e: usize, voter: usize, signature: Signature) -> bool {
+        self.chain
+            .signatures
+            .entry(block_hash)
+            .or_default()
+            .entry(voter)
+            .or_insert(signature);
+        self.count_vote(block_hash, e, voter)
+    }
+
+    /// Count the vote of voter for a block of epoch e without its signature, and return whether
+    /// the vote is new
+    pub fn count_vote(&mut self, block_hash: Hash, e: usize, voter: usize) -> bool {
+        let (validators, _) = &self.validator_stake[self.validator_set_index(e)];
+        self.chain.add_vote(block_hash, voter, validators)
+    }
+
+    /// Attempt to notarize a block given the stored votes. Once the block is notarized, further
+    /// votes for it change nothing, so we only notarize it and attempt finalization once.
     pub fn notarize(&mut self, block_hash: Hash) {
-        let block = self.chain.blocks.get(&block_hash).unwrap();
-        // We need more than 2n/3 votes in order to notarize
+        if self.chain.notarized.contains(&block_hash) {
+            return;
+        }
+        let block = self.chain.blocks.get(&block_hash).unwrap().clone();
+        // We need votes of the validators of the block's epoch holding more than two thirds of
+        // their stake in order to notarize
+        let (validators, total_stake) = &self.validator_stake[self.validator_set_index(block.e)];
         if !self.chain.contains_block(block_hash)
-            || self.chain.votes.get(&block_hash).unwrap().len()
-                < (self.n as f64 * 2.0 / 3.0) as usize
+            || !self.chain.has_quorum(block_hash, validators, *total_stake)
         {
             return;
         }
@@ -365,7 +1717,22 @@ impl Node {
                 .unwrap()
                 .name
         ));
-        self.chain.notarized.insert(block_hash);
+        self.chain.notarize(block_hash, validators);
+        self.stats.blocks_notarized += 1;
+        self.emit(Event::Notarized {
+            block: block_hash,
+            e: block.e,
+        });
+        if self.causal_tracking {
+            let votes = self.chain.votes.get(&block_hash).unwrap();
+            self.causal_graph.record(
+                CausalEvent::Notarization { block: block_hash },
+                votes.iter().map(|voter| CausalEvent::Vote {
+                    block: block_hash,
+                    voter: *voter,
+                }),
+            );
+        }
 
         // Attempt to finalize parent
         if block.parent_hash.is_none() {
@@ -375,7 +1742,105 @@ impl Node {
             );
             return;
         }
+        self.stats.finalize_attempts += 1;
         self.finalize(block.parent_hash.unwrap(), block.e - 1);
+        self.finalize_descendants(block_hash);
+        self.retry_pending_finalizations();
+    }
+
+    /// Returns the lowest ancestor of the block above our highest finalized block that is not
+    /// notarized, if any
+    fn unnotarized_ancestor(&self, block_hash: Hash) -> Option<Hash> {
+        let mut missing = None;
+        let mut h = block_hash;
+        while let Some(parent_hash) = self.chain.parent_of(h) {
+            if self.chain.finalized.contains(&parent_hash) {
+                break;
+            }
+            if !self.chain.notarized.contains(&parent_hash) {
+                missing = Some(parent_hash);
+            }
+            h = parent_hash;
+        }
+        missing
+    }
+
+    /// Returns the number of blocks whose finalization waits for the votes of an ancestor
+    pub fn pending_finalizations(&self) -> usize {
+        self.pending_finalizations.len()
+    }
+
+    /// Finalize the blocks whose finalization waited for the votes of an ancestor, once all of
+    /// their ancestors are notarized. Pending blocks at or below our finalized height were
+    /// finalized along with a descendant, or conflict with our finalized chain, so we forget them.
+    fn retry_pending_finalizations(&mut self) {
+        let finalized_height = self.finalized_height();
+        let blocks = &self.chain.blocks;
+        self.pending_finalizations.retain(|block_hash, _| {
+            blocks
+                .get(block_hash)
+                .is_some_and(|block| block.height > finalized_height)
+        });
+        if self.pending_finalizations.is_empty() {
+            self.pending_sync = None;
+        }
+        let ready: Vec<(Hash, usize)> = self
+            .pending_finalizations
+            .iter()
+            .filter(|(block_hash, _)| self.unnotarized_ancestor(**block_hash).is_none())
+            .map(|(block_hash, e)| (*block_hash, *e))
+            .collect();
+        for (block_hash, e) in ready {
+            self.pending_finalizations.remove(&block_hash);
+            self.finalize(block_hash, e);
+        }
+    }
+
+    /// Repeat our sync request if the ancestors of a pending finalization are still not
+    /// notarized SYNC_RETRY_EPOCHS after we requested them, from the lowest of them on
+    fn retry_pending_sync(&mut self) {
+        let Some(requested) = self.pending_sync else {
+            return;
+        };
+        if requested + SYNC_RETRY_EPOCHS > self.current_epoch {
+            return;
+        }
+        self.retry_pending_finalizations();
+        let missing_height = self
+            .pending_finalizations
+            .keys()
+            .filter_map(|block_hash| self.unnotarized_ancestor(*block_hash))
+            .map(|missing| self.chain.blocks[&missing].height)
+            .min();
+        if let Some(height) = missing_height {
+            self.pending_sync = Some(self.current_epoch);
+            self.begin_sync(height);
+        }
+    }
+
+    /// Attempt to finalize the descendants of a newly notarized block that were notarized before
+    /// it. Their finalization may have waited for this block to complete the window of
+    /// finalization_depth notarized blocks of consecutive epochs. As we notarize each block only
+    /// once, further votes for the descendants do not retry their finalization, so we retry here.
+    fn finalize_descendants(&mut self, block_hash: Hash) {
+        let mut frontier = vec![block_hash];
+        for _ in 0..self.finalization_depth {
+            let mut next = Vec::new();
+            for h in frontier {
+                let block = self.chain.blocks.get(&h).unwrap();
+                let (e, children) = (block.e, block.children.clone());
+                for child in children {
+                    if self.chain.notarized.contains(&child)
+                        && self.chain.blocks.get(&child).is_some_and(|c| c.e == e + 1)
+                    {
+                        self.stats.finalize_attempts += 1;
+                        self.finalize(h, e);
+                        next.push(child);
+                    }
+                }
+            }
+            frontier = next;
+        }
     }
 
     /// Attempt to finalize a notarized block b.
@@ -386,18 +1851,6 @@ impl Node {
             return;
         }
 
-        // Parent must be notarized
-        let block = self.chain.blocks.get(&block_hash).unwrap();
-        let parent_hash = block.parent_hash.as_ref().unwrap();
-        let parent = self.chain.blocks.get(parent_hash).unwrap();
-        if !self.chain.notarized.contains(parent_hash) {
-            self.dbg_type(
-                "Parent of notarized block undefined or not notarized",
-                Some("ERROR"),
-            );
-            return;
-        }
-
         // b must be notarized
         if !self.chain.notarized.contains(&block_hash) {
             self.dbg_type(
@@ -407,59 +1860,297 @@ impl Node {
             return;
         }
 
-        // Finalize b if it and parent have consecutive epoch numbers.
-        // Note that we already checked consecutive epoch number of child by
-        // precondition.
-        if block.e == e && parent.e == e - 1 {
-            // Recursively finalize b and its parents
-            let mut h = block_hash;
-            while !self.chain.finalized.contains(&h) {
-                self.chain.finalized.insert(h.clone());
+        // Walk back from b over finalization_depth notarized blocks (b included), which must
+        // have consecutive epoch numbers e, e-1, ... Note that we already checked consecutive
+        // epoch number of child by precondition.
+        let mut h = block_hash;
+        let mut window = Vec::new();
+        for i in 0..self.finalization_depth {
+            if i > 0 && !self.chain.notarized.contains(&h) {
+                self.dbg_type(
+                    "Parent of notarized block undefined or not notarized",
+                    Some("ERROR"),
+                );
+                return;
+            }
+            let block = self.chain.blocks.get(&h).unwrap();
+            if block.e + i != e {
+                return;
+            }
+            window.push(CausalEvent::Notarization { block: h });
+            if i + 1 < self.finalization_depth {
+                match self.chain.parent_of(h) {
+                    Some(parent_hash) => h = parent_hash,
+                    None => return,
+                }
+            }
+        }
+
+        // Collect b and its ancestors down to the highest finalized one. Every block on the path
+        // must be stored, otherwise our chain is malformed and we finalize nothing.
+        let mut path = Vec::new();
+        let mut h = block_hash;
+        while !self.chain.finalized.contains(&h) {
+            path.push(h);
+            match self.chain.parent_of(h) {
+                Some(parent_hash) => h = parent_hash,
+                None => {
+                    self.dbg_type(
+                        &format!(
+                            "Cannot finalize {}, the parent of its ancestor {} is missing",
+                            self.chain.blocks.get(&block_hash).unwrap(),
+                            self.chain.blocks.get(&h).unwrap()
+                        ),
+                        Some("ERROR"),
+                    );
+                    return;
+                }
+            }
+        }
+
+        // Honest nodes only vote for blocks extending a notarized chain, so a quorum voted for the
+        // ancestors of b as well. If some of their votes have not reached us, we request the
+        // certified blocks from the lowest such ancestor on, and finalize b once we notarized them.
+        if let Some(missing) = self.unnotarized_ancestor(block_hash) {
+            if self.pending_finalizations.insert(block_hash, e).is_none() {
+                let missing = self.chain.blocks.get(&missing).unwrap();
                 self.dbg(&format!(
-                    "Finalizing block {}",
-                    self.chain.blocks.get(&block_hash).unwrap()
+                    "Cannot finalize {} yet, its ancestor {} is not notarized",
+                    self.chain.blocks.get(&block_hash).unwrap(),
+                    missing
                 ));
-                h = self.chain.parent_of(h).unwrap();
+                self.pending_sync = Some(self.current_epoch);
+                self.begin_sync(missing.height);
             }
+            return;
+        }
+
+        // Finalize b and its parents
+        let finalization = CausalEvent::Finalization { block: block_hash };
+        if self.causal_tracking {
+            self.causal_graph.record(finalization, window);
+        }
+        for h in path {
+            if self.causal_tracking && h != block_hash {
+                self.causal_graph
+                    .record(CausalEvent::Finalization { block: h }, [finalization]);
+            }
+            let block_e = self.chain.blocks.get(&h).unwrap().e;
+            self.chain.finalize(h, self.current_epoch);
+            self.stats.blocks_finalized += 1;
+            self.emit(Event::Finalized {
+                block: h,
+                e: block_e,
+            });
+            for tx in &self.chain.blocks.get(&h).unwrap().txs {
+                if let Some(submitted) = self.tx_submitted.get(tx) {
+                    self.tx_confirmation
+                        .insert(tx.clone(), self.current_epoch.saturating_sub(*submitted));
+                }
+            }
+            self.dbg(&format!(
+                "Finalizing block {}",
+                self.chain.blocks.get(&h).unwrap()
+            ));
+            let reconfigs: Vec<ReconfigMessage> = self.chain.blocks[&h]
+                .txs
+                .iter()
+                .filter_map(ReconfigMessage::from_transaction)
+                .collect();
+            for reconfig in reconfigs {
+                self.apply_reconfig(reconfig, block_e);
+            }
+        }
+        debug_assert!(self.chain.check_finalized_subset_notarized());
+        if let Err(violation) = self.chain.check_safety_invariant() {
+            self.dbg_type(&violation.to_string(), Some("SOUDNESS_ERROR"));
         }
     }
 
     /// The unprocessed_pool contains messages that we previously could not
     /// process, e.g., a child block whose parent we have not received yet.
-    /// We periodically attempt to process these messages again.
+    /// We periodically attempt to process these messages again, in the order of their epochs, so
+    /// that a pooled parent is processed before its pooled children.
     pub fn process_unprocessed_pool(&mut self) {
-        let messages: Vec<_> = self.unprocessed_pool.drain(..).collect();
+        let mut messages: Vec<_> = self.unprocessed_pool.drain(..).collect();
+        self.pooled_proposals.clear();
+        messages.sort_by_key(|m| {
+            m.as_any()
+                .downcast_ref::<BlockMessage>()
+                .map_or(0, |block_message| block_message.e)
+        });
         for m in messages {
             self.dbg(&format!("Processing {} from unprocessed pool", m));
             self.incoming_message(m.as_ref(), m.creator());
         }
     }
 
-    /// Check that a given transaction is valid. As an example, we enforce a
-    /// limit of MAXLENGTH_SINGLE_TX characters.
-    fn validate_transaction(&self, tx: &str) -> bool {
-        let over_max_length = tx.len() > MAXLENGTH_SINGLE_TX;
-        if over_max_length {
-            self.dbg_type(
-                &format!("Can't include transaction, too large: {}", tx),
-                Some("USER_ATTACK"),
-            );
-        }
-        over_max_length
+    /// Returns whether a given transaction is valid. We enforce a limit of max_single_tx
+    /// characters on the payload, as given by the chain parameters, and the payload must not be
+    /// empty.
+    pub fn is_transaction_valid(&self, tx: &Transaction) -> bool {
+        let reason = if tx.payload.is_empty() {
+            "empty"
+        } else if tx.payload.len() > self.chain.params.max_single_tx {
+            "too large"
+        } else {
+            return true;
+        };
+        Debug::dbg(
+            &format!("Can't include transaction, {}: {}", reason, tx),
+            self.id,
+            Some("USER_ATTACK"),
+        );
+        false
+    }
+
+    /// Vote for the empty block of an epoch if we received no proposal for it within the given
+    /// number of rounds after the epoch began, or never if None. The timeout must leave enough
+    /// rounds in the epoch for the votes to arrive.
+    pub fn set_empty_block_timeout(&mut self, timeout: Option<usize>) {
+        self.empty_block_timeout = timeout;
+    }
+
+    /// Returns the empty block timeout, see set_empty_block_timeout
+    pub fn empty_block_timeout(&self) -> Option<usize> {
+        self.empty_block_timeout
+    }
+
+    /// Let a backup leader propose for an epoch if there is no proposal for it the given number of
+    /// rounds after the epoch began, and the next backup leader after as many rounds again, up to
+    /// MAX_BACKUP_ATTEMPTS. If None, only the leader of an epoch proposes. The timeout must leave
+    /// the leader's proposal enough rounds to arrive, and when combined with empty blocks, it
+    /// should be shorter than the empty block timeout.
+    pub fn set_backup_leader_timeout(&mut self, timeout: Option<usize>) {
+        self.backup_leader_timeout = timeout;
+    }
+
+    /// Returns the backup leader timeout, see set_backup_leader_timeout
+    pub fn backup_leader_timeout(&self) -> Option<usize> {
+        self.backup_leader_timeout
+    }
+
+    /// Handle the messages of the given kind (see Message::kind) with the handler, e.g. to extend
+    /// the protocol by new kinds of messages without changing the node. The kinds we process
+    /// natively cannot be overridden. Registering a handler for a kind again replaces it.
+    pub fn register_handler(&mut self, kind: &'static str, handler: MessageHandler) {
+        self.handlers.insert(kind, handler);
+    }
+
+    /// Returns the registered message handlers, by message kind
+    pub fn handlers(&self) -> &HashMap<&'static str, MessageHandler> {
+        &self.handlers
+    }
+
+    /// Bound the number of transactions in the transaction pool. When it is full, new
+    /// transactions are handled according to overflow.
+    pub fn set_max_tx_pool(&mut self, max_tx_pool: usize, overflow: TxPoolOverflow) {
+        self.max_tx_pool = Some(max_tx_pool);
+        self.tx_pool_overflow = overflow;
+    }
+
+    /// Returns the number of messages in the unprocessed_pool
+    pub fn unprocessed_pool_len(&self) -> usize {
+        self.unprocessed_pool.len()
+    }
+
+    /// Returns the number of transactions in the transaction pool
+    pub fn tx_pool_len(&self) -> usize {
+        self.tx_pool.len()
     }
 
     /// Invoked by a user that wants to include a transaction tx in the
     /// blockchain.
-    pub fn send_transaction(&mut self, tx: String) {
-        if self.validate_transaction(&tx) {
+    pub fn send_transaction(&mut self, tx: Transaction) {
+        if !self.is_transaction_valid(&tx) {
             return;
         }
+        if self
+            .max_tx_pool
+            .is_some_and(|max_tx_pool| self.tx_pool.len() >= max_tx_pool)
+        {
+            self.stats.txs_dropped += 1;
+            match self.tx_pool_overflow {
+                TxPoolOverflow::RejectNew => return,
+                TxPoolOverflow::DropOldest => {
+                    if let Some(evicted) = self.tx_pool.pop_front() {
+                        self.tx_submitted.remove(&evicted);
+                    }
+                }
+            }
+            if self.max_tx_pool == Some(0) {
+                return;
+            }
+        }
+        self.tx_submitted.insert(tx.clone(), self.current_epoch);
         self.tx_pool.push_back(tx);
     }
 
-    /// Shortcut for debugging output.
-    pub fn dbg_type(&self, text: &str, type_: Option<&str>) {
+    /// Returns the confirmation status of the transaction. If it is in notarized blocks of several
+    /// branches, the highest of them is returned.
+    pub fn transaction_status(&self, tx: &Transaction) -> TxStatus {
+        let contains = |block_hash: &Hash| self.chain.blocks[block_hash].txs.contains(tx);
+        if let Some(block_hash) = self.chain.finalized_chain().into_iter().find(contains) {
+            return TxStatus::Finalized(block_hash);
+        }
+        let notarized = self
+            .chain
+            .notarized
+            .iter()
+            .filter(|block_hash| contains(block_hash))
+            .max_by_key(|block_hash| (self.chain.blocks[*block_hash].height, **block_hash));
+        if let Some(block_hash) = notarized {
+            return TxStatus::InNotarizedBlock(*block_hash);
+        }
+        if self.tx_pool.contains(tx) || self.chain.blocks.keys().any(contains) {
+            return TxStatus::Pending;
+        }
+        TxStatus::Unknown
+    }
+
+    /// Returns how many of the transactions submitted to us were finalized after how many epochs.
+    /// Transactions that are not finalized yet are counted under None.
+    pub fn confirmation_time_histogram(&self) -> BTreeMap<Option<usize>, usize> {
+        let mut histogram = BTreeMap::new();
+        for tx in self.tx_submitted.keys() {
+            *histogram
+                .entry(self.tx_confirmation.get(tx).copied())
+                .or_insert(0) += 1;
+        }
+        histogram
+    }
+
+    /// Returns the transactions this node has finalized, in chain order.
+    pub fn finalized_log(&self) -> Vec<Transaction> {
+        self.chain.finalized_transactions()
+    }
+
+    /// Record a structured event, stamped with our id and current epoch. If we keep MAX_EVENTS
+    /// events already, we drop the older half of them first.
+    pub fn emit(&mut self, event: Event) {
+        if self.events.len() >= MAX_EVENTS {
+            self.events.drain(..MAX_EVENTS / 2);
+        }
+        self.events_emitted += 1;
+        self.events.push(EventRecord {
+            node: self.id,
+            epoch: self.current_epoch,
+            event,
+        });
+    }
+
+    /// Shortcut for debugging output. Attacks and errors are also emitted as events.
+    pub fn dbg_type(&mut self, text: &str, type_: Option<&str>) {
         Debug::dbg(text, self.id, type_);
+        match type_ {
+            Some("ATTACK") => self.emit(Event::AttackDetected {
+                reason: text.to_string(),
+            }),
+            Some("ERROR") | Some("SOUDNESS_ERROR") => self.emit(Event::Error {
+                reason: text.to_string(),
+            }),
+            _ => {}
+        }
     }
     pub fn dbg(&self, text: &str) {
         Debug::dbg(text, self.id, None);
@@ -486,15 +2177,66 @@ impl NodeTrait for Node {
     fn process_unprocessed_pool(&mut self) {
         self.process_unprocessed_pool();
     }
-    fn send_transaction(&mut self, transaction: String) {
+    fn send_transaction(&mut self, transaction: Transaction) {
         self.send_transaction(transaction);
     }
-    fn new_epoch(&mut self, e: usize) {
-        self.new_epoch(e);
+    fn new_epoch(&mut self, e: usize, clock: u64) {
+        self.new_epoch(e, clock);
+    }
+    fn new_round(&mut self) {
+        self.new_round();
+    }
+    fn delayed_proposals(&self) -> usize {
+        self.delayed_proposals.len()
+    }
+    fn set_validators(&mut self, e: usize, validators: Vec<usize>) {
+        self.set_validators(e, validators);
+    }
+    fn stats(&self) -> NodeStats {
+        self.stats
+    }
+    fn set_leader_election(&mut self, leader_election: Box<dyn LeaderElection>) {
+        self.set_leader_election(leader_election);
+    }
+    fn set_mempool_policy(&mut self, mempool_policy: Box<dyn MempoolPolicy>) {
+        self.set_mempool_policy(mempool_policy);
+    }
+    fn set_fork_choice(&mut self, fork_choice: Box<dyn ForkChoice>) {
+        self.set_fork_choice(fork_choice);
+    }
+    fn set_stake(&mut self, stake: Vec<u64>) {
+        self.set_stake(stake);
+    }
+    fn set_chain_params(&mut self, params: ChainParams) {
+        self.set_chain_params(params);
+    }
+    fn set_max_tx_pool(&mut self, max_tx_pool: usize, overflow: TxPoolOverflow) {
+        self.set_max_tx_pool(max_tx_pool, overflow);
+    }
+    fn set_empty_block_timeout(&mut self, timeout: Option<usize>) {
+        self.set_empty_block_timeout(timeout);
+    }
+    fn set_backup_leader_timeout(&mut self, timeout: Option<usize>) {
+        self.set_backup_leader_timeout(timeout);
+    }
+    fn register_handler(&mut self, kind: &'static str, handler: MessageHandler) {
+        self.register_handler(kind, handler);
+    }
+    fn events(&self) -> &[EventRecord] {
+        &self.events
+    }
+    fn events_emitted(&self) -> usize {
+        self.events_emitted
+    }
+    fn timeout(&mut self, e: usize) {
+        self.timeout(e);
     }
     fn as_any(&self) -> &dyn Any {
         self
     }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
 }
 
 impl fmt::Display for Node {
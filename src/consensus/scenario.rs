@@ -0,0 +1,271 @@
+use super::network::{Network, NetworkModel, DEFAULT_ROUNDS_PER_EPOCH, DEFAULT_SEED};
+use super::workload::{Arrival, Workload};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// A declarative description of an experiment: the network, its attackers, how messages are
+/// delivered, and the transactions submitted. Scenarios can be parsed from a file with
+/// Scenario::parse, e.g. the one below, or deserialized with any serde format, using the same
+/// keys as a file.
+///
+/// ```toml
+/// # Two equivocating attackers, while half of the messages are delayed
+/// n = 7
+/// epochs = 30
+/// model = "delays_then_synchrony"
+/// fraction = 0.5
+/// attackers = 2
+/// attacker_config = ["equivocate"]
+/// seed = 1
+/// transactions = 50
+/// ```
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "ScenarioFile")]
+pub struct Scenario {
+    pub n: usize,
+    pub epochs: usize,
+    pub model: NetworkModel,
+    pub rounds_per_epoch: usize,
+    // The nodes with the highest ids are attackers with the given configuration, see AttackerNode
+    pub attackers: usize,
+    pub attacker_config: Vec<String>,
+    pub seed: [u8; 32],
+    pub workload: Workload,
+}
+
+/// The keys of a scenario file, see Scenario::parse
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioFile {
+    n: Option<usize>,
+    epochs: Option<usize>,
+    rounds_per_epoch: Option<usize>,
+    attackers: Option<usize>,
+    model: Option<String>,
+    fraction: Option<f64>,
+    #[serde(default)]
+    attacker_config: Vec<String>,
+    seed: Option<u8>,
+    transactions: Option<usize>,
+    transactions_per_epoch: Option<usize>,
+}
+
+impl TryFrom<ScenarioFile> for Scenario {
+    type Error = String;
+
+    fn try_from(file: ScenarioFile) -> Result<Scenario, String> {
+        let missing = |key: &str| format!("Missing key `{}`", key);
+        let mut scenario = Scenario::new(
+            file.n.ok_or_else(|| missing("n"))?,
+            file.epochs.ok_or_else(|| missing("epochs"))?,
+        );
+        if let Some(fraction) = file.fraction {
+            if !(0.0..=1.0).contains(&fraction) {
+                return Err(format!(
+                    "Expected a number between 0 and 1, got `{}`",
+                    fraction
+                ));
+            }
+        }
+        let model = file.model.unwrap_or_else(|| "simple".to_string());
+        scenario.model = match (model.as_str(), file.fraction) {
+            ("simple", None) => NetworkModel::Simple,
+            ("reorder", None) => NetworkModel::Reorder,
+            ("delays_then_synchrony", Some(fraction)) => {
+                NetworkModel::DelaysThenSynchrony(fraction)
+            }
+            ("adversarial", None) => NetworkModel::Adversarial,
+            ("delays_then_synchrony", None) => return Err(missing("fraction")),
+            (_, Some(_)) if ["simple", "reorder", "adversarial"].contains(&model.as_str()) => {
+                return Err(format!("The model {} takes no fraction", model))
+            }
+            _ => return Err(format!("Unknown model `{}`", model)),
+        };
+        if let Some(rounds_per_epoch) = file.rounds_per_epoch {
+            scenario.rounds_per_epoch = rounds_per_epoch;
+        }
+        scenario.attackers = file.attackers.unwrap_or(0);
+        if scenario.attackers > scenario.n {
+            return Err(format!(
+                "{} attackers, but only {} nodes",
+                scenario.attackers, scenario.n
+            ));
+        }
+        scenario.attacker_config = file.attacker_config;
+        if let Some(seed) = file.seed {
+            scenario.seed = [seed; 32];
+        }
+        if let Some(count) = file.transactions {
+            scenario.workload.count = count;
+        }
+        if let Some(rate) = file.transactions_per_epoch {
+            scenario.workload.arrival = Arrival::Steady(rate);
+        }
+        scenario.workload.seed = scenario.seed;
+        Ok(scenario)
+    }
+}
+
+/// A scenario file we cannot parse, with the line at fault (starting at 1), or line 0 if we
+/// cannot read the file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScenarioError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScenarioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "Cannot read scenario: {}", self.message)
+        } else {
+            write!(
+                f,
+                "Invalid scenario in line {}: {}",
+                self.line, self.message
+            )
+        }
+    }
+}
+
+impl Scenario {
+    /// A scenario of n honest nodes and the default workload, running for the given number of
+    /// epochs in the simple network model
+    pub fn new(n: usize, epochs: usize) -> Self {
+        Scenario {
+            n,
+            epochs,
+            model: NetworkModel::Simple,
+            rounds_per_epoch: DEFAULT_ROUNDS_PER_EPOCH,
+            attackers: 0,
+            attacker_config: Vec::new(),
+            seed: DEFAULT_SEED,
+            workload: Workload::default(),
+        }
+    }
+
+    /// Parses a scenario given as lines of `key = value`, a subset of TOML. Empty lines and
+    /// comments starting with # outside of quoted strings are ignored. The keys are:
+    /// - n, epochs (required), rounds_per_epoch, attackers: integers
+    /// - model: "simple" (default), "reorder", "delays_then_synchrony" or "adversarial"
+    /// - fraction: the fraction of messages delivered per round with "delays_then_synchrony"
+    /// - attacker_config: a list of strings, e.g. ["equivocate", "flood"]
+    /// - seed: an integer between 0 and 255, used for every byte of the seed
+    /// - transactions: the number of transactions submitted upfront
+    /// - transactions_per_epoch: if given, the transactions arrive at this rate instead
+    pub fn parse(text: &str) -> Result<Scenario, ScenarioError> {
+        let mut file = ScenarioFile::default();
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: String| ScenarioError {
+                line: i + 1,
+                message,
+            };
+            let line = Scenario::split_unquoted(line, '#')[0].trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(error(format!("Expected `key = value`, got `{}`", line)));
+            };
+            let value = value.trim();
+            let integer = || {
+                value
+                    .parse::<usize>()
+                    .map_err(|_| error(format!("Expected an integer, got `{}`", value)))
+            };
+            match key.trim() {
+                "n" => file.n = Some(integer()?),
+                "epochs" => file.epochs = Some(integer()?),
+                "rounds_per_epoch" => file.rounds_per_epoch = Some(integer()?),
+                "attackers" => file.attackers = Some(integer()?),
+                "model" => file.model = Some(Scenario::parse_string(value).map_err(error)?),
+                "fraction" => {
+                    let fraction = value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|f| (0.0..=1.0).contains(f));
+                    file.fraction = Some(fraction.ok_or_else(|| {
+                        error(format!(
+                            "Expected a number between 0 and 1, got `{}`",
+                            value
+                        ))
+                    })?);
+                }
+                "attacker_config" => {
+                    file.attacker_config = Scenario::parse_list(value).map_err(error)?
+                }
+                "seed" => {
+                    let seed = value.parse::<u8>().map_err(|_| {
+                        error(format!("Expected a seed up to 255, got `{}`", value))
+                    })?;
+                    file.seed = Some(seed);
+                }
+                "transactions" => file.transactions = Some(integer()?),
+                "transactions_per_epoch" => file.transactions_per_epoch = Some(integer()?),
+                key => return Err(error(format!("Unknown key `{}`", key))),
+            }
+        }
+        // The keys are consistent or not as a whole, so we blame the last line
+        Scenario::try_from(file).map_err(|message| ScenarioError {
+            line: text.lines().count(),
+            message,
+        })
+    }
+
+    /// Reads and parses a scenario file, see parse
+    pub fn load(path: impl AsRef<Path>) -> Result<Scenario, ScenarioError> {
+        let text = fs::read_to_string(path).map_err(|err| ScenarioError {
+            line: 0,
+            message: err.to_string(),
+        })?;
+        Scenario::parse(&text)
+    }
+
+    fn parse_string(value: &str) -> Result<String, String> {
+        value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .filter(|value| !value.contains('"'))
+            .map(|value| value.to_string())
+            .ok_or_else(|| format!("Expected a quoted string, got `{}`", value))
+    }
+
+    fn parse_list(value: &str) -> Result<Vec<String>, String> {
+        let inner = value
+            .strip_prefix('[')
+            .and_then(|value| value.strip_suffix(']'))
+            .ok_or_else(|| format!("Expected a list, got `{}`", value))?;
+        Scenario::split_unquoted(inner, ',')
+            .into_iter()
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(Scenario::parse_string)
+            .collect()
+    }
+
+    /// Splits the text at the separators that are not within a quoted string
+    fn split_unquoted(text: &str, separator: char) -> Vec<&str> {
+        let mut parts = Vec::new();
+        let mut quoted = false;
+        let mut start = 0;
+        for (i, c) in text.char_indices() {
+            if c == '"' {
+                quoted = !quoted;
+            } else if c == separator && !quoted {
+                parts.push(&text[start..i]);
+                start = i + c.len_utf8();
+            }
+        }
+        parts.push(&text[start..]);
+        parts
+    }
+
+    /// Creates the network of the scenario and runs it for the scenario's number of epochs
+    pub fn run(&self) -> Network {
+        let mut network = Network::from_scenario(self);
+        network.run(self.epochs);
+        network
+    }
+}
@@ -1,13 +1,132 @@
 extern crate rand;
 extern crate sha2;
 
-use super::network::Network;
-use super::node::{Node, NodeTrait};
-use super::utils::{Debug, Hash};
-use std::collections::HashSet;
+use super::attacker_node::AttackerNode;
+use super::blockchain::{
+    Block, BlockMessage, Blockchain, ChainDag, ChainParams, GenesisConfig, GenesisError,
+    GetBlockMessage, Message, MessageType, ReconfigMessage, SyncRequest, SyncResponse, Transaction,
+    VoteMessage, MAXLENGTH_SINGLE_TX, MAXLENGTH_TXS,
+};
+use super::builder::ConsensusBuilder;
+use super::causal_graph::CausalEvent;
+use super::events::{Event, MAX_EVENTS};
+use super::fork_choice::{ForkChoice, HeaviestSubtree, HighestNotarizedEpoch};
+use super::leader_election::{
+    HashLeaderElection, LeaderElection, StakeWeightedLeaderElection, VrfLeaderElection,
+};
+use super::mempool::{FeePriorityPolicy, FifoPolicy, MempoolPolicy};
+use super::network::{
+    CapturedMessage, Churn, Network, NetworkModel, PoolProcessing, Topology, TraceEntry,
+    DEFAULT_ROUNDS_PER_EPOCH, REPLAY_HISTORY_EPOCHS,
+};
+use super::node::{
+    ConsensusError, MessageHandler, Node, NodeTrait, TxPoolOverflow, TxStatus,
+    DEFAULT_FINALIZATION_DEPTH, MAX_FUTURE_EPOCHS, MAX_PAST_EPOCHS, MAX_PROPOSALS_PER_LEADER,
+    SYNC_RETRY_EPOCHS,
+};
+use super::scenario::{Scenario, ScenarioError};
+use super::transport::{read_frame, write_frame, TcpTransport, MAX_FRAME_LEN};
+use super::utils::{Crypto, Debug, Hash, HashAlgo, Signature};
+use super::workload::{Arrival, PayloadSize, Workload};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+
+/// The sender of the transactions of hand-built blocks, see extend_chain. It is neither a
+/// validator nor RECONFIG_SENDER, so that its nonces cannot collide with those of generated
+/// transactions, and its transactions never carry a reconfiguration.
+const TEST_SENDER: usize = usize::MAX - 1;
 
 struct TestNetwork;
 
+/// A kind of message that nodes do not process natively, see test_custom_message: announces the
+/// highest finalized block of its creator
+#[derive(Clone, Debug)]
+struct CheckpointMessage {
+    creator: usize,
+    block_hash: Hash,
+}
+
+impl Message for CheckpointMessage {
+    fn clone_box(&self) -> Box<dyn Message> {
+        Box::new(self.clone())
+    }
+    fn creator(&self) -> usize {
+        self.creator
+    }
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn name(&self) -> String {
+        format!("<CheckpointM: {}>", hex::encode(&self.block_hash[0..2]))
+    }
+    fn kind(&self) -> &'static str {
+        "checkpoint"
+    }
+    fn size_bytes(&self) -> usize {
+        self.block_hash.len() + 8
+    }
+}
+
+/// Aggregate statistics of a run, over the blocks stored by any honest node. Genesis is not
+/// counted.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct RunSummary {
+    blocks_proposed: usize,
+    blocks_notarized: usize,
+    blocks_finalized: usize,
+    // Blocks whose parent has other children
+    forks: usize,
+    // The most blocks on a branch that was abandoned in favor of the finalized chain
+    longest_fork: usize,
+    // The most forks in the chain of an honest node, see Blockchain::fork_count
+    fork_count: usize,
+    // The deepest fork in the chain of an honest node, see Blockchain::max_fork_depth
+    max_fork_depth: usize,
+    // The fraction of validators that voted for the average notarized block, see
+    // Network::average_participation
+    participation: f64,
+    // The finalized transactions of the network's workload if it has one, otherwise all
+    transactions_finalized: usize,
+    // The number of transactions of the network's workload, if it has one
+    transactions_submitted: Option<usize>,
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Blocks: {} proposed, {} notarized, {} finalized",
+            self.blocks_proposed, self.blocks_notarized, self.blocks_finalized
+        )?;
+        writeln!(
+            f,
+            "Forks: {} blocks with siblings, longest abandoned fork of {} blocks",
+            self.forks, self.longest_fork
+        )?;
+        writeln!(
+            f,
+            "Fork tree: up to {} forks per node, up to {} blocks past their branching point",
+            self.fork_count, self.max_fork_depth
+        )?;
+        writeln!(
+            f,
+            "Participation: {:.1}% of the validators voted for the average notarized block",
+            100.0 * self.participation
+        )?;
+        match self.transactions_submitted {
+            Some(submitted) => write!(
+                f,
+                "Transactions: {} of {} finalized",
+                self.transactions_finalized, submitted
+            ),
+            None => write!(f, "Transactions: {} finalized", self.transactions_finalized),
+        }
+    }
+}
+
 impl TestNetwork {
     /// All honest, perfect network conditions
     fn test_honest_only_perfect_network() {
@@ -49,8 +168,9 @@ impl TestNetwork {
         let mut attacker_config = HashSet::new();
         attacker_config.insert("fail_stop".to_string());
         let n = 4;
+        let num_attackers = 1;
         let epochs = 10;
-        let mut network = Network::new_byzantine(n, attacker_config);
+        let mut network = Network::new_byzantine(n, num_attackers, attacker_config);
         TestNetwork::generate_transactions(&mut network.nodes, n);
         network.run_simple(epochs);
         TestNetwork::validate(&network);
@@ -66,9 +186,10 @@ impl TestNetwork {
         let mut attacker_config = HashSet::new();
         attacker_config.insert("fail_stop".to_string());
         let n = 4;
+        let num_attackers = 1;
         let epochs = 50;
         let fraction = 0.9;
-        let mut network = Network::new_byzantine(n, attacker_config);
+        let mut network = Network::new_byzantine(n, num_attackers, attacker_config);
         TestNetwork::generate_transactions(&mut network.nodes, n);
         network.run_delays_then_synchrony(epochs, fraction);
         TestNetwork::validate(&network);
@@ -86,9 +207,10 @@ impl TestNetwork {
         attacker_config.insert("vote_everything".to_string());
         attacker_config.insert("equivocate".to_string());
         let n = 4;
+        let num_attackers = 1;
         let epochs = 30;
         let fraction = 0.75;
-        let mut network = Network::new_byzantine(n, attacker_config);
+        let mut network = Network::new_byzantine(n, num_attackers, attacker_config);
         TestNetwork::generate_transactions(&mut network.nodes, n);
         network.run_delays_then_synchrony(epochs, fraction);
         TestNetwork::validate(&network);
@@ -107,172 +229,6308 @@ impl TestNetwork {
         attacker_config.insert("vote_everything".to_string());
         attacker_config.insert("equivocate".to_string());
         let n = 4;
+        let num_attackers = 1;
         let epochs = 20;
         let fraction = 0.75;
-        let mut network = Network::new_byzantine(n, attacker_config);
+        let mut network = Network::new_byzantine(n, num_attackers, attacker_config);
         TestNetwork::generate_transactions(&mut network.nodes, n);
         network.run_delays_then_synchrony(epochs, fraction);
         TestNetwork::validate(&network);
     }
 
-    /// Validation means checking consistency of the chains (as defined in
-    /// the paper)
-    pub fn validate(network: &Network) -> bool {
-        TestNetwork::print_all(network);
-        assert!(TestNetwork::consistency(network));
-        true
+    /// Transactions are stored as a list, so different splits of the same bytes must yield
+    /// different blocks, and every transaction must be recoverable from its serialized form.
+    fn test_transactions_unambiguous() {
+        TestNetwork::print_test_case_header("Transactions are unambiguous");
+        let txs_a = vec![
+            Transaction::new(0, 0, "12".to_string()),
+            Transaction::new(0, 1, "3".to_string()),
+        ];
+        let txs_b = vec![
+            Transaction::new(0, 0, "1".to_string()),
+            Transaction::new(0, 1, "23".to_string()),
+        ];
+        let block_a = Block::new(
+            None,
+            1,
+            txs_a.clone(),
+            "a".to_string(),
+            1,
+            HashAlgo::default(),
+        );
+        let block_b = Block::new(None, 1, txs_b, "b".to_string(), 1, HashAlgo::default());
+        assert_ne!(block_a.hash, block_b.hash);
+        for tx in &txs_a {
+            assert_eq!(Transaction::deserialize(&tx.serialize()).as_ref(), Some(tx));
+        }
+        assert_eq!(Transaction::deserialize(&[0xff]), None);
     }
 
-    /// Check the consistency: If two blockchains are ever considered final by
-    /// two honest nodes, it must be that one is a prefix of another.
-    /// We show this of an execution in two steps: first, there is a unique
-    /// chain that consists of exactly the finalized blocks (meaning that all
-    /// of its blocks are finalized and no other blocks are finalized).
-    /// Second, this chain is equal between all honest nodes.
-    fn consistency(network: &Network) -> bool {
-        let honest_nodes: Vec<&Node> = network
+    /// All honest, perfect network conditions. The finalized transaction logs of all nodes must
+    /// be non-empty and prefixes of one another.
+    fn test_finalized_transaction_log() {
+        TestNetwork::print_test_case_header("Finalized transaction log");
+        let n = 4;
+        let epochs = 6;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+        let logs: Vec<Vec<Transaction>> = network
             .nodes
             .iter()
             .filter_map(|node| node.as_any().downcast_ref::<Node>())
+            .map(|node| node.finalized_log())
             .collect();
-        let mut finalized_chains = vec![Vec::new(); honest_nodes.len()];
-        for (i, node) in honest_nodes.iter().enumerate() {
-            // Starting from the highest finalized block, we construct the
-            // highest finalized chain by recursively adding the parents.
-            let mut block_hash = node.chain.highest_finalized_block().clone();
-            finalized_chains[i].push(block_hash);
-            while let Some(parent_hash) = node.chain.parent_of(block_hash) {
-                block_hash = parent_hash;
-                finalized_chains[i].push(block_hash);
+        for log1 in &logs {
+            assert!(!log1.is_empty());
+            for log2 in &logs {
+                assert!(log1.starts_with(log2) || log2.starts_with(log1));
             }
+        }
+    }
 
-            // Consistency criterion #1: this chain consists exactly of the
-            // finalized blocks
-            if finalized_chains[i].iter().cloned().collect::<HashSet<_>>() != node.chain.finalized {
-                TestNetwork::dbg(
-                    "Finalized blocks inconsistent",
-                    Some(node.id),
-                    Some("SOUDNESS_ERROR"),
-                );
-                return false;
-            }
+    /// Nodes take the number of validators from the validator set of their genesis block, so
+    /// that nodes of the same genesis agree on it. Nodes with different validator sets are
+    /// detected to have mismatching genesis blocks, and a genesis block without a validator set
+    /// cannot start a node.
+    fn test_genesis_validator_set() {
+        TestNetwork::print_test_case_header("Genesis validator set");
+        let genesis_config = GenesisConfig::with_validators(vec![0, 2, 5]);
+        let node1 = Node::with_genesis(0, genesis_config.clone()).unwrap();
+        let node2 = Node::with_genesis(2, genesis_config).unwrap();
+        assert_eq!(node1.n, 3);
+        assert_eq!(node2.n, 3);
+        assert_eq!(node1.validators(), &[0, 2, 5]);
+        assert!(node1.chain.same_genesis(&node2.chain));
 
-            // We constructed the chains in opposite direction, reverse
-            finalized_chains[i].reverse();
-        }
+        let node3 = Node::new(2, (0..5).collect(), DEFAULT_FINALIZATION_DEPTH);
+        let node4 = Node::new(3, (0..4).collect(), DEFAULT_FINALIZATION_DEPTH);
+        assert_eq!(node4.n, 4);
+        assert!(!node1.chain.same_genesis(&node3.chain));
+        assert!(!node1.chain.same_genesis(&node4.chain));
+        assert!(!node4.chain.same_genesis(&Blockchain::new(4)));
+        assert!(node4
+            .chain
+            .same_genesis(&Blockchain::with_validators(4, Some((0..4).collect()))));
+        assert_eq!(
+            Node::with_genesis(0, GenesisConfig::with_chain_id(7)).err(),
+            Some(GenesisError::MissingValidators)
+        );
+        assert_eq!(
+            Node::with_genesis(0, GenesisConfig::with_validators(Vec::new())).err(),
+            Some(GenesisError::NoValidators)
+        );
+        // Networks fill in the missing validator set with all nodes
+        let network = Network::with_genesis(4, GenesisConfig::with_chain_id(7));
+        assert_eq!(network.validators(), (0..4).collect::<Vec<_>>());
+    }
 
-        // Consistency criterion #2: When compared pairwise with each other, the
-        // longest finalized chains of two honest nodes must be related by the
-        // prefix relation (in either direction)
-        for chain1 in &finalized_chains {
-            for chain2 in &finalized_chains {
-                if !TestNetwork::is_prefix(chain1, chain2)
-                    && !TestNetwork::is_prefix(chain2, chain1)
-                {
-                    TestNetwork::dbg(
-                        "Finalized chains nodes are not prefixes of another",
-                        None,
-                        Some("SOUDNESS_ERROR"),
-                    );
-                    return false;
-                }
+    /// Capture the messages of an equivocating attacker and replay exactly those messages
+    /// against a network of honest nodes only.
+    fn test_replay_equivocation_attack() {
+        TestNetwork::print_test_case_header("Replay equivocation attack against honest nodes");
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("always_leader".to_string());
+        attacker_config.insert("equivocate".to_string());
+        let n = 4;
+        let num_attackers = 1;
+        let epochs = 10;
+        let mut network = Network::new_byzantine(n, num_attackers, attacker_config);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+        let captured = network.captured_attacker_messages().to_vec();
+        assert!(!captured.is_empty());
+
+        let mut honest_network = Network::new(n);
+        TestNetwork::generate_transactions(&mut honest_network.nodes, n);
+        honest_network.run_replay_attack(&captured, epochs);
+        // The replayed messages impersonate two of the four (honest) nodes, so only consistency,
+        // but not liveness is guaranteed.
+        TestNetwork::print_all(&honest_network);
+        assert!(TestNetwork::consistency(&honest_network));
+    }
+
+    /// A slow leader's proposal arrives after the epoch timed out. The node that timed out must
+    /// not vote for it, while a node that did not time out still does. Networks only time out
+    /// epochs in which they deliver messages late.
+    fn test_timeout_late_proposal() {
+        TestNetwork::print_test_case_header("Timeout and late proposal");
+        let n = 4;
+        let mut nodes: Vec<Node> = (0..n)
+            .map(|i| Node::new(i, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH))
+            .collect();
+        let leader = nodes[0].leader(1);
+        let (timed_out, on_time) = ((leader + 1) % n, (leader + 2) % n);
+        nodes[leader].new_epoch(1, 0);
+        let proposal = NodeTrait::clear_outgoing_messages(&mut nodes[leader])[0]
+            .1
+            .clone();
+        let block_hash = *nodes[leader].chain.block_by_epoch[1].iter().next().unwrap();
+
+        nodes[timed_out].timeout(1);
+        assert_eq!(nodes[timed_out].view, 2);
+        nodes[timed_out].incoming_message(&*proposal, leader);
+        assert!(!nodes[timed_out].chain.votes[&block_hash].contains(&timed_out));
+
+        nodes[on_time].incoming_message(&*proposal, leader);
+        assert!(nodes[on_time].chain.votes[&block_hash].contains(&on_time));
+
+        // Networks only time out epochs while they deliver messages late, so the epochs of a
+        // fail-stop leader advance the views of the honest nodes only then
+        let views = |network: &Network| -> Vec<usize> {
+            network
+                .nodes
+                .iter()
+                .filter_map(|node| node.as_any().downcast_ref::<Node>())
+                .map(|node| node.view)
+                .collect()
+        };
+        let epochs = 20;
+        let mut network = ConsensusBuilder::new(n)
+            .attackers(1, &["fail_stop"])
+            .build();
+        network.run_simple(epochs);
+        assert_eq!(views(&network), vec![0; n - 1]);
+        let mut network = ConsensusBuilder::new(n)
+            .attackers(1, &["fail_stop"])
+            .build();
+        network.run_delays_then_synchrony(epochs, 1.0);
+        assert!(views(&network).iter().all(|view| *view > 0));
+    }
+
+    /// One of four nodes floods the network with copies of every message it receives. The rate
+    /// limit drops only the attacker's excess messages, and honest nodes still finalize.
+    fn test_rate_limit_flooding_attacker() {
+        TestNetwork::print_test_case_header("Rate limit against flooding attacker");
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("flood".to_string());
+        let n = 4;
+        let num_attackers = 1;
+        let epochs = 10;
+        let mut network = Network::new_byzantine(n, num_attackers, attacker_config);
+        network.set_rate_limit(50);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+        for (i, node) in network.nodes.iter().enumerate() {
+            if node.is_attacker() {
+                assert!(network.dropped_messages()[i] > 0);
+            } else {
+                assert_eq!(network.dropped_messages()[i], 0);
+                let honest_node = node.as_any().downcast_ref::<Node>().unwrap();
+                assert!(honest_node.chain.finalized.len() > 1);
             }
         }
-        true
     }
 
-    fn generate_transactions(nodes: &mut Vec<Box<dyn NodeTrait>>, n: usize) {
-        // Generate some transactions to be included in blocks
-        for i in 0..1000 {
-            nodes[i % n]
-                .as_mut()
-                .send_transaction(format!("This is transaction number {}", i));
+    /// One of four nodes equivocates. All honest nodes must obtain evidence of the equivocation
+    /// and slash exactly the attacker.
+    fn test_equivocation_evidence() {
+        TestNetwork::print_test_case_header("Equivocation evidence");
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("always_leader".to_string());
+        attacker_config.insert("equivocate".to_string());
+        let n = 4;
+        let num_attackers = 1;
+        let epochs = 10;
+        let mut network = Network::new_byzantine(n, num_attackers, attacker_config);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+        let attackers: HashSet<usize> =
+            (0..n).filter(|i| network.nodes[*i].is_attacker()).collect();
+        for node in network.nodes.iter() {
+            if let Some(honest_node) = node.as_any().downcast_ref::<Node>() {
+                assert_eq!(honest_node.slashed, attackers);
+            }
         }
     }
 
-    fn print_test_case_header(test_case: &str) {
-        println!("==============================================");
-        println!("Running test case: {}", test_case);
-        println!("==============================================");
-    }
+    /// The chains of honest nodes after a run do not diverge. Two hand-built chains that agree on
+    /// the block at height 1 but not at height 2 diverge at height 2. The divergence point is a
+    /// height, also if one of the chains was pruned.
+    fn test_divergence_point() {
+        TestNetwork::print_test_case_header("Divergence point");
+        let n = 4;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(6);
+        let node0 = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        let node1 = network.nodes[1].as_any().downcast_ref::<Node>().unwrap();
+        assert_eq!(node0.chain.divergence_point(&node1.chain), None);
 
-    fn is_prefix(prefix: &[Hash], main_list: &[Hash]) -> bool {
-        main_list.starts_with(prefix)
+        let mut chain1 = Blockchain::new(0);
+        let mut chain2 = Blockchain::new(1);
+        let genesis = chain1.genesis;
+        let a1 = TestNetwork::extend_finalized(&mut chain1, genesis, 1, "a");
+        let a2 = TestNetwork::extend_finalized(&mut chain2, genesis, 1, "a");
+        assert_eq!(a1, a2);
+        assert_eq!(chain1.divergence_point(&chain2), None);
+        TestNetwork::extend_finalized(&mut chain1, a1, 2, "b");
+        assert_eq!(chain1.divergence_point(&chain2), None);
+        TestNetwork::extend_finalized(&mut chain2, a2, 2, "c");
+        assert_eq!(chain1.divergence_point(&chain2), Some(2));
+
+        let mut chain3 = Blockchain::new(2);
+        let a3 = TestNetwork::extend_finalized(&mut chain3, genesis, 1, "a");
+        let b3 = TestNetwork::extend_finalized(&mut chain3, a3, 2, "b");
+        TestNetwork::extend_finalized(&mut chain3, b3, 3, "d");
+        chain3.prune_below(2);
+        assert_eq!(chain1.divergence_point(&chain3), None);
+        assert_eq!(chain3.divergence_point(&chain2), Some(2));
+        TestNetwork::extend_finalized(&mut chain1, b3, 3, "e");
+        assert_eq!(chain1.divergence_point(&chain3), Some(3));
+        assert_eq!(chain3.divergence_point(&chain1), Some(3));
     }
 
-    fn dbg(text: &str, id: Option<usize>, type_: Option<&str>) {
-        Debug::dbg(text, id.unwrap_or(0), type_);
+    /// A chain of blocks with epochs 1, 3, 4, 5 is notarized block by block. With the default
+    /// depth of 2, the block of epoch 4 and its ancestors are finalized. With depth 3, the gap
+    /// between epochs 1 and 3 prevents any finalization. Under bad network conditions, a deeper
+    /// rule never finalizes more than the default one. A depth of 0 is rejected.
+    fn test_finalization_depth() {
+        TestNetwork::print_test_case_header("Finalization depth");
+        assert!(std::panic::catch_unwind(|| Node::new(0, (0..1).collect(), 0)).is_err());
+        for (finalization_depth, expected_finalized) in [(2, 4), (3, 1)] {
+            let mut node = Node::new(0, (0..1).collect(), finalization_depth);
+            let mut parent_hash = node.chain.genesis;
+            for e in [1, 3, 4, 5] {
+                parent_hash = TestNetwork::extend_chain(&mut node.chain, parent_hash, e, "tx");
+                node.chain.votes.insert(parent_hash, HashSet::from([0]));
+                node.notarize(parent_hash);
+            }
+            assert_eq!(node.chain.finalized.len(), expected_finalized);
+        }
+
+        let n = 7;
+        let epochs = 20;
+        let fraction = 0.5;
+        let mut finalized_lengths = Vec::new();
+        for finalization_depth in [DEFAULT_FINALIZATION_DEPTH, 4] {
+            let mut network = Network::with_finalization_depth(n, finalization_depth);
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_delays_then_synchrony(epochs, fraction);
+            TestNetwork::validate(&network);
+            let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+            finalized_lengths.push(node.chain.finalized.len());
+        }
+        assert!(finalized_lengths[1] <= finalized_lengths[0]);
     }
 
-    fn print_all(network: &Network) {
-        println!("==============================================");
-        for (i, node) in network.nodes.iter().enumerate() {
-            let is_attacker = if node.is_attacker() {
-                " (FAULTY/ATTACKER)"
-            } else {
-                ""
-            };
-            println!("Node #{} {}:", i, is_attacker);
-            if let Some(honest_node) = node.as_any().downcast_ref::<Node>() {
-                honest_node.chain.print_blockchain();
+    /// A node receives the block of epoch 2 before its parent, the block of epoch 1, at the very
+    /// start of epoch 1. When the unprocessed_pool is processed at the start of the epoch, the
+    /// orphan is resolved and relayed within epoch 1. When it is processed at the end, the
+    /// other nodes only learn about the block in epoch 2.
+    fn test_pool_processing_at_epoch_start() {
+        TestNetwork::print_test_case_header("Unprocessed pool processing at epoch start");
+        let n = 4;
+        // Produce a parent and a child block in a separate network
+        let mut source = Network::new(n);
+        source.run_simple(2);
+        let source_node = source.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        let messages: Vec<Box<dyn Message>> = [1, 2]
+            .iter()
+            .map(|e| {
+                let block_hash = *source_node.chain.block_by_epoch[*e].iter().next().unwrap();
+                let block = source_node.chain.blocks.get(&block_hash).unwrap();
+                let leader = source_node.leader(*e);
+                Box::new(TestNetwork::signed_proposal(block, leader)) as Box<dyn Message>
+            })
+            .collect();
+        let child_hash = *source_node.chain.block_by_epoch[2].iter().next().unwrap();
+
+        // Deliver the child before the parent to a node that leads neither epoch
+        let receiver = (0..n)
+            .find(|i| *i != source_node.leader(1) && *i != source_node.leader(2))
+            .unwrap();
+        let inject: Vec<CapturedMessage> = vec![
+            (1, source_node.leader(2), receiver, messages[1].clone()),
+            (1, source_node.leader(1), receiver, messages[0].clone()),
+        ];
+        // The child is not relayed back to its leader, which appears to have sent it
+        for (pool_processing, expected_nodes_with_child) in [
+            (PoolProcessing::EpochStart, n - 1),
+            (PoolProcessing::Both, n - 1),
+            (PoolProcessing::EpochEnd, 1),
+        ] {
+            // Run the epoch round by round rather than with run_replay_attack, which would drain
+            // the network afterwards
+            let mut network = Network::new(n);
+            network.set_pool_processing(pool_processing);
+            for (_, sender, receiver, m) in &inject {
+                network.send(*sender, m.clone(), *receiver);
             }
+            for _ in 0..DEFAULT_ROUNDS_PER_EPOCH {
+                network.step();
+            }
+            let nodes_with_child = network
+                .nodes
+                .iter()
+                .filter_map(|node| node.as_any().downcast_ref::<Node>())
+                .filter(|node| node.chain.contains_block(child_hash))
+                .count();
+            assert_eq!(nodes_with_child, expected_nodes_with_child);
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// The number of attackers is configurable, and the attackers are the nodes with the highest
+    /// ids. Below the n/3 threshold, fail-stopped attackers cannot break consistency.
+    fn test_configurable_attacker_count() {
+        TestNetwork::print_test_case_header("Configurable number of attackers");
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("fail_stop".to_string());
+        let n = 7;
+        let epochs = 10;
+        for num_attackers in 0..=2 {
+            let mut network = Network::new_byzantine(n, num_attackers, attacker_config.clone());
+            let attackers: Vec<usize> =
+                (0..n).filter(|i| network.nodes[*i].is_attacker()).collect();
+            assert_eq!(attackers, (n - num_attackers..n).collect::<Vec<usize>>());
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_simple(epochs);
+            TestNetwork::validate(&network);
+        }
+    }
 
-    #[test]
-    fn test_honest_only_perfect_network() {
-        TestNetwork::test_honest_only_perfect_network();
+    /// A node loses its chain after five epochs. It must not vote for a conflicting proposal of
+    /// an epoch it already voted in. After receiving the blocks and votes of a peer, it has
+    /// caught up and keeps up with the others in later epochs.
+    fn test_reset_chain() {
+        TestNetwork::print_test_case_header("Reset chain to genesis");
+        let n = 4;
+        let epochs = 5;
+        let reset_id = 1;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+
+        // The chain of a peer, as block and vote messages, in order of epochs
+        let peer = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        let mut sync_messages: Vec<Box<dyn Message>> = Vec::new();
+        for e in 1..peer.chain.block_by_epoch.len() {
+            for block_hash in &peer.chain.block_by_epoch[e] {
+                let block = peer.chain.blocks.get(block_hash).unwrap();
+                let leader = peer.leader(e);
+                sync_messages.push(Box::new(TestNetwork::signed_proposal(block, leader)));
+                for voter in &peer.chain.votes[block_hash] {
+                    sync_messages.push(Box::new(TestNetwork::signed_vote(block, *voter)));
+                }
+            }
+        }
+        let leader = peer.leader(1);
+        let conflicting_block = Block::new(
+            Some(peer.chain.genesis),
+            1,
+            vec![Transaction::new(0, 0, "conflicting".to_string())],
+            "1/conflicting".to_string(),
+            1,
+            HashAlgo::default(),
+        );
+        let conflicting_message = TestNetwork::signed_proposal(&conflicting_block, leader);
+
+        let node = network.nodes[reset_id]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap();
+        assert!(!node.confirmation_time_histogram().is_empty());
+        node.reset_chain();
+        assert_eq!(node.chain.blocks.len(), 1);
+        // The transactions submitted to us are dropped along with our pool
+        assert!(node.confirmation_time_histogram().is_empty());
+        node.incoming_message(&conflicting_message, leader);
+        assert!(!node.chain.votes[&conflicting_block.hash].contains(&reset_id));
+        for m in &sync_messages {
+            node.incoming_message(&**m, 0);
+        }
+        let node_height = node
+            .chain
+            .blocks
+            .get(node.chain.highest_finalized_block())
+            .unwrap()
+            .height;
+        assert!(node_height >= epochs - 1);
+
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
     }
 
-    #[test]
-    fn test_honest_only_with_reorder() {
-        TestNetwork::test_honest_only_with_reorder();
+    /// Two of seven honest nodes receive all messages one round late. The network still
+    /// finalizes, and the slow nodes lag behind the others by at most one block.
+    fn test_slow_nodes() {
+        TestNetwork::print_test_case_header("Slow nodes");
+        let n = 7;
+        let epochs = 10;
+        let slow_nodes = HashSet::from([1, 4]);
+        let mut network = Network::new(n);
+        network.set_slow_nodes(&slow_nodes, 1);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+        let finalized_height = |i: usize| {
+            let node = network.nodes[i].as_any().downcast_ref::<Node>().unwrap();
+            node.chain
+                .blocks
+                .get(node.chain.highest_finalized_block())
+                .unwrap()
+                .height
+        };
+        for i in &slow_nodes {
+            assert!(finalized_height(*i) + 1 >= finalized_height(0));
+        }
     }
 
-    #[test]
-    fn test_honest_only_with_delays_then_synchrony() {
-        TestNetwork::test_honest_only_with_delays_then_synchrony();
+    /// A node that receives a block whose parent it does not know asks the
+    /// sender for the parent, and processes the block once the parent arrives.
+    fn test_get_block() {
+        TestNetwork::print_test_case_header("Request missing parent blocks");
+        let n = 4;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(2);
+
+        // Block messages for epochs 1 and 2, as signed by their leaders
+        let peer = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        let mut proposals = Vec::new();
+        for e in 1..=2 {
+            let block_hash = *peer.chain.block_by_epoch[e].iter().next().unwrap();
+            let block = peer.chain.blocks.get(&block_hash).unwrap();
+            let leader = peer.leader(e);
+            proposals.push(TestNetwork::signed_proposal(block, leader));
+        }
+        let parent_hash = proposals[0].block_hash(HashAlgo::Sha256);
+        let child_hash = proposals[1].block_hash(HashAlgo::Sha256);
+
+        // Node 1 forgets everything and then only hears about the child from node 0
+        let node = network.nodes[1]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap();
+        node.reset_chain();
+        node.incoming_message(&proposals[1], 0);
+        assert!(!node.chain.blocks.contains_key(&child_hash));
+        let (receiver, request) = node.outgoing_messages.pop_front().unwrap();
+        let request = request
+            .as_any()
+            .downcast_ref::<GetBlockMessage>()
+            .unwrap()
+            .clone();
+        assert_eq!(receiver, 0);
+        assert_eq!(request.block_hash, parent_hash);
+
+        // Node 0 answers the request with the parent, and only to node 1
+        let peer = network.nodes[0]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap();
+        peer.clear_outgoing_messages();
+        peer.incoming_message(&request, 1);
+        assert_eq!(peer.outgoing_messages.len(), 1);
+        let (receiver, reply) = peer.outgoing_messages.pop_front().unwrap();
+        assert_eq!(receiver, 1);
+        let reply = reply
+            .as_any()
+            .downcast_ref::<BlockMessage>()
+            .unwrap()
+            .clone();
+        assert_eq!(reply.block_hash(HashAlgo::Sha256), parent_hash);
+
+        // With the parent in place, node 1 can process the child from its pool
+        let node = network.nodes[1]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap();
+        node.incoming_message(&reply, 0);
+        node.process_unprocessed_pool();
+        assert!(node.chain.blocks.contains_key(&parent_hash));
+        assert!(node.chain.blocks.contains_key(&child_hash));
+
+        // MAX_PAST_EPOCHS later, node 1 forgets the proposals, since it never saw the blocks
+        // notarized, while node 0 keeps serving the notarized parent
+        let e = 3 + MAX_PAST_EPOCHS;
+        node.enter_epoch(e, 0);
+        node.clear_outgoing_messages();
+        node.incoming_message(&request, 2);
+        assert!(node.outgoing_messages.is_empty());
+        let peer = network.nodes[0]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap();
+        peer.enter_epoch(e, 0);
+        peer.clear_outgoing_messages();
+        peer.incoming_message(&request, 2);
+        assert_eq!(peer.outgoing_messages.len(), 1);
     }
 
-    #[test]
-    fn test_one_third_stopped() {
-        TestNetwork::test_one_third_stopped();
+    /// Every finalized block must be notarized. Holds on a healthy run, and the audit catches a
+    /// hand-corrupted chain that finalized a block it never notarized. Debug builds check the
+    /// audit at the end of every run, so continuing to run the corrupted network panics.
+    fn test_finalized_subset_notarized() {
+        TestNetwork::print_test_case_header("Finalized blocks are notarized");
+        let n = 4;
+        let epochs = 10;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        assert!(network.finalized_subset_notarized());
+
+        // Corrupt the state of one node: finalize the highest block without it being notarized
+        let node = network.nodes[0]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap();
+        let last_epoch = node.chain.block_by_epoch.len() - 1;
+        let block_hash = *node.chain.block_by_epoch[last_epoch].iter().next().unwrap();
+        node.chain.notarized.remove(&block_hash);
+        node.chain.finalized.insert(block_hash);
+        assert!(!node.chain.check_finalized_subset_notarized());
+        assert!(!network.finalized_subset_notarized());
+        if cfg!(debug_assertions) {
+            let mut network = std::panic::AssertUnwindSafe(network);
+            assert!(std::panic::catch_unwind(move || network.run_simple(1)).is_err());
+        }
     }
 
-    #[test]
-    fn test_one_third_stopped_with_delays_then_synchrony() {
-        TestNetwork::test_one_third_stopped_with_delays_then_synchrony();
+    /// Runs with the same seed produce the same chains, and runs with other seeds are still
+    /// consistent and live.
+    fn test_network_seed() {
+        TestNetwork::print_test_case_header("Configurable network seed");
+        let n = 4;
+        let epochs = 15;
+        let fraction = 0.5;
+        let run = |seed: [u8; 32]| {
+            let mut network = Network::with_seed(n, seed);
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_delays_then_synchrony(epochs, fraction);
+            TestNetwork::validate(&network);
+            network
+        };
+        let finalized_chains = |network: &Network| -> Vec<Vec<Hash>> {
+            network
+                .nodes
+                .iter()
+                .map(|node| {
+                    node.as_any()
+                        .downcast_ref::<Node>()
+                        .unwrap()
+                        .chain
+                        .finalized_chain()
+                })
+                .collect()
+        };
+        let network1 = run([1; 32]);
+        let network2 = run([1; 32]);
+        assert_eq!(finalized_chains(&network1), finalized_chains(&network2));
+        run([2; 32]);
+
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("fail_stop".to_string());
+        let mut network = Network::new_byzantine_with_seed(n, 1, attacker_config, [3; 32]);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_reorder(epochs);
+        TestNetwork::validate(&network);
     }
 
-    #[test]
-    fn test_one_third_misbehave_with_delays_then_synchrony() {
-        TestNetwork::test_one_third_misbehave_with_delays_then_synchrony();
+    /// The causal graph of a finalized block traces back through its notarization votes to the
+    /// original proposal. Nodes only record it with causal tracking on.
+    fn test_causal_graph() {
+        TestNetwork::print_test_case_header("Causal graph");
+        let n = 4;
+        let epochs = 10;
+        let mut network = Network::new(n);
+        for node in network.nodes.iter_mut().skip(1) {
+            let node = node.as_any_mut().downcast_mut::<Node>().unwrap();
+            node.set_causal_tracking(true);
+        }
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+
+        let untracked = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        assert!(untracked.causal_graph.is_empty());
+        let node = network.nodes[1].as_any().downcast_ref::<Node>().unwrap();
+        let graph = &node.causal_graph;
+        let block_hash = *node.chain.highest_finalized_block();
+        let block = node.chain.blocks.get(&block_hash).unwrap();
+        let finalization = CausalEvent::Finalization { block: block_hash };
+        let notarization = CausalEvent::Notarization { block: block_hash };
+        let proposal = CausalEvent::Proposal {
+            block: block_hash,
+            creator: node.leader(block.e),
+        };
+        assert!(graph.causes_of(&finalization).contains(&notarization));
+        // The votes that caused the notarization are those that formed the first quorum. As we
+        // notarize each block only once, votes arriving later are not among its causes, so the
+        // causes are a subset of the stored votes, and we notarized as soon as they formed a
+        // quorum: without the last of them, the others do not.
+        let votes = graph.causes_of(&notarization);
+        let voters: HashSet<usize> = votes
+            .iter()
+            .map(|vote| match vote {
+                CausalEvent::Vote { block, voter } if *block == block_hash => *voter,
+                _ => panic!("Unexpected cause {} of a notarization", vote),
+            })
+            .collect();
+        assert!(voters.is_subset(&node.chain.votes[&block_hash]));
+        assert!(node.chain.is_quorum(&voters, node.validators()));
+        assert!(voters.iter().any(|last| {
+            let others = voters.iter().filter(|voter| *voter != last);
+            !node.chain.is_quorum(others, node.validators())
+        }));
+        let own_vote = CausalEvent::Vote {
+            block: block_hash,
+            voter: node.id,
+        };
+        assert!(graph.causes_of(&own_vote).contains(&proposal));
+        assert!(graph.traces_back_to(&finalization, &proposal));
+
+        // Ancestors are finalized because of the finalization of their descendants
+        for ancestor in node.chain.finalized_chain().iter().skip(1) {
+            let ancestor_block = node.chain.blocks.get(ancestor).unwrap();
+            let ancestor_proposal = CausalEvent::Proposal {
+                block: *ancestor,
+                creator: node.leader(ancestor_block.e),
+            };
+            let ancestor_finalization = CausalEvent::Finalization { block: *ancestor };
+            assert!(graph.traces_back_to(&ancestor_finalization, &ancestor_proposal));
+        }
+
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph causal {"));
+        assert!(dot.contains(&format!("[label=\"{}\"]", finalization)));
+        assert!(dot.contains(" -> "));
     }
 
-    #[test]
-    fn test_one_third_fake_sigs_with_delays_then_synchrony() {
-        TestNetwork::test_one_third_fake_sigs_with_delays_then_synchrony();
+    /// Safety bugs often only show up under specific message orderings. Run the delays then
+    /// synchrony scenario with many seeds and random fractions of delivered messages.
+    fn test_consistency_across_seeds() {
+        TestNetwork::print_test_case_header("Consistency across many seeds");
+        let n = 4;
+        let epochs = 20;
+        let num_seeds = 200;
+        let mut fraction_rng = StdRng::from_seed([0; 32]);
+        for i in 0..num_seeds {
+            let mut seed = [0; 32];
+            seed[..8].copy_from_slice(&(i as u64).to_le_bytes());
+            let fraction: f64 = fraction_rng.gen_range(0.0..1.0);
+            let mut network = Network::with_seed(n, seed);
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_delays_then_synchrony(epochs, fraction);
+            assert!(
+                TestNetwork::consistency(&network),
+                "Consistency violated with seed {} and fraction {}",
+                i,
+                fraction
+            );
+            TestNetwork::validate(&network);
+        }
     }
-}
 
-pub fn main() {
-    // This main function is only for running the tests manually if needed
-    TestNetwork::test_honest_only_perfect_network();
-    TestNetwork::test_honest_only_with_reorder();
-    TestNetwork::test_honest_only_with_delays_then_synchrony();
-    TestNetwork::test_one_third_stopped();
-    TestNetwork::test_one_third_stopped_with_delays_then_synchrony();
-    TestNetwork::test_one_third_misbehave_with_delays_then_synchrony();
-    TestNetwork::test_one_third_fake_sigs_with_delays_then_synchrony();
+    /// A broadcast message has hop count 0 at its first recipients, and each relay increments it.
+    /// Nodes do not relay messages that reached their maximum hop count.
+    fn test_relay_hop_count() {
+        TestNetwork::print_test_case_header("Relay hop count");
+        let n = 4;
+        let e = 1;
+        let mut nodes: Vec<Node> = (0..n)
+            .map(|i| Node::new(i, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH))
+            .collect();
+        let leader = nodes[0].leader(e);
+        let (relay1, relay2) = ((leader + 1) % n, (leader + 2) % n);
+        nodes[leader].propose_block(e).unwrap();
+        let (_, proposal) = nodes[leader].outgoing_messages.pop_front().unwrap();
+        assert_eq!(proposal.hop(), 0);
+        assert!(proposal.name().ends_with("hop=0>"));
 
-    println!("==============================================");
+        // The first recipient relays the proposal with hop count 1
+        nodes[relay1].incoming_message(proposal.as_ref(), leader);
+        let relayed = nodes[relay1]
+            .outgoing_messages
+            .iter()
+            .find(|(_, m)| m.as_any().downcast_ref::<BlockMessage>().is_some())
+            .unwrap()
+            .1
+            .clone();
+        assert_eq!(relayed.hop(), 1);
+        assert!(relayed.name().ends_with("hop=1>"));
+
+        // The second recipient relays it with hop count 2
+        nodes[relay2].incoming_message(relayed.as_ref(), relay1);
+        let relayed_again = nodes[relay2]
+            .outgoing_messages
+            .iter()
+            .find(|(_, m)| m.as_any().downcast_ref::<BlockMessage>().is_some())
+            .unwrap()
+            .1
+            .clone();
+        assert_eq!(relayed_again.hop(), 2);
+
+        // A node with a maximum hop count of 1 processes, but does not relay, the relayed message
+        let last = (leader + 3) % n;
+        nodes[last].max_hops = Some(1);
+        nodes[last].incoming_message(relayed.as_ref(), relay1);
+        let block_hash = relayed
+            .as_any()
+            .downcast_ref::<BlockMessage>()
+            .unwrap()
+            .block_hash(HashAlgo::Sha256);
+        assert!(nodes[last].chain.blocks.contains_key(&block_hash));
+        assert!(nodes[last]
+            .outgoing_messages
+            .iter()
+            .all(|(_, m)| m.as_any().downcast_ref::<BlockMessage>().is_none()));
+    }
+
+    /// Split-brain: a majority group and an isolated node. Only the majority finalizes during the
+    /// partition, and after healing the chains of all nodes are consistent again.
+    fn test_partition() {
+        TestNetwork::print_test_case_header("Network partition");
+        let n = 4;
+        let heal_epoch = 10;
+        let epochs = 20;
+        let majority: HashSet<usize> = [0, 1, 2].into_iter().collect();
+        let minority: HashSet<usize> = [3].into_iter().collect();
+        let partition = vec![majority.clone(), minority.clone()];
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+
+        network.run_with_partition(heal_epoch - 1, partition.clone(), heal_epoch);
+        assert!(TestNetwork::consistency(&network));
+        for (i, node) in network.nodes.iter().enumerate() {
+            let node = node.as_any().downcast_ref::<Node>().unwrap();
+            let finalized = node.chain.finalized.len() - 1;
+            if minority.contains(&i) {
+                assert_eq!(finalized, 0);
+            } else {
+                assert!(finalized > 0);
+            }
+        }
+
+        network.run_with_partition(epochs - network.epoch(), partition, heal_epoch);
+        TestNetwork::validate(&network);
+    }
+
+    /// Validators leave and join on a schedule: node 3 leaves in epoch 10, and a new node joins in
+    /// epoch 20 and has to sync from genesis. The chains stay consistent and the current
+    /// validators keep finalizing blocks after each change.
+    fn test_validator_churn() {
+        TestNetwork::print_test_case_header("Validator churn");
+        let n = 4;
+        let schedule = [(10, Churn::Remove(3)), (20, Churn::Add)];
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        let finalized_heights = |network: &Network| -> Vec<usize> {
+            network
+                .validators()
+                .iter()
+                .map(|i| {
+                    let node = network.nodes[*i].as_any().downcast_ref::<Node>().unwrap();
+                    node.chain.blocks[node.chain.highest_finalized_block()].height
+                })
+                .collect()
+        };
+
+        let mut min_height = 0;
+        for epochs in [9, 10, 20] {
+            network.run_with_churn(epochs, &schedule);
+            assert!(TestNetwork::consistency(&network));
+            let heights = finalized_heights(&network);
+            TestNetwork::dbg(
+                &format!(
+                    "Finalized heights after epoch {}: {:?}",
+                    network.epoch(),
+                    heights
+                ),
+                None,
+                None,
+            );
+            assert!(heights.iter().all(|height| *height > min_height));
+            assert!(heights.iter().max().unwrap() - heights.iter().min().unwrap() <= 1);
+            min_height = *heights.iter().min().unwrap();
+        }
+        assert_eq!(network.validators(), vec![0, 1, 2, 4]);
+    }
+
+    /// Transactions submitted across epochs are finalized within a bounded number of epochs under
+    /// synchrony. During a partition, the transactions of the isolated node are not confirmed.
+    fn test_confirmation_time_histogram() {
+        TestNetwork::print_test_case_header("Confirmation time histogram");
+        let n = 4;
+        let submission_epochs = 10;
+        let max_delay = 12;
+        let submit = |network: &mut Network| {
+            let e = network.epoch();
+            for (i, node) in network.nodes.iter_mut().enumerate() {
+                let tx = Transaction::new(i, e as u64, format!("{} in epoch {}", i, e));
+                node.send_transaction(tx);
+            }
+        };
+
+        let mut network = Network::new(n);
+        for _ in 0..submission_epochs {
+            submit(&mut network);
+            network.run_simple(1);
+        }
+        network.run_simple(max_delay);
+        TestNetwork::validate(&network);
+        for node in network.nodes.iter() {
+            let node = node.as_any().downcast_ref::<Node>().unwrap();
+            let histogram = node.confirmation_time_histogram();
+            TestNetwork::dbg(
+                &format!("Confirmation times: {:?}", histogram),
+                Some(node.id),
+                None,
+            );
+            assert_eq!(histogram.values().sum::<usize>(), submission_epochs);
+            assert!(!histogram.contains_key(&None));
+            assert!(histogram.keys().all(|delay| delay.unwrap() <= max_delay));
+        }
+
+        let majority: HashSet<usize> = [0, 1, 2].into_iter().collect();
+        let minority: HashSet<usize> = [3].into_iter().collect();
+        let mut network = Network::new(n);
+        for _ in 0..submission_epochs {
+            submit(&mut network);
+            network.run_with_partition(1, vec![majority.clone(), minority.clone()], usize::MAX);
+        }
+        let node = network.nodes[3].as_any().downcast_ref::<Node>().unwrap();
+        let histogram = node.confirmation_time_histogram();
+        assert_eq!(histogram.get(&None), Some(&submission_epochs));
+    }
+
+    /// An attacker that proposes and relays blocks, but never sends its own votes. The honest
+    /// nodes still notarize and finalize blocks.
+    fn test_withhold_votes() {
+        TestNetwork::print_test_case_header("Attacker withholds votes");
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("withhold_votes".to_string());
+        let n = 4;
+        let num_attackers = 1;
+        let epochs = 20;
+        let mut network = Network::new_byzantine(n, num_attackers, attacker_config);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+
+        let attacker = n - 1;
+        let captured = network.captured_attacker_messages();
+        assert!(captured
+            .iter()
+            .any(|(_, _, _, m)| m.as_any().is::<BlockMessage>()));
+        for (_, _, _, m) in captured {
+            if let Some(vote) = m.as_any().downcast_ref::<VoteMessage>() {
+                assert_ne!(vote.signer, attacker);
+            }
+        }
+    }
+
+    /// The network records per-epoch metrics that match the state of the nodes after the run.
+    fn test_network_metrics() {
+        TestNetwork::print_test_case_header("Network metrics");
+        let n = 4;
+        let epochs = 10;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+
+        let metrics = network.metrics();
+        assert_eq!(metrics.epochs.len(), epochs);
+        for (e, epoch) in metrics.epochs.iter().enumerate() {
+            assert_eq!(epoch.epoch, e + 1);
+            assert_eq!(epoch.blocks_proposed, 1);
+            assert!(epoch.messages_sent > 0);
+        }
+        let nodes: Vec<&Node> = network
+            .nodes
+            .iter()
+            .map(|node| node.as_any().downcast_ref::<Node>().unwrap())
+            .collect();
+        let total = metrics.total();
+        // The genesis block is notarized and finalized from the start
+        let notarized: usize = nodes
+            .iter()
+            .map(|node| node.chain.notarized.len() - 1)
+            .sum();
+        let finalized: usize = nodes
+            .iter()
+            .map(|node| node.chain.finalized.len() - 1)
+            .sum();
+        assert_eq!(total.blocks_proposed, epochs);
+        assert_eq!(total.blocks_notarized, notarized);
+        assert_eq!(total.blocks_finalized, finalized);
+        assert!(total.blocks_finalized > 0);
+    }
+
+    /// Transactions submitted several times, to the same or to different nodes, and before or
+    /// after they were included in a block, appear at most once in the finalized log.
+    fn test_duplicate_transactions() {
+        TestNetwork::print_test_case_header("Duplicate transactions");
+        let n = 4;
+        let epochs = 20;
+        let txs: Vec<Transaction> = (0..8)
+            .map(|i| Transaction::new(i % n, i as u64, format!("Duplicated transaction {}", i)))
+            .collect();
+        let mut network = Network::new(n);
+        for _ in 0..epochs / 2 {
+            for node in network.nodes.iter_mut() {
+                for tx in &txs {
+                    node.send_transaction(tx.clone());
+                    node.send_transaction(tx.clone());
+                }
+            }
+            network.run_simple(1);
+        }
+        network.run_simple(epochs / 2);
+        TestNetwork::validate(&network);
+
+        for node in network.nodes.iter() {
+            let node = node.as_any().downcast_ref::<Node>().unwrap();
+            let log = node.finalized_log();
+            let unique: HashSet<&Transaction> = log.iter().collect();
+            assert_eq!(unique.len(), log.len());
+            for tx in &txs {
+                assert!(unique.contains(tx));
+            }
+        }
+    }
+
+    /// Periodically pruning finalized blocks below a checkpoint keeps the number of stored blocks
+    /// bounded in a long run, while nodes keep finalizing blocks consistently.
+    fn test_prune_below() {
+        TestNetwork::print_test_case_header("Prune below checkpoint");
+        let n = 4;
+        let epochs = 500;
+        let prune_interval = 20;
+        let keep = 5;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        for _ in 0..epochs / prune_interval {
+            network.run_simple(prune_interval);
+            let checkpoint_height = network.global_safe_height() - keep;
+            for node in network.nodes.iter_mut() {
+                let chain = &mut node.as_any_mut().downcast_mut::<Node>().unwrap().chain;
+                chain.prune_below(checkpoint_height);
+                assert_eq!(chain.blocks[&chain.root].height, checkpoint_height);
+                assert!(chain.blocks.len() <= prune_interval + keep + 1);
+                assert!(chain.notarized.len() <= chain.blocks.len());
+                assert!(chain.finalized.len() <= chain.blocks.len());
+            }
+            assert!(TestNetwork::consistency(&network));
+        }
+        TestNetwork::validate(&network);
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        assert!(node.chain.blocks[node.chain.highest_finalized_block()].height > epochs / 2);
+    }
+
+    /// If two blocks of the same epoch are notarized, the highest notarized block is the one with
+    /// the smaller hash, no matter in which order the blocks were added.
+    fn test_highest_notarized_tie_break() {
+        TestNetwork::print_test_case_header("Tie-break of highest notarized block");
+        let build = |payloads: [&str; 2]| {
+            let mut chain = Blockchain::new(0);
+            let genesis = chain.genesis;
+            let mut blocks = Vec::new();
+            for payload in payloads {
+                let block_hash = TestNetwork::extend_chain(&mut chain, genesis, 1, payload);
+                chain.notarized.insert(block_hash);
+                blocks.push(block_hash);
+            }
+            (chain.get_highest_notarized_block(), blocks)
+        };
+        let (highest, blocks) = build(["fork a", "fork b"]);
+        assert_eq!(highest, *blocks.iter().min().unwrap());
+        for _ in 0..10 {
+            assert_eq!(build(["fork a", "fork b"]).0, highest);
+            assert_eq!(build(["fork b", "fork a"]).0, highest);
+        }
+    }
+
+    /// A blockchain saved to disk and loaded again has the same blocks, votes, notarized and
+    /// finalized blocks, and thus the same finalized chain.
+    fn test_save_and_load_blockchain() {
+        TestNetwork::print_test_case_header("Save and load blockchain");
+        let n = 4;
+        let epochs = 10;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+
+        let chain = &network.nodes[0]
+            .as_any()
+            .downcast_ref::<Node>()
+            .unwrap()
+            .chain;
+        let path = std::env::temp_dir().join(format!("blockchain-{}.bin", std::process::id()));
+        chain.save(&path).unwrap();
+        let loaded = Blockchain::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(loaded.same_genesis(chain));
+        assert_eq!(loaded.finalized_chain(), chain.finalized_chain());
+        assert_eq!(
+            loaded.finalized_transactions(),
+            chain.finalized_transactions()
+        );
+        assert_eq!(
+            loaded.blocks.keys().collect::<HashSet<_>>(),
+            chain.blocks.keys().collect()
+        );
+        assert_eq!(loaded.votes, chain.votes);
+        assert_eq!(loaded.notarized, chain.notarized);
+        assert_eq!(loaded.finalized, chain.finalized);
+        assert_eq!(loaded.block_by_epoch, chain.block_by_epoch);
+        assert_eq!(
+            loaded.get_highest_notarized_block(),
+            chain.get_highest_notarized_block()
+        );
+
+        // Loading a file that does not contain a blockchain fails
+        std::fs::write(&path, b"not a blockchain").unwrap();
+        assert!(Blockchain::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// A node is down from epoch 3 to epoch 7 and then recovers. It catches up on the blocks it
+    /// missed and converges to the same finalized chain as the other nodes.
+    fn test_crash_and_recover() {
+        TestNetwork::print_test_case_header("Crash and recover");
+        let n = 4;
+        let epochs = 20;
+        let crashed = 1;
+        let schedule = vec![(3, crashed, true), (8, crashed, false)];
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_with_crashes(7, schedule.clone());
+        let finalized_while_down = network.nodes[crashed]
+            .as_any()
+            .downcast_ref::<Node>()
+            .unwrap()
+            .chain
+            .finalized_chain();
+        network.run_with_crashes(epochs - 7, schedule);
+        TestNetwork::validate(&network);
+
+        let chains: Vec<Vec<Hash>> = network
+            .nodes
+            .iter()
+            .map(|node| {
+                node.as_any()
+                    .downcast_ref::<Node>()
+                    .unwrap()
+                    .chain
+                    .finalized_chain()
+            })
+            .collect();
+        assert!(chains[crashed].len() > finalized_while_down.len());
+        let shortest = chains.iter().map(|chain| chain.len()).min().unwrap();
+        for chain in &chains {
+            assert_eq!(chain[..shortest], chains[crashed][..shortest]);
+        }
+
+        // The recovered node votes again
+        let peer = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        assert!(peer.chain.blocks.values().any(|block| block.e > 8
+            && peer
+                .chain
+                .votes
+                .get(&block.hash)
+                .is_some_and(|votes| votes.contains(&crashed))));
+    }
+
+    /// The hash leader election picks the validator at position hash(e) mod n. With stake
+    /// weights, a validator holding almost all stake leads almost all epochs, all nodes agree on
+    /// the leaders, and the protocol still reaches consensus.
+    fn test_stake_weighted_leader_election() {
+        TestNetwork::print_test_case_header("Stake-weighted leader election");
+        let n = 4;
+        for e in 0..100 {
+            assert_eq!(
+                HashLeaderElection.leader(e, n, HashAlgo::default()),
+                Crypto::short_hash(&Crypto::hash_var(HashAlgo::default(), e)) % n
+            );
+        }
+
+        let election = StakeWeightedLeaderElection::new(vec![1, 1, 1, 97]);
+        let led_by_3 = (0..100)
+            .filter(|e| election.leader(*e, n, HashAlgo::default()) == 3)
+            .count();
+        assert!(
+            led_by_3 > 80,
+            "validator 3 led only {} of 100 epochs",
+            led_by_3
+        );
+
+        let epochs = 20;
+        let mut network = Network::new(n);
+        network.set_leader_election(&election);
+        for e in 0..epochs {
+            let leaders: HashSet<usize> = network
+                .nodes
+                .iter()
+                .map(|node| node.as_any().downcast_ref::<Node>().unwrap().leader(e))
+                .collect();
+            assert_eq!(
+                leaders.len(),
+                1,
+                "nodes disagree on the leader of epoch {}",
+                e
+            );
+        }
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+    }
+
+    /// Nodes emit structured events: a node only proposes in epochs it leads, every finalized
+    /// block was notarized before, and honest nodes report a leader faking signatures as an attack.
+    fn test_events() {
+        TestNetwork::print_test_case_header("Structured events");
+        let n = 4;
+        let epochs = 20;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+        for node in &network.nodes {
+            let node = node.as_any().downcast_ref::<Node>().unwrap();
+            let mut notarized = HashSet::new();
+            let mut finalized = 0;
+            for (i, record) in node.events.iter().enumerate() {
+                assert_eq!(record.node, node.id);
+                if i > 0 {
+                    assert!(node.events[i - 1].epoch <= record.epoch);
+                }
+                match &record.event {
+                    Event::BlockProposed { e, .. } => assert_eq!(node.leader(*e), node.id),
+                    Event::Notarized { block, .. } => assert!(notarized.insert(*block)),
+                    Event::Finalized { block, .. } => {
+                        assert!(notarized.contains(block));
+                        assert!(node.chain.finalized.contains(block));
+                        finalized += 1;
+                    }
+                    _ => {}
+                }
+            }
+            // All finalized blocks but genesis were finalized with an event
+            assert_eq!(finalized + 1, node.chain.finalized.len());
+        }
+
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("always_leader".to_string());
+        attacker_config.insert("fake_block_signature".to_string());
+        let mut network = Network::new_byzantine(n, 1, attacker_config);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+        for node in network.nodes.iter().filter(|node| !node.is_attacker()) {
+            assert!(node
+                .events()
+                .iter()
+                .any(|record| matches!(record.event, Event::AttackDetected { .. })));
+        }
+
+        // A node keeps at most MAX_EVENTS events, dropping the oldest ones
+        let mut node = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        for i in 0..=MAX_EVENTS {
+            node.emit(Event::Error {
+                reason: i.to_string(),
+            });
+        }
+        assert!(node.events().len() <= MAX_EVENTS);
+        assert_eq!(node.events_emitted(), MAX_EVENTS + 1);
+        assert_eq!(
+            node.events.last().unwrap().event,
+            Event::Error {
+                reason: MAX_EVENTS.to_string()
+            }
+        );
+    }
+
+    /// Transactions are valid iff their payload is non-empty and has at most MAXLENGTH_SINGLE_TX
+    /// characters. Invalid transactions are not accepted by send_transaction.
+    fn test_transaction_validity() {
+        TestNetwork::print_test_case_header("Transaction validity");
+        let mut node = Node::new(0, (0..4).collect(), DEFAULT_FINALIZATION_DEPTH);
+        let tx = |len: usize| Transaction::new(0, len as u64, "x".repeat(len));
+        assert!(!node.is_transaction_valid(&tx(0)));
+        assert!(node.is_transaction_valid(&tx(1)));
+        assert!(node.is_transaction_valid(&tx(MAXLENGTH_SINGLE_TX - 1)));
+        assert!(node.is_transaction_valid(&tx(MAXLENGTH_SINGLE_TX)));
+        assert!(!node.is_transaction_valid(&tx(MAXLENGTH_SINGLE_TX + 1)));
+
+        for len in [0, 1, MAXLENGTH_SINGLE_TX, MAXLENGTH_SINGLE_TX + 1] {
+            node.send_transaction(tx(len));
+        }
+        let submitted: usize = node.confirmation_time_histogram().values().sum();
+        assert_eq!(submitted, 2);
+    }
+
+    /// Attackers that equivocate and cast validly signed votes for every block on every fork
+    /// (nothing at stake) cannot break consistency, as long as they are fewer than n/3.
+    fn test_vote_all_forks() {
+        TestNetwork::print_test_case_header("Attackers vote for all forks");
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("always_leader".to_string());
+        attacker_config.insert("equivocate".to_string());
+        attacker_config.insert("vote_all_forks".to_string());
+        let epochs = 30;
+        let fraction = 0.5;
+        for (n, num_attackers) in [(4, 1), (7, 2)] {
+            let mut network = Network::new_byzantine(n, num_attackers, attacker_config.clone());
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_delays_then_synchrony(epochs, fraction);
+            TestNetwork::validate(&network);
+
+            // Honest nodes accepted votes of the attacker for blocks that the attacker, following
+            // the protocol, would not have voted for
+            let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+            let attacker = network.nodes[n - 1]
+                .as_any()
+                .downcast_ref::<AttackerNode>()
+                .unwrap();
+            let fork_votes = node
+                .chain
+                .votes
+                .iter()
+                .filter(|(block_hash, votes)| {
+                    votes.contains(&attacker.id)
+                        && attacker
+                            .chain
+                            .votes
+                            .get(*block_hash)
+                            .is_none_or(|own_votes| !own_votes.contains(&attacker.id))
+                })
+                .count();
+            assert!(fork_votes > 0);
+        }
+    }
+
+    /// With 13 nodes and delayed messages, three rounds per epoch are often too few for blocks,
+    /// votes and relays to reach all nodes. More rounds per epoch lead to more finalized blocks.
+    fn test_rounds_per_epoch() {
+        TestNetwork::print_test_case_header("Rounds per epoch");
+        let n = 13;
+        let epochs = 20;
+        let fraction = 0.3;
+        let finalized_height = |rounds_per_epoch: usize| {
+            let mut network = Network::new(n);
+            network.set_rounds_per_epoch(rounds_per_epoch);
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_delays_then_synchrony(epochs, fraction);
+            assert!(TestNetwork::consistency(&network));
+            let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+            node.chain
+                .blocks
+                .get(node.chain.highest_finalized_block())
+                .unwrap()
+                .height
+        };
+        let default_height = finalized_height(DEFAULT_ROUNDS_PER_EPOCH);
+        let more_rounds_height = finalized_height(10);
+        TestNetwork::dbg(
+            &format!(
+                "Finalized height {} with {} rounds, {} with 10 rounds",
+                default_height, DEFAULT_ROUNDS_PER_EPOCH, more_rounds_height
+            ),
+            None,
+            None,
+        );
+        assert!(more_rounds_height > default_height);
+    }
+
+    /// Blocks of old epochs can arrive after blocks of later epochs, e.g. when their parent was
+    /// missing. Adding such a block keeps the blocks of the later epochs in block_by_epoch.
+    fn test_block_by_epoch_old_block() {
+        TestNetwork::print_test_case_header("Block of an old epoch");
+        let mut chain = Blockchain::new(0);
+        let genesis = chain.genesis;
+        let late_block = Block::new(
+            Some(genesis),
+            3,
+            vec![],
+            "3/late".to_string(),
+            1,
+            HashAlgo::default(),
+        );
+        let late_hash = late_block.hash;
+        assert!(chain.validate_and_extend(late_block, genesis));
+        let old_block = Block::new(
+            Some(genesis),
+            1,
+            vec![],
+            "1/old".to_string(),
+            1,
+            HashAlgo::default(),
+        );
+        let old_hash = old_block.hash;
+        assert!(chain.validate_and_extend(old_block, genesis));
+        assert_eq!(chain.block_by_epoch.len(), 4);
+        assert!(chain.block_by_epoch[1].contains(&old_hash));
+        assert!(chain.block_by_epoch[3].contains(&late_hash));
+    }
+
+    /// Votes are weighed by stake, and a quorum needs strictly more than two thirds of the total
+    /// stake. If a fail-stopped validator holds little stake, the others still notarize, while
+    /// they cannot without a validator holding half of the stake.
+    fn test_stake_weighted_quorum() {
+        TestNetwork::print_test_case_header("Stake-weighted quorum");
+        let validators = [0, 1, 2, 3];
+        let mut chain = Blockchain::new(0);
+        assert!(!chain.is_quorum(&[0, 1], &validators));
+        assert!(chain.is_quorum(&[0, 1, 2], &validators));
+        // Votes of non-validators do not count
+        assert!(!chain.is_quorum(&[0, 1, 4, 5], &validators));
+
+        chain.stake = vec![1, 1, 1, 97];
+        assert!(chain.is_quorum(&[3], &validators));
+        assert!(!chain.is_quorum(&[0, 1, 2], &validators));
+        // Exactly two thirds of the stake is not enough
+        chain.stake = vec![2, 2, 1, 1];
+        assert!(!chain.is_quorum(&[0, 1], &validators));
+        assert!(chain.is_quorum(&[0, 1, 2], &validators));
+
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("fail_stop".to_string());
+        let n = 4;
+        let epochs = 10;
+        let mut network = Network::new_byzantine(n, 1, attacker_config.clone());
+        network.set_stake(vec![3, 3, 3, 1]);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+
+        let mut network = Network::new_byzantine(n, 1, attacker_config);
+        network.set_stake(vec![1, 1, 1, 3]);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        assert!(TestNetwork::consistency(&network));
+        for node in network.nodes.iter().filter(|node| !node.is_attacker()) {
+            let node = node.as_any().downcast_ref::<Node>().unwrap();
+            assert_eq!(node.chain.notarized.len(), 1);
+        }
+    }
+
+    /// With equal stakes, a block needs strictly more than 2n/3 votes to be notarized: 3 of 3,
+    /// 3 of 4, 5 of 7 and 7 of 10.
+    fn test_quorum_size() {
+        TestNetwork::print_test_case_header("Quorum size");
+        let chain = Blockchain::new(0);
+        for (n, quorum) in [(3, 3), (4, 3), (7, 5), (10, 7)] {
+            let validators: Vec<usize> = (0..n).collect();
+            assert!(!chain.is_quorum(&validators[..quorum - 1], &validators));
+            assert!(chain.is_quorum(&validators[..quorum], &validators));
+        }
+    }
+
+    /// The adversarial scheduler delivers blocks to half of the honest nodes a round late and
+    /// votes in reverse order, to make honest nodes diverge. Consistency must still hold, also
+    /// with equivocating attackers.
+    fn test_adversarial_schedule() {
+        TestNetwork::print_test_case_header("Adversarial message schedule");
+        let n = 7;
+        let epochs = 20;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_adversarial_schedule(epochs);
+        TestNetwork::validate(&network);
+
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("always_leader".to_string());
+        attacker_config.insert("equivocate".to_string());
+        attacker_config.insert("vote_all_forks".to_string());
+        let mut network = Network::new_byzantine(n, 2, attacker_config);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_adversarial_schedule(epochs);
+        TestNetwork::validate(&network);
+    }
+
+    /// The builder configures the number of nodes, the attackers, the seed and the network model,
+    /// and returns a network that reaches consensus.
+    fn test_consensus_builder() {
+        TestNetwork::print_test_case_header("Consensus builder");
+        let n = 7;
+        let epochs = 20;
+        let mut network = ConsensusBuilder::new(n)
+            .attackers(2, &["always_leader", "equivocate"])
+            .seed([7; 32])
+            .network_model(NetworkModel::DelaysThenSynchrony(0.5))
+            .rounds_per_epoch(4)
+            .build();
+        let attackers: Vec<usize> = (0..n).filter(|i| network.nodes[*i].is_attacker()).collect();
+        assert_eq!(attackers, vec![5, 6]);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run(epochs);
+        assert_eq!(network.epoch(), epochs);
+        TestNetwork::validate(&network);
+    }
+
+    /// The safety invariant holds after an honest run. Two finalized blocks at the same height
+    /// are reported as a violation, also by a node the moment it finalizes.
+    fn test_safety_invariant() {
+        TestNetwork::print_test_case_header("Safety invariant");
+        let n = 4;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(10);
+        TestNetwork::validate(&network);
+        for node in &network.nodes {
+            let node = node.as_any().downcast_ref::<Node>().unwrap();
+            assert_eq!(node.chain.check_safety_invariant(), Ok(()));
+        }
+
+        // A finalized block of height 1 exists. Finalizing a conflicting block of height 1
+        // violates safety.
+        let mut node = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        let genesis = node.chain.genesis;
+        let block_a = TestNetwork::extend_chain(&mut node.chain, genesis, 1, "a");
+        let block_b = TestNetwork::extend_chain(&mut node.chain, block_a, 2, "b");
+        let block_c = TestNetwork::extend_finalized(&mut node.chain, genesis, 3, "c");
+        node.chain.notarized.insert(block_a);
+        assert_eq!(node.chain.check_safety_invariant(), Ok(()));
+        node.chain.votes.insert(block_b, (0..n).collect());
+        node.notarize(block_b);
+        assert!(node.chain.finalized.contains(&block_a));
+        let violation = node.chain.check_safety_invariant().unwrap_err();
+        assert_eq!(violation.height, 1);
+        let mut conflicting = [block_a, block_c];
+        conflicting.sort();
+        assert_eq!([violation.block1, violation.block2], conflicting);
+        assert!(node.events.iter().any(|record| record.event
+            == Event::Error {
+                reason: violation.to_string()
+            }));
+    }
+
+    /// The block hash covers the Merkle root over the transactions. The inclusion of the first,
+    /// a middle and the last transaction can be proven, while proofs for other transactions fail.
+    fn test_merkle_tx_root() {
+        TestNetwork::print_test_case_header("Merkle root over transactions");
+        let txs: Vec<Transaction> = (0..5)
+            .map(|i| Transaction::new(0, i, format!("tx {}", i)))
+            .collect();
+        let block = Block::new(
+            None,
+            1,
+            txs.clone(),
+            "1/0".to_string(),
+            1,
+            HashAlgo::default(),
+        );
+        assert_eq!(
+            block.tx_root,
+            Block::compute_tx_root(HashAlgo::Sha256, &txs)
+        );
+        for i in [0, 2, 4] {
+            let proof = block.tx_inclusion_proof(i).unwrap();
+            let leaf = txs[i].serialize();
+            assert!(Crypto::verify_merkle_proof(
+                HashAlgo::Sha256,
+                &block.tx_root,
+                &leaf,
+                &proof
+            ));
+            let other = txs[(i + 1) % txs.len()].serialize();
+            assert!(!Crypto::verify_merkle_proof(
+                HashAlgo::Sha256,
+                &block.tx_root,
+                &other,
+                &proof
+            ));
+        }
+        assert!(block.tx_inclusion_proof(5).is_none());
+
+        // Changing a transaction changes the root, and thus the block hash
+        let mut changed_txs = txs.clone();
+        changed_txs[2] = Transaction::new(0, 2, "changed".to_string());
+        let changed = Block::new(
+            None,
+            1,
+            changed_txs,
+            "1/0".to_string(),
+            1,
+            HashAlgo::default(),
+        );
+        assert_ne!(changed.tx_root, block.tx_root);
+        assert_ne!(changed.hash, block.hash);
+
+        // A block without transactions has the root of no leaves, and no inclusion proofs
+        let empty = Block::new(
+            None,
+            1,
+            Vec::new(),
+            "1/0".to_string(),
+            1,
+            HashAlgo::default(),
+        );
+        assert_eq!(empty.tx_root, Crypto::merkle_root(HashAlgo::Sha256, &[]));
+        assert!(empty.tx_inclusion_proof(0).is_none());
+        assert_ne!(empty.tx_root, block.tx_root);
+    }
+
+    /// A single node notarizes its own proposals and finalizes. Two nodes both need to vote, so
+    /// they finalize together, but not if one of them fail-stops. Networks without nodes or with
+    /// more attackers than nodes are rejected.
+    fn test_tiny_networks() {
+        TestNetwork::print_test_case_header("Networks of one and two nodes");
+        let epochs = 10;
+        for n in [1, 2] {
+            let mut network = Network::new(n);
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_simple(epochs);
+            TestNetwork::validate(&network);
+            for node in &network.nodes {
+                let node = node.as_any().downcast_ref::<Node>().unwrap();
+                let height = node.chain.blocks[node.chain.highest_finalized_block()].height;
+                assert_eq!(height, epochs - 1);
+            }
+        }
+
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("fail_stop".to_string());
+        let mut network = Network::new_byzantine(2, 1, attacker_config.clone());
+        network.run_simple(epochs);
+        assert!(TestNetwork::consistency(&network));
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        assert_eq!(node.chain.notarized.len(), 1);
+
+        let panics = |f: fn()| std::panic::catch_unwind(f).is_err();
+        assert!(panics(|| {
+            Network::new(0);
+        }));
+        assert!(panics(|| {
+            Network::new_byzantine(2, 3, HashSet::new());
+        }));
+    }
+
+    /// In a perfect network, each block is finalized one epoch after its proposal, when its child
+    /// gets notarized. Delayed messages increase the average finalization delay.
+    fn test_finalization_delay() {
+        TestNetwork::print_test_case_header("Finalization delay");
+        let n = 4;
+        let epochs = 20;
+        let average_delay = |network: &Network| {
+            let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+            assert_eq!(
+                node.chain.finalized_at.len() + 1,
+                node.chain.finalized.len()
+            );
+            node.chain.average_finalization_delay()
+        };
+        assert_eq!(Blockchain::new(0).average_finalization_delay(), 0.0);
+
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+        assert_eq!(average_delay(&network), 1.0);
+
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_delays_then_synchrony(epochs, 0.3);
+        TestNetwork::validate(&network);
+        assert!(average_delay(&network) > 1.0);
+    }
+
+    /// An attacker that proposes on the grandparent of the highest notarized block tries to
+    /// orphan honest blocks. Honest nodes do not vote for its proposals, since they do not
+    /// advance the notarization height, so its blocks are never notarized and liveness holds.
+    fn test_old_parent_attacker() {
+        TestNetwork::print_test_case_header("Attacker proposes on an old parent");
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("old_parent".to_string());
+        let n = 4;
+        let epochs = 30;
+        let attacker = n - 1;
+        let mut network = Network::new_byzantine(n, 1, attacker_config);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        let attacker_blocks: Vec<&Hash> = (2..=epochs)
+            .filter(|e| node.leader(*e) == attacker)
+            .flat_map(|e| node.chain.block_by_epoch[e].iter())
+            .collect();
+        assert!(!attacker_blocks.is_empty());
+        for block_hash in attacker_blocks {
+            assert!(!node.chain.notarized.contains(block_hash));
+            assert!(node.chain.votes[block_hash]
+                .iter()
+                .all(|voter| *voter == attacker));
+        }
+    }
+
+    /// The block and transaction size limits are configurable per network. With a tiny block
+    /// size, the same transactions are split over more blocks, and longer transactions are
+    /// rejected.
+    fn test_chain_params() {
+        TestNetwork::print_test_case_header("Configurable chain parameters");
+        let n = 4;
+        let epochs = 20;
+        let tiny = ChainParams {
+            max_txs: 100,
+            max_single_tx: 10,
+            max_clock_skew: None,
+        };
+        let run = |params: ChainParams| {
+            let mut network = Network::new(n);
+            network.set_chain_params(params);
+            for (i, node) in network.nodes.iter_mut().enumerate() {
+                for nonce in 0..10 {
+                    node.send_transaction(Transaction::new(i, nonce, format!("tx {}", nonce)));
+                }
+            }
+            network.run_simple(epochs);
+            TestNetwork::validate(&network);
+            let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+            let blocks: Vec<&Block> = node
+                .chain
+                .finalized
+                .iter()
+                .map(|block_hash| &node.chain.blocks[block_hash])
+                .filter(|block| !block.txs.is_empty())
+                .collect();
+            for block in &blocks {
+                assert!(Transaction::serialize_all(&block.txs).len() < params.max_txs);
+            }
+            blocks.len()
+        };
+        let default_blocks = run(ChainParams::default());
+        let tiny_blocks = run(tiny);
+        assert!(tiny_blocks > default_blocks);
+
+        let mut node = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        let tx = Transaction::new(0, 0, "x".repeat(11));
+        assert!(node.is_transaction_valid(&tx));
+        node.set_chain_params(tiny);
+        assert!(!node.is_transaction_valid(&tx));
+    }
+
+    /// Each node relays a message with the same content and signer at most once, even if it
+    /// processes the message again after a reset of its chain. Relay deduplication does not
+    /// change which blocks finalize. Without it, each copy a node receives would be relayed to the
+    /// n - 2 peers other than itself and the signer, so with n = 13, deduplication cuts the
+    /// messages sent by far more than half. Relayed messages are forgotten MAX_PAST_EPOCHS epochs
+    /// later.
+    fn test_relay_once() {
+        TestNetwork::print_test_case_header("Relay each message at most once");
+        let n = 13;
+        let epochs = 10;
+        let mut network = Network::new(n);
+        network.record_trace();
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+        // Each node sends each signed proposal and vote to each peer at most once, whether it
+        // created or relayed it
+        let mut sent = HashMap::new();
+        for (_, sender, receiver, m) in network.trace().unwrap() {
+            let content = if let Some(b) = m.as_any().downcast_ref::<BlockMessage>() {
+                (m.kind(), b.signer, b.block_hash(HashAlgo::Sha256))
+            } else if let Some(v) = m.as_any().downcast_ref::<VoteMessage>() {
+                (m.kind(), v.signer, v.block_hash(HashAlgo::Sha256))
+            } else {
+                continue;
+            };
+            *sent.entry((*sender, *receiver, content)).or_insert(0) += 1;
+        }
+        assert!(!sent.is_empty());
+        assert!(sent.values().all(|count| *count == 1));
+        // Relaying is what makes receivers get the same message from several peers
+        let mut received: HashMap<_, usize> = HashMap::new();
+        for (_, receiver, content) in sent.keys() {
+            *received.entry((receiver, content)).or_default() += 1;
+        }
+        assert!(received.values().any(|count| *count > 1));
+        let sent = network.trace().unwrap().len();
+        let avoided: usize = network
+            .nodes
+            .iter()
+            .map(|node| node.stats().relays_avoided)
+            .sum();
+        let baseline = sent + avoided * (n - 2);
+        TestNetwork::dbg(
+            &format!(
+                "Messages sent with n = {}: {}, {} without relay deduplication",
+                n, sent, baseline
+            ),
+            None,
+            None,
+        );
+        assert!(2 * sent < baseline);
+
+        let mut node = Node::new(0, (0..4).collect(), DEFAULT_FINALIZATION_DEPTH);
+        let block = Block::new(
+            Some(node.chain.genesis),
+            1,
+            vec![Transaction::new(0, 0, "relayed".to_string())],
+            "1/relayed".to_string(),
+            1,
+            HashAlgo::default(),
+        );
+        let vote = TestNetwork::signed_vote(&block, 2);
+        node.incoming_message(&vote, 2);
+        // The vote is relayed to all peers except its signer
+        assert_eq!(node.clear_outgoing_messages().len(), 2);
+        node.reset_chain();
+        node.incoming_message(&vote, 2);
+        assert!(node.clear_outgoing_messages().is_empty());
+        assert_eq!(node.stats.relays_avoided, 1);
+        node.enter_epoch(1 + MAX_PAST_EPOCHS, 0);
+        node.reset_chain();
+        node.incoming_message(&vote, 2);
+        assert!(node.clear_outgoing_messages().is_empty());
+        assert_eq!(node.stats.relays_avoided, 2);
+        node.enter_epoch(2 + MAX_PAST_EPOCHS, 0);
+        node.reset_chain();
+        node.incoming_message(&vote, 2);
+        assert_eq!(node.clear_outgoing_messages().len(), 2);
+    }
+
+    /// Nodes are connected in a ring or a random 3-regular graph, so messages only reach most
+    /// nodes by being relayed. Finalization still completes, but epochs need enough rounds for a
+    /// proposal to reach all nodes and for the votes to travel back.
+    fn test_ring_topology() {
+        TestNetwork::print_test_case_header("Ring and random regular topologies");
+        let n = 8;
+        let epochs = 30;
+        for topology in [Topology::Ring, Topology::RandomRegular(3)] {
+            let mut network = Network::new(n);
+            network.set_topology(topology);
+            network.set_rounds_per_epoch(n);
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_simple(epochs);
+            TestNetwork::validate(&network);
+        }
+    }
+
+    /// A node receives a quorum of votes for a block before the block itself. Once the block
+    /// arrives, the node notarizes it, even if it does not vote for it itself.
+    fn test_votes_before_block() {
+        TestNetwork::print_test_case_header("Votes arriving before their block");
+        let n = 4;
+        let mut node = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        // We already voted in epoch 1, so receiving the block does not trigger our own vote
+        node.voted_epochs.insert(1);
+        let block = Block::new(
+            Some(node.chain.genesis),
+            1,
+            vec![Transaction::new(0, 0, "early votes".to_string())],
+            "1/early".to_string(),
+            1,
+            HashAlgo::default(),
+        );
+        for voter in 1..n {
+            node.incoming_message(&TestNetwork::signed_vote(&block, voter), voter);
+        }
+        assert!(!node.chain.notarized.contains(&block.hash));
+
+        let leader = node.leader(1);
+        node.incoming_message(&TestNetwork::signed_proposal(&block, leader), leader);
+        assert!(node.chain.notarized.contains(&block.hash));
+    }
+
+    /// Transactions of a workload trickle in per epoch. They are reproducible given the seed,
+    /// and only transactions that already arrived can be included in blocks.
+    fn test_workload() {
+        TestNetwork::print_test_case_header("Reproducible workloads");
+        let n = 4;
+        let workload = Workload::uniform(40)
+            .payload_size(PayloadSize::Uniform(10, 50))
+            .arrival(Arrival::Steady(4))
+            .seed([7; 32]);
+        let transactions = workload.transactions(n);
+        assert_eq!(transactions, workload.clone().transactions(n));
+        assert!(transactions
+            .iter()
+            .all(|tx| (10..=50).contains(&tx.payload.len())));
+        assert_eq!(workload.arriving(0), 0..0);
+        assert_eq!(workload.arriving(3), 8..12);
+        assert_eq!(workload.arriving(11), 40..40);
+
+        let included = |network: &Network| {
+            let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+            node.chain
+                .finalized
+                .iter()
+                .flat_map(|block_hash| node.chain.blocks[block_hash].txs.iter())
+                .collect::<HashSet<_>>()
+                .len()
+        };
+        let mut network = Network::new(n);
+        network.set_workload(workload);
+        network.run_simple(5);
+        assert!(included(&network) > 0);
+        assert!(included(&network) <= 20);
+        network.run_simple(25);
+        TestNetwork::validate(&network);
+        assert_eq!(included(&network), 40);
+    }
+
+    /// A workload keeps its senders and nonces when validators join or leave while it is
+    /// submitted, and only the current validators that are up receive its transactions
+    fn test_workload_with_churn() {
+        TestNetwork::print_test_case_header("Workload with churn");
+        let n = 4;
+        let workload = Workload::uniform(40)
+            .arrival(Arrival::Steady(4))
+            .seed([8; 32]);
+        let mut network = Network::new(n);
+        network.set_workload(workload);
+        network.run_with_churn(30, &[(1, Churn::Remove(1)), (3, Churn::Add)]);
+        assert!(TestNetwork::consistency(&network));
+        assert_eq!(network.workload_transactions().len(), 40);
+
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        let included: HashSet<_> = node
+            .chain
+            .finalized
+            .iter()
+            .flat_map(|block_hash| node.chain.blocks[block_hash].txs.iter())
+            .collect();
+        assert_eq!(included.len(), 40);
+        assert!(network
+            .workload_transactions()
+            .iter()
+            .all(|tx| included.contains(tx)));
+    }
+
+    /// Attackers that equivocate as leaders also equivocate as voters: holding two competing
+    /// blocks of an epoch, they send a vote for each of them to a different half of the nodes.
+    /// Below the n/3 threshold, consistency still holds.
+    fn test_vote_equivocate() {
+        TestNetwork::print_test_case_header("Attackers equivocate on votes");
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("equivocate".to_string());
+        attacker_config.insert("vote_equivocate".to_string());
+        let epochs = 30;
+        for (n, num_attackers) in [(4, 1), (7, 2)] {
+            let mut network = Network::new_byzantine(n, num_attackers, attacker_config.clone());
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_reorder(epochs);
+            TestNetwork::validate(&network);
+
+            // Some attacker signed votes for two different blocks of the same epoch
+            let mut votes: HashSet<(usize, usize, Hash)> = HashSet::new();
+            for (_, _, _, m) in network.captured_attacker_messages() {
+                if let Some(vote) = m.as_any().downcast_ref::<VoteMessage>() {
+                    let block = Block::new(
+                        vote.parent_hash,
+                        vote.e,
+                        vote.txs.clone(),
+                        vote.name.clone(),
+                        0,
+                        HashAlgo::default(),
+                    );
+                    votes.insert((vote.signer, vote.e, block.hash));
+                }
+            }
+            let equivocations = votes
+                .iter()
+                .filter(|(signer, e, block_hash)| {
+                    votes
+                        .iter()
+                        .any(|(s, f, h)| s == signer && f == e && h != block_hash)
+                })
+                .count();
+            assert!(equivocations > 0);
+        }
+    }
+
+    /// The diff of two chains' snapshots reports the blocks only one of them finalized or
+    /// notarized. Nodes of a network that finalized the same blocks have an empty diff.
+    fn test_chain_diff() {
+        TestNetwork::print_test_case_header("Chain snapshots and diffs");
+        let mut chain1 = Blockchain::new(0);
+        let mut chain2 = Blockchain::new(1);
+        let genesis = chain1.genesis;
+        let block_a = TestNetwork::extend_finalized(&mut chain1, genesis, 1, "a");
+        TestNetwork::extend_finalized(&mut chain2, genesis, 1, "a");
+        let block_b = TestNetwork::extend_finalized(&mut chain1, block_a, 2, "b");
+        let block_c = TestNetwork::extend_chain(&mut chain2, block_a, 3, "c");
+        chain2.notarized.insert(block_c);
+
+        let diff = chain1.snapshot().diff(&chain2.snapshot());
+        assert!(!diff.is_empty());
+        assert_eq!((diff.id, diff.other_id), (0, 1));
+        assert_eq!(diff.only_finalized, vec![(2, block_b)]);
+        assert!(diff.only_other_finalized.is_empty());
+        assert_eq!(diff.only_notarized, vec![(2, block_b)]);
+        assert_eq!(diff.only_other_notarized, vec![(2, block_c)]);
+        TestNetwork::dbg(&diff.to_string(), None, None);
+        assert!(chain1.snapshot().diff(&chain1.snapshot()).is_empty());
+
+        let n = 4;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(10);
+        let node0 = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        let node1 = network.nodes[1].as_any().downcast_ref::<Node>().unwrap();
+        let diff = node0.chain.snapshot().diff(&node1.chain.snapshot());
+        assert!(diff.only_finalized.is_empty() && diff.only_other_finalized.is_empty());
+    }
+
+    /// The network accounts for the bytes it sends, per node and per message type. Messages
+    /// carrying transactions cost more bytes than messages of empty blocks.
+    fn test_bytes_sent() {
+        TestNetwork::print_test_case_header("Bytes sent");
+        let n = 4;
+        let epochs = 10;
+        let block = Block::new(
+            None,
+            1,
+            vec![Transaction::new(0, 0, "payload".to_string())],
+            "1/0".to_string(),
+            1,
+            HashAlgo::default(),
+        );
+        let vote = block.to_vote_message(0, Crypto::sign(0, &[]));
+        assert_eq!(vote.size_bytes(), bincode::serialize(&vote).unwrap().len());
+
+        let bytes_sent = |with_transactions: bool| {
+            let mut network = Network::new(n);
+            if with_transactions {
+                TestNetwork::generate_transactions(&mut network.nodes, n);
+            }
+            network.run_simple(epochs);
+            TestNetwork::validate(&network);
+            let by_kind = network.bytes_sent_by_kind();
+            TestNetwork::dbg(&format!("Bytes sent: {:?}", by_kind), None, None);
+            assert!(by_kind["block"] > 0 && by_kind["vote"] > 0);
+            assert_eq!(by_kind.values().sum::<usize>(), network.total_bytes_sent());
+            assert_eq!(
+                network.bytes_sent().iter().sum::<usize>(),
+                network.total_bytes_sent()
+            );
+            network.total_bytes_sent()
+        };
+        assert!(bytes_sent(true) > bytes_sent(false));
+    }
+
+    /// Finalizing a block whose ancestors are not all stored finalizes nothing and reports an
+    /// error, instead of panicking halfway through.
+    fn test_finalize_malformed_chain() {
+        TestNetwork::print_test_case_header("Finalization on a malformed chain");
+        let mut node = Node::new(0, (0..4).collect(), DEFAULT_FINALIZATION_DEPTH);
+        let genesis = node.chain.genesis;
+        let block_a = TestNetwork::extend_chain(&mut node.chain, genesis, 1, "a");
+        let block_b = TestNetwork::extend_chain(&mut node.chain, block_a, 2, "b");
+        let block_c = TestNetwork::extend_chain(&mut node.chain, block_b, 3, "c");
+        node.chain.notarized.extend([block_b, block_c]);
+        node.chain.blocks.remove(&block_a);
+        node.finalize(block_c, 3);
+        assert!(!node.chain.finalized.contains(&block_c));
+        assert!(!node.chain.finalized.contains(&block_b));
+        assert!(node
+            .events
+            .iter()
+            .any(|record| matches!(record.event, Event::Error { .. })));
+    }
+
+    /// Stepping through an execution one round at a time reports the epoch, the round, and the
+    /// finalizations as they happen, and ends up in the same state as running whole epochs.
+    fn test_step() {
+        TestNetwork::print_test_case_header("Step-by-step execution");
+        let n = 4;
+        let epochs = 5;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        let first = network.step();
+        assert_eq!((first.epoch, first.round, first.epoch_ended), (1, 0, false));
+        assert!(first
+            .events
+            .iter()
+            .any(|record| matches!(record.event, Event::BlockProposed { .. })));
+        let mut finalized = first.finalized.len();
+        for step in 1..epochs * DEFAULT_ROUNDS_PER_EPOCH {
+            let result = network.step();
+            assert_eq!(result.epoch, step / DEFAULT_ROUNDS_PER_EPOCH + 1);
+            assert_eq!(result.round, step % DEFAULT_ROUNDS_PER_EPOCH);
+            assert_eq!(
+                result.epoch_ended,
+                result.round + 1 == DEFAULT_ROUNDS_PER_EPOCH
+            );
+            finalized += result.finalized.len();
+        }
+        assert!(finalized > 0);
+        assert_eq!(finalized, network.metrics().total().blocks_finalized);
+        TestNetwork::validate(&network);
+
+        // run_simple drains the network at the end
+        network.drain();
+        let mut reference = Network::new(n);
+        TestNetwork::generate_transactions(&mut reference.nodes, n);
+        reference.run_simple(epochs);
+        for (node, reference_node) in network.nodes.iter().zip(&reference.nodes) {
+            let chain = &node.as_any().downcast_ref::<Node>().unwrap().chain;
+            let reference_chain = &reference_node
+                .as_any()
+                .downcast_ref::<Node>()
+                .unwrap()
+                .chain;
+            assert!(chain
+                .snapshot()
+                .diff(&reference_chain.snapshot())
+                .is_empty());
+        }
+    }
+
+    /// The fork tree of a chain is exported as a graph with one node per block and one edge per
+    /// parent link, and as JSON with one entry per block and per link, which escapes quotes,
+    /// backslashes and control characters in block names
+    fn test_chain_json() {
+        TestNetwork::print_test_case_header("JSON export of the chain");
+        let mut chain = Blockchain::new(0);
+        let genesis = chain.genesis;
+        let block_a = TestNetwork::extend_finalized(&mut chain, genesis, 1, "a");
+        let block_b = TestNetwork::extend_chain(&mut chain, block_a, 2, "b \"quoted\"");
+        TestNetwork::extend_chain(&mut chain, block_a, 3, "c \\ \"\\\"\n\t");
+        chain.votes.insert(block_b, [0, 1, 2].into_iter().collect());
+
+        let dag = chain.dag();
+        assert_eq!(dag.nodes.len(), 4);
+        assert_eq!(dag.edges.len(), 3);
+        let node_a = dag
+            .nodes
+            .iter()
+            .find(|node| node.hash == hex::encode(block_a))
+            .unwrap();
+        assert_eq!((node_a.epoch, node_a.height), (1, 1));
+        assert!(node_a.notarized && node_a.finalized);
+        let node_b = dag
+            .nodes
+            .iter()
+            .find(|node| node.hash == hex::encode(block_b))
+            .unwrap();
+        assert_eq!(node_b.votes, 3);
+        assert!(!node_b.notarized && !node_b.finalized);
+        assert!(dag
+            .edges
+            .iter()
+            .any(|edge| edge.parent == hex::encode(block_a) && edge.child == hex::encode(block_b)));
+        let decoded: ChainDag = bincode::deserialize(&bincode::serialize(&dag).unwrap()).unwrap();
+        assert_eq!(decoded, dag);
+
+        let json = chain.to_json();
+        TestNetwork::dbg(&json, None, None);
+        assert!(json.starts_with("{\"nodes\":[{\"hash\":"));
+        assert!(json.contains("\"name\":\"2/b \\\"quoted\\\"\""));
+        assert!(json.contains(&format!(
+            "{{\"parent\":\"{}\",\"child\":\"{}\"}}",
+            hex::encode(block_a),
+            hex::encode(block_b)
+        )));
+
+        // Each block and parent link has exactly one entry, with escaped quotes, backslashes and
+        // control characters in names
+        assert!(json.ends_with("}]}"));
+        assert_eq!(json.matches("{\"hash\":").count(), dag.nodes.len());
+        assert_eq!(json.matches("{\"parent\":").count(), dag.edges.len());
+        let block_c = dag
+            .nodes
+            .iter()
+            .find(|node| node.name.starts_with("3/"))
+            .unwrap();
+        assert!(json.contains(&format!(
+            "{{\"hash\":\"{}\",\"name\":\"3/c \\\\ \\\"\\\\\\\"\\u000a\\u0009\",\"epoch\":3,\"height\":2,\"votes\":0,\"notarized\":false,\"finalized\":false}}",
+            block_c.hash
+        )));
+        assert!(json.contains(&format!(
+            "{{\"hash\":\"{}\",\"name\":\"1/a\",\"epoch\":1,\"height\":1,\"votes\":0,\"notarized\":true,\"finalized\":true}}",
+            hex::encode(block_a)
+        )));
+    }
+
+    /// The validator set grows from 4 to 7 validators through a reconfiguration that is finalized
+    /// into the chain. From the given epoch on, leaders and quorums are based on the new set, and
+    /// finalization continues consistently across the change. The new validators only learn
+    /// about it once it is finalized. A reconfiguration included in a block of the epoch from which
+    /// on it should apply never takes effect, and neither does one submitted by a validator
+    /// instead of RECONFIG_SENDER.
+    fn test_reconfiguration() {
+        TestNetwork::print_test_case_header("Reconfiguration through the chain");
+        let n = 4;
+        let from_epoch = 8;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(3);
+        network.reconfigure((0..7).collect(), from_epoch);
+        let joined = network.nodes[n].as_any().downcast_ref::<Node>().unwrap();
+        assert_eq!(joined.validators_at(from_epoch), &[0, 1, 2, 3]);
+        network.run_simple(27);
+        TestNetwork::validate(&network);
+
+        let new_validators: Vec<usize> = (0..7).collect();
+        assert_eq!(network.validators(), new_validators);
+        for node in &network.nodes {
+            let node = node.as_any().downcast_ref::<Node>().unwrap();
+            assert_eq!(node.validators_at(from_epoch - 1), &[0, 1, 2, 3]);
+            assert_eq!(node.validators_at(from_epoch), new_validators.as_slice());
+        }
+        // The reconfiguration was finalized, and new validators proposed finalized blocks
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        assert!(node
+            .chain
+            .finalized_transactions()
+            .iter()
+            .any(|tx| ReconfigMessage::from_transaction(tx).is_some()));
+        assert!(node
+            .chain
+            .finalized
+            .iter()
+            .any(|block_hash| node.leader(node.chain.blocks[block_hash].e) >= n));
+
+        // Proposals of epoch 4 are the first to include the reconfiguration
+        let mut network = Network::new(n);
+        network.run_simple(3);
+        network.reconfigure((0..7).collect(), 4);
+        network.run_simple(10);
+        // The nodes created for the new validators never join, so they do not finalize anything
+        assert!(TestNetwork::consistency(&network));
+        assert!(TestNetwork::safety_invariant(&network));
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        assert!(node
+            .chain
+            .finalized_transactions()
+            .iter()
+            .any(|tx| ReconfigMessage::from_transaction(tx).is_some()));
+        for node in &network.nodes {
+            let node = node.as_any().downcast_ref::<Node>().unwrap();
+            assert_eq!(node.validators_at(network.epoch()), &[0, 1, 2, 3]);
+        }
+        assert_eq!(network.validators(), vec![0, 1, 2, 3]);
+
+        let mut network = Network::new(n);
+        let tx = ReconfigMessage::new(vec![0, 1, 2], 8).to_transaction(0);
+        assert!(ReconfigMessage::from_transaction(&tx).is_none());
+        for node in network.nodes.iter_mut() {
+            node.send_transaction(tx.clone());
+        }
+        network.run_simple(15);
+        TestNetwork::validate(&network);
+        for node in &network.nodes {
+            let node = node.as_any().downcast_ref::<Node>().unwrap();
+            assert!(node.chain.finalized_transactions().contains(&tx));
+            assert_eq!(node.validators_at(network.epoch()), &[0, 1, 2, 3]);
+        }
+    }
+
+    /// A malicious user submits far more transactions than the bounded transaction pools hold.
+    /// The pools stay capped, and the honest transactions submitted before still finalize.
+    fn test_tx_pool_spam() {
+        TestNetwork::print_test_case_header("Transaction pool spam");
+        let n = 4;
+        let max_tx_pool = 300;
+        let spammer = 99;
+        for overflow in [TxPoolOverflow::RejectNew, TxPoolOverflow::DropOldest] {
+            let mut network = Network::new(n);
+            network.set_max_tx_pool(max_tx_pool, overflow);
+            let honest: Vec<Transaction> = Workload::default().transactions(n);
+            for (i, tx) in honest.iter().enumerate() {
+                network.nodes[i % n].send_transaction(tx.clone());
+            }
+            let spam_per_node = 10000;
+            for node in network.nodes.iter_mut() {
+                for nonce in 0..spam_per_node {
+                    node.send_transaction(Transaction::new(spammer, nonce, "spam".to_string()));
+                }
+            }
+            for node in &network.nodes {
+                let node = node.as_any().downcast_ref::<Node>().unwrap();
+                assert_eq!(node.tx_pool_len(), max_tx_pool);
+                // Only the transactions still pooled are tracked for their confirmation time
+                let tracked: usize = node.confirmation_time_histogram().values().sum();
+                assert_eq!(tracked, max_tx_pool);
+            }
+            network.run_simple(20);
+            TestNetwork::validate(&network);
+
+            let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+            let finalized: HashSet<Transaction> =
+                node.chain.finalized_transactions().into_iter().collect();
+            let total_dropped: usize = network
+                .nodes
+                .iter()
+                .map(|node| node.stats().txs_dropped)
+                .sum();
+            assert_eq!(
+                total_dropped,
+                honest.len() + n * spam_per_node as usize - n * max_tx_pool
+            );
+            match overflow {
+                // The honest transactions arrived first and occupy the pools
+                TxPoolOverflow::RejectNew => {
+                    assert!(honest.iter().all(|tx| finalized.contains(tx)));
+                }
+                // The spam pushed the honest transactions out of the pools
+                TxPoolOverflow::DropOldest => {
+                    assert!(honest.iter().all(|tx| !finalized.contains(tx)));
+                    assert!(!finalized.is_empty());
+                }
+            }
+        }
+    }
+
+    /// Nodes attach their highest notarized height to the blocks and votes they send. A peer
+    /// that crashed stops reporting and falls behind, until it recovers and catches up. Heights
+    /// are attributed to the node that sent a message, not to a node that relays it.
+    fn test_peer_status() {
+        TestNetwork::print_test_case_header("Peer status");
+        let n = 4;
+        let mut network = Network::new(n);
+        for node in network.nodes.iter_mut() {
+            node.as_any_mut()
+                .downcast_mut::<Node>()
+                .unwrap()
+                .report_status = true;
+        }
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        let schedule = vec![(3, 3, true), (11, 3, false)];
+        network.run_with_crashes(10, schedule.clone());
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        assert_eq!(node.peer_status.len(), n - 1);
+        assert_eq!(node.lagging_peers(), vec![3]);
+        TestNetwork::print_lagging_peers(&network);
+
+        network.run_with_crashes(10, schedule);
+        TestNetwork::validate(&network);
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        assert!(node.lagging_peers().is_empty());
+
+        // Only node 0 reports its status, so the others relay its messages without a height
+        let mut network = Network::new(n);
+        network.nodes[0]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap()
+            .report_status = true;
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(5);
+        for node in network.nodes.iter().skip(1) {
+            let node = node.as_any().downcast_ref::<Node>().unwrap();
+            assert_eq!(node.peer_status.keys().collect::<Vec<_>>(), vec![&0]);
+        }
+    }
+
+    /// After running with delayed messages, many messages are still in flight. Draining the
+    /// network delivers them, so that all nodes end up with the same notarized and finalized
+    /// blocks, without advancing the epoch. Draining advances rounds, so that a proposal delayed
+    /// beyond the rounds run so far is broadcast and notarized.
+    fn test_drain() {
+        TestNetwork::print_test_case_header("Draining the network");
+        let n = 4;
+        let epochs = 10;
+        let mut network = Network::new(n);
+        network.set_network_model(NetworkModel::DelaysThenSynchrony(0.3));
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        let mut in_flight = 0;
+        for _ in 0..epochs * DEFAULT_ROUNDS_PER_EPOCH {
+            in_flight = network.step().messages_in_flight;
+        }
+        assert!(in_flight > 0);
+        let finalized = |network: &Network| -> Vec<usize> {
+            network
+                .nodes
+                .iter()
+                .map(|node| {
+                    node.as_any()
+                        .downcast_ref::<Node>()
+                        .unwrap()
+                        .chain
+                        .finalized
+                        .len()
+                })
+                .collect()
+        };
+        let finalized_before = finalized(&network);
+
+        network.drain();
+        assert_eq!(network.epoch(), epochs);
+        let finalized_after = finalized(&network);
+        assert!(finalized_before
+            .iter()
+            .zip(&finalized_after)
+            .all(|(before, after)| before <= after));
+        let snapshot = network.nodes[0]
+            .as_any()
+            .downcast_ref::<Node>()
+            .unwrap()
+            .chain
+            .snapshot();
+        for node in &network.nodes {
+            let node = node.as_any().downcast_ref::<Node>().unwrap();
+            assert!(node.chain.snapshot().diff(&snapshot).is_empty());
+        }
+
+        let mut network = Network::new(n);
+        for node in network.nodes.iter_mut() {
+            let node = node.as_any_mut().downcast_mut::<Node>().unwrap();
+            node.proposal_delay = DEFAULT_ROUNDS_PER_EPOCH;
+        }
+        for _ in 0..DEFAULT_ROUNDS_PER_EPOCH - 1 {
+            network.step();
+        }
+        let notarized = |network: &Network| -> Vec<usize> {
+            network
+                .nodes
+                .iter()
+                .map(|node| {
+                    node.as_any()
+                        .downcast_ref::<Node>()
+                        .unwrap()
+                        .notarized_height()
+                })
+                .collect()
+        };
+        assert_eq!(notarized(&network), vec![0; n]);
+        network.drain();
+        assert_eq!(network.epoch(), 1);
+        assert_eq!(notarized(&network), vec![1; n]);
+    }
+
+    /// An attacker floods a node with blocks on parents that do not exist, carrying forged
+    /// signatures. The node drops them right away: it neither stores them in its
+    /// unprocessed_pool nor requests their parents. A validly signed orphan is still pooled.
+    fn test_forged_orphans() {
+        TestNetwork::print_test_case_header("Forged orphan blocks are not pooled");
+        let n = 4;
+        let attacker = n - 1;
+        let mut network = Network::new(n);
+        let node = network.nodes[0]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap();
+        let orphan = |i: usize, signature: Signature| {
+            BlockMessage::new(
+                attacker,
+                Some(Crypto::hash(HashAlgo::default(), &i.to_le_bytes())),
+                i + 1,
+                vec![],
+                format!("orphan #{}", i),
+                attacker,
+                signature,
+            )
+        };
+        for i in 0..1000 {
+            node.incoming_message(&orphan(i, (attacker as u64, vec![0; 32])), attacker);
+        }
+        assert_eq!(node.unprocessed_pool_len(), 0);
+        assert!(node.outgoing_messages.is_empty());
+
+        let valid = BlockMessage {
+            e: 1,
+            ..orphan(1000, (0, vec![]))
+        };
+        let block_hash = valid.block_hash(HashAlgo::Sha256);
+        let signature = TestNetwork::signature(attacker, MessageType::BlockProposal, block_hash);
+        node.incoming_message(&BlockMessage { signature, ..valid }, attacker);
+        assert_eq!(node.unprocessed_pool_len(), 1);
+    }
+
+    /// One honest node broadcasts its block proposals a round late, as if it ran on a slow
+    /// machine. The proposal still reaches all nodes within the epoch, so once the network is
+    /// synchronous, the blocks of the slow leader finalize like all others.
+    fn test_slow_leader() {
+        TestNetwork::print_test_case_header("Honest but slow leader");
+        let n = 4;
+        let slow = 1;
+        let epochs = 30;
+        let fraction = 0.5;
+        let mut network = Network::new(n);
+        let node = network.nodes[slow]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap();
+        node.proposal_delay = 1;
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_delays_then_synchrony(epochs, fraction);
+        TestNetwork::validate(&network);
+
+        // The slow node led some epochs of the synchronous half, and its blocks finalized
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        let slow_epochs: Vec<usize> = (epochs / 2 + 1..epochs)
+            .filter(|&e| node.leader(e) == slow)
+            .collect();
+        assert!(!slow_epochs.is_empty());
+        let finalized_epochs: HashSet<usize> = node
+            .chain
+            .finalized
+            .iter()
+            .map(|hash| node.chain.blocks[hash].e)
+            .collect();
+        assert!(slow_epochs.iter().any(|e| finalized_epochs.contains(e)));
+    }
+
+    /// A run with random delays and an equivocating attacker records all deliveries and the
+    /// epochs that timed out. The trace, saved to disk and loaded again, replays the run exactly
+    /// on a network with a different seed: all nodes end up with the same blocks, notarizations,
+    /// finalizations and views.
+    fn test_replay_trace() {
+        TestNetwork::print_test_case_header("Record and replay a trace");
+        let n = 4;
+        let num_attackers = 1;
+        let epochs = 20;
+        let fraction = 0.5;
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("always_leader".to_string());
+        attacker_config.insert("equivocate".to_string());
+        let network = |seed: [u8; 32]| {
+            let mut network =
+                Network::new_byzantine_with_seed(n, num_attackers, attacker_config.clone(), seed);
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network
+        };
+        let deliveries = |trace: &[TraceEntry]| -> Vec<(usize, usize, usize)> {
+            trace
+                .iter()
+                .map(|(round, i, j, _)| (*round, *i, *j))
+                .collect()
+        };
+        let path = std::env::temp_dir().join(format!("trace-{}.bin", std::process::id()));
+
+        for seed in 1..=4 {
+            let mut recorded = network([seed; 32]);
+            recorded.record_trace();
+            recorded.run_delays_then_synchrony(epochs, fraction);
+            TestNetwork::validate(&recorded);
+            recorded.save_trace(&path).unwrap();
+            let trace = Network::load_trace(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(deliveries(&trace), deliveries(recorded.trace().unwrap()));
+            // Only the epochs of the first half, which deliver messages late, time out
+            let timeouts = trace.iter().filter(|(_, _, _, m)| m.kind() == "timeout");
+            assert_eq!(timeouts.count(), n * (epochs / 2));
+
+            let mut replayed = network([seed + 100; 32]);
+            replayed.record_trace();
+            replayed.replay(&trace, epochs);
+            TestNetwork::validate(&replayed);
+            assert_eq!(deliveries(replayed.trace().unwrap()), deliveries(&trace));
+            for i in 0..n {
+                assert_eq!(recorded.nodes[i].stats(), replayed.nodes[i].stats());
+            }
+            for i in 0..n - num_attackers {
+                let node = |network: &Network| {
+                    let node = network.nodes[i].as_any().downcast_ref::<Node>().unwrap();
+                    (node.chain.snapshot(), node.view)
+                };
+                let (recorded_chain, recorded_view) = node(&recorded);
+                let (replayed_chain, replayed_view) = node(&replayed);
+                assert!(recorded_chain.diff(&replayed_chain).is_empty());
+                assert_eq!(recorded_view, replayed_view);
+            }
+
+            // A run with the other seed, but without the trace, differs
+            let mut other = network([seed + 100; 32]);
+            other.record_trace();
+            other.run_delays_then_synchrony(epochs, fraction);
+            assert_ne!(deliveries(other.trace().unwrap()), deliveries(&trace));
+        }
+
+        // Loading a file that does not contain a trace fails
+        std::fs::write(&path, b"not a trace").unwrap();
+        assert!(Network::load_trace(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// With the VRF leader election, the validator with the lowest VRF output over the epoch
+    /// leads it. When every validator proposes in every epoch, a node accepts exactly one block
+    /// per epoch, the one of the leader. A leader's proposal without a valid VRF proof is
+    /// rejected. The protocol reaches consensus with this leader election.
+    fn test_vrf_leader_election() {
+        TestNetwork::print_test_case_header("VRF leader election");
+        let n = 4;
+        let epochs = 20;
+        let election = VrfLeaderElection;
+        let input = Crypto::var_to_bytes(1);
+        let hash_algo = HashAlgo::default();
+        let (output, proof) = Crypto::vrf(hash_algo, 2, &input);
+        assert!(Crypto::verify_vrf(hash_algo, 2, &input, &output, &proof));
+        assert!(!Crypto::verify_vrf(hash_algo, 3, &input, &output, &proof));
+        assert!(!Crypto::verify_vrf(
+            hash_algo,
+            2,
+            &Crypto::var_to_bytes(2),
+            &output,
+            &proof
+        ));
+
+        let mut receiver = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        receiver.set_leader_election(Box::new(election.clone()));
+        let genesis = receiver.chain.genesis;
+        for e in 1..=epochs {
+            receiver.enter_epoch(e, receiver.chain.clock);
+            let leader = election.leader(e, n, hash_algo);
+            assert!(
+                (0..n).all(|i| VrfLeaderElection::evaluate(e, leader, hash_algo).0
+                    <= VrfLeaderElection::evaluate(e, i, hash_algo).0)
+            );
+            for i in 0..n {
+                let mut proposer = Node::new(i, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+                proposer.set_leader_election(Box::new(election.clone()));
+                proposer.propose_block_on(e, genesis).unwrap();
+                let (_, proposal) = proposer.clear_outgoing_messages().remove(0);
+                let proposal = proposal
+                    .as_any()
+                    .downcast_ref::<BlockMessage>()
+                    .unwrap()
+                    .clone();
+                assert!(proposal.leader_proof.is_some());
+                if i == leader {
+                    // Without its proof, even the leader's proposal is rejected
+                    let unproven = BlockMessage {
+                        leader_proof: None,
+                        ..proposal.clone()
+                    };
+                    receiver.incoming_message(&unproven, i);
+                    assert!(receiver
+                        .chain
+                        .block_by_epoch
+                        .get(e)
+                        .is_none_or(HashSet::is_empty));
+                }
+                receiver.incoming_message(&proposal, i);
+            }
+            let accepted = &receiver.chain.block_by_epoch[e];
+            assert_eq!(
+                accepted.len(),
+                1,
+                "accepted {} blocks in epoch {}",
+                accepted.len(),
+                e
+            );
+            let block = &receiver.chain.blocks[accepted.iter().next().unwrap()];
+            assert_eq!(block.name, format!("{}/{}", e, leader));
+        }
+
+        let mut network = Network::new(n);
+        network.set_leader_election(&election);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+    }
+
+    /// With timestamps, blocks carry the network's logical clock at their proposal, and honest
+    /// runs finalize as usual. A leader that forward-dates its block beyond the clock skew,
+    /// backdates it before its parent, or omits the timestamp has its block rejected.
+    fn test_block_timestamps() {
+        TestNetwork::print_test_case_header("Block timestamps");
+        let n = 4;
+        let epochs = 10;
+        let skew = 2;
+        let mut network = Network::new(n);
+        network.set_chain_params(ChainParams {
+            max_clock_skew: Some(skew),
+            ..ChainParams::default()
+        });
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        let finalized = node.chain.finalized_chain();
+        assert!(finalized.len() > 1);
+        for pair in finalized[1..].windows(2) {
+            let (parent, child) = (&node.chain.blocks[&pair[0]], &node.chain.blocks[&pair[1]]);
+            assert!(parent.timestamp.unwrap() < child.timestamp.unwrap());
+        }
+
+        let e = network.epoch() + 1;
+        let leader = node.leader(e);
+        let receiver = (leader + 1) % n;
+        let node = network.nodes[receiver]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap();
+        let clock = node.chain.clock;
+        let parent = node.chain.blocks[&node.chain.get_highest_notarized_block()].clone();
+        let propose = |timestamp: Option<u64>| {
+            let block = Block::new(
+                Some(parent.hash),
+                e,
+                vec![],
+                format!("{}/{} at {:?}", e, leader, timestamp),
+                parent.height + 1,
+                HashAlgo::default(),
+            )
+            .with_timestamp(timestamp)
+            .with_proposer(Some(leader));
+            (block.hash, TestNetwork::signed_proposal(&block, leader))
+        };
+        for timestamp in [
+            Some(clock + skew + 1),
+            Some(parent.timestamp.unwrap() - 1),
+            None,
+        ] {
+            let (hash, proposal) = propose(timestamp);
+            node.incoming_message(&proposal, leader);
+            assert!(
+                !node.chain.contains_block(hash),
+                "accepted timestamp {:?}",
+                timestamp
+            );
+        }
+        let (hash, proposal) = propose(Some(clock + skew));
+        node.incoming_message(&proposal, leader);
+        assert!(node.chain.contains_block(hash));
+    }
+
+    /// A transaction submitted to the leader of the first epoch is pending in its pool and in its
+    /// proposal, then in a notarized block, and finally finalized in that same block. Other nodes
+    /// do not know it until they receive the proposal, and nobody knows a transaction that was
+    /// never submitted.
+    fn test_transaction_status() {
+        TestNetwork::print_test_case_header("Transaction status");
+        let n = 4;
+        let epochs = 10;
+        let mut network = Network::new(n);
+        let leader = network.nodes[0]
+            .as_any()
+            .downcast_ref::<Node>()
+            .unwrap()
+            .leader(1);
+        let other = (leader + 1) % n;
+        let tx = Transaction::new(leader, 0, "Where is my transaction?".to_string());
+        let unknown = Transaction::new(leader, 1, "Never submitted".to_string());
+        let status = |network: &Network, i: usize, tx: &Transaction| {
+            let node = network.nodes[i].as_any().downcast_ref::<Node>().unwrap();
+            node.transaction_status(tx)
+        };
+        assert_eq!(status(&network, leader, &tx), TxStatus::Unknown);
+        network.nodes[leader].send_transaction(tx.clone());
+
+        let mut statuses = vec![status(&network, leader, &tx)];
+        assert_eq!(status(&network, other, &tx), TxStatus::Unknown);
+        for _ in 0..epochs * DEFAULT_ROUNDS_PER_EPOCH {
+            network.step();
+            let current = status(&network, leader, &tx);
+            if statuses.last() != Some(&current) {
+                statuses.push(current);
+            }
+        }
+        let TxStatus::Finalized(block_hash) = statuses[statuses.len() - 1] else {
+            panic!("transaction not finalized: {:?}", statuses);
+        };
+        assert_eq!(
+            statuses,
+            vec![
+                TxStatus::Pending,
+                TxStatus::InNotarizedBlock(block_hash),
+                TxStatus::Finalized(block_hash)
+            ]
+        );
+        let node = network.nodes[leader]
+            .as_any()
+            .downcast_ref::<Node>()
+            .unwrap();
+        assert_eq!(node.chain.blocks[&block_hash].e, 1);
+        for i in 0..n {
+            assert_eq!(status(&network, i, &tx), TxStatus::Finalized(block_hash));
+            assert_eq!(status(&network, i, &unknown), TxStatus::Unknown);
+        }
+    }
+
+    /// Equivocating attackers forge votes in the name of the honest validators for every block
+    /// they receive, trying to notarize both of their competing blocks. Honest nodes reject the
+    /// forged votes, since their signatures do not match the claimed signers: every vote an
+    /// honest node counts for an honest validator was cast by that validator itself.
+    fn test_forge_votes() {
+        TestNetwork::print_test_case_header("Attackers forge votes of honest nodes");
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("equivocate".to_string());
+        attacker_config.insert("forge_votes".to_string());
+        let epochs = 30;
+        for (n, num_attackers) in [(4, 1), (7, 2)] {
+            let mut network = Network::new_byzantine(n, num_attackers, attacker_config.clone());
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_reorder(epochs);
+            TestNetwork::validate(&network);
+
+            let honest = n - num_attackers;
+            let forged = network
+                .captured_attacker_messages()
+                .iter()
+                .filter_map(|(_, sender, _, m)| {
+                    let vote = m.as_any().downcast_ref::<VoteMessage>()?;
+                    (vote.signer != *sender).then_some(vote)
+                })
+                .count();
+            assert!(forged > 0);
+            let nodes: Vec<&Node> = network.nodes[..honest]
+                .iter()
+                .map(|node| node.as_any().downcast_ref::<Node>().unwrap())
+                .collect();
+            for node in &nodes {
+                for (block_hash, votes) in &node.chain.votes {
+                    for voter in votes.iter().filter(|voter| **voter < honest) {
+                        assert!(nodes[*voter].chain.votes[block_hash].contains(voter));
+                    }
+                }
+                assert!(node.events.iter().any(|record| matches!(
+                    &record.event,
+                    Event::AttackDetected { reason } if reason.contains("Signature check failed")
+                )));
+            }
+        }
+    }
+
+    /// Low-fee transactions are submitted before high-fee ones, and a block only fits half of
+    /// them. In FIFO order, the proposal contains the low-fee transactions; with fee priority, it
+    /// contains the high-fee ones, highest fee first. Either way, the other transactions remain
+    /// in the pool, and a network prioritizing fees still reaches consensus.
+    fn test_mempool_policy() {
+        TestNetwork::print_test_case_header("Mempool ordering policies");
+        let count = 10;
+        let low_fee =
+            (0..count).map(|i| Transaction::new(0, i, format!("tx {:02}", i)).with_fee(1));
+        let high_fee = (count..2 * count)
+            .map(|i| Transaction::new(0, i, format!("tx {:02}", i)).with_fee(10 + i));
+        let transactions: Vec<Transaction> = low_fee.chain(high_fee).collect();
+        let block_size: usize = transactions[..count as usize]
+            .iter()
+            .map(|tx| tx.serialize().len())
+            .sum();
+        let propose = |mempool_policy: Box<dyn MempoolPolicy>| {
+            let mut node = Node::new(0, (0..1).collect(), DEFAULT_FINALIZATION_DEPTH);
+            node.set_chain_params(ChainParams {
+                max_txs: block_size + 1,
+                ..ChainParams::default()
+            });
+            node.set_mempool_policy(mempool_policy);
+            for tx in &transactions {
+                node.send_transaction(tx.clone());
+            }
+            let block = node.propose_block_on(1, node.chain.genesis).unwrap();
+            assert_eq!(node.tx_pool_len(), transactions.len() - block.txs.len());
+            block.txs
+        };
+
+        let fifo = propose(Box::new(FifoPolicy));
+        assert_eq!(fifo, transactions[..count as usize]);
+        let priority = propose(Box::new(FeePriorityPolicy));
+        let mut expected = transactions[count as usize..].to_vec();
+        expected.reverse();
+        assert_eq!(priority, expected);
+
+        let n = 4;
+        let epochs = 10;
+        let mut network = Network::new(n);
+        network.set_mempool_policy(&FeePriorityPolicy);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+    }
+
+    /// Honest nodes at the same finalized height agree on their checkpoints, and pruning the chain
+    /// does not change them. A node whose finalized chain was corrupted below its highest finalized
+    /// block ends up with a different checkpoint at the same height.
+    fn test_checkpoints() {
+        TestNetwork::print_test_case_header("Checkpoints");
+        let n = 4;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(30);
+        let checkpoint = |network: &Network, i: usize| {
+            let node = network.nodes[i].as_any().downcast_ref::<Node>().unwrap();
+            node.latest_checkpoint()
+        };
+        let checkpoints: Vec<(usize, Hash)> = (0..n).map(|i| checkpoint(&network, i)).collect();
+        for (height1, checkpoint1) in checkpoints.iter() {
+            assert!(*height1 > 0);
+            for (height2, checkpoint2) in checkpoints.iter() {
+                assert_eq!(height1 == height2, checkpoint1 == checkpoint2);
+            }
+        }
+        assert!(TestNetwork::consistency(&network));
+        // The checkpoint rolled forward as blocks were finalized is that of the whole chain
+        for (i, (_, checkpoint)) in checkpoints.iter().enumerate() {
+            let chain = &network.nodes[i]
+                .as_any()
+                .downcast_ref::<Node>()
+                .unwrap()
+                .chain;
+            let finalized = chain.finalized_chain();
+            let expected = finalized[1..]
+                .iter()
+                .fold(chain.genesis, |checkpoint, block_hash| {
+                    chain
+                        .hash_algo()
+                        .hash(&[checkpoint.as_slice(), block_hash.as_slice()].concat())
+                });
+            assert_eq!(*checkpoint, expected);
+            assert_eq!(
+                chain.finalized_checkpoint,
+                Some((*finalized.last().unwrap(), expected))
+            );
+        }
+
+        // Replace the highest finalized block of node 1 by a different block of the same height
+        let (height, before) = checkpoints[1];
+        let chain = &mut network.nodes[1]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap()
+            .chain;
+        let highest = *chain.highest_finalized_block();
+        let parent = chain.parent_of(highest).unwrap();
+        let e = chain.blocks[&highest].e;
+        chain.finalized.remove(&highest);
+        chain.finalized_at.remove(&highest);
+        TestNetwork::extend_finalized(chain, parent, e, "corrupted");
+        let (corrupted_height, after) = checkpoint(&network, 1);
+        assert_eq!(corrupted_height, height);
+        assert_ne!(after, before);
+        assert!(!TestNetwork::consistency(&network));
+
+        // Pruning keeps the checkpoint
+        let (height, before) = checkpoints[0];
+        let chain = &mut network.nodes[0]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap()
+            .chain;
+        chain.prune_below(height - 2);
+        assert_eq!(checkpoint(&network, 0), (height, before));
+    }
+
+    /// Colluding equivocating attackers send their first block to the same half of the honest
+    /// nodes, their second block to the other half, and both blocks to each other. Consistency
+    /// still holds, since fewer than a third of the nodes are attackers.
+    fn test_colluding_attackers() {
+        TestNetwork::print_test_case_header("Colluding attackers");
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("always_leader".to_string());
+        attacker_config.insert("equivocate".to_string());
+        attacker_config.insert("vote_everything".to_string());
+        let n = 7;
+        let num_attackers = 2;
+        let epochs = 20;
+        let mut network = Network::new_byzantine(n, num_attackers, attacker_config);
+        network.set_colluding();
+
+        // Both attackers split the honest nodes 0..5 the same way
+        let block_message = |name: &str| {
+            let block = Block::new(
+                None,
+                1,
+                Vec::new(),
+                name.to_string(),
+                1,
+                HashAlgo::default(),
+            );
+            Box::new(block.to_block_message(0, (0, vec![]))) as Box<dyn Message>
+        };
+        let recipients = |network: &mut Network, attacker: usize| {
+            let node = network.nodes[attacker].as_any_mut();
+            let node = node.downcast_mut::<AttackerNode>().unwrap();
+            node.equivocate_message(block_message("first"), block_message("second"));
+            let mut recipients = [Vec::new(), Vec::new()];
+            for (i, m) in network.nodes[attacker].clear_outgoing_messages() {
+                let name = &m.as_any().downcast_ref::<BlockMessage>().unwrap().name;
+                recipients[if name == "first" { 0 } else { 1 }].push(i);
+            }
+            recipients
+        };
+        for attacker in [5, 6] {
+            let other = 11 - attacker;
+            assert_eq!(
+                recipients(&mut network, attacker),
+                [vec![0, 1, 2, other], vec![3, 4, other]]
+            );
+        }
+
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        assert!(node.chain.blocks[node.chain.highest_finalized_block()].height > 0);
+
+        // Once nodes 3 and 4 leave the validator set, the attackers split the remaining honest
+        // nodes
+        for node in network.nodes.iter_mut() {
+            node.set_validators(epochs + 1, vec![0, 1, 2, 5, 6]);
+        }
+        assert_eq!(recipients(&mut network, 5), [vec![0, 1, 6], vec![2, 6]]);
+    }
+
+    /// If the highest notarized block is not stored, the leader logs a warning and proposes on
+    /// the root of its chain instead of panicking: genesis, or the checkpoint after pruning. Only
+    /// if the root is missing as well, proposing fails.
+    fn test_missing_parent() {
+        TestNetwork::print_test_case_header("Missing parent");
+        let n = 4;
+        let missing = Crypto::hash(HashAlgo::default(), b"missing");
+        let mut node = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        node.chain.notarized.insert(missing);
+        node.chain.block_by_epoch.push(HashSet::from([missing]));
+        assert_eq!(node.chain.get_highest_notarized_block(), missing);
+        let block = node.propose_block(2).unwrap();
+        assert_eq!(block.parent_hash, Some(node.chain.genesis));
+        assert!(node.chain.contains_block(block.hash));
+
+        // After pruning, the checkpoint is the root
+        let mut network = Network::new(n);
+        network.run_simple(10);
+        let node = network.nodes[0]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap();
+        let height = node.chain.blocks[node.chain.highest_finalized_block()].height;
+        node.chain.prune_below(height);
+        let root = node.chain.root;
+        let block = node.propose_block_on(11, node.chain.genesis).unwrap();
+        assert_eq!(block.parent_hash, Some(root));
+        assert_eq!(block.height, height + 1);
+
+        node.chain.blocks.remove(&root);
+        assert_eq!(
+            node.propose_block_on(12, missing),
+            Err(ConsensusError::MissingParent { parent: root })
+        );
+    }
+
+    /// With a slow link between nodes 0 and 1, their messages to each other arrive later than
+    /// their messages to other nodes, by the additional latency of the link. Nodes still
+    /// finalize consistently.
+    fn test_latency_matrix() {
+        TestNetwork::print_test_case_header("Latency matrix");
+        let n = 4;
+        let epochs = 20;
+        let slow_latency = 4;
+        let mut latency = vec![vec![1; n]; n];
+        latency[0][1] = slow_latency;
+        latency[1][0] = slow_latency;
+        let mut network = Network::new(n);
+        network.record_trace();
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_with_latency_matrix(epochs, latency);
+        TestNetwork::validate(&network);
+        for i in 0..n {
+            let node = network.nodes[i].as_any().downcast_ref::<Node>().unwrap();
+            assert!(node.chain.blocks[node.chain.highest_finalized_block()].height > epochs / 2);
+        }
+
+        // Node 0 sends each of its proposals to nodes 1 and 2 in the same round
+        let proposal_rounds = |receiver: usize| -> Vec<(Hash, usize)> {
+            network
+                .trace()
+                .unwrap()
+                .iter()
+                .filter(|(_, i, j, m)| *i == 0 && *j == receiver && m.hop() == 0)
+                .filter_map(|(round, _, _, m)| {
+                    let proposal = m.as_any().downcast_ref::<BlockMessage>()?;
+                    (proposal.creator == 0).then(|| (proposal.block_hash(HashAlgo::Sha256), *round))
+                })
+                .collect()
+        };
+        let slow = proposal_rounds(1);
+        let fast = proposal_rounds(2);
+        assert!(!slow.is_empty());
+        assert_eq!(slow.len(), fast.len());
+        for ((slow_hash, slow_round), (fast_hash, fast_round)) in slow.iter().zip(fast.iter()) {
+            assert_eq!(slow_hash, fast_hash);
+            // Draining after the last epoch delivers the delayed messages right away
+            if *slow_round <= epochs * DEFAULT_ROUNDS_PER_EPOCH {
+                assert_eq!(*slow_round, fast_round + slow_latency - 1);
+            }
+        }
+    }
+
+    /// If the leader of an epoch fail-stops, honest nodes vote for the empty block of the epoch
+    /// instead, so that the chain grows by one block per epoch and the empty blocks are finalized
+    /// like any other block. Without empty blocks, the epochs of the dead leader leave gaps.
+    /// Honest nodes agree on the parent of an empty block even if their views of the notarized
+    /// blocks differ, and a block is empty by its lack of a proposer and transactions, not by
+    /// its name.
+    fn test_empty_blocks() {
+        TestNetwork::print_test_case_header("Empty blocks for a silent leader");
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("fail_stop".to_string());
+        let n = 4;
+        let dead = n - 1;
+        let epochs = 20;
+        let run = |timeout: Option<usize>| {
+            let mut network = Network::new_byzantine(n, 1, attacker_config.clone());
+            network.set_empty_block_timeout(timeout);
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_simple(epochs);
+            TestNetwork::validate(&network);
+            network
+        };
+
+        let network = run(Some(2));
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        let finalized = node.chain.finalized_chain();
+        assert!(finalized.len() > epochs / 2);
+        let dead_epochs: Vec<usize> = (1..finalized.len())
+            .filter(|e| node.leader(*e) == dead)
+            .collect();
+        assert!(!dead_epochs.is_empty());
+        for (height, block_hash) in finalized.iter().enumerate().skip(1) {
+            let block = &node.chain.blocks[block_hash];
+            assert_eq!(block.height, height);
+            assert_eq!(block.e, height);
+            assert_eq!(block.is_empty_block(), dead_epochs.contains(&height));
+        }
+
+        let network = run(None);
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        let finalized = node.chain.finalized_chain();
+        let last = &node.chain.blocks[finalized.last().unwrap()];
+        assert!(last.height < last.e);
+        assert!(finalized
+            .iter()
+            .all(|block_hash| !node.chain.blocks[block_hash].is_empty_block()));
+
+        // Two nodes build the same empty block of epoch 2 on the proposal of epoch 1, even if
+        // only one of them saw the proposal notarized
+        let leader = node.leader(1);
+        let genesis = node.chain.genesis;
+        let tx = Transaction::new(n, 1, "tx".to_string());
+        let proposal = Block::new(
+            Some(genesis),
+            1,
+            vec![tx],
+            "1".to_string(),
+            1,
+            HashAlgo::default(),
+        )
+        .with_proposer(Some(leader));
+        let empty_votes: Vec<VoteMessage> = [false, true]
+            .into_iter()
+            .map(|notarized| {
+                let mut node = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+                node.set_empty_block_timeout(Some(1));
+                node.receive_block(TestNetwork::signed_proposal(&proposal, leader), leader)
+                    .unwrap();
+                if notarized {
+                    for voter in 1..n {
+                        let _ = node.receive_vote(TestNetwork::signed_vote(&proposal, voter));
+                    }
+                }
+                assert_eq!(node.chain.notarized.contains(&proposal.hash), notarized);
+                node.clear_outgoing_messages();
+                node.enter_epoch(2, 0);
+                node.new_round();
+                node.clear_outgoing_messages()
+                    .into_iter()
+                    .filter_map(|(_, m)| m.as_any().downcast_ref::<VoteMessage>().cloned())
+                    .find(|vote| vote.e == 2)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(empty_votes[0].parent_hash, Some(proposal.hash));
+        let hash_algo = HashAlgo::default();
+        assert_eq!(
+            empty_votes[0].block_hash(hash_algo),
+            empty_votes[1].block_hash(hash_algo)
+        );
+
+        // A leader cannot propose an empty block, whatever its name
+        let mut node = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        let forged = Block::new(
+            Some(genesis),
+            1,
+            Vec::new(),
+            "1/4".to_string(),
+            1,
+            hash_algo,
+        );
+        assert!(forged.is_empty_block());
+        assert_eq!(
+            node.receive_block(TestNetwork::signed_proposal(&forged, leader), leader),
+            Err(ConsensusError::InvalidBlock)
+        );
+        assert!(!Block::new(
+            Some(genesis),
+            1,
+            Vec::new(),
+            Block::empty_block_name(1),
+            1,
+            hash_algo
+        )
+        .with_proposer(Some(leader))
+        .is_empty_block());
+    }
+
+    /// Feeding random bytes and randomly corrupted encodings of real messages into the message
+    /// decoding and on into a node never panics: malformed bytes are rejected by the decoder, and
+    /// messages that decode but carry garbage are rejected by the node, also if they are validly
+    /// signed, e.g. blocks of epochs far in the future.
+    fn test_fuzz_message_decoding() {
+        TestNetwork::print_test_case_header("Fuzz message decoding");
+        let n = 4;
+        let iterations = 5000;
+        let mut network = Network::new(n);
+        network.record_trace();
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(5);
+        let encoded: Vec<Vec<u8>> = network
+            .trace()
+            .unwrap()
+            .iter()
+            .map(|(_, _, _, m)| m.to_bytes().unwrap())
+            .collect();
+        for (_, _, _, m) in network.trace().unwrap() {
+            let decoded = <dyn Message>::from_bytes(&m.to_bytes().unwrap()).unwrap();
+            assert_eq!(decoded.to_bytes().unwrap(), m.to_bytes().unwrap());
+        }
+
+        let mut rng = StdRng::from_seed([3; 32]);
+        let mut rejected = 0;
+        for i in 0..iterations {
+            let bytes: Vec<u8> = if i % 2 == 0 {
+                let len = rng.gen_range(0..256);
+                (0..len).map(|_| rng.gen()).collect()
+            } else {
+                let mut bytes = encoded[rng.gen_range(0..encoded.len())].clone();
+                for _ in 0..rng.gen_range(1..4) {
+                    let pos = rng.gen_range(0..bytes.len());
+                    bytes[pos] = rng.gen();
+                }
+                if rng.gen_bool(0.1) {
+                    bytes.truncate(rng.gen_range(0..bytes.len()));
+                }
+                bytes
+            };
+            let Some(mut m) = <dyn Message>::from_bytes(&bytes) else {
+                rejected += 1;
+                continue;
+            };
+            // Attackers can sign garbage, so make the signatures of half of the messages valid
+            if rng.gen_bool(0.5) {
+                if let Some(block) = m.as_any().downcast_ref::<BlockMessage>() {
+                    let block_hash = block.block_hash(HashAlgo::Sha256);
+                    m = Box::new(BlockMessage {
+                        signature: TestNetwork::signature(
+                            block.signer,
+                            MessageType::BlockProposal,
+                            block_hash,
+                        ),
+                        ..block.clone()
+                    });
+                } else if let Some(vote) = m.as_any().downcast_ref::<VoteMessage>() {
+                    let block_hash = vote.block_hash(HashAlgo::Sha256);
+                    m = Box::new(VoteMessage {
+                        signature: TestNetwork::signature(
+                            vote.signer,
+                            MessageType::Vote,
+                            block_hash,
+                        ),
+                        ..vote.clone()
+                    });
+                }
+            }
+            network.nodes[0].incoming_message(m.as_ref(), rng.gen_range(1..n));
+        }
+        assert!(rejected > 0 && rejected < iterations);
+        assert!(TestNetwork::consistency(&network));
+    }
+
+    /// The global safe height is the lowest finalized height of the honest validators, so a slow
+    /// node holds it back until it leaves the validator set. It never decreases from one epoch to
+    /// the next, and pruning below it after every epoch keeps all nodes finalizing consistently.
+    fn test_global_safe_height() {
+        TestNetwork::print_test_case_header("Global safe height");
+        let n = 7;
+        let epochs = 30;
+        let slow = 1;
+        let mut network = Network::new(n);
+        network.set_slow_nodes(&HashSet::from([slow]), 2);
+        network.set_pruning(true);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        let safe_height = |network: &Network, i: usize| {
+            let node = network.nodes[i].as_any().downcast_ref::<Node>().unwrap();
+            node.chain.safe_height()
+        };
+        let mut heights = vec![network.global_safe_height()];
+        let mut held_back = false;
+        for _ in 0..epochs {
+            for _ in 0..DEFAULT_ROUNDS_PER_EPOCH {
+                network.step();
+            }
+            let height = network.global_safe_height();
+            assert_eq!(
+                height,
+                (0..n).map(|i| safe_height(&network, i)).min().unwrap()
+            );
+            held_back |= height == safe_height(&network, slow) && height < safe_height(&network, 0);
+            heights.push(height);
+            for node in network.nodes.iter() {
+                let chain = &node.as_any().downcast_ref::<Node>().unwrap().chain;
+                assert_eq!(chain.blocks[&chain.root].height, height);
+            }
+        }
+        assert!(held_back);
+        assert!(heights.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(heights[epochs] > epochs / 2);
+        assert!(TestNetwork::consistency(&network));
+
+        // A node that left no longer holds back the global safe height, so pruning continues
+        // without it
+        network.apply_churn(Churn::Remove(slow));
+        network.run_simple(10);
+        let height = network.global_safe_height();
+        let validators: Vec<usize> = (0..n).filter(|i| *i != slow).collect();
+        assert_eq!(
+            height,
+            validators
+                .iter()
+                .map(|i| safe_height(&network, *i))
+                .min()
+                .unwrap()
+        );
+        assert!(height > heights[epochs] + 5);
+        for i in validators {
+            let node = network.nodes[i].as_any().downcast_ref::<Node>().unwrap();
+            assert_eq!(node.chain.blocks[&node.chain.root].height, height);
+        }
+        assert!(TestNetwork::consistency(&network));
+    }
+
+    /// BLAKE3 matches the test vectors of its specification, including inputs of more than one
+    /// 1024-byte chunk. The same scenario finalizes consistently with either hash function, while
+    /// the block hashes differ. The leader election and the transaction hashes use the hash
+    /// function of the chain as well.
+    fn test_hash_algo() {
+        TestNetwork::print_test_case_header("Configurable hash function");
+        let blake3 = |input: &[u8]| hex::encode(HashAlgo::Blake3.hash(input));
+        assert_eq!(
+            blake3(b""),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        assert_eq!(
+            blake3(b"abc"),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        assert_eq!(
+            blake3(&[0]),
+            "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213"
+        );
+        // The inputs of the official test vectors repeat the bytes 0, 1, ..., 250
+        for (len, hash) in [
+            (
+                1023,
+                "10108970eeda3eb932baac1428c7a2163b0e924c9a9e25b35bba72b28f70bd11",
+            ),
+            (
+                1024,
+                "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7",
+            ),
+            (
+                1025,
+                "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444",
+            ),
+            (
+                2048,
+                "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a",
+            ),
+            (
+                2049,
+                "5f4d72f40d7a5f82b15ca2b2e44b1de3c2ef86c426c95c1af0b6879522563030",
+            ),
+            (
+                3072,
+                "b98cb0ff3623be03326b373de6b9095218513e64f1ee2edd2525c7ad1e5cffd2",
+            ),
+            (
+                4096,
+                "015094013f57a5277b59d8475c0501042c0b642e531b0a1c8f58d2163229e969",
+            ),
+            (
+                8192,
+                "aae792484c8efe4f19e2ca7d371d8c467ffb10748d8a5a1ae579948f718a2a63",
+            ),
+        ] {
+            let input: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            assert_eq!(blake3(&input), hash, "input of {} bytes", len);
+        }
+        assert_eq!(
+            hex::encode(HashAlgo::Sha256.hash(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let n = 4;
+        let epochs = 20;
+        let mut genesis = Vec::new();
+        for hash_algo in [HashAlgo::Sha256, HashAlgo::Blake3] {
+            let mut network = Network::with_hash_algo(n, hash_algo);
+            assert_eq!(network.hash_algo(), hash_algo);
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_simple(epochs);
+            TestNetwork::validate(&network);
+            let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+            assert!(node.chain.safe_height() > epochs / 2);
+            assert_eq!(node.chain.hash_algo(), hash_algo);
+            let tip = &node.chain.blocks[&node.chain.get_highest_notarized_block()];
+            assert_eq!(tip.hash_algo, hash_algo);
+            let rehashed = Block::new(
+                tip.parent_hash,
+                tip.e,
+                tip.txs.clone(),
+                tip.name.clone(),
+                0,
+                hash_algo,
+            )
+            .with_timestamp(tip.timestamp)
+            .with_proposer(tip.proposer);
+            assert_eq!(tip.hash, rehashed.hash);
+            for e in 1..=epochs {
+                assert_eq!(node.leader(e), HashLeaderElection.leader(e, n, hash_algo));
+            }
+            genesis.push(node.chain.genesis);
+        }
+        assert_ne!(genesis[0], genesis[1]);
+        assert!(
+            (1..=epochs).any(|e| HashLeaderElection.leader(e, n, HashAlgo::Sha256)
+                != HashLeaderElection.leader(e, n, HashAlgo::Blake3))
+        );
+        let tx = Transaction::new(0, 0, "tx".to_string());
+        assert_ne!(tx.hash(HashAlgo::Sha256), tx.hash(HashAlgo::Blake3));
+
+        // The builder configures the hash function as part of the genesis configuration
+        let network = ConsensusBuilder::new(n)
+            .genesis(GenesisConfig::with_chain_id(7))
+            .hash_algo(HashAlgo::Blake3)
+            .build();
+        assert_eq!(network.hash_algo(), HashAlgo::Blake3);
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        assert_eq!(node.chain.genesis_config.chain_id, 7);
+        assert_eq!(node.chain.hash_algo(), HashAlgo::Blake3);
+    }
+
+    /// If the leader of an epoch is fail-stopped, a backup leader proposes instead once the backup
+    /// leader timeout has passed, so that the epoch still produces a block. Backup proposals of
+    /// attempts that have not started yet are rejected.
+    fn test_backup_leader() {
+        TestNetwork::print_test_case_header("Backup leader for a silent leader");
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("fail_stop".to_string());
+        let n = 4;
+        let dead = n - 1;
+        let epochs = 20;
+        let run = |timeout: Option<usize>| {
+            let mut network = Network::new_byzantine(n, 1, attacker_config.clone());
+            // Time for the proposal of the leader and for those of two backup leaders
+            network.set_rounds_per_epoch(6);
+            network.set_backup_leader_timeout(timeout);
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_simple(epochs);
+            TestNetwork::validate(&network);
+            network
+        };
+
+        let network = run(Some(2));
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        let dead_epochs: Vec<usize> = (1..=epochs).filter(|e| node.leader(*e) == dead).collect();
+        assert!(!dead_epochs.is_empty());
+        for e in 1..=epochs {
+            let blocks = &node.chain.block_by_epoch[e];
+            assert_eq!(blocks.len(), 1);
+            let block = &node.chain.blocks[blocks.iter().next().unwrap()];
+            assert!(node.chain.notarized.contains(&block.hash));
+            let proposer = if dead_epochs.contains(&e) {
+                let attempt = (1..).find(|a| node.backup_leader(e, *a) != dead).unwrap();
+                node.backup_leader(e, attempt)
+            } else {
+                node.leader(e)
+            };
+            assert_eq!(block.name, format!("{}/{}", e, proposer));
+        }
+        let finalized = node.chain.finalized_chain();
+        let last = &node.chain.blocks[finalized.last().unwrap()];
+        assert_eq!(last.height, last.e);
+
+        let network = run(None);
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        assert!(dead_epochs.iter().all(|e| node
+            .chain
+            .block_by_epoch
+            .get(*e)
+            .is_none_or(|blocks| blocks.is_empty())));
+
+        // A backup leader may not propose before its attempt has started
+        let mut node = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        node.set_backup_leader_timeout(Some(1));
+        let e = dead_epochs[0];
+        node.new_epoch(e, 0);
+        let backup = node.backup_leader(e, 1);
+        assert_eq!(node.backup_attempt(e, backup), None);
+        node.new_round();
+        assert_eq!(node.backup_attempt(e, backup), Some(1));
+        assert_eq!(node.backup_attempt(e + 1, backup), None);
+
+        // The blocks of the leader and of a backup leader differ even if both have no
+        // transactions, and a vote for a block carries its proposer
+        let blocks: Vec<Block> = [node.leader(e), backup]
+            .into_iter()
+            .map(|proposer| {
+                let mut node = Node::new(proposer, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+                let genesis = node.head();
+                node.propose_block_on(e, genesis).unwrap()
+            })
+            .collect();
+        assert_eq!(blocks[0].txs, blocks[1].txs);
+        assert_ne!(blocks[0].hash, blocks[1].hash);
+        for block in &blocks {
+            let vote = TestNetwork::signed_vote(block, 0);
+            assert_eq!(vote.block_hash(HashAlgo::default()), block.hash);
+        }
+    }
+
+    /// The message handlers report why they rejected a message, or why they did not vote for a
+    /// block they accepted. Handling the message through incoming_message logs the error, as an
+    /// attack if only a faulty or malicious sender can cause it.
+    fn test_consensus_errors() {
+        TestNetwork::print_test_case_header("Handlers report why they reject messages");
+        let n = 4;
+        let mut node = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        let genesis = node.chain.get_highest_notarized_block();
+        let e = (1..).find(|e| node.leader(*e) != 0).unwrap();
+        let leader = node.leader(e);
+        node.new_epoch(e, 0);
+        let proposal = |parent: Option<Hash>, e: usize, signer: usize, name: &str| {
+            let b = BlockMessage::new(
+                signer,
+                parent,
+                e,
+                vec![],
+                name.to_string(),
+                signer,
+                (0, vec![]),
+            );
+            let block_hash = b.block_hash(HashAlgo::Sha256);
+            let signature = TestNetwork::signature(signer, MessageType::BlockProposal, block_hash);
+            BlockMessage { signature, ..b }
+        };
+        let vote = TestNetwork::signed_vote;
+
+        // Rejected messages
+        assert_eq!(
+            node.receive_block(proposal(None, e, leader, "a"), leader),
+            Err(ConsensusError::NoParentHash)
+        );
+        let forged = BlockMessage {
+            signature: (leader as u64, vec![]),
+            ..proposal(Some(genesis), e, leader, "a")
+        };
+        assert_eq!(
+            node.receive_block(forged.clone(), leader),
+            Err(ConsensusError::BadSignature { signer: leader })
+        );
+        let other = (1..n).find(|i| *i != leader).unwrap();
+        assert_eq!(
+            node.receive_block(proposal(Some(genesis), e, other, "a"), other),
+            Err(ConsensusError::WrongLeader {
+                e,
+                signer: other,
+                leader
+            })
+        );
+        let far = e + MAX_FUTURE_EPOCHS + 1;
+        assert_eq!(
+            node.receive_block(proposal(Some(genesis), far, node.leader(far), "a"), leader),
+            Err(ConsensusError::FutureEpoch { e: far })
+        );
+        let missing = Crypto::hash(HashAlgo::default(), b"missing");
+        assert_eq!(
+            node.receive_block(proposal(Some(missing), e, leader, "a"), leader),
+            Err(ConsensusError::MissingParent { parent: missing })
+        );
+
+        // We accept and vote for a valid proposal, and ignore it the second time
+        let a = proposal(Some(genesis), e, leader, "a");
+        assert_eq!(node.receive_block(a.clone(), leader), Ok(()));
+        assert_eq!(node.receive_block(a.clone(), leader), Ok(()));
+        let block_a = node.chain.blocks[&a.block_hash(HashAlgo::Sha256)].clone();
+        assert_eq!(
+            node.vote(block_a.clone()),
+            Err(ConsensusError::AlreadyVoted { e })
+        );
+        assert_eq!(
+            node.receive_vote(VoteMessage {
+                signature: (other as u64, vec![]),
+                ..vote(&block_a, other)
+            }),
+            Err(ConsensusError::BadSignature { signer: other })
+        );
+        assert_eq!(node.receive_vote(vote(&block_a, other)), Ok(()));
+        assert!(node.chain.notarized.contains(&block_a.hash));
+
+        // Accepted proposals we do not vote for
+        let e2 = e + 1;
+        let leader2 = node.leader(e2);
+        assert_eq!(
+            node.receive_block(proposal(Some(genesis), e2, leader2, "b"), leader2),
+            Err(ConsensusError::StaleHeight {
+                height: 1,
+                notarized_height: 1
+            })
+        );
+        assert_eq!(
+            node.receive_block(proposal(Some(block_a.hash), e2, leader2, "c"), leader2),
+            Err(ConsensusError::Equivocation { e: e2 })
+        );
+        let e3 = e2 + 1;
+        let leader3 = node.leader(e3);
+        node.timeout(e3);
+        assert_eq!(
+            node.receive_block(proposal(Some(block_a.hash), e3, leader3, "d"), leader3),
+            Err(ConsensusError::EpochTimedOut {
+                e: e3,
+                view: e3 + 1
+            })
+        );
+
+        // Errors of messages handled through incoming_message are logged
+        node.incoming_message(&forged, leader);
+        assert!(matches!(
+            &node.events.last().unwrap().event,
+            Event::AttackDetected { reason } if reason.contains("Signature check failed")
+        ));
+    }
+
+    /// A user tries to double-spend by submitting two conflicting transactions, with the same
+    /// sender and nonce but different payloads, to different nodes. A block that reuses a nonce
+    /// of its branch is invalid, while competing forks may each contain one of the transactions.
+    /// Hence, at most one of them finalizes.
+    fn test_double_spend() {
+        TestNetwork::print_test_case_header("Double-spend attempts");
+        let n = 4;
+        // The sender is a user, not a validator, so the workload cannot collide with its nonces
+        let user = n;
+        let spend = Transaction::new(user, 0, "pay Alice".to_string());
+        let double_spend = Transaction::new(user, 0, "pay Bob".to_string());
+
+        let mut node = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        let genesis = node.chain.genesis;
+        let block = |parent: Hash, e: usize, height: usize, txs: &[&Transaction]| {
+            let txs = txs.iter().map(|tx| (*tx).clone()).collect();
+            Block::new(
+                Some(parent),
+                e,
+                txs,
+                format!("{}/double spend", e),
+                height,
+                HashAlgo::default(),
+            )
+        };
+        let a = block(genesis, 1, 1, &[&spend]);
+        assert!(node.chain.validate_and_extend(a.clone(), genesis));
+        assert!(!node
+            .chain
+            .validate_and_extend(block(a.hash, 2, 2, &[&double_spend]), a.hash));
+        assert!(!node
+            .chain
+            .validate_and_extend(block(a.hash, 2, 2, &[&spend]), a.hash));
+        assert!(node
+            .chain
+            .validate_and_extend(block(genesis, 2, 1, &[&double_spend]), genesis));
+        let both = block(genesis, 3, 1, &[&spend, &double_spend]);
+        assert!(!node.chain.validate_and_extend(both, genesis));
+        assert!(!node.is_nonce_used(user, 0));
+        node.chain.notarized.insert(a.hash);
+        assert!(node.is_nonce_used(user, 0));
+        assert!(!node.is_nonce_used(user, 1));
+
+        for seed in 0..5 {
+            let mut network = Network::new_byzantine_with_seed(n, 0, HashSet::new(), [seed; 32]);
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.nodes[1].send_transaction(spend.clone());
+            network.nodes[2].send_transaction(double_spend.clone());
+            network.run_delays_then_synchrony(20, 0.5);
+            TestNetwork::validate(&network);
+            for node in &network.nodes {
+                let node = node.as_any().downcast_ref::<Node>().unwrap();
+                let spent: Vec<&Transaction> = node
+                    .chain
+                    .finalized_chain()
+                    .iter()
+                    .flat_map(|block_hash| &node.chain.blocks[block_hash].txs)
+                    .filter(|tx| tx.sender == user)
+                    .collect();
+                assert_eq!(spent.len(), 1);
+                assert!(node.is_nonce_used(user, 0));
+
+                // The nonces of the finalized chain are indexed, and looking them up gives the
+                // same result as walking the whole branch
+                let head = node.chain.get_highest_notarized_block();
+                let nonces: HashSet<(usize, u64)> = node
+                    .chain
+                    .blocks
+                    .values()
+                    .flat_map(|block| &block.txs)
+                    .map(|tx| (tx.sender, tx.nonce))
+                    .collect();
+                assert!(node.chain.finalized_nonces.contains_key(&(user, 0)));
+                let mut branch = HashSet::new();
+                let mut block_hash = Some(head);
+                while let Some(h) = block_hash {
+                    branch.extend(
+                        node.chain.blocks[&h]
+                            .txs
+                            .iter()
+                            .map(|tx| (tx.sender, tx.nonce)),
+                    );
+                    block_hash = node.chain.parent_of(h);
+                }
+                assert_eq!(node.chain.used_nonces(head, &nonces), branch);
+            }
+        }
+    }
+
+    /// Nodes processing the messages delivered to them in parallel yields exactly the same
+    /// execution as processing them one node after the other: the same deliveries in the same
+    /// order, and the same chains.
+    fn test_parallel_delivery() {
+        TestNetwork::print_test_case_header("Parallel message processing");
+        let n = 10;
+        let num_attackers = 2;
+        let run = |threads: usize| {
+            let mut network = ConsensusBuilder::new(n)
+                .attackers(num_attackers, &["equivocate"])
+                .threads(threads)
+                .build();
+            network.record_trace();
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_simple(10);
+            TestNetwork::validate(&network);
+            network
+        };
+        let sequential = run(1);
+        let parallel = run(4);
+        let deliveries = |network: &Network| -> Vec<(usize, usize, usize, Vec<u8>)> {
+            network
+                .trace()
+                .unwrap()
+                .iter()
+                .map(|(round, i, j, m)| (*round, *i, *j, m.to_bytes().unwrap()))
+                .collect()
+        };
+        assert!(!deliveries(&sequential).is_empty());
+        assert_eq!(deliveries(&sequential), deliveries(&parallel));
+        for i in 0..n - num_attackers {
+            let chain = |network: &Network| {
+                let node = network.nodes[i].as_any().downcast_ref::<Node>().unwrap();
+                (node.chain.finalized_chain(), node.chain.notarized.clone())
+            };
+            assert_eq!(chain(&sequential), chain(&parallel));
+        }
+    }
+
+    /// Rational attackers follow the protocol, except that they never pass on the blocks of their
+    /// competitors. In a ring, blocks then need more rounds to reach the honest nodes behind an
+    /// attacker, so fewer epochs notarize a block in time and fewer blocks finalize. Safety is
+    /// preserved.
+    fn test_withhold_relay() {
+        TestNetwork::print_test_case_header("Rational attackers withholding relays");
+        let n = 10;
+        let epochs = 30;
+        let finalized_height = |num_attackers: usize| {
+            let mut attacker_config = HashSet::new();
+            attacker_config.insert("withhold_relay".to_string());
+            let mut network = Network::new_byzantine(n, num_attackers, attacker_config);
+            network.set_topology(Topology::Ring);
+            network.set_rounds_per_epoch(8);
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_simple(epochs);
+            assert!(TestNetwork::consistency(&network));
+            assert!(TestNetwork::safety_invariant(&network));
+            let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+            node.chain.blocks[node.chain.highest_finalized_block()].height
+        };
+        let honest = finalized_height(0);
+        let rational = finalized_height(3);
+        TestNetwork::dbg(
+            &format!(
+                "Finalized height after {} epochs: {} without attackers, {} with 3 rational attackers",
+                epochs, honest, rational
+            ),
+            None,
+            None,
+        );
+        assert!(honest >= epochs - DEFAULT_FINALIZATION_DEPTH);
+        assert!(rational > 0);
+        assert!(rational < honest);
+    }
+
+    /// Load the example scenarios, check that their networks are configured as described, and
+    /// run one of them. Malformed scenarios are rejected with the line at fault.
+    fn test_scenario() {
+        TestNetwork::print_test_case_header("Scenarios loaded from files");
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/scenarios");
+        let scenario = Scenario::load(format!("{}/equivocation.toml", dir)).unwrap();
+        assert_eq!(scenario.n, 7);
+        assert_eq!(scenario.epochs, 30);
+        assert_eq!(scenario.model, NetworkModel::DelaysThenSynchrony(0.5));
+        assert_eq!(scenario.attackers, 2);
+        assert_eq!(scenario.attacker_config, vec!["equivocate".to_string()]);
+        assert_eq!(scenario.seed, [1; 32]);
+        assert_eq!(scenario.workload, Workload::uniform(50).seed([1; 32]));
+        let network = scenario.run();
+        assert_eq!(network.epoch(), scenario.epochs);
+        assert_eq!(
+            network
+                .nodes
+                .iter()
+                .filter(|node| node.is_attacker())
+                .count(),
+            2
+        );
+        assert!(TestNetwork::validate(&network));
+
+        let steady = Scenario::load(format!("{}/steady_workload.toml", dir)).unwrap();
+        assert_eq!(steady.model, NetworkModel::Reorder);
+        assert_eq!(steady.workload.arrival, Arrival::Steady(5));
+
+        // The same scenario, parsed from a string
+        let parsed = Scenario::parse(
+            "n = 7\nepochs = 30 # comment\nmodel = \"delays_then_synchrony\"\nfraction = 0.5\n\
+             attackers = 2\nattacker_config = [\"equivocate\"]\nseed = 1\ntransactions = 50\n",
+        );
+        assert_eq!(parsed, Ok(scenario.clone()));
+
+        // Deserializing uses the keys of the file as well, here with bincode, which encodes the
+        // keys in the order of their declaration
+        let keys = (
+            Some(7usize),
+            Some(30usize),
+            None::<usize>,
+            Some(2usize),
+            Some("delays_then_synchrony".to_string()),
+            Some(0.5f64),
+            vec!["equivocate".to_string()],
+            Some(1u8),
+            Some(50usize),
+            None::<usize>,
+        );
+        let bytes = bincode::serialize(&keys).unwrap();
+        assert_eq!(bincode::deserialize::<Scenario>(&bytes).unwrap(), scenario);
+        let keys = (
+            None::<usize>,
+            Some(30usize),
+            None::<usize>,
+            None::<usize>,
+            None::<String>,
+            None::<f64>,
+            Vec::<String>::new(),
+            None::<u8>,
+            None::<usize>,
+            None::<usize>,
+        );
+        let bytes = bincode::serialize(&keys).unwrap();
+        let error = bincode::deserialize::<Scenario>(&bytes).unwrap_err();
+        assert!(error.to_string().contains("Missing key `n`"));
+
+        // Quoted strings may contain # and commas
+        let quoted = Scenario::parse(
+            "n = 4 # four nodes\nepochs = 5\nattacker_config = [\"a#b\", \"c,d\"] # two\n",
+        )
+        .unwrap();
+        assert_eq!(
+            quoted.attacker_config,
+            vec!["a#b".to_string(), "c,d".to_string()]
+        );
+
+        let error = |text: &str| Scenario::parse(text).unwrap_err();
+        assert_eq!(error("n = 4\nepochs = x").line, 2);
+        assert_eq!(error("n = 4\nrounds = 3\nepochs = 5").line, 2);
+        assert_eq!(error("n = 4\nepochs = 5\nmodel = reorder").line, 3);
+        assert_eq!(
+            error("n = 4\nepochs = 5\nmodel = \"delays_then_synchrony\""),
+            ScenarioError {
+                line: 3,
+                message: "Missing key `fraction`".to_string()
+            }
+        );
+        assert!(error("epochs = 5").to_string().contains("Missing key `n`"));
+        assert!(error("n = 1\nepochs = 5\nattackers = 2")
+            .to_string()
+            .contains("2 attackers"));
+        assert_eq!(
+            Scenario::load(format!("{}/missing.toml", dir))
+                .unwrap_err()
+                .line,
+            0
+        );
+    }
+
+    /// A block can be notarized after its child, e.g. when the child's votes arrive first. The
+    /// notarization of the block then completes the window of finalization_depth notarized blocks
+    /// of consecutive epochs, and the block is finalized without waiting for further votes.
+    fn test_finalize_descendants() {
+        TestNetwork::print_test_case_header("Finalize descendants");
+        let mut node = Node::new(0, (0..1).collect(), DEFAULT_FINALIZATION_DEPTH);
+        let genesis = node.chain.genesis;
+        let block_a = TestNetwork::extend_chain(&mut node.chain, genesis, 1, "a");
+        let block_b = TestNetwork::extend_chain(&mut node.chain, block_a, 2, "b");
+        node.chain.votes.insert(block_b, HashSet::from([0]));
+        node.notarize(block_b);
+        assert!(node.chain.notarized.contains(&block_b));
+        assert!(!node.chain.finalized.contains(&block_a));
+        node.chain.votes.insert(block_a, HashSet::from([0]));
+        node.notarize(block_a);
+        assert!(node.chain.finalized.contains(&block_a));
+    }
+
+    /// Notarize blocks of a large network with cached vote weights. Every block is notarized once,
+    /// after which further votes neither recount the votes nor trigger another attempt to
+    /// finalize. The cached weights agree with recounting the votes.
+    fn test_vote_weight_cache() {
+        TestNetwork::print_test_case_header("Vote weight caching");
+        let n = 31;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(5);
+        TestNetwork::validate(&network);
+        for node in &network.nodes {
+            let node = node.as_any().downcast_ref::<Node>().unwrap();
+            // Each notarization attempts to finalize the parent exactly once
+            assert!(node.stats.blocks_notarized > 0);
+            assert_eq!(node.stats.finalize_attempts, node.stats.blocks_notarized);
+            let notarized: Vec<Hash> = node
+                .events
+                .iter()
+                .filter_map(|record| match record.event {
+                    Event::Notarized { block, .. } => Some(block),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(
+                notarized.iter().collect::<HashSet<_>>().len(),
+                notarized.len()
+            );
+            for (block_hash, votes) in &node.chain.votes {
+                let (counted, weight) = node.chain.vote_weight[block_hash];
+                assert_eq!(counted, votes.len());
+                assert_eq!(weight, votes.len() as u64);
+            }
+        }
+
+        // Votes inserted directly are recounted, and a notarized block is not notarized again
+        let mut node = Node::new(0, (0..4).collect(), DEFAULT_FINALIZATION_DEPTH);
+        let genesis = node.chain.genesis;
+        let block_a = TestNetwork::extend_chain(&mut node.chain, genesis, 1, "a");
+        let block_b = TestNetwork::extend_chain(&mut node.chain, block_a, 2, "b");
+        assert!(node.count_vote(block_a, 1, 0));
+        assert!(!node.count_vote(block_a, 1, 0));
+        assert!(node.count_vote(block_a, 1, 1));
+        node.notarize(block_a);
+        assert!(!node.chain.notarized.contains(&block_a));
+        node.chain.votes.get_mut(&block_a).unwrap().insert(2);
+        node.notarize(block_a);
+        assert!(node.chain.notarized.contains(&block_a));
+        node.chain.votes.insert(block_b, (0..4).collect());
+        node.notarize(block_b);
+        node.notarize(block_b);
+        assert!(node.chain.finalized.contains(&block_a));
+        assert_eq!(node.stats.blocks_notarized, 2);
+        assert_eq!(node.stats.finalize_attempts, 2);
+
+        // The total stake of each validator set follows changes of the stake and the sets
+        assert_eq!(node.validator_stake[0].1, 4);
+        node.set_stake(vec![1, 1, 1, 3]);
+        assert_eq!(node.validator_stake[0].1, 6);
+        node.set_validators(5, vec![0, 3]);
+        assert_eq!(node.validator_stake[1], (HashSet::from([0, 3]), 4));
+    }
+
+    /// Extend the protocol by a custom kind of message, through which nodes announce their
+    /// highest finalized block. A registered handler processes it, and rejects announcements of
+    /// blocks the receiver did not finalize. Nodes without a handler ignore it.
+    fn test_custom_message() {
+        TestNetwork::print_test_case_header("Custom message handlers");
+
+        let n = 4;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(6);
+        TestNetwork::validate(&network);
+
+        // The receivers and senders of the checkpoints that matched the receiver's chain
+        let confirmed = Arc::new(Mutex::new(Vec::new()));
+        let handler: MessageHandler = {
+            let confirmed = confirmed.clone();
+            Arc::new(move |node: &mut Node, m: &dyn Message, j: usize| {
+                let checkpoint = m.as_any().downcast_ref::<CheckpointMessage>().unwrap();
+                if !node.chain.finalized.contains(&checkpoint.block_hash) {
+                    return Err(ConsensusError::InvalidMessage { kind: m.kind() });
+                }
+                confirmed.lock().unwrap().push((node.id, j));
+                Ok(())
+            })
+        };
+        network.register_handler("checkpoint", handler);
+
+        let node0 = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        let checkpoint = CheckpointMessage {
+            creator: 0,
+            block_hash: *node0.chain.highest_finalized_block(),
+        };
+        for j in 1..n {
+            network.send(0, Box::new(checkpoint.clone()), j);
+        }
+        let bogus = CheckpointMessage {
+            creator: 1,
+            block_hash: Crypto::hash(HashAlgo::default(), b"not a block"),
+        };
+        network.send(1, Box::new(bogus.clone()), 2);
+        network.record_trace();
+        network.drain();
+
+        let mut confirmed = confirmed.lock().unwrap().clone();
+        confirmed.sort();
+        assert_eq!(confirmed, vec![(1, 0), (2, 0), (3, 0)]);
+        // Custom messages cannot be serialized, so neither can traces containing them
+        let err = (&checkpoint as &dyn Message).to_bytes().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let path = std::env::temp_dir().join(format!("custom-{}.bin", std::process::id()));
+        assert!(network.save_trace(&path).is_err());
+        let node2 = network.nodes[2].as_any().downcast_ref::<Node>().unwrap();
+        assert!(node2.events.iter().any(|record| record.event
+            == Event::AttackDetected {
+                reason: format!("{}: Invalid checkpoint message", bogus.name()),
+            }));
+
+        // Without a handler, the message is ignored
+        let mut node = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        node.incoming_message(&checkpoint, 1);
+        assert!(node.handlers().is_empty());
+        assert!(node.events.iter().any(|record| matches!(
+            &record.event,
+            Event::AttackDetected { reason } if reason.contains("unknown kind checkpoint")
+        )));
+    }
+
+    /// A node rejects a very old proposal and votes for it, since they can only concern an
+    /// abandoned fork, but accepts old blocks above its finalized height when catching up
+    fn test_stale_epoch() {
+        TestNetwork::print_test_case_header("Stale proposals");
+        let n = 4;
+        let epochs = 20;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+
+        let node = network.nodes[0]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap();
+        assert_eq!(node.current_epoch(), epochs);
+        let genesis = node.chain.genesis;
+        let leader = node.leader(1);
+        let b = BlockMessage::new(
+            leader,
+            Some(genesis),
+            1,
+            vec![],
+            "old".to_string(),
+            leader,
+            (0, vec![]),
+        );
+        let block_hash = b.block_hash(HashAlgo::Sha256);
+        let signature = TestNetwork::signature(leader, MessageType::BlockProposal, block_hash);
+        let old = BlockMessage { signature, ..b };
+        let stale = Err(ConsensusError::StaleEpoch {
+            e: 1,
+            current_epoch: epochs,
+        });
+        let blocks = node.chain.blocks.len();
+        node.incoming_message(&old, leader);
+        assert_eq!(node.receive_block(old.clone(), leader), stale);
+        assert_eq!(node.chain.blocks.len(), blocks);
+        assert!(!node.chain.contains_block(old.block_hash(HashAlgo::Sha256)));
+        assert!(node.clear_outgoing_messages().is_empty());
+
+        let block = Block::new(
+            Some(genesis),
+            1,
+            vec![],
+            "old".to_string(),
+            1,
+            HashAlgo::default(),
+        );
+        assert_eq!(
+            node.receive_vote(TestNetwork::signed_vote(&block, leader)),
+            stale
+        );
+        assert!(!node.chain.votes.contains_key(&block.hash));
+
+        // A node that finalized nothing yet still needs old blocks to catch up
+        let mut node = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        node.new_epoch(epochs, 0);
+        assert_ne!(node.receive_block(old.clone(), leader), stale);
+        assert!(node.chain.contains_block(old.block_hash(HashAlgo::Sha256)));
+    }
+
+    /// The summary of a run counts the blocks of all honest nodes, the blocks with siblings and
+    /// the longest fork abandoned in favor of the finalized chain
+    fn test_run_summary() {
+        TestNetwork::print_test_case_header("Run summary");
+        // Blocks a, b, c are finalized. The fork x, y branches off genesis and the fork z off a.
+        // The competing blocks d and e extend the finalized chain and are not abandoned.
+        let mut network = Network::new(2);
+        let node = network.nodes[0]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap();
+        let genesis = node.chain.genesis;
+        let a = TestNetwork::extend_finalized(&mut node.chain, genesis, 1, "a");
+        let b = TestNetwork::extend_finalized(&mut node.chain, a, 2, "b");
+        let c = TestNetwork::extend_finalized(&mut node.chain, b, 3, "c");
+        let x = TestNetwork::extend_chain(&mut node.chain, genesis, 4, "x");
+        TestNetwork::extend_chain(&mut node.chain, x, 5, "y");
+        let d = TestNetwork::extend_chain(&mut node.chain, c, 6, "d");
+        node.chain.notarized.insert(d);
+        let node = network.nodes[1]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap();
+        let a = TestNetwork::extend_finalized(&mut node.chain, genesis, 1, "a");
+        TestNetwork::extend_chain(&mut node.chain, a, 7, "z");
+        let b = TestNetwork::extend_finalized(&mut node.chain, a, 2, "b");
+        let c = TestNetwork::extend_finalized(&mut node.chain, b, 3, "c");
+        TestNetwork::extend_chain(&mut node.chain, c, 8, "e");
+        assert_eq!(
+            TestNetwork::print_summary(&network),
+            RunSummary {
+                blocks_proposed: 8,
+                blocks_notarized: 4,
+                blocks_finalized: 3,
+                forks: 6,
+                longest_fork: 2,
+                fork_count: 2,
+                max_fork_depth: 4,
+                participation: 0.0,
+                transactions_finalized: 3,
+                transactions_submitted: None,
+            }
+        );
+
+        // Under an equivocation attack, only transactions of the workload are counted
+        let n = 7;
+        let mut network = ConsensusBuilder::new(n)
+            .attackers(2, &["equivocate"])
+            .network_model(NetworkModel::DelaysThenSynchrony(0.5))
+            .build();
+        network.set_workload(Workload::uniform(40));
+        network.run(20);
+        TestNetwork::validate(&network);
+        let summary = TestNetwork::summary(&network);
+        assert!(summary.blocks_finalized > 0);
+        assert!(summary.blocks_finalized <= summary.blocks_notarized);
+        assert!(summary.blocks_notarized <= summary.blocks_proposed);
+        assert_eq!(summary.transactions_submitted, Some(40));
+        assert!(0 < summary.transactions_finalized && summary.transactions_finalized <= 40);
+    }
+
+    /// Relayed messages never return to the node that signed them. In a complete network of n
+    /// nodes, each vote is thus delivered (n-1)^2 times: n-1 times by its signer, and n-2 times by
+    /// each of the other nodes relaying it.
+    fn test_relay_skips_origin() {
+        TestNetwork::print_test_case_header("Relays skip the origin of a message");
+        let n = 7;
+        let mut network = Network::new(n);
+        network.record_trace();
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(5);
+        TestNetwork::validate(&network);
+
+        let mut deliveries: HashMap<(usize, Hash), usize> = HashMap::new();
+        for (_, _, receiver, m) in network.trace().unwrap() {
+            if let Some(vote) = m.as_any().downcast_ref::<VoteMessage>() {
+                assert_ne!(vote.signer, *receiver);
+                *deliveries
+                    .entry((vote.signer, vote.block_hash(HashAlgo::Sha256)))
+                    .or_default() += 1;
+            } else if let Some(block) = m.as_any().downcast_ref::<BlockMessage>() {
+                assert_ne!(block.signer, *receiver);
+            }
+        }
+        assert!(!deliveries.is_empty());
+        assert!(deliveries.values().all(|count| *count == (n - 1) * (n - 1)));
+    }
+
+    /// The tightest safety stress case: the maximal number f = floor(n/3) of attackers (still
+    /// fewer than n/3) collude, propose two blocks of each epoch they lead, vote for both of
+    /// them and for every block on every fork, while the adversarial scheduler splits the honest
+    /// nodes. The attackers try to notarize competing blocks of the same height, and to extend
+    /// both with children of consecutive epochs to finalize conflicting chains. Consistency must
+    /// still hold; if it fails, the protocol is unsafe at the threshold.
+    /// Since all messages of the adversarial schedule arrive within the epoch, honest nodes never
+    /// notarize competing blocks of the same height there. A crafted schedule does make them: the
+    /// votes for the attacker's block of epoch 1 only reach one honest node, so another honest
+    /// node votes for the attacker's competing block of a later epoch, which thus also gets a
+    /// quorum. Quorums intersect in that node, and the chains still finalize consistently.
+    fn test_threshold_safety() {
+        TestNetwork::print_test_case_header("Safety at the attacker threshold");
+        let mut attacker_config = HashSet::new();
+        for config in [
+            "equivocate",
+            "vote_equivocate",
+            "vote_all_forks",
+            "vote_everything",
+        ] {
+            attacker_config.insert(config.to_string());
+        }
+        let epochs = 30;
+        for n in [4, 7, 10] {
+            let num_attackers = n / 3;
+            assert!(3 * num_attackers < n);
+            let mut network = Network::new_byzantine(n, num_attackers, attacker_config.clone());
+            network.set_colluding();
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_adversarial_schedule(epochs);
+            TestNetwork::validate(&network);
+
+            // The attackers did create competing blocks
+            let summary = TestNetwork::summary(&network);
+            assert!(summary.forks > 0);
+        }
+
+        // The crafted schedule, with n = 4 and the leader of epoch 1 as the attacker, whose
+        // messages we sign ourselves. It never runs its own node.
+        let n = 4;
+        let mut network = Network::new(n);
+        let attacker = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH).leader(1);
+        let honest: Vec<usize> = (0..n).filter(|i| *i != attacker).collect();
+        let (x, w, y) = (honest[0], honest[1], honest[2]);
+        fn node(network: &mut Network, i: usize) -> &mut Node {
+            network.nodes[i]
+                .as_any_mut()
+                .downcast_mut::<Node>()
+                .unwrap()
+        }
+        let genesis = node(&mut network, x).chain.genesis;
+        let e2 = (2..)
+            .find(|e| node(&mut network, x).leader(*e) == attacker)
+            .unwrap();
+        let mut delayed = Vec::new();
+        let mut competing = Vec::new();
+        for (e, voters, notarizer) in [(1, [x, w], x), (e2, [w, y], y)] {
+            let block = Block::new(
+                Some(genesis),
+                e,
+                Vec::new(),
+                format!("{}/competing", e),
+                1,
+                HashAlgo::default(),
+            )
+            .with_proposer(Some(attacker));
+            let proposal = TestNetwork::signed_proposal(&block, attacker);
+            for i in honest.iter() {
+                node(&mut network, *i).enter_epoch(e, e as u64);
+            }
+            for i in voters {
+                network.nodes[i].incoming_message(&proposal, attacker);
+            }
+            // Only the notarizer sees the quorum, the other votes are held back
+            let voter = voters.into_iter().find(|i| *i != notarizer).unwrap();
+            let vote = TestNetwork::signed_vote(&block, voter);
+            network.nodes[notarizer].incoming_message(&vote, voter);
+            for i in honest.iter() {
+                for (j, m) in network.nodes[*i].clear_outgoing_messages() {
+                    delayed.push((*i, j, m));
+                }
+            }
+            assert!(node(&mut network, notarizer)
+                .chain
+                .notarized
+                .contains(&block.hash));
+            competing.push(block.hash);
+        }
+        assert_eq!(TestNetwork::contested_heights(&network), 1);
+
+        // The held back messages arrive, and the honest nodes continue in synchrony, the attacker
+        // voting for every block
+        let deliver = |network: &mut Network,
+                       mut messages: Vec<(usize, usize, Box<dyn Message>)>| loop {
+            for i in honest.iter() {
+                for (j, m) in network.nodes[*i].clear_outgoing_messages() {
+                    messages.push((*i, j, m));
+                }
+            }
+            if messages.is_empty() {
+                break;
+            }
+            for (i, j, m) in messages.drain(..) {
+                if j != attacker {
+                    network.nodes[j].incoming_message(&*m, i);
+                }
+            }
+        };
+        deliver(&mut network, delayed);
+        for e in e2 + 1..e2 + 20 {
+            for i in honest.iter() {
+                network.nodes[*i].new_epoch(e, e as u64);
+            }
+            deliver(&mut network, Vec::new());
+            let blocks: HashSet<Hash> = honest
+                .iter()
+                .flat_map(|i| node(&mut network, *i).chain.block_by_epoch.get(e).cloned())
+                .flatten()
+                .collect();
+            for block_hash in blocks {
+                let block = node(&mut network, x).chain.blocks[&block_hash].clone();
+                let vote = TestNetwork::signed_vote(&block, attacker);
+                let votes = honest
+                    .iter()
+                    .map(|i| (attacker, *i, vote.clone_box()))
+                    .collect();
+                deliver(&mut network, votes);
+            }
+        }
+        assert!(TestNetwork::contested_heights(&network) > 0);
+        assert!(TestNetwork::consistency(&network));
+        for i in honest.iter() {
+            let chain = &node(&mut network, *i).chain;
+            assert!(competing.iter().all(|h| chain.notarized.contains(h)));
+            assert!(chain.safe_height() > 1);
+        }
+    }
+
+    /// All validators vote for the notarized blocks of an honest network. Silent attackers lower
+    /// the participation, which explains why fewer blocks get notarized. Participation is weighted
+    /// by stake.
+    fn test_participation_rate() {
+        TestNetwork::print_test_case_header("Vote participation rates");
+        let n = 4;
+        let epochs = 10;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+        assert_eq!(network.average_participation(), 1.0);
+
+        for config in ["fail_stop", "withhold_votes"] {
+            let mut network = ConsensusBuilder::new(n).attackers(1, &[config]).build();
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run(epochs);
+            TestNetwork::validate(&network);
+            let participation = network.average_participation();
+            TestNetwork::dbg(
+                &format!(
+                    "Participation with a {} attacker: {:.3}",
+                    config, participation
+                ),
+                None,
+                None,
+            );
+            assert!((0.75..1.0).contains(&participation));
+        }
+
+        // Votes of nodes that are not validators do not count
+        let mut chain = Blockchain::new(0);
+        let genesis = chain.genesis;
+        let block_hash = TestNetwork::extend_chain(&mut chain, genesis, 1, "a");
+        assert_eq!(chain.participation_rate(block_hash, &[0, 1, 2, 3]), 0.0);
+        chain.votes.insert(block_hash, HashSet::from([0, 2, 7]));
+        assert_eq!(chain.participation_rate(block_hash, &[0, 1, 2, 3]), 0.5);
+        assert_eq!(chain.participation_rate(block_hash, &[]), 0.0);
+
+        // Votes are weighted by the stake of the voters
+        chain.stake = vec![1, 1, 1, 3];
+        assert_eq!(
+            chain.participation_rate(block_hash, &[0, 1, 2, 3]),
+            2.0 / 6.0
+        );
+        chain.votes.insert(block_hash, HashSet::from([3]));
+        assert_eq!(chain.participation_rate(block_hash, &[0, 1, 2, 3]), 0.5);
+    }
+
+    /// The serialized transactions of a block must be shorter than MAXLENGTH_TXS bytes: a block of
+    /// MAXLENGTH_TXS - 1 bytes is valid, one of MAXLENGTH_TXS bytes is not. A leading attacker
+    /// proposes blocks of both sizes, and honest nodes only store those of the valid size.
+    fn test_oversized_block() {
+        TestNetwork::print_test_case_header("Attacker proposing oversized blocks");
+        let n = 4;
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("oversized_block".to_string());
+        let attacker = AttackerNode::new(n - 1, (0..n).collect(), attacker_config.clone());
+        let block = |chain: &Blockchain, size: usize| {
+            let txs = attacker.padded_transactions(1, size);
+            assert_eq!(Transaction::serialize_all(&txs).len(), size);
+            assert!(txs.iter().all(|tx| tx.payload.len() <= MAXLENGTH_SINGLE_TX));
+            Block::new(
+                Some(chain.genesis),
+                1,
+                txs,
+                format!("padded to {}", size),
+                1,
+                HashAlgo::default(),
+            )
+        };
+        let mut chain = Blockchain::new(0);
+        for size in [MAXLENGTH_TXS, MAXLENGTH_TXS + 1] {
+            let oversized = block(&chain, size);
+            assert!(!chain.validate_and_extend(oversized.clone(), chain.genesis));
+            assert!(!chain.contains_block(oversized.hash));
+        }
+        let at_limit = block(&chain, MAXLENGTH_TXS - 1);
+        assert!(chain.validate_and_extend(at_limit.clone(), chain.genesis));
+        assert!(chain.contains_block(at_limit.hash));
+
+        let epochs = 30;
+        let mut network = Network::new_byzantine(n, 1, attacker_config);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+        let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        let led: Vec<usize> = (1..=epochs).filter(|e| node.leader(*e) == n - 1).collect();
+        assert!(led.iter().any(|e| e % 2 == 0) && led.iter().any(|e| e % 2 == 1));
+        for node in network
+            .nodes
+            .iter()
+            .filter_map(|node| node.as_any().downcast_ref::<Node>())
+        {
+            let padded: Vec<usize> = node
+                .chain
+                .blocks
+                .values()
+                .filter(|block| block.name.contains("padded"))
+                .map(|block| Transaction::serialize_all(&block.txs).len())
+                .collect();
+            assert!(!padded.is_empty());
+            assert!(padded.iter().all(|size| *size == MAXLENGTH_TXS - 1));
+        }
+    }
+
+    /// Messages sent by TcpTransport are framed by their length: a sequence of frames reads back
+    /// as the same messages, and truncated or overly long frames are rejected. A TcpTransport
+    /// rejects node ids it has no address for, and stops listening once dropped.
+    fn test_frame() {
+        TestNetwork::print_test_case_header("Framing of messages sent over TCP");
+        let mut network = Network::new(4);
+        network.record_trace();
+        network.run(2);
+        let messages: Vec<&Box<dyn Message>> = network
+            .trace()
+            .unwrap()
+            .iter()
+            .map(|(_, _, _, m)| m)
+            .collect();
+        let mut stream = Vec::new();
+        for m in messages.iter() {
+            write_frame(&mut stream, &m.to_bytes().unwrap()).unwrap();
+        }
+        let mut reader = &stream[..];
+        for m in messages.iter() {
+            let received = <dyn Message>::from_bytes(&read_frame(&mut reader).unwrap()).unwrap();
+            assert_eq!(received.name(), m.name());
+            assert_eq!(received.to_bytes().unwrap(), m.to_bytes().unwrap());
+        }
+        assert!(reader.is_empty());
+        assert!(read_frame(&mut reader).is_err());
+
+        // A truncated frame
+        let mut reader = &stream[..stream.len() - 1];
+        let complete = (0..messages.len())
+            .take_while(|_| read_frame(&mut reader).is_ok())
+            .count();
+        assert_eq!(complete, messages.len() - 1);
+
+        // A frame announcing more than MAX_FRAME_LEN bytes is rejected before it is read
+        let header = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes();
+        let err = read_frame(&mut &header[..]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(write_frame(&mut Vec::new(), &vec![0; MAX_FRAME_LEN + 1]).is_err());
+
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let err = TcpTransport::bind(1, vec![addr]).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let mut transport = TcpTransport::bind(0, vec![addr]).unwrap();
+        let err = transport.send(messages[0].clone(), 1).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        drop(transport);
+        assert!(TcpListener::bind(addr).is_ok());
+    }
+
+    /// A node joining in epoch 15 of a 20-epoch run catches up on the finalized chain from the
+    /// responses of its peers to its sync request, and ends consistent with the others. Its old
+    /// blocks carry a quorum of votes, which it only learns from the certificates of the sync
+    /// responses. Responses with too few or forged signatures are rejected. Nodes only answer
+    /// validators, and each of them once per epoch.
+    fn test_late_joiner_sync() {
+        TestNetwork::print_test_case_header("Late joiner syncing the chain");
+        let n = 4;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_with_churn(20, &[(15, Churn::Add)]);
+        TestNetwork::validate(&network);
+        let joiner = network.nodes[n].as_any().downcast_ref::<Node>().unwrap();
+        let chain = joiner.chain.finalized_chain();
+        let other = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        assert!(other.chain.safe_height() >= 15);
+        assert!(
+            other
+                .chain
+                .safe_height()
+                .abs_diff(joiner.chain.safe_height())
+                <= 1
+        );
+        assert_eq!(chain[..], other.chain.finalized_chain()[..chain.len()]);
+        assert!(chain[1..]
+            .iter()
+            .all(|h| joiner.certified_block(*h).is_some()));
+
+        let mut network = Network::new(n);
+        network.run(10);
+        let node = network.nodes[0]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap();
+        node.outgoing_messages.clear();
+        let request = |creator: usize| SyncRequest {
+            creator,
+            from_height: 1,
+        };
+        node.receive_sync_request(&request(n), n);
+        assert!(node.outgoing_messages.is_empty());
+        let requester = 1;
+        node.receive_sync_request(&request(requester), requester);
+        let (receiver, m) = node.outgoing_messages.pop_front().unwrap();
+        assert_eq!(receiver, requester);
+        node.receive_sync_request(&request(requester), requester);
+        assert!(node.outgoing_messages.is_empty());
+        let response = m.as_any().downcast_ref::<SyncResponse>().unwrap().clone();
+        let expected = node.chain.finalized_chain();
+        assert!(response.blocks.len() >= expected.len() - 1);
+
+        let mut joiner = Node::new(n, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        assert_eq!(joiner.receive_sync_response(&response), Ok(()));
+        assert_eq!(joiner.chain.finalized_chain(), expected);
+
+        let mut joiner = Node::new(n, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        let mut too_few = response.clone();
+        // Two of the four signatures are no quorum
+        assert_eq!(too_few.blocks[0].signatures.len(), n);
+        too_few.blocks[0].signatures.retain(|voter, _| *voter < 2);
+        assert_eq!(
+            joiner.receive_sync_response(&too_few),
+            Err(ConsensusError::MissingQuorum {
+                e: too_few.blocks[0].e
+            })
+        );
+        assert_eq!(joiner.chain.blocks.len(), 1);
+
+        let mut forged = response.clone();
+        let (first, second) = {
+            let mut voters = forged.blocks[0].signatures.keys();
+            (*voters.next().unwrap(), *voters.next().unwrap())
+        };
+        let signature = forged.blocks[0].signatures[&first].clone();
+        forged.blocks[0].signatures.insert(second, signature);
+        assert_eq!(
+            joiner.receive_sync_response(&forged),
+            Err(ConsensusError::BadSignature { signer: second })
+        );
+        assert_eq!(joiner.chain.blocks.len(), 1);
+    }
+
+    /// The heaviest subtree rule extends the notarized fork with the most stake in votes, and
+    /// votes only for children of its head, and only if the head is a highest notarized block.
+    /// Without forks, both rules finalize the same chain. Under equivocation, both rules decide
+    /// between competing notarized blocks, stay consistent and keep finalizing blocks.
+    fn test_fork_choice() {
+        TestNetwork::print_test_case_header("Fork choice rules");
+        let mut chain = Blockchain::new(0);
+        let genesis = chain.genesis;
+        let add = |chain: &mut Blockchain, parent: Hash, e: usize, voters: usize| {
+            let height = chain.blocks[&parent].height + 1;
+            let block = Block::new(
+                Some(parent),
+                e,
+                Vec::new(),
+                format!("{}/fork", e),
+                height,
+                HashAlgo::default(),
+            );
+            assert!(chain.validate_and_extend(block.clone(), parent));
+            chain.notarized.insert(block.hash);
+            chain.votes.insert(block.hash, (0..voters).collect());
+            block.hash
+        };
+        let b1 = add(&mut chain, genesis, 1, 3);
+        let b2 = add(&mut chain, genesis, 2, 1);
+        assert_eq!(HighestNotarizedEpoch.head(&chain), b2);
+        assert_eq!(HeaviestSubtree.head(&chain), b1);
+        // The subtree of b2 outweighs b1 once b3 extends it
+        let b3 = add(&mut chain, b2, 3, 3);
+        assert_eq!(HighestNotarizedEpoch.head(&chain), b3);
+        assert_eq!(HeaviestSubtree.head(&chain), b3);
+        let child = |chain: &Blockchain, parent: Hash| {
+            let height = chain.blocks[&parent].height + 1;
+            Block::new(
+                Some(parent),
+                4,
+                Vec::new(),
+                "4/fork".to_string(),
+                height,
+                HashAlgo::default(),
+            )
+        };
+        let may_vote = |rule: &dyn ForkChoice, chain: &Blockchain, parent: Hash| {
+            rule.may_vote(chain, rule.head(chain), &child(chain, parent))
+        };
+        assert!(may_vote(&HighestNotarizedEpoch, &chain, b3));
+        assert!(may_vote(&HeaviestSubtree, &chain, b3));
+        assert!(!may_vote(&HeaviestSubtree, &chain, b1));
+        // The heavier fork of b1 is lower than b3, so the heaviest subtree rule votes for nothing
+        chain.votes.insert(b1, (0..10).collect());
+        assert_eq!(HeaviestSubtree.head(&chain), b1);
+        assert!(!may_vote(&HeaviestSubtree, &chain, b1));
+        assert!(!may_vote(&HeaviestSubtree, &chain, b3));
+        assert!(may_vote(&HighestNotarizedEpoch, &chain, b3));
+        // Votes weigh by stake: the single vote for b3 by a validator of large stake outweighs
+        // the votes for b1
+        chain.votes.insert(b3, HashSet::from([10]));
+        chain.stake = vec![1; 10];
+        chain.stake.push(20);
+        assert_eq!(HeaviestSubtree.head(&chain), b3);
+
+        // Without competing blocks, both rules extend the same blocks, so honest networks
+        // finalize the same chain
+        let n = 4;
+        let finalized_chains: Vec<Vec<Hash>> =
+            [&HighestNotarizedEpoch as &dyn ForkChoice, &HeaviestSubtree]
+                .into_iter()
+                .map(|rule| {
+                    let mut network = Network::new(n);
+                    network.set_fork_choice(rule);
+                    TestNetwork::generate_transactions(&mut network.nodes, n);
+                    network.run_simple(10);
+                    TestNetwork::validate(&network);
+                    let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+                    node.chain.finalized_chain()
+                })
+                .collect();
+        assert!(finalized_chains[0].len() > 1);
+        assert_eq!(finalized_chains[0], finalized_chains[1]);
+
+        // Equivocating attackers that vote for every fork, while messages are delayed, cause
+        // competing notarized blocks for the fork choice rules to decide between
+        let n = 7;
+        let epochs = 30;
+        let mut attacker_config = HashSet::new();
+        for config in ["equivocate", "vote_equivocate", "vote_all_forks"] {
+            attacker_config.insert(config.to_string());
+        }
+        let rules: [(&str, &dyn ForkChoice); 2] = [
+            ("highest notarized epoch", &HighestNotarizedEpoch),
+            ("heaviest subtree", &HeaviestSubtree),
+        ];
+        let mut finalized_heights = Vec::new();
+        for (name, rule) in rules {
+            let mut network = Network::new_byzantine(n, 2, attacker_config.clone());
+            network.set_colluding();
+            network.set_fork_choice(rule);
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            network.run_delays_then_synchrony(epochs, 0.3);
+            TestNetwork::validate(&network);
+            let contested = TestNetwork::contested_heights(&network);
+            TestNetwork::dbg(
+                &format!(
+                    "{}: {} heights with competing notarized blocks, finalized up to height {}",
+                    name,
+                    contested,
+                    network.global_safe_height()
+                ),
+                None,
+                None,
+            );
+            // The rules had to decide between competing notarized blocks, and kept the finalized
+            // chains of the honest nodes consistent
+            assert!(contested > 0);
+            assert!(TestNetwork::consistency(&network));
+            finalized_heights.push(network.global_safe_height());
+        }
+        // Both rules keep finalizing blocks
+        assert!(finalized_heights.iter().all(|height| *height >= epochs / 2));
+    }
+
+    /// Networks with different chain ids start from different genesis blocks, so none of their
+    /// block hashes coincide, and the messages of one network are useless in the other. Networks
+    /// with the same configuration produce the same blocks.
+    fn test_genesis_chain_id() {
+        TestNetwork::print_test_case_header("Genesis chain id");
+        let n = 4;
+        let epochs = 10;
+        let block_hashes = |network: &Network| -> HashSet<Hash> {
+            let node = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+            node.chain.blocks.keys().copied().collect()
+        };
+        let run = |chain_id: u64| {
+            let mut network = ConsensusBuilder::new(n)
+                .genesis(GenesisConfig::with_chain_id(chain_id))
+                .build();
+            network.record_trace();
+            network.run(epochs);
+            TestNetwork::validate(&network);
+            network
+        };
+        let network1 = run(1);
+        let network2 = run(2);
+        let chain1 = &network1.nodes[0]
+            .as_any()
+            .downcast_ref::<Node>()
+            .unwrap()
+            .chain;
+        let chain2 = &network2.nodes[0]
+            .as_any()
+            .downcast_ref::<Node>()
+            .unwrap()
+            .chain;
+        assert_eq!(chain1.genesis_config.chain_id, 1);
+        assert!(!chain1.same_genesis(chain2));
+        assert!(block_hashes(&network1).is_disjoint(&block_hashes(&network2)));
+        assert_eq!(block_hashes(&network1), block_hashes(&run(1)));
+        // The default configuration keeps the genesis hash of a chain without chain id
+        assert_eq!(
+            Blockchain::new(0).genesis,
+            Block::genesis(&GenesisConfig::default()).hash
+        );
+        assert!(!Blockchain::new(0).same_genesis(chain1));
+
+        // Replaying the messages of network 1 to a node of network 2 finalizes nothing
+        let mut node = Node::with_genesis(0, chain2.genesis_config.clone()).unwrap();
+        for (_, i, j, m) in network1.trace().unwrap() {
+            if *j == 0 {
+                node.incoming_message(&**m, *i);
+            }
+        }
+        assert_eq!(node.chain.blocks.len(), 1);
+        assert_eq!(node.chain.finalized.len(), 1);
+    }
+
+    /// The forks of a chain are its maximal paths from a leaf down to genesis. Equivocating
+    /// leaders make honest nodes store several forks, while their finalized chains still agree.
+    fn test_forks() {
+        TestNetwork::print_test_case_header("Forks of the block tree");
+        // a and b extend genesis, c and d extend a, and e extends d
+        let mut chain = Blockchain::new(0);
+        let genesis = chain.genesis;
+        assert_eq!(chain.forks(), vec![vec![genesis]]);
+        assert_eq!(chain.max_fork_depth(), 0);
+        let a = TestNetwork::extend_finalized(&mut chain, genesis, 1, "a");
+        assert_eq!(chain.fork_count(), 1);
+        assert_eq!(chain.max_fork_depth(), 0);
+        let b = TestNetwork::extend_chain(&mut chain, genesis, 2, "b");
+        let c = TestNetwork::extend_chain(&mut chain, a, 3, "c");
+        let d = TestNetwork::extend_chain(&mut chain, a, 4, "d");
+        let e = TestNetwork::extend_chain(&mut chain, d, 5, "e");
+        assert_eq!(
+            chain.forks(),
+            vec![
+                vec![e, d, a, genesis],
+                vec![c, a, genesis],
+                vec![b, genesis]
+            ]
+        );
+        assert_eq!(chain.fork_count(), 3);
+        assert_eq!(chain.max_fork_depth(), 2);
+
+        // Forks of an equivocating leader's blocks
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("equivocate".to_string());
+        let (n, num_attackers) = (4, 1);
+        let mut network = Network::new_byzantine(n, num_attackers, attacker_config);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_reorder(30);
+        TestNetwork::validate(&network);
+        let honest: Vec<&Node> = network
+            .nodes
+            .iter()
+            .filter_map(|node| node.as_any().downcast_ref::<Node>())
+            .collect();
+        assert!(honest.iter().any(|node| node.chain.fork_count() > 1));
+        assert!(TestNetwork::summary(&network).fork_count > 1);
+        for (node1, node2) in honest.iter().zip(honest.iter().skip(1)) {
+            assert_eq!(node1.chain.divergence_point(&node2.chain), None);
+        }
+    }
+
+    /// A leader that signs many distinct proposals for the same epoch only gets the first
+    /// MAX_PROPOSALS_PER_LEADER of them stored, which suffice to prove its equivocation. Further
+    /// proposals are dropped, while proposals we already accepted are still accepted again. The
+    /// cap also bounds the proposals with unknown parents that we keep in the unprocessed_pool.
+    /// A proposal we requested as the parent of another block is accepted despite the cap.
+    fn test_proposal_cap() {
+        TestNetwork::print_test_case_header("Cap on proposals per leader and epoch");
+        let n = 4;
+        let leader = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH).leader(1);
+        let mut node = Node::new(
+            (leader + 1) % n,
+            (0..n).collect(),
+            DEFAULT_FINALIZATION_DEPTH,
+        );
+        let proposals: Vec<BlockMessage> = (0..10)
+            .map(|i| {
+                let txs = vec![Transaction::new(leader, i, format!("spam {}", i))];
+                let block = Block::new(
+                    Some(node.chain.genesis),
+                    1,
+                    txs,
+                    format!("1/{}", i),
+                    1,
+                    HashAlgo::default(),
+                );
+                TestNetwork::signed_proposal(&block, leader)
+            })
+            .collect();
+        for (i, proposal) in proposals.iter().enumerate() {
+            let result = node.receive_block(proposal.clone(), leader);
+            if i >= MAX_PROPOSALS_PER_LEADER {
+                assert_eq!(
+                    result,
+                    Err(ConsensusError::TooManyProposals {
+                        e: 1,
+                        signer: leader
+                    })
+                );
+                assert!(result.unwrap_err().is_attack());
+            }
+        }
+        assert_eq!(node.chain.block_by_epoch[1].len(), MAX_PROPOSALS_PER_LEADER);
+        assert_eq!(node.chain.blocks.len(), MAX_PROPOSALS_PER_LEADER + 1);
+        assert!(node.slashed.contains(&leader));
+        assert_eq!(node.receive_block(proposals[0].clone(), leader), Ok(()));
+
+        // Proposals for epoch 2 extending a block we do not have wait in the unprocessed_pool,
+        // and count towards the cap as well
+        let leader = node.leader(2);
+        let mut node = Node::new(
+            (leader + 1) % n,
+            (0..n).collect(),
+            DEFAULT_FINALIZATION_DEPTH,
+        );
+        let unknown = Block::new(
+            Some(node.chain.genesis),
+            1,
+            Vec::new(),
+            "1".to_string(),
+            1,
+            HashAlgo::default(),
+        );
+        for i in 0..10 {
+            let txs = vec![Transaction::new(leader, i, format!("orphan {}", i))];
+            let block = Block::new(
+                Some(unknown.hash),
+                2,
+                txs,
+                format!("2/{}", i),
+                2,
+                HashAlgo::default(),
+            );
+            let proposal = TestNetwork::signed_proposal(&block, leader);
+            let result = node.receive_block(proposal.clone(), leader);
+            if i < MAX_PROPOSALS_PER_LEADER as u64 {
+                let missing = ConsensusError::MissingParent {
+                    parent: unknown.hash,
+                };
+                assert_eq!(result, Err(missing));
+                // Receiving a pooled proposal again is not another proposal
+                assert_eq!(node.receive_block(proposal, leader), Err(missing));
+            } else {
+                assert_eq!(
+                    result,
+                    Err(ConsensusError::TooManyProposals {
+                        e: 2,
+                        signer: leader
+                    })
+                );
+            }
+        }
+        assert_eq!(node.unprocessed_pool_len(), MAX_PROPOSALS_PER_LEADER);
+
+        // The leader of epoch 1 sent us two proposals, but the other nodes notarized a third one.
+        // A block of epoch 2 extending it makes us request it, and we accept the reply.
+        let leader = node.leader(1);
+        let next_leader = node.leader(2);
+        let receiver = (0..n).find(|i| *i != leader && *i != next_leader).unwrap();
+        let mut node = Node::new(receiver, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        for proposal in &proposals[..MAX_PROPOSALS_PER_LEADER] {
+            let _ = node.receive_block(proposal.clone(), leader);
+        }
+        assert_eq!(node.chain.block_by_epoch[1].len(), MAX_PROPOSALS_PER_LEADER);
+        let txs = vec![Transaction::new(leader, 10, "notarized".to_string())];
+        let parent = Block::new(
+            Some(node.chain.genesis),
+            1,
+            txs,
+            "1/notarized".to_string(),
+            1,
+            HashAlgo::default(),
+        );
+        let child = Block::new(
+            Some(parent.hash),
+            2,
+            Vec::new(),
+            "2".to_string(),
+            2,
+            HashAlgo::default(),
+        )
+        .with_proposer(Some(next_leader));
+        let result = node.receive_block(
+            TestNetwork::signed_proposal(&child, next_leader),
+            next_leader,
+        );
+        assert_eq!(
+            result,
+            Err(ConsensusError::MissingParent {
+                parent: parent.hash
+            })
+        );
+        let (_, request) = node.outgoing_messages.pop_back().unwrap();
+        let request = request.as_any().downcast_ref::<GetBlockMessage>().unwrap();
+        assert_eq!(request.block_hash, parent.hash);
+        let reply = TestNetwork::signed_proposal(&parent, leader);
+        assert_ne!(
+            node.receive_block(reply.clone(), next_leader),
+            Err(ConsensusError::TooManyProposals {
+                e: 1,
+                signer: leader
+            })
+        );
+        assert!(node.chain.contains_block(parent.hash));
+        node.process_unprocessed_pool();
+        assert!(node.chain.contains_block(child.hash));
+        // Once we hold the block, the exemption ends
+        let txs = vec![Transaction::new(leader, 11, "another".to_string())];
+        let another = Block::new(
+            Some(node.chain.genesis),
+            1,
+            txs,
+            "1/another".to_string(),
+            1,
+            HashAlgo::default(),
+        );
+        assert_eq!(
+            node.receive_block(TestNetwork::signed_proposal(&another, leader), leader),
+            Err(ConsensusError::TooManyProposals {
+                e: 1,
+                signer: leader
+            })
+        );
+
+        // Equivocating leaders still cannot break consistency, and no honest node stores more
+        // than the cap of blocks per epoch, as each epoch has a single leader
+        let mut attacker_config = HashSet::new();
+        attacker_config.insert("equivocate".to_string());
+        let mut network = Network::new_byzantine(n, 1, attacker_config);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_reorder(20);
+        TestNetwork::validate(&network);
+        for node in network
+            .nodes
+            .iter()
+            .filter_map(|node| node.as_any().downcast_ref::<Node>())
+        {
+            let block_by_epoch = &node.chain.block_by_epoch;
+            assert!(block_by_epoch
+                .iter()
+                .all(|blocks| blocks.len() <= MAX_PROPOSALS_PER_LEADER));
+        }
+    }
+
+    /// When the finalized chains of two honest nodes conflict, the consistency check reports the
+    /// lowest height at which they differ, and the blocks of both nodes at that height
+    fn test_consistency_divergence() {
+        TestNetwork::print_test_case_header("Consistency reports the diverging height");
+        let n = 4;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(10);
+        assert!(TestNetwork::consistency(&network));
+
+        // Node 1 forgets the finalized blocks above height 2, and instead finalizes a fork
+        let height = 2;
+        let e = network.epoch() + 1;
+        let node = network.nodes[1]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap();
+        let chain = node.chain.finalized_chain();
+        assert!(chain.len() > height + 1);
+        for block_hash in &chain[height + 1..] {
+            node.chain.finalized.remove(block_hash);
+        }
+        let fork = TestNetwork::extend_finalized(&mut node.chain, chain[height], e, "fork");
+
+        let node0 = network.nodes[0].as_any().downcast_ref::<Node>().unwrap();
+        let node1 = network.nodes[1].as_any().downcast_ref::<Node>().unwrap();
+        assert_eq!(node0.chain.divergence_point(&node1.chain), Some(height + 1));
+        assert_eq!(node1.chain.finalized_chain()[height + 1], fork);
+        assert_eq!(node0.chain.divergence_point(&node0.chain), None);
+        assert!(!TestNetwork::consistency(&network));
+    }
+
+    /// An adversary in the network re-sends old, genuinely signed blocks and votes in later
+    /// epochs. Honest nodes reject those of epochs far behind their own, and ignore the others,
+    /// so the replayed messages notarize nothing: the nodes end up with the same notarized and
+    /// finalized blocks as without the adversary.
+    fn test_replay_adversary() {
+        TestNetwork::print_test_case_header("Network replaying old messages");
+        let n = 4;
+        let epochs = 30;
+        let mut reference = Network::new(n);
+        TestNetwork::generate_transactions(&mut reference.nodes, n);
+        reference.run_simple(epochs);
+        let mut network = Network::new(n);
+        network.set_replay_adversary(20);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(epochs);
+        TestNetwork::validate(&network);
+        assert_eq!(network.replayed_messages().len(), 20 * (epochs - 1));
+        for i in 0..n {
+            let node = network.nodes[i].as_any().downcast_ref::<Node>().unwrap();
+            let reference_node = reference.nodes[i].as_any().downcast_ref::<Node>().unwrap();
+            assert_eq!(node.chain.notarized, reference_node.chain.notarized);
+            assert_eq!(node.chain.finalized, reference_node.chain.finalized);
+            assert_eq!(
+                node.stats().blocks_notarized,
+                reference_node.stats().blocks_notarized
+            );
+        }
+
+        // The replayed messages are of recent earlier epochs, and those of epochs far behind are
+        // rejected as stale
+        let mut stale = 0;
+        for (replay_epoch, sender, receiver, m) in network.replayed_messages().to_vec() {
+            let node = network.nodes[receiver]
+                .as_any_mut()
+                .downcast_mut::<Node>()
+                .unwrap();
+            let (e, result) = if let Some(vote) = m.as_any().downcast_ref::<VoteMessage>() {
+                (vote.e, node.receive_vote(vote.clone()))
+            } else {
+                let block = m.as_any().downcast_ref::<BlockMessage>().unwrap();
+                (block.e, node.receive_block(block.clone(), sender))
+            };
+            assert!(e < replay_epoch && replay_epoch <= e + REPLAY_HISTORY_EPOCHS);
+            if e + MAX_PAST_EPOCHS < epochs {
+                stale += 1;
+                let current_epoch = epochs;
+                assert_eq!(result, Err(ConsensusError::StaleEpoch { e, current_epoch }));
+            }
+        }
+        assert!(stale > 0);
+    }
+
+    /// The voters of a notarized block are sorted and form a quorum of the validators. Blocks
+    /// that are not notarized have no voters.
+    fn test_notarization_voters() {
+        TestNetwork::print_test_case_header("Voters of notarizations");
+        let n = 7;
+        let mut network = Network::new(n);
+        TestNetwork::generate_transactions(&mut network.nodes, n);
+        network.run_simple(10);
+        TestNetwork::validate(&network);
+        let node = network.nodes[0]
+            .as_any_mut()
+            .downcast_mut::<Node>()
+            .unwrap();
+        let validators: Vec<usize> = (0..n).collect();
+        let notarized: Vec<Hash> = node.chain.notarized.iter().copied().collect();
+        for block_hash in notarized
+            .iter()
+            .filter(|block_hash| **block_hash != node.chain.root)
+        {
+            let voters = node.chain.notarization_voters(*block_hash).unwrap();
+            assert!(voters.windows(2).all(|pair| pair[0] < pair[1]));
+            assert!(3 * voters.len() > 2 * n);
+            assert!(node.chain.is_quorum(&voters, &validators));
+        }
+        assert_eq!(
+            node.chain.notarization_voters(node.chain.genesis),
+            Some(Vec::new())
+        );
+
+        // The voters are those of the quorum when the block was notarized: late votes, even of
+        // validators, and votes of nodes outside the validator set are not reported
+        let parent = *node.chain.highest_finalized_block();
+        let block_hash = TestNetwork::extend_chain(&mut node.chain, parent, 11, "late votes");
+        let validator_set: HashSet<usize> = validators.iter().copied().collect();
+        for voter in [n, 4, 0, 2, 1, 3] {
+            node.chain.add_vote(block_hash, voter, &validator_set);
+        }
+        node.notarize(block_hash);
+        for voter in [5, 6, n + 1] {
+            node.chain.add_vote(block_hash, voter, &validator_set);
+        }
+        node.notarize(block_hash);
+        assert_eq!(
+            node.chain.notarization_voters(block_hash),
+            Some(vec![0, 1, 2, 3, 4])
+        );
+
+        // A block without votes is not notarized
+        let block_hash = TestNetwork::extend_chain(&mut node.chain, parent, 12, "unnotarized");
+        assert_eq!(node.chain.notarization_voters(block_hash), None);
+    }
+
+    /// A node that can finalize a block, but misses the votes of one of its ancestors, does not
+    /// treat the ancestor as notarized. It requests the certified blocks from the ancestor on,
+    /// repeats the request if no response arrives, and finalizes once a peer's response lets it
+    /// notarize the ancestor.
+    fn test_finalize_unnotarized_ancestor() {
+        TestNetwork::print_test_case_header("Finalization waits for unnotarized ancestors");
+        let n = 4;
+        let mut node = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        let mut peer = Node::new(1, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        let mut blocks = Vec::new();
+        let mut parent = node.chain.genesis;
+        for (e, payload) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            TestNetwork::extend_chain(&mut peer.chain, parent, e + 1, payload);
+            parent = TestNetwork::extend_chain(&mut node.chain, parent, e + 1, payload);
+            blocks.push(parent);
+        }
+        let [a, b, c, d] = blocks[..] else {
+            unreachable!()
+        };
+        // The peer receives all votes, our node all but those for a
+        for block_hash in blocks {
+            let block = node.chain.blocks[&block_hash].clone();
+            for voter in 0..n {
+                let vote = TestNetwork::signed_vote(&block, voter);
+                peer.receive_vote(vote.clone()).unwrap();
+                if block_hash != a {
+                    node.receive_vote(vote).unwrap();
+                }
+            }
+        }
+        assert!(peer.chain.finalized.contains(&c));
+        assert!(node.chain.notarized.contains(&d));
+        assert!(!node.chain.notarized.contains(&a));
+        assert!(!node.chain.finalized.contains(&c));
+        assert!(node.chain.check_finalized_subset_notarized());
+        assert_eq!(node.pending_finalizations(), 1);
+        let sync_requests = |node: &mut Node| -> Vec<SyncRequest> {
+            node.clear_outgoing_messages()
+                .into_iter()
+                .filter_map(|(_, m)| m.as_any().downcast_ref::<SyncRequest>().cloned())
+                .collect()
+        };
+        let requests = sync_requests(&mut node);
+        assert_eq!(requests.len(), n - 1);
+        assert!(requests.iter().all(|request| request.from_height == 1));
+
+        // The requests got lost, so the node repeats them after SYNC_RETRY_EPOCHS
+        for e in 1..SYNC_RETRY_EPOCHS {
+            node.enter_epoch(e, 0);
+            assert!(sync_requests(&mut node).is_empty());
+        }
+        node.enter_epoch(SYNC_RETRY_EPOCHS, 0);
+        let requests = sync_requests(&mut node);
+        assert_eq!(requests.len(), n - 1);
+        assert!(requests.iter().all(|request| request.from_height == 1));
+
+        // The peer's response carries the certificate of a, which resolves the pending
+        // finalization
+        peer.receive_sync_request(&requests[0], node.id);
+        let (_, response) = peer.clear_outgoing_messages().pop().unwrap();
+        let response = response.as_any().downcast_ref::<SyncResponse>().unwrap();
+        node.receive_sync_response(response).unwrap();
+        for block_hash in [a, b, c] {
+            assert!(node.chain.finalized.contains(&block_hash));
+        }
+        assert!(node.chain.check_finalized_subset_notarized());
+        assert_eq!(node.pending_finalizations(), 0);
+        node.enter_epoch(2 * SYNC_RETRY_EPOCHS, 0);
+        assert!(sync_requests(&mut node).is_empty());
+    }
+
+    /// A node receives the blocks of epochs 2 and 3 before their ancestor of epoch 1, as a node
+    /// catching up after joining late does. It requests the parent of a pooled block only once,
+    /// no matter how often it receives the block, so that a joiner does not flood its peers with
+    /// requests. Once the ancestor arrives, a single pass over the unprocessed_pool processes
+    /// both blocks, since it handles them in the order of their epochs; otherwise each pass
+    /// would only process one more block of a chain received in reverse order.
+    fn test_unprocessed_pool_order() {
+        TestNetwork::print_test_case_header("Order of the unprocessed pool");
+        let n = 4;
+        let mut node = Node::new(0, (0..n).collect(), DEFAULT_FINALIZATION_DEPTH);
+        let mut blocks = Vec::new();
+        let mut parent = node.chain.genesis;
+        for e in 1..=3 {
+            let tx = Transaction::new(n, e as u64, "pooled".to_string());
+            let block = Block::new(
+                Some(parent),
+                e,
+                vec![tx],
+                format!("{}/pooled", e),
+                e,
+                HashAlgo::default(),
+            );
+            parent = block.hash;
+            blocks.push(block);
+        }
+        let proposals: Vec<BlockMessage> = blocks
+            .iter()
+            .map(|block| TestNetwork::signed_proposal(block, node.leader(block.e)))
+            .collect();
+        let sender = 1;
+        node.incoming_message(&proposals[2], sender);
+        node.incoming_message(&proposals[2], sender);
+        node.incoming_message(&proposals[1], sender);
+        let requests = node
+            .clear_outgoing_messages()
+            .iter()
+            .filter(|(_, m)| m.kind() == "get_block")
+            .count();
+        assert_eq!(requests, 2);
+        assert_eq!(node.unprocessed_pool_len(), 2);
+
+        node.incoming_message(&proposals[0], sender);
+        node.process_unprocessed_pool();
+        assert_eq!(node.unprocessed_pool_len(), 0);
+        assert!(blocks
+            .iter()
+            .all(|block| node.chain.contains_block(block.hash)));
+    }
+
+    /// A node that was partitioned off misses the blocks of the other nodes, which are lost. Once
+    /// the partition heals, it requests the parents of the proposals it receives from their
+    /// senders, and catches up with the others. If it only waited in its unprocessed_pool
+    /// instead, the lost parents would never arrive, and finalization would fall behind.
+    fn test_get_block_after_partition() {
+        TestNetwork::print_test_case_header("Request missing parents after a partition");
+        let n = 4;
+        let epochs = 12;
+        let heal_epoch = 6;
+        let finalized_heights = |request_parents: bool| -> Vec<usize> {
+            let mut network = Network::new(n);
+            for node in network.nodes.iter_mut() {
+                let node = node.as_any_mut().downcast_mut::<Node>().unwrap();
+                node.request_parents = request_parents;
+            }
+            TestNetwork::generate_transactions(&mut network.nodes, n);
+            let partition = vec![HashSet::from([0, 1, 2]), HashSet::from([3])];
+            network.run_with_partition(epochs, partition, heal_epoch);
+            network
+                .nodes
+                .iter()
+                .map(|node| {
+                    let node = node.as_any().downcast_ref::<Node>().unwrap();
+                    node.finalized_height()
+                })
+                .collect()
+        };
+        let requesting = finalized_heights(true);
+        let waiting = finalized_heights(false);
+        TestNetwork::dbg(
+            &format!(
+                "Finalized heights when requesting parents: {:?}, when waiting: {:?}",
+                requesting, waiting
+            ),
+            None,
+            None,
+        );
+        assert!(requesting.iter().all(|height| *height > heal_epoch));
+        assert_eq!(waiting[3], 0);
+        assert!(requesting.iter().zip(&waiting).all(|(r, w)| r > w));
+    }
+
+    /// Validation means checking consistency of the chains (as defined in
+    /// the paper)
+    pub fn validate(network: &Network) -> bool {
+        TestNetwork::print_all(network);
+        TestNetwork::print_summary(network);
+        assert!(TestNetwork::consistency(network));
+        assert!(network.finalized_subset_notarized());
+        assert!(TestNetwork::safety_invariant(network));
+        // We expect at least one finalized block per five epochs
+        assert!(TestNetwork::liveness(network, network.epoch() / 5));
+        true
+    }
+
+    /// Check that the finalized blocks of each honest node form a single chain, i.e., that no
+    /// two of them conflict, see Blockchain::check_safety_invariant
+    fn safety_invariant(network: &Network) -> bool {
+        let mut ok = true;
+        for node in network
+            .nodes
+            .iter()
+            .filter_map(|node| node.as_any().downcast_ref::<Node>())
+        {
+            if let Err(violation) = node.chain.check_safety_invariant() {
+                TestNetwork::dbg(
+                    &violation.to_string(),
+                    Some(node.id),
+                    Some("SOUDNESS_ERROR"),
+                );
+                ok = false;
+            }
+        }
+        ok
+    }
+
+    /// Check liveness: every honest node has finalized at least min_finalized non-genesis blocks,
+    /// and the heights of the highest finalized blocks of honest nodes differ by at most one.
+    fn liveness(network: &Network, min_finalized: usize) -> bool {
+        // The id of each honest node with the height of its highest finalized block
+        let finalized_heights: Vec<(usize, usize)> = network
+            .nodes
+            .iter()
+            .filter_map(|node| node.as_any().downcast_ref::<Node>())
+            .map(|node| {
+                let height = node
+                    .chain
+                    .blocks
+                    .get(node.chain.highest_finalized_block())
+                    .unwrap()
+                    .height;
+                (node.id, height)
+            })
+            .collect();
+        for (id, height) in finalized_heights.iter() {
+            if *height < min_finalized {
+                TestNetwork::dbg(
+                    &format!(
+                        "Only {} blocks finalized, expected at least {}",
+                        height, min_finalized
+                    ),
+                    Some(*id),
+                    Some("ERROR"),
+                );
+                TestNetwork::print_lagging_peers(network);
+                return false;
+            }
+        }
+        let heights = finalized_heights.iter().map(|(_, height)| *height);
+        let min_height = heights.clone().min().unwrap_or(0);
+        let max_height = heights.max().unwrap_or(0);
+        if max_height - min_height > 1 {
+            TestNetwork::dbg(
+                &format!(
+                    "Finalized heights of honest nodes differ, by node id: {:?}",
+                    finalized_heights
+                ),
+                None,
+                Some("ERROR"),
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Check the consistency: If two blockchains are ever considered final by
+    /// two honest nodes, it must be that one is a prefix of another.
+    /// We show this of an execution in two steps: first, there is a unique
+    /// chain that consists of exactly the finalized blocks (meaning that all
+    /// of its blocks are finalized and no other blocks are finalized).
+    /// Second, this chain is equal between all honest nodes.
+    fn consistency(network: &Network) -> bool {
+        let honest_nodes: Vec<&Node> = network
+            .nodes
+            .iter()
+            .filter_map(|node| node.as_any().downcast_ref::<Node>())
+            .collect();
+        let mut finalized_chains = vec![Vec::new(); honest_nodes.len()];
+        for (i, node) in honest_nodes.iter().enumerate() {
+            // Starting from the highest finalized block, we construct the
+            // highest finalized chain by recursively adding the parents.
+            let mut block_hash = *node.chain.highest_finalized_block();
+            finalized_chains[i].push(block_hash);
+            while let Some(parent_hash) = node.chain.parent_of(block_hash) {
+                block_hash = parent_hash;
+                finalized_chains[i].push(block_hash);
+            }
+
+            // Consistency criterion #1: this chain consists exactly of the
+            // finalized blocks
+            if finalized_chains[i].iter().cloned().collect::<HashSet<_>>() != node.chain.finalized {
+                TestNetwork::dbg(
+                    "Finalized blocks inconsistent",
+                    Some(node.id),
+                    Some("SOUDNESS_ERROR"),
+                );
+                return false;
+            }
+
+            // We constructed the chains in opposite direction, reverse
+            finalized_chains[i].reverse();
+        }
+
+        // Consistency criterion #2: When compared pairwise with each other, the
+        // longest finalized chains of two honest nodes must be related by the
+        // prefix relation (in either direction). We first look for the height at which they
+        // diverge, to report the conflicting blocks.
+        for (i, chain1) in finalized_chains.iter().enumerate() {
+            for (j, chain2) in finalized_chains.iter().enumerate() {
+                let (node1, node2) = (honest_nodes[i], honest_nodes[j]);
+                if let Some(height) = node1.chain.divergence_point(&node2.chain) {
+                    // The chains start at the heights of their checkpoints
+                    let block1 = chain1[height - node1.chain.blocks[&chain1[0]].height];
+                    let block2 = chain2[height - node2.chain.blocks[&chain2[0]].height];
+                    TestNetwork::dbg(
+                        &format!(
+                            "Finalized chains of nodes {} and {} diverge at height {}: {} ({}) \
+                             and {} ({})",
+                            node1.id,
+                            node2.id,
+                            height,
+                            node1.chain.blocks[&block1],
+                            hex::encode(&block1[0..2]),
+                            node2.chain.blocks[&block2],
+                            hex::encode(&block2[0..2])
+                        ),
+                        None,
+                        Some("SOUDNESS_ERROR"),
+                    );
+                    return false;
+                }
+                // If the chains were pruned at different heights, compare them from the higher
+                // of their checkpoints on
+                let start1 = node1.chain.blocks[&chain1[0]].height;
+                let start2 = node2.chain.blocks[&chain2[0]].height;
+                let start = start1.max(start2);
+                let chain1 = &chain1[(start - start1).min(chain1.len())..];
+                let chain2 = &chain2[(start - start2).min(chain2.len())..];
+                if !TestNetwork::is_prefix(chain1, chain2)
+                    && !TestNetwork::is_prefix(chain2, chain1)
+                {
+                    let diff = honest_nodes[i]
+                        .chain
+                        .snapshot()
+                        .diff(&honest_nodes[j].chain.snapshot());
+                    TestNetwork::dbg(
+                        &format!(
+                            "Finalized chains nodes are not prefixes of another:\n{}",
+                            diff
+                        ),
+                        None,
+                        Some("SOUDNESS_ERROR"),
+                    );
+                    return false;
+                }
+            }
+        }
+
+        // Consistency criterion #3: honest nodes at the same finalized height must have the same
+        // checkpoint, which covers the whole finalized chain
+        let checkpoints: Vec<(usize, Hash)> = honest_nodes
+            .iter()
+            .map(|node| node.latest_checkpoint())
+            .collect();
+        for (i, (height1, checkpoint1)) in checkpoints.iter().enumerate() {
+            for (height2, checkpoint2) in checkpoints.iter().skip(i + 1) {
+                if height1 == height2 && checkpoint1 != checkpoint2 {
+                    TestNetwork::dbg(
+                        &format!("Checkpoints differ at finalized height {}", height1),
+                        Some(honest_nodes[i].id),
+                        Some("SOUDNESS_ERROR"),
+                    );
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Returns the signature of the signer on a message of the given type for the block
+    fn signature(signer: usize, message_type: MessageType, block_hash: Hash) -> Signature {
+        let signed = (message_type, block_hash);
+        Crypto::sign(signer as u64, &bincode::serialize(&signed).unwrap())
+    }
+
+    /// Returns the proposal of the block, signed by the signer
+    fn signed_proposal(block: &Block, signer: usize) -> BlockMessage {
+        let signature = TestNetwork::signature(signer, MessageType::BlockProposal, block.hash);
+        block.to_block_message(signer, signature)
+    }
+
+    /// Returns the vote of the signer for the block
+    fn signed_vote(block: &Block, signer: usize) -> VoteMessage {
+        let signature = TestNetwork::signature(signer, MessageType::Vote, block.hash);
+        block.to_vote_message(signer, signature)
+    }
+
+    fn generate_transactions(nodes: &mut [Box<dyn NodeTrait>], n: usize) {
+        // Generate some transactions to be included in blocks
+        for (i, transaction) in Workload::default().transactions(n).into_iter().enumerate() {
+            nodes[i % n].as_mut().send_transaction(transaction);
+        }
+    }
+
+    /// Extends a hand-built chain by a new block with a single transaction of TEST_SENDER.
+    fn extend_chain(chain: &mut Blockchain, parent_hash: Hash, e: usize, payload: &str) -> Hash {
+        let height = chain.blocks.get(&parent_hash).unwrap().height + 1;
+        let txs = vec![Transaction::new(TEST_SENDER, e as u64, payload.to_string())];
+        let block = Block::new(
+            Some(parent_hash),
+            e,
+            txs,
+            format!("{}/{}", e, payload),
+            height,
+            HashAlgo::default(),
+        );
+        let block_hash = block.hash;
+        chain.validate_and_extend(block, parent_hash);
+        block_hash
+    }
+
+    /// Extends a hand-built chain by a new block with a single transaction, and marks the new
+    /// block notarized and finalized.
+    fn extend_finalized(
+        chain: &mut Blockchain,
+        parent_hash: Hash,
+        e: usize,
+        payload: &str,
+    ) -> Hash {
+        let block_hash = TestNetwork::extend_chain(chain, parent_hash, e, payload);
+        chain.notarized.insert(block_hash);
+        chain.finalized.insert(block_hash);
+        block_hash
+    }
+
+    /// Print, for each honest node, the peers whose reported notarized height is behind its own
+    fn print_lagging_peers(network: &Network) {
+        for node in network.nodes.iter() {
+            let Some(node) = node.as_any().downcast_ref::<Node>() else {
+                continue;
+            };
+            for peer in node.lagging_peers() {
+                TestNetwork::dbg(
+                    &format!(
+                        "Node {} is {} heights behind",
+                        peer,
+                        node.notarized_height() - node.peer_status[&peer]
+                    ),
+                    Some(node.id),
+                    None,
+                );
+            }
+        }
+    }
+
+    fn print_test_case_header(test_case: &str) {
+        println!("==============================================");
+        println!("Running test case: {}", test_case);
+        println!("==============================================");
+    }
+
+    fn is_prefix(prefix: &[Hash], main_list: &[Hash]) -> bool {
+        main_list.starts_with(prefix)
+    }
+
+    fn dbg(text: &str, id: Option<usize>, type_: Option<&str>) {
+        Debug::dbg(text, id.unwrap_or(0), type_);
+    }
+
+    /// Returns the number of heights at which honest nodes notarized competing blocks
+    fn contested_heights(network: &Network) -> usize {
+        let mut notarized_per_height: HashMap<usize, HashSet<Hash>> = HashMap::new();
+        let honest = network
+            .nodes
+            .iter()
+            .filter_map(|node| node.as_any().downcast_ref::<Node>());
+        for node in honest {
+            for block_hash in &node.chain.notarized {
+                let height = node.chain.blocks[block_hash].height;
+                notarized_per_height
+                    .entry(height)
+                    .or_default()
+                    .insert(*block_hash);
+            }
+        }
+        notarized_per_height
+            .values()
+            .filter(|blocks| blocks.len() > 1)
+            .count()
+    }
+
+    /// Computes the aggregate statistics of a run from the chains of the honest nodes
+    fn summary(network: &Network) -> RunSummary {
+        let honest: Vec<&Node> = network
+            .nodes
+            .iter()
+            .filter_map(|node| node.as_any().downcast_ref::<Node>())
+            .collect();
+        let mut blocks: HashMap<Hash, &Block> = HashMap::new();
+        let mut notarized = HashSet::new();
+        let mut finalized = HashSet::new();
+        for node in &honest {
+            blocks.extend(node.chain.blocks.iter().map(|(hash, block)| (*hash, block)));
+            notarized.extend(node.chain.notarized.iter().copied());
+            finalized.extend(node.chain.finalized.iter().copied());
+        }
+        let roots: HashSet<Hash> = honest.iter().map(|node| node.chain.root).collect();
+        blocks.retain(|hash, _| !roots.contains(hash));
+        notarized.retain(|hash| blocks.contains_key(hash));
+        finalized.retain(|hash| blocks.contains_key(hash));
+
+        let mut children: HashMap<Hash, Vec<Hash>> = HashMap::new();
+        for (hash, block) in &blocks {
+            if let Some(parent_hash) = block.parent_hash {
+                children.entry(parent_hash).or_default().push(*hash);
+            }
+        }
+        let forks = children
+            .values()
+            .filter(|c| c.len() > 1)
+            .map(Vec::len)
+            .sum();
+        // An abandoned fork branches off a finalized block that has a finalized child
+        // The number of blocks on the longest branch from hash down to a leaf, computed with an
+        // explicit stack, since forks can be arbitrarily long
+        fn depth(hash: Hash, children: &HashMap<Hash, Vec<Hash>>) -> usize {
+            let mut deepest = 0;
+            let mut stack = vec![(hash, 1)];
+            while let Some((hash, d)) = stack.pop() {
+                deepest = deepest.max(d);
+                for child in children.get(&hash).into_iter().flatten() {
+                    stack.push((*child, d + 1));
+                }
+            }
+            deepest
+        }
+        let longest_fork = children
+            .iter()
+            .filter(|(parent, c)| {
+                (finalized.contains(*parent) || roots.contains(*parent))
+                    && c.iter().any(|child| finalized.contains(child))
+            })
+            .flat_map(|(_, c)| c.iter().filter(|child| !finalized.contains(*child)))
+            .map(|root| depth(*root, &children))
+            .max()
+            .unwrap_or(0);
+
+        let mut transactions: HashSet<&Transaction> = finalized
+            .iter()
+            .flat_map(|hash| blocks[hash].txs.iter())
+            .collect();
+        let workload: Option<HashSet<Transaction>> = network
+            .workload()
+            .map(|_| network.workload_transactions().iter().cloned().collect());
+        if let Some(workload) = &workload {
+            transactions.retain(|tx| workload.contains(tx));
+        }
+        RunSummary {
+            blocks_proposed: blocks.len(),
+            blocks_notarized: notarized.len(),
+            blocks_finalized: finalized.len(),
+            forks,
+            longest_fork,
+            fork_count: honest
+                .iter()
+                .map(|node| node.chain.fork_count())
+                .max()
+                .unwrap_or(0),
+            max_fork_depth: honest
+                .iter()
+                .map(|node| node.chain.max_fork_depth())
+                .max()
+                .unwrap_or(0),
+            participation: network.average_participation(),
+            transactions_finalized: transactions.len(),
+            transactions_submitted: workload.map(|workload| workload.len()),
+        }
+    }
+
+    /// Prints a compact summary of a run, see summary, instead of the chains of all nodes
+    fn print_summary(network: &Network) -> RunSummary {
+        let summary = TestNetwork::summary(network);
+        println!("==============================================");
+        println!("{}", summary);
+        summary
+    }
+
+    fn print_all(network: &Network) {
+        println!("==============================================");
+        for (i, node) in network.nodes.iter().enumerate() {
+            let is_attacker = if node.is_attacker() {
+                " (FAULTY/ATTACKER)"
+            } else {
+                ""
+            };
+            println!("Node #{} {}:", i, is_attacker);
+            if let Some(honest_node) = node.as_any().downcast_ref::<Node>() {
+                honest_node.chain.print_blockchain();
+                println!(
+                    "Average finalization delay: {:.2} epochs",
+                    honest_node.chain.average_finalization_delay()
+                );
+            }
+        }
+    }
+}
+
+pub fn main() {
+    // This main function is only for running the tests manually if needed
+    TestNetwork::test_honest_only_perfect_network();
+    TestNetwork::test_honest_only_with_reorder();
+    TestNetwork::test_honest_only_with_delays_then_synchrony();
+    TestNetwork::test_one_third_stopped();
+    TestNetwork::test_one_third_stopped_with_delays_then_synchrony();
+    TestNetwork::test_one_third_misbehave_with_delays_then_synchrony();
+    TestNetwork::test_one_third_fake_sigs_with_delays_then_synchrony();
+    TestNetwork::test_transactions_unambiguous();
+    TestNetwork::test_finalized_transaction_log();
+    TestNetwork::test_genesis_validator_set();
+    TestNetwork::test_replay_equivocation_attack();
+    TestNetwork::test_timeout_late_proposal();
+    TestNetwork::test_rate_limit_flooding_attacker();
+    TestNetwork::test_equivocation_evidence();
+    TestNetwork::test_divergence_point();
+    TestNetwork::test_finalization_depth();
+    TestNetwork::test_pool_processing_at_epoch_start();
+    TestNetwork::test_configurable_attacker_count();
+    TestNetwork::test_reset_chain();
+    TestNetwork::test_slow_nodes();
+    TestNetwork::test_get_block();
+    TestNetwork::test_finalized_subset_notarized();
+    TestNetwork::test_network_seed();
+    TestNetwork::test_causal_graph();
+    TestNetwork::test_consistency_across_seeds();
+    TestNetwork::test_relay_hop_count();
+    TestNetwork::test_partition();
+    TestNetwork::test_validator_churn();
+    TestNetwork::test_confirmation_time_histogram();
+    TestNetwork::test_withhold_votes();
+    TestNetwork::test_network_metrics();
+    TestNetwork::test_duplicate_transactions();
+    TestNetwork::test_prune_below();
+    TestNetwork::test_highest_notarized_tie_break();
+    TestNetwork::test_save_and_load_blockchain();
+    TestNetwork::test_crash_and_recover();
+    TestNetwork::test_stake_weighted_leader_election();
+    TestNetwork::test_events();
+    TestNetwork::test_transaction_validity();
+    TestNetwork::test_vote_all_forks();
+    TestNetwork::test_rounds_per_epoch();
+    TestNetwork::test_block_by_epoch_old_block();
+    TestNetwork::test_stake_weighted_quorum();
+    TestNetwork::test_quorum_size();
+    TestNetwork::test_adversarial_schedule();
+    TestNetwork::test_consensus_builder();
+    TestNetwork::test_safety_invariant();
+    TestNetwork::test_merkle_tx_root();
+    TestNetwork::test_tiny_networks();
+    TestNetwork::test_finalization_delay();
+    TestNetwork::test_old_parent_attacker();
+    TestNetwork::test_chain_params();
+    TestNetwork::test_relay_once();
+    TestNetwork::test_ring_topology();
+    TestNetwork::test_votes_before_block();
+    TestNetwork::test_workload();
+    TestNetwork::test_workload_with_churn();
+    TestNetwork::test_vote_equivocate();
+    TestNetwork::test_chain_diff();
+    TestNetwork::test_bytes_sent();
+    TestNetwork::test_finalize_malformed_chain();
+    TestNetwork::test_step();
+    TestNetwork::test_chain_json();
+    TestNetwork::test_reconfiguration();
+    TestNetwork::test_tx_pool_spam();
+    TestNetwork::test_peer_status();
+    TestNetwork::test_drain();
+    TestNetwork::test_forged_orphans();
+    TestNetwork::test_slow_leader();
+    TestNetwork::test_replay_trace();
+    TestNetwork::test_vrf_leader_election();
+    TestNetwork::test_block_timestamps();
+    TestNetwork::test_transaction_status();
+    TestNetwork::test_forge_votes();
+    TestNetwork::test_mempool_policy();
+    TestNetwork::test_checkpoints();
+    TestNetwork::test_colluding_attackers();
+    TestNetwork::test_missing_parent();
+    TestNetwork::test_latency_matrix();
+    TestNetwork::test_empty_blocks();
+    TestNetwork::test_fuzz_message_decoding();
+    TestNetwork::test_global_safe_height();
+    TestNetwork::test_hash_algo();
+    TestNetwork::test_backup_leader();
+    TestNetwork::test_consensus_errors();
+    TestNetwork::test_double_spend();
+    TestNetwork::test_parallel_delivery();
+    TestNetwork::test_withhold_relay();
+    TestNetwork::test_scenario();
+    TestNetwork::test_finalize_descendants();
+    TestNetwork::test_vote_weight_cache();
+    TestNetwork::test_custom_message();
+    TestNetwork::test_stale_epoch();
+    TestNetwork::test_run_summary();
+    TestNetwork::test_relay_skips_origin();
+    TestNetwork::test_threshold_safety();
+    TestNetwork::test_participation_rate();
+    TestNetwork::test_oversized_block();
+    TestNetwork::test_frame();
+    TestNetwork::test_late_joiner_sync();
+    TestNetwork::test_fork_choice();
+    TestNetwork::test_genesis_chain_id();
+    TestNetwork::test_forks();
+    TestNetwork::test_proposal_cap();
+    TestNetwork::test_consistency_divergence();
+    TestNetwork::test_replay_adversary();
+    TestNetwork::test_notarization_voters();
+    TestNetwork::test_finalize_unnotarized_ancestor();
+    TestNetwork::test_unprocessed_pool_order();
+    TestNetwork::test_get_block_after_partition();
+
+    println!("==============================================");
     println!("If there are no errors, the tests passed.");
     println!(
         "Warning: Need to check for ERROR and SOUDNESS bugs manually in the output (using grep)."
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_honest_only_perfect_network() {
+        TestNetwork::test_honest_only_perfect_network();
+    }
+
+    #[test]
+    fn test_honest_only_with_reorder() {
+        TestNetwork::test_honest_only_with_reorder();
+    }
+
+    #[test]
+    fn test_honest_only_with_delays_then_synchrony() {
+        TestNetwork::test_honest_only_with_delays_then_synchrony();
+    }
+
+    #[test]
+    fn test_one_third_stopped() {
+        TestNetwork::test_one_third_stopped();
+    }
+
+    #[test]
+    fn test_one_third_stopped_with_delays_then_synchrony() {
+        TestNetwork::test_one_third_stopped_with_delays_then_synchrony();
+    }
+
+    #[test]
+    fn test_one_third_misbehave_with_delays_then_synchrony() {
+        TestNetwork::test_one_third_misbehave_with_delays_then_synchrony();
+    }
+
+    #[test]
+    fn test_one_third_fake_sigs_with_delays_then_synchrony() {
+        TestNetwork::test_one_third_fake_sigs_with_delays_then_synchrony();
+    }
+
+    #[test]
+    fn test_transactions_unambiguous() {
+        TestNetwork::test_transactions_unambiguous();
+    }
+
+    #[test]
+    fn test_finalized_transaction_log() {
+        TestNetwork::test_finalized_transaction_log();
+    }
+
+    #[test]
+    fn test_genesis_validator_set() {
+        TestNetwork::test_genesis_validator_set();
+    }
+
+    #[test]
+    fn test_replay_equivocation_attack() {
+        TestNetwork::test_replay_equivocation_attack();
+    }
+
+    #[test]
+    fn test_timeout_late_proposal() {
+        TestNetwork::test_timeout_late_proposal();
+    }
+
+    #[test]
+    fn test_rate_limit_flooding_attacker() {
+        TestNetwork::test_rate_limit_flooding_attacker();
+    }
+
+    #[test]
+    fn test_equivocation_evidence() {
+        TestNetwork::test_equivocation_evidence();
+    }
+
+    #[test]
+    fn test_divergence_point() {
+        TestNetwork::test_divergence_point();
+    }
+
+    #[test]
+    fn test_finalization_depth() {
+        TestNetwork::test_finalization_depth();
+    }
+
+    #[test]
+    fn test_pool_processing_at_epoch_start() {
+        TestNetwork::test_pool_processing_at_epoch_start();
+    }
+
+    #[test]
+    fn test_configurable_attacker_count() {
+        TestNetwork::test_configurable_attacker_count();
+    }
+
+    #[test]
+    fn test_reset_chain() {
+        TestNetwork::test_reset_chain();
+    }
+
+    #[test]
+    fn test_slow_nodes() {
+        TestNetwork::test_slow_nodes();
+    }
+
+    #[test]
+    fn test_get_block() {
+        TestNetwork::test_get_block();
+    }
+
+    #[test]
+    fn test_finalized_subset_notarized() {
+        TestNetwork::test_finalized_subset_notarized();
+    }
+
+    #[test]
+    fn test_network_seed() {
+        TestNetwork::test_network_seed();
+    }
+
+    #[test]
+    fn test_causal_graph() {
+        TestNetwork::test_causal_graph();
+    }
+
+    #[test]
+    fn test_consistency_across_seeds() {
+        TestNetwork::test_consistency_across_seeds();
+    }
+
+    #[test]
+    fn test_relay_hop_count() {
+        TestNetwork::test_relay_hop_count();
+    }
+
+    #[test]
+    fn test_partition() {
+        TestNetwork::test_partition();
+    }
+
+    #[test]
+    fn test_validator_churn() {
+        TestNetwork::test_validator_churn();
+    }
+
+    #[test]
+    fn test_confirmation_time_histogram() {
+        TestNetwork::test_confirmation_time_histogram();
+    }
+
+    #[test]
+    fn test_withhold_votes() {
+        TestNetwork::test_withhold_votes();
+    }
+
+    #[test]
+    fn test_network_metrics() {
+        TestNetwork::test_network_metrics();
+    }
+
+    #[test]
+    fn test_duplicate_transactions() {
+        TestNetwork::test_duplicate_transactions();
+    }
+
+    #[test]
+    fn test_prune_below() {
+        TestNetwork::test_prune_below();
+    }
+
+    #[test]
+    fn test_highest_notarized_tie_break() {
+        TestNetwork::test_highest_notarized_tie_break();
+    }
+
+    #[test]
+    fn test_save_and_load_blockchain() {
+        TestNetwork::test_save_and_load_blockchain();
+    }
+
+    #[test]
+    fn test_crash_and_recover() {
+        TestNetwork::test_crash_and_recover();
+    }
+
+    #[test]
+    fn test_stake_weighted_leader_election() {
+        TestNetwork::test_stake_weighted_leader_election();
+    }
+
+    #[test]
+    fn test_events() {
+        TestNetwork::test_events();
+    }
+
+    #[test]
+    fn test_transaction_validity() {
+        TestNetwork::test_transaction_validity();
+    }
+
+    #[test]
+    fn test_vote_all_forks() {
+        TestNetwork::test_vote_all_forks();
+    }
+
+    #[test]
+    fn test_rounds_per_epoch() {
+        TestNetwork::test_rounds_per_epoch();
+    }
+
+    #[test]
+    fn test_block_by_epoch_old_block() {
+        TestNetwork::test_block_by_epoch_old_block();
+    }
+
+    #[test]
+    fn test_stake_weighted_quorum() {
+        TestNetwork::test_stake_weighted_quorum();
+    }
+
+    #[test]
+    fn test_quorum_size() {
+        TestNetwork::test_quorum_size();
+    }
+
+    #[test]
+    fn test_adversarial_schedule() {
+        TestNetwork::test_adversarial_schedule();
+    }
+
+    #[test]
+    fn test_consensus_builder() {
+        TestNetwork::test_consensus_builder();
+    }
+
+    #[test]
+    fn test_safety_invariant() {
+        TestNetwork::test_safety_invariant();
+    }
+
+    #[test]
+    fn test_merkle_tx_root() {
+        TestNetwork::test_merkle_tx_root();
+    }
+
+    #[test]
+    fn test_tiny_networks() {
+        TestNetwork::test_tiny_networks();
+    }
+
+    #[test]
+    fn test_finalization_delay() {
+        TestNetwork::test_finalization_delay();
+    }
+
+    #[test]
+    fn test_old_parent_attacker() {
+        TestNetwork::test_old_parent_attacker();
+    }
+
+    #[test]
+    fn test_chain_params() {
+        TestNetwork::test_chain_params();
+    }
+
+    #[test]
+    fn test_relay_once() {
+        TestNetwork::test_relay_once();
+    }
+
+    #[test]
+    fn test_ring_topology() {
+        TestNetwork::test_ring_topology();
+    }
+
+    #[test]
+    fn test_votes_before_block() {
+        TestNetwork::test_votes_before_block();
+    }
+
+    #[test]
+    fn test_workload() {
+        TestNetwork::test_workload();
+    }
+
+    #[test]
+    fn test_workload_with_churn() {
+        TestNetwork::test_workload_with_churn();
+    }
+
+    #[test]
+    fn test_vote_equivocate() {
+        TestNetwork::test_vote_equivocate();
+    }
+
+    #[test]
+    fn test_chain_diff() {
+        TestNetwork::test_chain_diff();
+    }
+
+    #[test]
+    fn test_bytes_sent() {
+        TestNetwork::test_bytes_sent();
+    }
+
+    #[test]
+    fn test_finalize_malformed_chain() {
+        TestNetwork::test_finalize_malformed_chain();
+    }
+
+    #[test]
+    fn test_step() {
+        TestNetwork::test_step();
+    }
+
+    #[test]
+    fn test_chain_json() {
+        TestNetwork::test_chain_json();
+    }
+
+    #[test]
+    fn test_reconfiguration() {
+        TestNetwork::test_reconfiguration();
+    }
+
+    #[test]
+    fn test_tx_pool_spam() {
+        TestNetwork::test_tx_pool_spam();
+    }
+
+    #[test]
+    fn test_peer_status() {
+        TestNetwork::test_peer_status();
+    }
+
+    #[test]
+    fn test_drain() {
+        TestNetwork::test_drain();
+    }
+
+    #[test]
+    fn test_forged_orphans() {
+        TestNetwork::test_forged_orphans();
+    }
+
+    #[test]
+    fn test_slow_leader() {
+        TestNetwork::test_slow_leader();
+    }
+
+    #[test]
+    fn test_replay_trace() {
+        TestNetwork::test_replay_trace();
+    }
+
+    #[test]
+    fn test_vrf_leader_election() {
+        TestNetwork::test_vrf_leader_election();
+    }
+
+    #[test]
+    fn test_block_timestamps() {
+        TestNetwork::test_block_timestamps();
+    }
+
+    #[test]
+    fn test_transaction_status() {
+        TestNetwork::test_transaction_status();
+    }
+
+    #[test]
+    fn test_forge_votes() {
+        TestNetwork::test_forge_votes();
+    }
+
+    #[test]
+    fn test_mempool_policy() {
+        TestNetwork::test_mempool_policy();
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        TestNetwork::test_checkpoints();
+    }
+
+    #[test]
+    fn test_colluding_attackers() {
+        TestNetwork::test_colluding_attackers();
+    }
+
+    #[test]
+    fn test_missing_parent() {
+        TestNetwork::test_missing_parent();
+    }
+
+    #[test]
+    fn test_latency_matrix() {
+        TestNetwork::test_latency_matrix();
+    }
+
+    #[test]
+    fn test_empty_blocks() {
+        TestNetwork::test_empty_blocks();
+    }
+
+    #[test]
+    fn test_fuzz_message_decoding() {
+        TestNetwork::test_fuzz_message_decoding();
+    }
+
+    #[test]
+    fn test_global_safe_height() {
+        TestNetwork::test_global_safe_height();
+    }
+
+    #[test]
+    fn test_hash_algo() {
+        TestNetwork::test_hash_algo();
+    }
+
+    #[test]
+    fn test_backup_leader() {
+        TestNetwork::test_backup_leader();
+    }
+
+    #[test]
+    fn test_consensus_errors() {
+        TestNetwork::test_consensus_errors();
+    }
+
+    #[test]
+    fn test_double_spend() {
+        TestNetwork::test_double_spend();
+    }
+
+    #[test]
+    fn test_parallel_delivery() {
+        TestNetwork::test_parallel_delivery();
+    }
+
+    #[test]
+    fn test_withhold_relay() {
+        TestNetwork::test_withhold_relay();
+    }
+
+    #[test]
+    fn test_scenario() {
+        TestNetwork::test_scenario();
+    }
+
+    #[test]
+    fn test_finalize_descendants() {
+        TestNetwork::test_finalize_descendants();
+    }
+
+    #[test]
+    fn test_vote_weight_cache() {
+        TestNetwork::test_vote_weight_cache();
+    }
+
+    #[test]
+    fn test_custom_message() {
+        TestNetwork::test_custom_message();
+    }
+
+    #[test]
+    fn test_stale_epoch() {
+        TestNetwork::test_stale_epoch();
+    }
+
+    #[test]
+    fn test_run_summary() {
+        TestNetwork::test_run_summary();
+    }
+
+    #[test]
+    fn test_relay_skips_origin() {
+        TestNetwork::test_relay_skips_origin();
+    }
+
+    #[test]
+    fn test_threshold_safety() {
+        TestNetwork::test_threshold_safety();
+    }
+
+    #[test]
+    fn test_participation_rate() {
+        TestNetwork::test_participation_rate();
+    }
+
+    #[test]
+    fn test_oversized_block() {
+        TestNetwork::test_oversized_block();
+    }
+
+    #[test]
+    fn test_frame() {
+        TestNetwork::test_frame();
+    }
+
+    #[test]
+    fn test_late_joiner_sync() {
+        TestNetwork::test_late_joiner_sync();
+    }
+
+    #[test]
+    fn test_fork_choice() {
+        TestNetwork::test_fork_choice();
+    }
+
+    #[test]
+    fn test_genesis_chain_id() {
+        TestNetwork::test_genesis_chain_id();
+    }
+
+    #[test]
+    fn test_forks() {
+        TestNetwork::test_forks();
+    }
+
+    #[test]
+    fn test_proposal_cap() {
+        TestNetwork::test_proposal_cap();
+    }
+
+    #[test]
+    fn test_consistency_divergence() {
+        TestNetwork::test_consistency_divergence();
+    }
+
+    #[test]
+    fn test_replay_adversary() {
+        TestNetwork::test_replay_adversary();
+    }
+
+    #[test]
+    fn test_notarization_voters() {
+        TestNetwork::test_notarization_voters();
+    }
+
+    #[test]
+    fn test_finalize_unnotarized_ancestor() {
+        TestNetwork::test_finalize_unnotarized_ancestor();
+    }
+
+    #[test]
+    fn test_unprocessed_pool_order() {
+        TestNetwork::test_unprocessed_pool_order();
+    }
+
+    #[test]
+    fn test_get_block_after_partition() {
+        TestNetwork::test_get_block_after_partition();
+    }
+}
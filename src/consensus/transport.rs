@@ -0,0 +1,277 @@
+use super::blockchain::Message;
+use super::network::DEFAULT_ROUNDS_PER_EPOCH;
+use super::node::NodeTrait;
+use super::utils::Debug;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// The largest frame we accept, so that a peer cannot make us allocate arbitrary amounts of memory
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// How long to wait between attempts to connect to a peer that is not listening yet
+const CONNECT_RETRY: Duration = Duration::from_millis(50);
+
+/// Sends and receives the messages of a single node over TCP, so that each node can run in its own
+/// process, see run. Node i listens on peers[i] and opens one connection to each peer it sends
+/// to. A connection starts with the id of the connecting node as a big-endian u32, followed by
+/// one frame per message: its length as a big-endian u32 and its serialization (to_bytes). Like
+/// in the simulator, we assume secure channels, i.e., we trust the id a peer announces.
+pub struct TcpTransport {
+    id: usize,
+    peers: Vec<SocketAddr>,
+    connections: HashMap<usize, TcpStream>,
+    incoming: Receiver<(usize, Box<dyn Message>)>,
+    // The address we listen on, which the listener thread accepts connections on
+    local_addr: SocketAddr,
+    // The thread accepting the connections of peers, see bind
+    listener: Option<JoinHandle<()>>,
+    // Set once the transport is dropped, upon which the listener thread stops
+    closed: Arc<AtomicBool>,
+    // The connections peers opened to us, which we close when the transport is dropped
+    accepted: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl TcpTransport {
+    /// Listens on the address of node id in peers. Messages from peers are received in the
+    /// background until the transport is dropped, which stops listening and closes all
+    /// connections. Fails if peers has no address for id.
+    pub fn bind(id: usize, peers: Vec<SocketAddr>) -> io::Result<Self> {
+        let addr = *peers
+            .get(id)
+            .ok_or_else(|| TcpTransport::unknown_peer(id))?;
+        TcpTransport::from_listener(id, TcpListener::bind(addr)?, peers)
+    }
+
+    /// Like bind, but listens on a listener bound before, e.g. to a port the OS picked, so that
+    /// no other process can take the port in between
+    pub fn from_listener(
+        id: usize,
+        listener: TcpListener,
+        peers: Vec<SocketAddr>,
+    ) -> io::Result<Self> {
+        let mut local_addr = listener.local_addr()?;
+        if local_addr.ip().is_unspecified() {
+            let loopback = match local_addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            };
+            local_addr.set_ip(loopback);
+        }
+        let (sender, incoming) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let accepted = Arc::new(Mutex::new(Vec::new()));
+        let listener = {
+            let (closed, accepted) = (closed.clone(), accepted.clone());
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    if closed.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    if let Ok(stream) = stream.try_clone() {
+                        accepted.lock().unwrap().push(stream);
+                    }
+                    let sender = sender.clone();
+                    thread::spawn(move || TcpTransport::receive_all(stream, sender));
+                }
+            })
+        };
+        Ok(TcpTransport {
+            id,
+            peers,
+            connections: HashMap::new(),
+            incoming,
+            local_addr,
+            listener: Some(listener),
+            closed,
+            accepted,
+        })
+    }
+
+    /// The error for a node id that peers has no address for
+    fn unknown_peer(j: usize) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("No address for node {}", j),
+        )
+    }
+
+    /// Reads the frames of one connection and passes the messages on, until the peer closes the
+    /// connection or sends something malformed
+    fn receive_all(mut stream: TcpStream, sender: Sender<(usize, Box<dyn Message>)>) {
+        let mut id = [0; 4];
+        if stream.read_exact(&mut id).is_err() {
+            return;
+        }
+        let j = u32::from_be_bytes(id) as usize;
+        while let Ok(bytes) = read_frame(&mut stream) {
+            let Some(m) = <dyn Message>::from_bytes(&bytes) else {
+                return;
+            };
+            if sender.send((j, m)).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Connects to peer j, retrying until the deadline if it is not listening yet. Fails if peers
+    /// has no address for j.
+    fn connect(&mut self, j: usize, deadline: Instant) -> io::Result<&mut TcpStream> {
+        if !self.connections.contains_key(&j) {
+            let addr = *self
+                .peers
+                .get(j)
+                .ok_or_else(|| TcpTransport::unknown_peer(j))?;
+            let mut stream = loop {
+                match TcpStream::connect(addr) {
+                    Ok(stream) => break stream,
+                    Err(err) if Instant::now() >= deadline => return Err(err),
+                    Err(_) => thread::sleep(CONNECT_RETRY),
+                }
+            };
+            stream.set_nodelay(true)?;
+            stream.write_all(&(self.id as u32).to_be_bytes())?;
+            self.connections.insert(j, stream);
+        }
+        Ok(self.connections.get_mut(&j).unwrap())
+    }
+
+    /// Connects to all peers, waiting up to timeout for them to start listening
+    pub fn connect_all(&mut self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let id = self.id;
+        for j in (0..self.peers.len()).filter(|j| *j != id) {
+            self.connect(j, deadline)?;
+        }
+        Ok(())
+    }
+
+    /// Sends message m to peer j. Fails without sending anything if m cannot be serialized. If
+    /// the connection fails, it is dropped, and the next message to j opens a new one.
+    pub fn send(&mut self, m: Box<dyn Message>, j: usize) -> io::Result<()> {
+        let bytes = m.to_bytes()?;
+        let stream = self.connect(j, Instant::now())?;
+        let result = write_frame(stream, &bytes);
+        if result.is_err() {
+            self.connections.remove(&j);
+        }
+        result
+    }
+
+    /// Returns the next message received, together with its sender, waiting up to timeout
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<(usize, Box<dyn Message>)> {
+        self.incoming.recv_timeout(timeout).ok()
+    }
+
+    /// Sends the outgoing messages of the node. Messages to unreachable peers are lost.
+    fn send_outgoing(&mut self, node: &mut dyn NodeTrait) {
+        for (j, m) in node.clear_outgoing_messages() {
+            if let Err(err) = self.send(m, j) {
+                Debug::dbg(
+                    &format!("Cannot send to node {}: {}", j, err),
+                    self.id,
+                    Some("NETWORK"),
+                );
+            }
+        }
+    }
+
+    /// Runs the node for the given number of epochs in real time. All processes must be started
+    /// with the same start time, which is when epoch 1 begins, and the same epoch duration.
+    /// Like in the simulator, each epoch is divided into DEFAULT_ROUNDS_PER_EPOCH rounds, and
+    /// the node processes its unprocessed pool and times out the epoch at its end. The messages
+    /// the node sends are sent right away.
+    pub fn run(
+        &mut self,
+        node: &mut dyn NodeTrait,
+        start: SystemTime,
+        epochs: usize,
+        epoch_duration: Duration,
+    ) {
+        let round_duration = epoch_duration / DEFAULT_ROUNDS_PER_EPOCH as u32;
+        // Translate the start time, which all processes share, into our monotonic clock
+        let start = Instant::now()
+            + start
+                .duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO);
+        let mut round = 0;
+        for e in 1..=epochs {
+            let epoch_start = start + epoch_duration * (e - 1) as u32;
+            self.receive_until(node, epoch_start);
+            node.new_epoch(e, round);
+            self.send_outgoing(node);
+            for r in 0..DEFAULT_ROUNDS_PER_EPOCH {
+                round += 1;
+                self.receive_until(node, epoch_start + round_duration * (r + 1) as u32);
+                if r == 0 {
+                    node.process_unprocessed_pool();
+                }
+                node.new_round();
+                self.send_outgoing(node);
+            }
+            node.process_unprocessed_pool();
+            node.timeout(e);
+            self.send_outgoing(node);
+        }
+    }
+
+    /// Passes the messages received until the deadline to the node, and sends its replies
+    fn receive_until(&mut self, node: &mut dyn NodeTrait, deadline: Instant) {
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return;
+            }
+            if let Some((j, m)) = self.recv_timeout(timeout) {
+                node.incoming_message(&*m, j);
+                self.send_outgoing(node);
+            }
+        }
+    }
+}
+
+impl Drop for TcpTransport {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        // The listener thread blocks until the next connection, so we connect to wake it up. If
+        // that fails, we leave the thread behind rather than waiting for it forever.
+        if TcpStream::connect(self.local_addr).is_ok() {
+            if let Some(listener) = self.listener.take() {
+                let _ = listener.join();
+            }
+        }
+        for stream in self.accepted.lock().unwrap().iter() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Writes a frame: the length of bytes as a big-endian u32, followed by bytes
+pub fn write_frame(writer: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Frame too long",
+        ));
+    }
+    writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    writer.write_all(bytes)
+}
+
+/// Reads a frame written by write_frame, and returns its bytes
+pub fn read_frame(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Frame too long"));
+    }
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
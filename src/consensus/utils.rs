@@ -1,37 +1,161 @@
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 pub type Hash = [u8; 32];
 
 pub struct Crypto;
 
+/// The hash function of a chain, which determines the block hashes, the Merkle roots over their
+/// transactions, the transaction hashes, the leader election and the VRF outputs. It is part of
+/// the genesis configuration, so all nodes of a network use the same one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashAlgo {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgo {
+    /// Hashes a given byte array with this hash function
+    pub fn hash(self, x: &[u8]) -> Hash {
+        match self {
+            HashAlgo::Sha256 => {
+                let mut hasher = Sha256::new();
+                hasher.update(x);
+                let result = hasher.finalize();
+                let mut hash = [0u8; 32];
+                hash.copy_from_slice(&result);
+                hash
+            }
+            HashAlgo::Blake3 => *blake3::hash(x).as_bytes(),
+        }
+    }
+}
+
 pub type Signature = (u64, Vec<u8>);
 
+/// The proof that a VRF output was computed correctly by its signer
+pub type VrfProof = Signature;
+
+/// The sibling hashes on the path from a leaf to the Merkle root, bottom up. The flag states
+/// whether the sibling is the left child.
+pub type MerkleProof = Vec<(Hash, bool)>;
+
 impl Crypto {
     /// Converts a variable into bytes
     pub fn var_to_bytes(x: usize) -> Vec<u8> {
         x.to_le_bytes().to_vec()
     }
 
-    /// Hashes a given byte array
-    pub fn hash(x: &[u8]) -> Hash {
-        let mut hasher = Sha256::new();
-        hasher.update(x);
-        let result = hasher.finalize();
-        let mut hash = [0u8; 32];
-        hash.copy_from_slice(&result);
-        hash
+    /// Hashes a given byte array with the given hash function
+    pub fn hash(hash_algo: HashAlgo, x: &[u8]) -> Hash {
+        hash_algo.hash(x)
     }
 
-    /// Converts a variable into bytes and hashes the result
-    pub fn sha256_var(x: usize) -> Hash {
-        Crypto::hash(&Crypto::var_to_bytes(x))
+    /// Converts a variable into bytes and hashes the result with the given hash function
+    pub fn hash_var(hash_algo: HashAlgo, x: usize) -> Hash {
+        Crypto::hash(hash_algo, &Crypto::var_to_bytes(x))
     }
-    
+
     /// Shorts a given hash to fit into a usize
     pub fn short_hash(x: &[u8]) -> usize {
         let short_hash = &x[..8];
         usize::from_le_bytes(short_hash.try_into().unwrap())
     }
 
+    fn merkle_leaf(hash_algo: HashAlgo, leaf: &[u8]) -> Hash {
+        let mut bytes = vec![0u8];
+        bytes.extend_from_slice(leaf);
+        hash_algo.hash(&bytes)
+    }
+
+    fn merkle_node(hash_algo: HashAlgo, left: &Hash, right: &Hash) -> Hash {
+        let mut bytes = vec![1u8];
+        bytes.extend_from_slice(left);
+        bytes.extend_from_slice(right);
+        hash_algo.hash(&bytes)
+    }
+
+    /// Returns the levels of the Merkle tree over the leaves, from the leaf hashes up to the
+    /// root. Leaves and inner nodes are hashed with different prefixes, so that an inner node
+    /// cannot be passed off as a leaf. An unpaired node is moved up a level unchanged.
+    fn merkle_levels(hash_algo: HashAlgo, leaves: &[Vec<u8>]) -> Vec<Vec<Hash>> {
+        let mut levels: Vec<Vec<Hash>> = vec![leaves
+            .iter()
+            .map(|leaf| Crypto::merkle_leaf(hash_algo, leaf))
+            .collect()];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => Crypto::merkle_node(hash_algo, left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        levels
+    }
+
+    /// Computes the Merkle root over the leaves with the given hash function. The root of no
+    /// leaves is the hash of the empty byte array.
+    pub fn merkle_root(hash_algo: HashAlgo, leaves: &[Vec<u8>]) -> Hash {
+        match Crypto::merkle_levels(hash_algo, leaves)
+            .last()
+            .unwrap()
+            .first()
+        {
+            Some(root) => *root,
+            None => hash_algo.hash(&[]),
+        }
+    }
+
+    /// Returns the proof that the leaf at the given index is included in the Merkle root over
+    /// the leaves, or None if there is no such leaf
+    pub fn merkle_proof(
+        hash_algo: HashAlgo,
+        leaves: &[Vec<u8>],
+        index: usize,
+    ) -> Option<MerkleProof> {
+        if index >= leaves.len() {
+            return None;
+        }
+        let levels = Crypto::merkle_levels(hash_algo, leaves);
+        let mut proof = Vec::new();
+        let mut i = index;
+        for level in &levels[..levels.len() - 1] {
+            let sibling = i ^ 1;
+            if sibling < level.len() {
+                proof.push((level[sibling], sibling < i));
+            }
+            i /= 2;
+        }
+        Some(proof)
+    }
+
+    /// Checks that the proof shows the inclusion of the leaf in the Merkle root, computed with
+    /// the given hash function
+    pub fn verify_merkle_proof(
+        hash_algo: HashAlgo,
+        root: &Hash,
+        leaf: &[u8],
+        proof: &MerkleProof,
+    ) -> bool {
+        let computed = proof.iter().fold(
+            Crypto::merkle_leaf(hash_algo, leaf),
+            |hash, (sibling, is_left)| {
+                if *is_left {
+                    Crypto::merkle_node(hash_algo, sibling, &hash)
+                } else {
+                    Crypto::merkle_node(hash_algo, &hash, sibling)
+                }
+            },
+        );
+        &computed == root
+    }
+
     /// FIXME Dummy crypto!
     pub fn sign(signer: u64, x: &[u8]) -> Signature {
         (signer, x.to_vec())
@@ -41,13 +165,48 @@ impl Crypto {
     pub fn check_signature(signer: u64, plaintext: &[u8], signature: &Signature) -> bool {
         signature == &Crypto::sign(signer, plaintext)
     }
+
+    /// Evaluates the verifiable random function (VRF) of signer on input. Returns the output,
+    /// which only the signer can compute, but which looks random to everyone else, and the proof
+    /// that it is correct. The output is the hash of a deterministic signature on the input, computed
+    /// with the given hash function.
+    /// FIXME Dummy crypto! Like signatures, anyone can compute the output of any signer.
+    pub fn vrf(hash_algo: HashAlgo, signer: u64, input: &[u8]) -> (Hash, VrfProof) {
+        let proof = Crypto::sign(signer, &Crypto::vrf_input(input));
+        (Crypto::vrf_output(hash_algo, &proof), proof)
+    }
+
+    /// Checks that output is the VRF output of signer on input, as shown by the proof
+    pub fn verify_vrf(
+        hash_algo: HashAlgo,
+        signer: u64,
+        input: &[u8],
+        output: &Hash,
+        proof: &VrfProof,
+    ) -> bool {
+        Crypto::check_signature(signer, &Crypto::vrf_input(input), proof)
+            && output == &Crypto::vrf_output(hash_algo, proof)
+    }
+
+    /// The VRF signs its input with a prefix, such that VRF proofs cannot be passed off as
+    /// signatures on protocol messages
+    fn vrf_input(input: &[u8]) -> Vec<u8> {
+        let mut bytes = b"vrf".to_vec();
+        bytes.extend_from_slice(input);
+        bytes
+    }
+
+    fn vrf_output(hash_algo: HashAlgo, proof: &VrfProof) -> Hash {
+        let mut bytes = proof.0.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&proof.1);
+        Crypto::hash(hash_algo, &bytes)
+    }
 }
 
 pub struct Debug;
 
 impl Debug {
     /// Used for debugging purposes
-
     pub fn dbg(m: &str, id: usize, type_: Option<&str>) {
         let colors = [
             "\x1b[0m",     // default
@@ -66,6 +225,7 @@ impl Debug {
                 "SOUDNESS_ERROR" => {
                     message = format!("\x1b[1;31m !SOUDNESS_ERROR! {}", m);
                 }
+                "WARNING" => message = format!("\x1b[1;33m !WARNING! {}", m),
                 "ATTACK" => message = format!("\x1b[31m !ATTACK! {}", m),
                 "USER_ATTACK" => message = format!("\x1b[31m USER ATTACK {}", m),
                 "NETWORK" => message = format!("\x1b[1;34m NETWORK {}", m),
@@ -79,6 +239,6 @@ impl Debug {
             colors[0]
         };
 
-        println!("{}{}{}", color_code, message, "\x1b[0m");
+        println!("{}{}\x1b[0m", color_code, message);
     }
 }
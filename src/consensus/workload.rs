@@ -0,0 +1,104 @@
+use super::blockchain::Transaction;
+use super::network::DEFAULT_SEED;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// The length of the transaction payloads of a workload
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PayloadSize {
+    // "This is transaction number i"
+    Descriptive,
+    // A length between the given minimum and maximum (inclusive), chosen at random
+    Uniform(usize, usize),
+}
+
+/// When the transactions of a workload arrive. Epoch 0 is before the first epoch of the network.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Arrival {
+    // All transactions arrive in epoch 0
+    Upfront,
+    // The given number of transactions arrive in every epoch from epoch 1 on
+    Steady(usize),
+    // The given number of transactions arrive in every epoch that is a multiple of every
+    Bursts { every: usize, size: usize },
+}
+
+/// A reproducible set of transactions submitted by users, together with their arrival schedule.
+/// Transaction i is submitted to node i mod n, with that node as sender. Given the same seed,
+/// a workload always produces the same transactions.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Workload {
+    pub count: usize,
+    pub payload_size: PayloadSize,
+    pub arrival: Arrival,
+    pub seed: [u8; 32],
+}
+
+impl Workload {
+    /// The given number of transactions with descriptive payloads, all arriving upfront
+    pub fn uniform(count: usize) -> Self {
+        Workload {
+            count,
+            payload_size: PayloadSize::Descriptive,
+            arrival: Arrival::Upfront,
+            seed: DEFAULT_SEED,
+        }
+    }
+
+    pub fn payload_size(mut self, payload_size: PayloadSize) -> Self {
+        if let PayloadSize::Uniform(min, max) = payload_size {
+            assert!(
+                0 < min && min <= max,
+                "Payloads must be non-empty, and min <= max"
+            );
+        }
+        self.payload_size = payload_size;
+        self
+    }
+
+    pub fn arrival(mut self, arrival: Arrival) -> Self {
+        self.arrival = arrival;
+        self
+    }
+
+    /// The seed of the random number generator that chooses payload sizes
+    pub fn seed(mut self, seed: [u8; 32]) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Returns all transactions of the workload, for a network of n nodes
+    pub fn transactions(&self, n: usize) -> Vec<Transaction> {
+        let mut rng = StdRng::from_seed(self.seed);
+        (0..self.count)
+            .map(|i| {
+                let payload = match self.payload_size {
+                    PayloadSize::Descriptive => format!("This is transaction number {}", i),
+                    PayloadSize::Uniform(min, max) => "x".repeat(rng.gen_range(min..=max)),
+                };
+                Transaction::new(i % n, (i / n) as u64, payload)
+            })
+            .collect()
+    }
+
+    /// Returns the indices of the transactions that arrive in the given epoch
+    pub fn arriving(&self, epoch: usize) -> Range<usize> {
+        let batch =
+            |b: usize, size: usize| (b * size).min(self.count)..((b + 1) * size).min(self.count);
+        match self.arrival {
+            Arrival::Upfront if epoch == 0 => 0..self.count,
+            Arrival::Steady(size) if epoch > 0 => batch(epoch - 1, size),
+            Arrival::Bursts { every, size } if epoch > 0 && epoch.is_multiple_of(every) => {
+                batch(epoch / every - 1, size)
+            }
+            _ => 0..0,
+        }
+    }
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Workload::uniform(1000)
+    }
+}
@@ -0,0 +1,21 @@
+//! A simulator of a simple consensus protocol, with honest and attacker nodes that
+//! exchange messages over configurable network models.
+
+pub mod consensus;
+
+pub use consensus::attacker_node::AttackerNode;
+pub use consensus::blockchain::{
+    Block, BlockMessage, Blockchain, CertifiedBlock, ChainDag, ChainDiff, ChainSnapshot, DagEdge,
+    DagNode, EquivocationProof, GenesisConfig, GenesisError, GetBlockMessage, Message,
+    ReconfigMessage, SafetyViolation, SyncRequest, SyncResponse, TimeoutMarker, Transaction,
+    VoteMessage,
+};
+pub use consensus::builder::ConsensusBuilder;
+pub use consensus::network::{Network, NetworkModel, StepResult, Topology, TraceEntry};
+pub use consensus::node::{
+    ConsensusError, MessageHandler, Node, NodeTrait, TxPoolOverflow, TxStatus,
+};
+pub use consensus::scenario::{Scenario, ScenarioError};
+pub use consensus::transport::TcpTransport;
+pub use consensus::utils::HashAlgo;
+pub use consensus::workload::{Arrival, PayloadSize, Workload};
@@ -1,6 +1,31 @@
-mod consensus;
+use consensus::Scenario;
+use std::env;
+use std::process;
 
 fn main() {
-    // The only main function we have is that of our testing framework, which runs all tests
-    consensus::test_network::main();
+    let paths: Vec<String> = env::args().skip(1).collect();
+    if paths.is_empty() {
+        // Without scenario files, we run all tests of our testing framework
+        consensus::consensus::test_network::main();
+        return;
+    }
+    for path in paths {
+        let scenario = Scenario::load(&path).unwrap_or_else(|err| {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        });
+        let network = scenario.run();
+        let total = network.metrics().total();
+        println!(
+            "{}: {} epochs, {} blocks proposed, {} messages sent, {} notarizations, \
+             {} finalizations, finalized by all honest nodes up to height {}",
+            path,
+            scenario.epochs,
+            total.blocks_proposed,
+            total.messages_sent,
+            total.blocks_notarized,
+            total.blocks_finalized,
+            network.global_safe_height()
+        );
+    }
 }
@@ -0,0 +1,68 @@
+//! Runs 4 honest nodes as separate tcp_node processes that reach consensus over TCP connections
+//! on localhost
+
+use std::net::{SocketAddr, TcpListener};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// The time the processes get to start up and connect to each other before epoch 1 begins
+const STARTUP: Duration = Duration::from_secs(3);
+
+// The duration of an epoch. Generous, so that all messages of an epoch arrive within it even on
+// a busy machine.
+const EPOCH_MS: u64 = 500;
+
+/// Returns a port on localhost that the OS picked as free
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Returns the finalized chain that a tcp_node process printed at the end, as block hashes
+fn finalized_chain(stdout: &str) -> Vec<String> {
+    let line = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("finalized chain:"))
+        .expect("The node did not print its finalized chain");
+    line.split_whitespace().map(str::to_string).collect()
+}
+
+#[test]
+fn test_tcp_nodes_reach_consensus() {
+    let n = 4;
+    let epochs = 8;
+    let peers: Vec<String> = (0..n).map(|_| free_addr().to_string()).collect();
+    let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + STARTUP;
+    let children: Vec<_> = (0..n)
+        .map(|i| {
+            Command::new(env!("CARGO_BIN_EXE_tcp_node"))
+                .arg(i.to_string())
+                .arg(epochs.to_string())
+                .arg(EPOCH_MS.to_string())
+                .arg(start.as_millis().to_string())
+                .args(&peers)
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    let chains: Vec<Vec<String>> = children
+        .into_iter()
+        .enumerate()
+        .map(|(i, child)| {
+            let output = child.wait_with_output().unwrap();
+            assert!(output.status.success(), "Node {} failed", i);
+            finalized_chain(&String::from_utf8(output.stdout).unwrap())
+        })
+        .collect();
+
+    // Every node finalized blocks beyond genesis, and the finalized chains are prefixes of each
+    // other
+    let longest = chains.iter().max_by_key(|chain| chain.len()).unwrap();
+    for chain in chains.iter() {
+        assert!(chain.len() > 1);
+        assert_eq!(chain[..], longest[..chain.len()]);
+    }
+}